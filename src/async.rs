@@ -0,0 +1,225 @@
+//! async
+//!
+//! Await a sync from async code, whatever the runtime.
+//!
+//! No async I/O is done: the sync runs on its own threads, with blocking
+//! I/O, exactly like `rusync::Syncer::sync()`. Only waiting for it and for
+//! its progress is asynchronous, so the executor is never blocked.
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use crate::cancel::CancellationToken;
+use crate::error::SyncError;
+use crate::progress::{Progress, ProgressInfo};
+use crate::sync::{self, Stats, SyncOptions};
+
+/// Like `rusync::Syncer`, which it derefs to for configuration, but `sync()`
+/// returns a Future, and progress is reported by `progress()` instead of a
+/// ProgressInfo. The sync still runs on a thread, with blocking I/O
+///
+/// ```no_run
+/// # async fn example() -> Result<(), rusync::SyncError> {
+/// let source = std::path::Path::new("src");
+/// let destination = std::path::Path::new("dest");
+/// let mut syncer = rusync::r#async::Syncer::new(source, destination, Default::default());
+/// syncer.exclude("*.tmp");
+/// let mut progress = syncer.progress();
+/// let sync = syncer.sync();
+/// // poll `progress.next()` and `sync` concurrently, for instance with
+/// // tokio::select! or futures::join!
+/// let stats = sync.await?;
+/// println!("Transfered {} files", stats.copied);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Syncer {
+    syncer: sync::Syncer,
+    progress: Arc<Mutex<Queue<Progress>>>,
+}
+
+impl Syncer {
+    pub fn new(source: &Path, destination: &Path, options: SyncOptions) -> Syncer {
+        let (sender, progress) = queue();
+        let progress_info = StreamingProgressInfo { sender };
+        Syncer {
+            syncer: sync::Syncer::new(source, destination, options, Box::new(progress_info)),
+            progress,
+        }
+    }
+
+    /// Returns a stream of the progress of the transfer, which ends with
+    /// the sync. Every update is kept until it is polled, and is only
+    /// yielded by one of the streams returned here
+    pub fn progress(&self) -> ProgressStream {
+        ProgressStream {
+            queue: self.progress.clone(),
+        }
+    }
+
+    /// Start syncing in the background, and return a Future resolving
+    /// to the result of `rusync::Syncer::sync()`.
+    ///
+    /// Dropping the Future before it resolves cancels the sync, see
+    /// `rusync::Syncer::cancellation_token()`.
+    pub fn sync(self) -> SyncFuture {
+        let cancel = self.syncer.cancellation_token();
+        let (sender, result) = queue();
+        let syncer = self.syncer;
+        thread::spawn(move || sender.send(syncer.sync()));
+        SyncFuture {
+            result,
+            cancel,
+            done: false,
+        }
+    }
+}
+
+impl Deref for Syncer {
+    type Target = sync::Syncer;
+
+    fn deref(&self) -> &sync::Syncer {
+        &self.syncer
+    }
+}
+
+impl DerefMut for Syncer {
+    fn deref_mut(&mut self) -> &mut sync::Syncer {
+        &mut self.syncer
+    }
+}
+
+/// Returned by `Syncer::sync()`
+pub struct SyncFuture {
+    result: Arc<Mutex<Queue<Result<Stats, SyncError>>>>,
+    cancel: CancellationToken,
+    done: bool,
+}
+
+impl Future for SyncFuture {
+    type Output = Result<Stats, SyncError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match poll_queue(&this.result, cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(result) => {
+                this.done = true;
+                // The queue is closed without a result if the thread panicked
+                Poll::Ready(result.unwrap_or_else(|| Err(SyncError::Join("syncer".to_string()))))
+            }
+        }
+    }
+}
+
+impl Drop for SyncFuture {
+    fn drop(&mut self) {
+        if !self.done {
+            self.cancel.cancel();
+        }
+    }
+}
+
+/// A series of values produced asynchronously. Same as the `Stream` trait
+/// of the futures crate, so that implementations are easy to wrap
+pub trait Stream {
+    type Item;
+
+    /// Returns `Poll::Ready(None)` once the stream is over
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>;
+}
+
+/// Returned by `Syncer::progress()`
+pub struct ProgressStream {
+    queue: Arc<Mutex<Queue<Progress>>>,
+}
+
+impl ProgressStream {
+    /// Wait for the next update, or None once the sync is done
+    pub async fn next(&mut self) -> Option<Progress> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl Stream for ProgressStream {
+    type Item = Progress;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Progress>> {
+        poll_queue(&self.queue, cx)
+    }
+}
+
+struct StreamingProgressInfo {
+    sender: QueueSender<Progress>,
+}
+
+impl ProgressInfo for StreamingProgressInfo {
+    fn progress(&mut self, progress: &Progress) {
+        self.sender.send(progress.clone());
+    }
+}
+
+// Holds the values sent from a thread until they are polled
+struct Queue<T> {
+    values: VecDeque<T>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+// Closes the queue when dropped, including when the thread panics
+struct QueueSender<T> {
+    queue: Arc<Mutex<Queue<T>>>,
+}
+
+impl<T> QueueSender<T> {
+    fn send(&self, value: T) {
+        let mut queue = self.queue.lock().expect("queue poisoned");
+        queue.values.push_back(value);
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        // Do not panic again if the lock was poisoned by a panic
+        if let Ok(mut queue) = self.queue.lock() {
+            queue.closed = true;
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+fn queue<T>() -> (QueueSender<T>, Arc<Mutex<Queue<T>>>) {
+    let queue = Arc::new(Mutex::new(Queue {
+        values: VecDeque::new(),
+        closed: false,
+        waker: None,
+    }));
+    (
+        QueueSender {
+            queue: queue.clone(),
+        },
+        queue,
+    )
+}
+
+fn poll_queue<T>(queue: &Mutex<Queue<T>>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+    let mut queue = queue.lock().expect("queue poisoned");
+    if let Some(value) = queue.values.pop_front() {
+        return Poll::Ready(Some(value));
+    }
+    if queue.closed {
+        return Poll::Ready(None);
+    }
+    queue.waker = Some(cx.waker().clone());
+    Poll::Pending
+}
@@ -0,0 +1,139 @@
+//! backup
+//!
+//! Move destination entries out of the way instead of overwriting or
+//! deleting them, either to a backup dir, keeping their path relative to
+//! the destination, or beside themselves, with a suffix added to their name
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error};
+
+#[derive(Clone, Debug)]
+pub struct Backup {
+    dir: Option<PathBuf>,
+    suffix: String,
+}
+
+impl Backup {
+    /// Backups in `dir`, with `suffix` added to their names
+    pub fn new(dir: &Path, suffix: &str) -> Backup {
+        Backup {
+            dir: Some(dir.to_path_buf()),
+            suffix: suffix.to_string(),
+        }
+    }
+
+    /// Backups next to the entries, with `suffix`, which must not be empty,
+    /// added to their names
+    pub fn beside(suffix: &str) -> Backup {
+        Backup {
+            dir: None,
+            suffix: suffix.to_string(),
+        }
+    }
+
+    /// Returns true if `path` is a backup: in the backup dir, which happens
+    /// when the backup dir is inside the destination, or with the suffix
+    /// when backups are next to the entries
+    pub fn contains(&self, path: &Path) -> bool {
+        match &self.dir {
+            Some(dir) => path.starts_with(dir),
+            None => path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().ends_with(&self.suffix)),
+        }
+    }
+
+    /// Move the entry at `path` to its backup, `rel_path` being its path
+    /// relative to the destination. Replaces the previous backup of the
+    /// same entry, if any
+    pub fn save(&self, path: &Path, rel_path: &Path) -> Result<(), Error> {
+        let backup_path = match &self.dir {
+            Some(dir) => with_suffix(&dir.join(rel_path), &self.suffix),
+            None => with_suffix(path, &self.suffix),
+        };
+        let context = || {
+            format!(
+                "Could not move '{}' to '{}'",
+                path.display(),
+                backup_path.display()
+            )
+        };
+        if let Some(parent) = backup_path.parent() {
+            fs::create_dir_all(parent).with_context(context)?;
+        }
+        match fs::symlink_metadata(&backup_path) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&backup_path),
+            Ok(_) => fs::remove_file(&backup_path),
+            Err(_) => Ok(()),
+        }
+        .with_context(context)?;
+        match fs::rename(path, &backup_path) {
+            Err(ref e) if is_cross_device(e) && path.is_file() => {
+                fs::copy(path, &backup_path).with_context(context)?;
+                fs::remove_file(path).with_context(context)?;
+                Ok(())
+            }
+            other => other.with_context(context),
+        }
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(unix)]
+fn is_cross_device(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(libc::EXDEV)
+}
+
+#[cfg(not(unix))]
+fn is_cross_device(_error: &io::Error) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_save_replaces_previous_backup() {
+        let tmp_dir = TempDir::new("test-rusync-backup").unwrap();
+        let backup = Backup::new(&tmp_dir.path().join("backup"), "");
+        let path = tmp_dir.path().join("foo.txt");
+        let rel_path = Path::new("sub/foo.txt");
+
+        fs::write(&path, "first").unwrap();
+        backup.save(&path, rel_path).unwrap();
+        fs::write(&path, "second").unwrap();
+        backup.save(&path, rel_path).unwrap();
+
+        assert!(!path.exists());
+        let backup_path = tmp_dir.path().join("backup/sub/foo.txt");
+        assert_eq!(fs::read_to_string(backup_path).unwrap(), "second");
+        assert!(backup.contains(&tmp_dir.path().join("backup/sub")));
+        assert!(!backup.contains(&path));
+    }
+
+    #[test]
+    fn test_save_beside() {
+        let tmp_dir = TempDir::new("test-rusync-backup").unwrap();
+        let backup = Backup::beside(".bak");
+        let path = tmp_dir.path().join("foo.txt");
+
+        fs::write(&path, "first").unwrap();
+        backup.save(&path, Path::new("foo.txt")).unwrap();
+
+        assert!(!path.exists());
+        let backup_path = tmp_dir.path().join("foo.txt.bak");
+        assert_eq!(fs::read_to_string(&backup_path).unwrap(), "first");
+        assert!(backup.contains(&backup_path));
+        assert!(!backup.contains(&path));
+    }
+}
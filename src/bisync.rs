@@ -0,0 +1,609 @@
+//! bisync
+//!
+//! Two-way sync between the source and the destination, see
+//! `Syncer::bisync()`.
+//!
+//! What both directories looked like after the previous run is recorded in
+//! a `.rusync-bisync` state file, in the root of the destination. A file
+//! created, modified or deleted on one side since then gets created,
+//! modified or deleted on the other side too. A file modified on both sides
+//! is a conflict, resolved with `SyncOptions.conflicts`. A file modified on
+//! one side and deleted on the other one is copied back.
+//! A file found on both sides but missing from the state file is only a
+//! conflict if its contents differ.
+//!
+//! Only regular files and directories are synced.
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use filetime::FileTime;
+
+use crate::cancel::CancellationToken;
+use crate::entry::Entry;
+use crate::error::SyncError;
+use crate::filters::{self, Filters};
+use crate::fsops;
+use crate::state::{Stamp, SyncState};
+use crate::sync::{ConflictPolicy, SyncOptions};
+use crate::trash;
+
+/// Name of the state file, in the root of the destination
+pub const BISYNC_STATE_FILE_NAME: &str = ".rusync-bisync";
+
+/// What `Syncer::bisync()` did, or would do with `SyncOptions.dry_run`.
+/// All paths are relative, and sorted
+#[derive(Debug, Default)]
+pub struct Bisync {
+    /// Files copied from the source to the destination
+    pub to_destination: Vec<PathBuf>,
+    /// Files copied from the destination to the source
+    pub to_source: Vec<PathBuf>,
+    /// Entries deleted in the destination, since they were deleted in the
+    /// source
+    pub deleted_in_destination: Vec<PathBuf>,
+    /// Entries deleted in the source, since they were deleted in the
+    /// destination
+    pub deleted_in_source: Vec<PathBuf>,
+    /// Files changed on both sides, and how the conflict was resolved
+    pub conflicts: Vec<(PathBuf, String)>,
+    /// Entries that could not be synced, and what went wrong
+    pub errors: Vec<(PathBuf, io::Error)>,
+}
+
+impl Bisync {
+    fn sort(&mut self) {
+        self.to_destination.sort();
+        self.to_source.sort();
+        self.deleted_in_destination.sort();
+        self.deleted_in_source.sort();
+        self.conflicts.sort_by(|a, b| a.0.cmp(&b.0));
+        self.errors.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Side {
+    Source,
+    Destination,
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    // Copy the file from this side to the other one
+    Copy(Side, PathBuf),
+    Delete(Side, PathBuf),
+    // Both sides are the same already
+    Record(PathBuf),
+    // Leave both sides alone, so that the file still conflicts next time
+    Keep(PathBuf),
+    // Rename the destination file, copy it to the source, and then copy the
+    // source file to the destination
+    KeepBoth(PathBuf),
+}
+
+#[derive(Default)]
+struct Tree {
+    files: HashMap<PathBuf, Stamp>,
+    dirs: HashSet<PathBuf>,
+    // Directories that could not be read: what they contain is unknown
+    unreadable: Vec<PathBuf>,
+}
+
+impl Tree {
+    fn knows(&self, rel_path: &Path) -> bool {
+        !self.unreadable.iter().any(|dir| rel_path.starts_with(dir))
+    }
+}
+
+struct Roots<'a> {
+    source: &'a Path,
+    destination: &'a Path,
+}
+
+impl Roots<'_> {
+    fn get(&self, side: Side) -> &Path {
+        match side {
+            Side::Source => self.source,
+            Side::Destination => self.destination,
+        }
+    }
+}
+
+pub fn bisync(
+    source: &Path,
+    destination: &Path,
+    filters: &Filters,
+    opts: SyncOptions,
+    cancel: &CancellationToken,
+) -> Result<Bisync, SyncError> {
+    let mut report = Bisync::default();
+    let mut source_tree = Tree::default();
+    let mut dest_tree = Tree::default();
+    walk(
+        source,
+        Path::new(""),
+        filters,
+        opts,
+        &mut source_tree,
+        &mut report,
+    )
+    .map_err(|e| SyncError::Walk(anyhow!("Could not read '{}': {}", source.display(), e)))?;
+    walk(
+        destination,
+        Path::new(""),
+        filters,
+        opts,
+        &mut dest_tree,
+        &mut report,
+    )
+    .map_err(|e| SyncError::Walk(anyhow!("Could not read '{}': {}", destination.display(), e)))?;
+    dest_tree.files.remove(Path::new(BISYNC_STATE_FILE_NAME));
+
+    let state = SyncState::load(&destination.join(BISYNC_STATE_FILE_NAME));
+    let roots = Roots {
+        source,
+        destination,
+    };
+    let policy = opts.conflicts.unwrap_or(ConflictPolicy::KeepBoth);
+    let same_contents = |rel_path: &Path| same_contents(&roots, rel_path, opts);
+    let (actions, conflicts) = plan(&source_tree, &dest_tree, &state, policy, same_contents);
+    report.conflicts = conflicts;
+
+    if let Some(max_delete) = opts.max_delete {
+        let count = actions
+            .iter()
+            .filter(|action| matches!(action, Action::Delete(..)))
+            .count() as u64;
+        let total = source_tree.files.len().max(dest_tree.files.len()) as u64;
+        if max_delete.is_exceeded(count, total) {
+            return Err(SyncError::Delete(anyhow!(
+                "Not deleting anything: {} files were deleted on one side, more than the limit of {}",
+                count,
+                max_delete
+            )));
+        }
+    }
+
+    let mut cancelled = false;
+    for action in actions {
+        if cancel.is_cancelled() {
+            cancelled = true;
+            break;
+        }
+        apply(&action, &roots, &state, opts, &mut report);
+    }
+    if !opts.dry_run && !cancelled {
+        sync_dirs(&source_tree, &dest_tree, &roots, &state, opts, &mut report);
+    }
+    if !opts.dry_run {
+        state.save(!cancelled).map_err(SyncError::State)?;
+    }
+    report.sort();
+    Ok(report)
+}
+
+// Collect the files and the directories under `root`. Only fails if `root`
+// itself cannot be read
+fn walk(
+    root: &Path,
+    rel_dir: &Path,
+    filters: &Filters,
+    opts: SyncOptions,
+    tree: &mut Tree,
+    report: &mut Bisync,
+) -> io::Result<()> {
+    let entries = match fs::read_dir(root.join(rel_dir)) {
+        Ok(entries) => entries,
+        Err(e) if rel_dir == Path::new("") => return Err(e),
+        Err(e) => {
+            tree.unreadable.push(rel_dir.to_path_buf());
+            report.errors.push((rel_dir.to_path_buf(), e));
+            return Ok(());
+        }
+    };
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                tree.unreadable.push(rel_dir.to_path_buf());
+                report.errors.push((rel_dir.to_path_buf(), e));
+                continue;
+            }
+        };
+        let rel_path = rel_dir.join(entry.file_name());
+        let metadata = match fs::symlink_metadata(entry.path()) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                tree.unreadable.push(rel_path.clone());
+                report.errors.push((rel_path, e));
+                continue;
+            }
+        };
+        let name = entry.file_name().to_string_lossy().to_string();
+        if filters.is_excluded(&rel_path, metadata.is_dir())
+            || (opts.skip_hidden && filters::is_hidden(&rel_path))
+            || name.ends_with(fsops::TMP_SUFFIX)
+        {
+            continue;
+        }
+        if metadata.is_dir() {
+            tree.dirs.insert(rel_path.clone());
+            walk(root, &rel_path, filters, opts, tree, report)?;
+        } else if metadata.is_file() {
+            tree.files.insert(rel_path, Stamp::new(&metadata));
+        }
+    }
+    Ok(())
+}
+
+// What to do with each file, and the conflicts found. `same_contents` tells
+// wether a file found on both sides but never synced is the same on both
+fn plan(
+    source: &Tree,
+    destination: &Tree,
+    state: &SyncState,
+    policy: ConflictPolicy,
+    same_contents: impl Fn(&Path) -> bool,
+) -> (Vec<Action>, Vec<(PathBuf, String)>) {
+    let paths: BTreeSet<PathBuf> = source
+        .files
+        .keys()
+        .chain(destination.files.keys())
+        .cloned()
+        .chain(state.previous_paths())
+        .collect();
+    let mut actions = vec![];
+    let mut conflicts = vec![];
+    for path in paths {
+        if !source.knows(&path) || !destination.knows(&path) {
+            actions.push(Action::Keep(path));
+            continue;
+        }
+        let previous = state.previous(&path);
+        let src = source.files.get(&path).copied();
+        let dest = destination.files.get(&path).copied();
+        // Created, modified or deleted since the previous run
+        let src_changed = src != previous.map(|(src, _)| src);
+        let dest_changed = dest != previous.map(|(_, dest)| dest);
+        let action = match (src, dest) {
+            (None, None) => continue,
+            // Created on both sides before the first run, or since the file
+            // was deleted
+            (Some(src), Some(dest))
+                if previous.is_none() && src != dest && same_contents(&path) =>
+            {
+                Action::Record(path)
+            }
+            (Some(src), Some(dest)) if src_changed && dest_changed && src != dest => {
+                let (action, resolution) = resolve(&path, src, dest, policy);
+                conflicts.push((path, resolution));
+                action
+            }
+            (Some(_), Some(_)) if src_changed && !dest_changed => Action::Copy(Side::Source, path),
+            (Some(_), Some(_)) if dest_changed && !src_changed => {
+                Action::Copy(Side::Destination, path)
+            }
+            (Some(_), Some(_)) => Action::Record(path),
+            (Some(_), None) if previous.is_none() => Action::Copy(Side::Source, path),
+            (None, Some(_)) if previous.is_none() => Action::Copy(Side::Destination, path),
+            (Some(_), None) if src_changed => {
+                conflicts.push((
+                    path.clone(),
+                    "modified in the source and deleted in the destination, copying it back"
+                        .to_string(),
+                ));
+                Action::Copy(Side::Source, path)
+            }
+            (None, Some(_)) if dest_changed => {
+                conflicts.push((
+                    path.clone(),
+                    "modified in the destination and deleted in the source, copying it back"
+                        .to_string(),
+                ));
+                Action::Copy(Side::Destination, path)
+            }
+            (Some(_), None) => Action::Delete(Side::Source, path),
+            (None, Some(_)) => Action::Delete(Side::Destination, path),
+        };
+        actions.push(action);
+    }
+    (actions, conflicts)
+}
+
+// Wether the file at `rel_path` is the same on both sides. Files that
+// cannot be read are reported as different
+fn same_contents(roots: &Roots, rel_path: &Path, opts: SyncOptions) -> bool {
+    let description = rel_path.to_string_lossy();
+    let src = Entry::new(&description, &roots.source.join(rel_path));
+    let dest = Entry::new(&description, &roots.destination.join(rel_path));
+    match (src.metadata(), dest.metadata()) {
+        (Some(src_meta), Some(dest_meta)) if src_meta.len() == dest_meta.len() => {
+            matches!(
+                fsops::has_different_contents(&src, &dest, None, opts.hash),
+                Ok(false)
+            )
+        }
+        _ => false,
+    }
+}
+
+fn resolve(path: &Path, src: Stamp, dest: Stamp, policy: ConflictPolicy) -> (Action, String) {
+    let path = path.to_path_buf();
+    let (action, resolution) = match policy {
+        ConflictPolicy::SourceWins => (Action::Copy(Side::Source, path), "keeping the source"),
+        ConflictPolicy::NewerWins if dest.is_newer_than(src) => (
+            Action::Copy(Side::Destination, path),
+            "keeping the destination, which is newer",
+        ),
+        ConflictPolicy::NewerWins => (
+            Action::Copy(Side::Source, path),
+            "keeping the source, which is newer",
+        ),
+        ConflictPolicy::KeepBoth => (Action::KeepBoth(path), "keeping both"),
+        ConflictPolicy::ReportOnly => (Action::Keep(path), "leaving both sides alone"),
+    };
+    (action, format!("modified on both sides, {}", resolution))
+}
+
+fn other(side: Side) -> Side {
+    match side {
+        Side::Source => Side::Destination,
+        Side::Destination => Side::Source,
+    }
+}
+
+fn apply(
+    action: &Action,
+    roots: &Roots,
+    state: &SyncState,
+    opts: SyncOptions,
+    report: &mut Bisync,
+) {
+    let result = match action {
+        Action::Copy(from, path) => {
+            let copied = if opts.dry_run {
+                Ok(())
+            } else {
+                copy_file(roots, *from, path, state)
+            };
+            if copied.is_ok() {
+                match from {
+                    Side::Source => report.to_destination.push(path.clone()),
+                    Side::Destination => report.to_source.push(path.clone()),
+                }
+            }
+            copied.map_err(|e| (path, e))
+        }
+        Action::Delete(side, path) => {
+            let deleted = if opts.dry_run {
+                Ok(())
+            } else {
+                remove(&roots.get(*side).join(path), opts)
+            };
+            if deleted.is_ok() {
+                match side {
+                    Side::Source => report.deleted_in_source.push(path.clone()),
+                    Side::Destination => report.deleted_in_destination.push(path.clone()),
+                }
+            }
+            deleted.map_err(|e| (path, e))
+        }
+        Action::Record(path) => {
+            record(roots, path, state);
+            Ok(())
+        }
+        Action::Keep(path) => {
+            state.keep(path);
+            Ok(())
+        }
+        Action::KeepBoth(path) if opts.dry_run => {
+            report.to_destination.push(path.clone());
+            Ok(())
+        }
+        Action::KeepBoth(path) => keep_both(roots, path, state, report).map_err(|e| (path, e)),
+    };
+    if let Err((path, e)) = result {
+        report.errors.push((path.clone(), e));
+    }
+}
+
+fn keep_both(roots: &Roots, path: &Path, state: &SyncState, report: &mut Bisync) -> io::Result<()> {
+    let dest_path = roots.destination.join(path);
+    let renamed = fsops::conflict_path(&dest_path);
+    fs::rename(&dest_path, &renamed)?;
+    let renamed_rel = fsops::get_rel_path(&renamed, roots.destination);
+    copy_file(roots, Side::Destination, &renamed_rel, state)?;
+    report.to_source.push(renamed_rel);
+    copy_file(roots, Side::Source, path, state)?;
+    report.to_destination.push(path.to_path_buf());
+    Ok(())
+}
+
+// Copy the file at `rel_path` from the `from` side to the other one, with
+// its modification time, replacing the previous file atomically
+fn copy_file(roots: &Roots, from: Side, rel_path: &Path, state: &SyncState) -> io::Result<()> {
+    let src_path = roots.get(from).join(rel_path);
+    let dest_path = roots.get(other(from)).join(rel_path);
+    if let Some(parent) = dest_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp_path = fsops::tmp_path(&dest_path);
+    let copied = fs::copy(&src_path, &tmp_path).and_then(|_| {
+        let metadata = fs::metadata(&src_path)?;
+        let atime = FileTime::from_last_access_time(&metadata);
+        let mtime = FileTime::from_last_modification_time(&metadata);
+        filetime::set_file_times(&tmp_path, atime, mtime)?;
+        fs::rename(&tmp_path, &dest_path)
+    });
+    if copied.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    copied?;
+    record(roots, rel_path, state);
+    Ok(())
+}
+
+fn record(roots: &Roots, rel_path: &Path, state: &SyncState) {
+    let src = fs::symlink_metadata(roots.source.join(rel_path));
+    let dest = fs::symlink_metadata(roots.destination.join(rel_path));
+    if let (Ok(src), Ok(dest)) = (src, dest) {
+        state.record(rel_path, Stamp::new(&src), Stamp::new(&dest));
+    }
+}
+
+fn remove(path: &Path, opts: SyncOptions) -> io::Result<()> {
+    if opts.delete_to_trash {
+        trash::trash(path).map_err(|e| io::Error::other(format!("{:#}", e)))
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+// Create the new directories on the other side, and remove the ones that
+// were deleted on one side if they are empty on the other one, once the
+// files are synced
+fn sync_dirs(
+    source: &Tree,
+    destination: &Tree,
+    roots: &Roots,
+    state: &SyncState,
+    opts: SyncOptions,
+    report: &mut Bisync,
+) {
+    let previous = state.previous_paths();
+    let dirs: BTreeSet<&PathBuf> = source.dirs.iter().chain(destination.dirs.iter()).collect();
+    // Subdirectories first, so that their parents can be removed too
+    for dir in dirs.into_iter().rev() {
+        if !source.knows(dir) || !destination.knows(dir) {
+            continue;
+        }
+        let in_source = roots.source.join(dir).is_dir();
+        let in_destination = roots.destination.join(dir).is_dir();
+        let side = match (in_source, in_destination) {
+            (true, false) => Side::Source,
+            (false, true) => Side::Destination,
+            _ => continue,
+        };
+        let was_synced = previous.iter().any(|path| path.starts_with(dir));
+        let path = roots.get(side).join(dir);
+        if was_synced {
+            if fs::read_dir(&path).is_ok_and(|mut entries| entries.next().is_none()) {
+                match remove_dir(&path, opts) {
+                    Ok(()) if side == Side::Source => report.deleted_in_source.push(dir.clone()),
+                    Ok(()) => report.deleted_in_destination.push(dir.clone()),
+                    Err(e) => report.errors.push((dir.clone(), e)),
+                }
+            }
+        } else if let Err(e) = fs::create_dir_all(roots.get(other(side)).join(dir)) {
+            report.errors.push((dir.clone(), e));
+        }
+    }
+}
+
+fn remove_dir(path: &Path, opts: SyncOptions) -> io::Result<()> {
+    if opts.delete_to_trash {
+        trash::trash(path).map_err(|e| io::Error::other(format!("{:#}", e)))
+    } else {
+        fs::remove_dir(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamp(path: &Path, mtime: u64) -> Stamp {
+        fs::write(path, "contents").unwrap();
+        let mtime = FileTime::from_seconds_since_1970(mtime, 0);
+        filetime::set_file_times(path, mtime, mtime).unwrap();
+        Stamp::new(&fs::metadata(path).unwrap())
+    }
+
+    #[test]
+    fn test_plan() {
+        let tmp_dir = tempdir::TempDir::new("test-rusync-bisync").unwrap();
+        let old = stamp(&tmp_dir.path().join("old"), 1_600_000_000);
+        let new = stamp(&tmp_dir.path().join("new"), 1_700_000_000);
+        let newer = stamp(&tmp_dir.path().join("newer"), 1_800_000_000);
+        let state_path = tmp_dir.path().join("state");
+        let previous = SyncState::load(&state_path);
+        for name in [
+            "same",
+            "src_changed",
+            "both_changed",
+            "src_deleted",
+            "dest_deleted",
+        ] {
+            previous.record(Path::new(name), old, old);
+        }
+        previous.save(true).unwrap();
+        let state = SyncState::load(&state_path);
+
+        let mut source = Tree::default();
+        let mut destination = Tree::default();
+        for (name, src, dest) in [
+            ("same", Some(old), Some(old)),
+            ("src_changed", Some(new), Some(old)),
+            ("both_changed", Some(new), Some(newer)),
+            ("src_deleted", None, Some(old)),
+            ("dest_deleted", Some(new), None),
+            ("created", None, Some(new)),
+            ("created_twice", Some(old), Some(new)),
+            ("created_twice_same", Some(old), Some(new)),
+        ] {
+            if let Some(src) = src {
+                source.files.insert(PathBuf::from(name), src);
+            }
+            if let Some(dest) = dest {
+                destination.files.insert(PathBuf::from(name), dest);
+            }
+        }
+
+        let same_contents = |path: &Path| path == Path::new("created_twice_same");
+        let (actions, conflicts) = plan(
+            &source,
+            &destination,
+            &state,
+            ConflictPolicy::NewerWins,
+            same_contents,
+        );
+        assert_eq!(
+            actions,
+            vec![
+                Action::Copy(Side::Destination, PathBuf::from("both_changed")),
+                Action::Copy(Side::Destination, PathBuf::from("created")),
+                Action::Copy(Side::Destination, PathBuf::from("created_twice")),
+                Action::Record(PathBuf::from("created_twice_same")),
+                Action::Copy(Side::Source, PathBuf::from("dest_deleted")),
+                Action::Record(PathBuf::from("same")),
+                Action::Copy(Side::Source, PathBuf::from("src_changed")),
+                Action::Delete(Side::Destination, PathBuf::from("src_deleted")),
+            ]
+        );
+        let conflicting: Vec<_> = conflicts.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(
+            conflicting,
+            vec![
+                PathBuf::from("both_changed"),
+                PathBuf::from("created_twice"),
+                PathBuf::from("dest_deleted")
+            ]
+        );
+
+        // Unknown because their directory could not be read
+        destination.unreadable.push(PathBuf::new());
+        let (actions, _) = plan(
+            &source,
+            &destination,
+            &state,
+            ConflictPolicy::NewerWins,
+            same_contents,
+        );
+        assert!(actions
+            .iter()
+            .all(|action| matches!(action, Action::Keep(_))));
+    }
+}
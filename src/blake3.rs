@@ -0,0 +1,325 @@
+//! blake3
+//!
+//! Streaming BLAKE3 hasher, with the default 32-byte output. Much faster
+//! than SHA-256 while still cryptographic, see `HashAlgorithm::Blake3`.
+
+const IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const MSG_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+const BLOCK_LEN: usize = 64;
+const CHUNK_LEN: usize = 1024;
+
+const CHUNK_START: u32 = 1;
+const CHUNK_END: u32 = 2;
+const PARENT: u32 = 4;
+const ROOT: u32 = 8;
+
+fn g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, mx: u32, my: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(mx);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(my);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn compress(
+    chaining_value: &[u32; 8],
+    block: &[u8; BLOCK_LEN],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+) -> [u32; 16] {
+    let mut m = [0u32; 16];
+    for (i, word) in block.chunks(4).enumerate() {
+        m[i] = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    let mut state = [
+        chaining_value[0],
+        chaining_value[1],
+        chaining_value[2],
+        chaining_value[3],
+        chaining_value[4],
+        chaining_value[5],
+        chaining_value[6],
+        chaining_value[7],
+        IV[0],
+        IV[1],
+        IV[2],
+        IV[3],
+        counter as u32,
+        (counter >> 32) as u32,
+        block_len,
+        flags,
+    ];
+    for round in 0..7 {
+        g(&mut state, 0, 4, 8, 12, m[0], m[1]);
+        g(&mut state, 1, 5, 9, 13, m[2], m[3]);
+        g(&mut state, 2, 6, 10, 14, m[4], m[5]);
+        g(&mut state, 3, 7, 11, 15, m[6], m[7]);
+        g(&mut state, 0, 5, 10, 15, m[8], m[9]);
+        g(&mut state, 1, 6, 11, 12, m[10], m[11]);
+        g(&mut state, 2, 7, 8, 13, m[12], m[13]);
+        g(&mut state, 3, 4, 9, 14, m[14], m[15]);
+        if round != 6 {
+            let original = m;
+            for (i, &j) in MSG_PERMUTATION.iter().enumerate() {
+                m[i] = original[j];
+            }
+        }
+    }
+    for i in 0..8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= chaining_value[i];
+    }
+    state
+}
+
+fn first_8_words(words: [u32; 16]) -> [u32; 8] {
+    let mut first = [0; 8];
+    first.copy_from_slice(&words[..8]);
+    first
+}
+
+// What is needed to compute either the chaining value of a node, or the
+// root hash when it is the last one
+struct Output {
+    input_chaining_value: [u32; 8],
+    block: [u8; BLOCK_LEN],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+}
+
+impl Output {
+    fn chaining_value(&self) -> [u32; 8] {
+        first_8_words(compress(
+            &self.input_chaining_value,
+            &self.block,
+            self.counter,
+            self.block_len,
+            self.flags,
+        ))
+    }
+
+    fn root_hash(&self) -> Vec<u8> {
+        let words = compress(
+            &self.input_chaining_value,
+            &self.block,
+            self.counter,
+            self.block_len,
+            self.flags | ROOT,
+        );
+        words[..8].iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+}
+
+fn parent_output(left: [u32; 8], right: [u32; 8]) -> Output {
+    let mut block = [0; BLOCK_LEN];
+    for (i, word) in left.iter().chain(right.iter()).enumerate() {
+        block[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    Output {
+        input_chaining_value: IV,
+        block,
+        counter: 0,
+        block_len: BLOCK_LEN as u32,
+        flags: PARENT,
+    }
+}
+
+struct ChunkState {
+    chaining_value: [u32; 8],
+    chunk_counter: u64,
+    block: [u8; BLOCK_LEN],
+    block_len: usize,
+    blocks_compressed: usize,
+}
+
+impl ChunkState {
+    fn new(chunk_counter: u64) -> ChunkState {
+        ChunkState {
+            chaining_value: IV,
+            chunk_counter,
+            block: [0; BLOCK_LEN],
+            block_len: 0,
+            blocks_compressed: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        BLOCK_LEN * self.blocks_compressed + self.block_len
+    }
+
+    fn start_flag(&self) -> u32 {
+        if self.blocks_compressed == 0 {
+            CHUNK_START
+        } else {
+            0
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // The last block of the chunk is only compressed in output(),
+            // with the CHUNK_END flag
+            if self.block_len == BLOCK_LEN {
+                self.chaining_value = first_8_words(compress(
+                    &self.chaining_value,
+                    &self.block,
+                    self.chunk_counter,
+                    BLOCK_LEN as u32,
+                    self.start_flag(),
+                ));
+                self.blocks_compressed += 1;
+                self.block = [0; BLOCK_LEN];
+                self.block_len = 0;
+            }
+            let n = std::cmp::min(BLOCK_LEN - self.block_len, data.len());
+            self.block[self.block_len..self.block_len + n].copy_from_slice(&data[..n]);
+            self.block_len += n;
+            data = &data[n..];
+        }
+    }
+
+    fn output(&self) -> Output {
+        Output {
+            input_chaining_value: self.chaining_value,
+            block: self.block,
+            counter: self.chunk_counter,
+            block_len: self.block_len as u32,
+            flags: self.start_flag() | CHUNK_END,
+        }
+    }
+}
+
+pub struct Blake3 {
+    chunk_state: ChunkState,
+    // The chaining values of the complete subtrees on the left of the
+    // current chunk, at most one per level
+    cv_stack: Vec<[u32; 8]>,
+}
+
+impl Blake3 {
+    pub fn new() -> Blake3 {
+        Blake3 {
+            chunk_state: ChunkState::new(0),
+            cv_stack: vec![],
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // A full chunk is only finalized once more data comes, since
+            // the last one must be finalized as the root
+            if self.chunk_state.len() == CHUNK_LEN {
+                let chunk_cv = self.chunk_state.output().chaining_value();
+                let total_chunks = self.chunk_state.chunk_counter + 1;
+                self.add_chunk_chaining_value(chunk_cv, total_chunks);
+                self.chunk_state = ChunkState::new(total_chunks);
+            }
+            let n = std::cmp::min(CHUNK_LEN - self.chunk_state.len(), data.len());
+            self.chunk_state.update(&data[..n]);
+            data = &data[n..];
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let mut output = self.chunk_state.output();
+        for &left in self.cv_stack.iter().rev() {
+            output = parent_output(left, output.chaining_value());
+        }
+        output.root_hash()
+    }
+
+    // Merge the subtrees completed by the new chunk: as many as there are
+    // trailing zeros in the number of chunks
+    fn add_chunk_chaining_value(&mut self, mut new_cv: [u32; 8], mut total_chunks: u64) {
+        while total_chunks & 1 == 0 {
+            let left = self.cv_stack.pop().expect("cv stack should not be empty");
+            new_cv = parent_output(left, new_cv).chaining_value();
+            total_chunks >>= 1;
+        }
+        self.cv_stack.push(new_cv);
+    }
+}
+
+impl Default for Blake3 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum_cache::to_hex;
+
+    fn blake3_hex(data: &[u8]) -> String {
+        let mut hasher = Blake3::new();
+        hasher.update(data);
+        to_hex(&hasher.finish())
+    }
+
+    #[test]
+    fn test_blake3_known_vectors() {
+        assert_eq!(
+            blake3_hex(b""),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        assert_eq!(
+            blake3_hex(b"abc"),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        // Same inputs as the official test vectors: bytes 0 to 250, repeated
+        let vectors = [
+            (
+                1,
+                "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213",
+            ),
+            (
+                1024,
+                "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7",
+            ),
+            (
+                1025,
+                "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444",
+            ),
+            (
+                2049,
+                "5f4d72f40d7a5f82b15ca2b2e44b1de3c2ef86c426c95c1af0b6879522563030",
+            ),
+            (
+                4096,
+                "015094013f57a5277b59d8475c0501042c0b642e531b0a1c8f58d2163229e969",
+            ),
+            (
+                7169,
+                "a003fc7a51754a9b3c7fae0367ab3d782dccf28855a03d435f8cfe74605e7817",
+            ),
+            (
+                31744,
+                "62b6960e1a44bcc1eb1a611a8d6235b6b4b78f32e7abc4fb4c6cdcce94895c47",
+            ),
+        ];
+        for (len, expected) in vectors.iter() {
+            let data: Vec<u8> = (0..*len).map(|i| (i % 251) as u8).collect();
+            assert_eq!(blake3_hex(&data), *expected, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn test_blake3_streaming() {
+        let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let mut hasher = Blake3::new();
+        for chunk in data.chunks(37) {
+            hasher.update(chunk);
+        }
+        assert_eq!(to_hex(&hasher.finish()), blake3_hex(&data));
+    }
+}
@@ -0,0 +1,181 @@
+//! builder
+//!
+//! Configure a Syncer step by step, see `Syncer::builder()`
+use std::path::{Path, PathBuf};
+
+use crate::error::SyncError;
+use crate::progress::ProgressInfo;
+use crate::sync::{QuietProgressInfo, SyncOptions, Syncer};
+
+/// A filtering rule, see `Syncer::exclude()` and `Syncer::exclude_regex()`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    /// Sync entries matching the glob pattern, unless an earlier rule
+    /// excludes them
+    Include(String),
+    /// Skip entries matching the glob pattern
+    Exclude(String),
+    /// Like `Include`, with a regular expression
+    IncludeRegex(String),
+    /// Like `Exclude`, with a regular expression
+    ExcludeRegex(String),
+}
+
+/// Builds a Syncer, checking the whole configuration in `build()`, before
+/// anything is synced
+///
+/// ```no_run
+/// let syncer = rusync::Syncer::builder()
+///     .source("photos")
+///     .destination("/mnt/backup/photos")
+///     .workers(4)
+///     .filters(vec![rusync::Filter::Exclude("*.tmp".to_string())])
+///     .dry_run(true)
+///     .build()?;
+/// let stats = syncer.sync()?;
+/// # Ok::<(), rusync::SyncError>(())
+/// ```
+pub struct SyncerBuilder {
+    sources: Vec<PathBuf>,
+    destination: Option<PathBuf>,
+    options: SyncOptions,
+    num_workers: usize,
+    filters: Vec<Filter>,
+    progress_info: Option<Box<dyn ProgressInfo + Send>>,
+}
+
+impl SyncerBuilder {
+    pub fn new() -> SyncerBuilder {
+        SyncerBuilder {
+            sources: vec![],
+            destination: None,
+            options: SyncOptions::default(),
+            num_workers: 1,
+            filters: vec![],
+            progress_info: None,
+        }
+    }
+
+    /// Add a source directory. With several sources, they are merged, see
+    /// `Syncer::new_multi()`
+    pub fn source(mut self, source: impl AsRef<Path>) -> Self {
+        self.sources.push(source.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn destination(mut self, destination: impl AsRef<Path>) -> Self {
+        self.destination = Some(destination.as_ref().to_path_buf());
+        self
+    }
+
+    /// Replace all the options. Call it before the methods setting a single
+    /// option, like `dry_run()`
+    pub fn options(mut self, options: SyncOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// See `SyncOptions.dry_run`
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.options.dry_run = dry_run;
+        self
+    }
+
+    /// See `Syncer::num_workers()`
+    pub fn workers(mut self, num_workers: usize) -> Self {
+        self.num_workers = num_workers;
+        self
+    }
+
+    /// Add filtering rules, evaluated in order after the ones added before:
+    /// the first matching rule wins
+    pub fn filters(mut self, filters: impl IntoIterator<Item = Filter>) -> Self {
+        self.filters.extend(filters);
+        self
+    }
+
+    /// Where to report the progress. Nothing is reported by default
+    pub fn progress_info(mut self, progress_info: Box<dyn ProgressInfo + Send>) -> Self {
+        self.progress_info = Some(progress_info);
+        self
+    }
+
+    /// Check the configuration: there is at least one source and they all
+    /// are directories, the destination is not a file, the filters are valid
+    /// and the options can be used together
+    pub fn build(self) -> Result<Syncer, SyncError> {
+        if self.sources.is_empty() {
+            return Err(SyncError::NoSource);
+        }
+        let destination = self.destination.ok_or(SyncError::NoDestination)?;
+        check_options(self.options)?;
+        let progress_info = self
+            .progress_info
+            .unwrap_or_else(|| Box::new(QuietProgressInfo {}));
+        let mut syncer =
+            Syncer::new_multi(&self.sources, &destination, self.options, progress_info);
+        syncer.check_sources()?;
+        syncer.check_destination()?;
+        syncer.num_workers(self.num_workers);
+        let invalid =
+            |pattern: &str, reason| SyncError::InvalidPattern(pattern.to_string(), reason);
+        for filter in self.filters {
+            match filter {
+                Filter::Include(pattern) => syncer.include(&pattern),
+                Filter::Exclude(pattern) => syncer.exclude(&pattern),
+                Filter::IncludeRegex(pattern) => syncer
+                    .include_regex(&pattern)
+                    .map_err(|reason| invalid(&pattern, reason))?,
+                Filter::ExcludeRegex(pattern) => syncer
+                    .exclude_regex(&pattern)
+                    .map_err(|reason| invalid(&pattern, reason))?,
+            }
+        }
+        Ok(syncer)
+    }
+}
+
+impl Default for SyncerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The combinations the command line rejects too
+fn check_options(options: SyncOptions) -> Result<(), SyncError> {
+    if options.checksum && options.size_only {
+        return Err(SyncError::ConflictingOptions("checksum", "size_only"));
+    }
+    if options.checksum_cache && !options.checksum {
+        return Err(SyncError::MissingOption("checksum_cache", "checksum"));
+    }
+    if options.remove_source_files && options.delete_extraneous {
+        return Err(SyncError::ConflictingOptions(
+            "remove_source_files",
+            "delete_extraneous",
+        ));
+    }
+    if options.vss && options.remove_source_files {
+        return Err(SyncError::ConflictingOptions("vss", "remove_source_files"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_options() {
+        assert!(check_options(SyncOptions::default()).is_ok());
+        let options = SyncOptions {
+            checksum: true,
+            size_only: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            check_options(options),
+            Err(SyncError::ConflictingOptions("checksum", "size_only"))
+        ));
+    }
+}
@@ -0,0 +1,28 @@
+//! cancel
+//!
+//! Let another thread stop a sync, see `Syncer::cancellation_token()`
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Shared flag telling the workers of a Syncer to stop. Clones refer to the
+/// same flag, so it can be moved to another thread
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Ask the sync to stop. Entries being synced are finished first, so
+    /// that no half-written file is left in the destination
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
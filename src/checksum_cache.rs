@@ -0,0 +1,227 @@
+//! checksum_cache
+//!
+//! Remember the checksums computed with `SyncOptions.checksum` from one
+//! sync to the next, see `SyncOptions.checksum_cache`.
+//!
+//! The cache is a text file with one line per file: the hex digest, the
+//! size, the modification time (seconds and nanoseconds) and the path.
+//! Entries are only valid as long as the size and the modification time
+//! of the file do not change. Unless the checksums are SHA-256 ones, the
+//! first line names their algorithm, like `algorithm blake3`, and caches
+//! made with another algorithm are ignored.
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Error};
+use filetime::FileTime;
+
+use crate::checksum::{self, HashAlgorithm};
+
+/// Name of the cache file, in the root of the destination
+pub const CACHE_FILE_NAME: &str = ".rusync-checksums";
+
+// Files modified this recently may change again within the same
+// modification time, so their checksums are not kept
+const RACY_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug, PartialEq)]
+struct Cached {
+    size: u64,
+    mtime: (u64, u32),
+    digest: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Entries {
+    previous: HashMap<PathBuf, Cached>,
+    // Only the entries used during this sync are saved, so that the
+    // cache does not grow forever
+    used: HashMap<PathBuf, Cached>,
+}
+
+pub struct ChecksumCache {
+    path: PathBuf,
+    algorithm: HashAlgorithm,
+    entries: Mutex<Entries>,
+}
+
+impl ChecksumCache {
+    /// Read the cache at `path`, for checksums computed with `algorithm`.
+    /// Missing or invalid caches are just empty
+    pub fn load(path: &Path, algorithm: HashAlgorithm) -> ChecksumCache {
+        let previous = fs::read_to_string(path)
+            .map(|contents| parse(&contents, algorithm))
+            .unwrap_or_default();
+        ChecksumCache {
+            path: path.to_path_buf(),
+            algorithm,
+            entries: Mutex::new(Entries {
+                previous,
+                used: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Like `checksum::hash_file()`, unless the file did not change since its
+    /// checksum was cached
+    pub fn hash_file(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        let metadata = fs::metadata(path)
+            .with_context(|| format!("Could not read metadata from '{}'", path.display()))?;
+        let mtime = FileTime::from_last_modification_time(&metadata);
+        let key = Cached {
+            size: metadata.len(),
+            mtime: (mtime.seconds(), mtime.nanoseconds()),
+            digest: vec![],
+        };
+        {
+            let mut entries = self.lock();
+            let cached = entries
+                .previous
+                .get(path)
+                .or_else(|| entries.used.get(path))
+                .filter(|c| c.size == key.size && c.mtime == key.mtime)
+                .cloned();
+            if let Some(cached) = cached {
+                let digest = cached.digest.clone();
+                entries.used.insert(path.to_path_buf(), cached);
+                return Ok(digest);
+            }
+        }
+        // Do not hold the lock while hashing
+        let digest = checksum::hash_file(path, self.algorithm)?;
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        if age.is_some_and(|age| age >= RACY_DELAY) {
+            let cached = Cached {
+                digest: digest.clone(),
+                ..key
+            };
+            self.lock().used.insert(path.to_path_buf(), cached);
+        }
+        Ok(digest)
+    }
+
+    /// Write the entries used during this sync, replacing the previous cache
+    pub fn save(&self) -> Result<(), Error> {
+        let mut out = String::new();
+        if self.algorithm != HashAlgorithm::Sha256 {
+            out.push_str(&format!("algorithm {}\n", self.algorithm));
+        }
+        let entries = self.lock();
+        let mut used: Vec<_> = entries.used.iter().collect();
+        used.sort_by(|a, b| a.0.cmp(b.0));
+        for (path, cached) in used {
+            // Such paths would not be read back correctly
+            let path = match path.to_str() {
+                Some(path) if !path.contains('\n') => path,
+                _ => continue,
+            };
+            out.push_str(&format!(
+                "{} {} {}.{:09} {}\n",
+                to_hex(&cached.digest),
+                cached.size,
+                cached.mtime.0,
+                cached.mtime.1,
+                path
+            ));
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, out)
+            .with_context(|| format!("Could not write '{}'", self.path.display()))?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Could not write '{}'", self.path.display()))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        self.entries
+            .lock()
+            .expect("another sync worker panicked while holding the checksum cache")
+    }
+}
+
+fn parse(contents: &str, algorithm: HashAlgorithm) -> HashMap<PathBuf, Cached> {
+    let mut lines = contents.lines().peekable();
+    let cached_algorithm = match lines
+        .peek()
+        .and_then(|line| line.strip_prefix("algorithm "))
+    {
+        Some(name) => {
+            lines.next();
+            name.parse().ok()
+        }
+        None => Some(HashAlgorithm::Sha256),
+    };
+    if cached_algorithm != Some(algorithm) {
+        return HashMap::new();
+    }
+    lines.filter_map(parse_line).collect()
+}
+
+fn parse_line(line: &str) -> Option<(PathBuf, Cached)> {
+    let mut fields = line.splitn(4, ' ');
+    let digest = from_hex(fields.next()?)?;
+    let size = fields.next()?.parse().ok()?;
+    let (seconds, nanoseconds) = fields.next()?.split_once('.')?;
+    let mtime = (seconds.parse().ok()?, nanoseconds.parse().ok()?);
+    let path = PathBuf::from(fields.next()?);
+    Some((
+        path,
+        Cached {
+            size,
+            mtime,
+            digest,
+        },
+    ))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        let (path, cached) =
+            parse_line("00ff10 42 1600000000.000000123 dir/with space.txt").unwrap();
+        assert_eq!(path, PathBuf::from("dir/with space.txt"));
+        assert_eq!(
+            cached,
+            Cached {
+                size: 42,
+                mtime: (1_600_000_000, 123),
+                digest: vec![0, 255, 16],
+            }
+        );
+        assert!(parse_line("not a cache line").is_none());
+        assert!(parse_line("0g 42 1.0 foo").is_none());
+    }
+
+    #[test]
+    fn test_parse_algorithm() {
+        let sha256 = "00ff 42 1.0 foo\n";
+        let blake3 = "algorithm blake3\n00ff 42 1.0 foo\n";
+        assert_eq!(parse(sha256, HashAlgorithm::Sha256).len(), 1);
+        assert!(parse(sha256, HashAlgorithm::Blake3).is_empty());
+        assert_eq!(parse(blake3, HashAlgorithm::Blake3).len(), 1);
+        assert!(parse(blake3, HashAlgorithm::Xxh3).is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+//! chmod
+//!
+//! Permission rules applied to the destination entries, see
+//! `Syncer::chmod()`.
+//!
+//! Rules use the syntax of `rsync --chmod`: comma-separated clauses of
+//! chmod(1), like `u+rw,go-w` or `644`, each optionally starting with `D`
+//! to only apply to directories or `F` to only apply to files.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Target {
+    All,
+    Dirs,
+    Files,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Change {
+    Octal(u32),
+    // The affected bits, and the operators with their permissions
+    Symbolic(u32, Vec<(char, String)>),
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Chmod {
+    rules: Vec<(Target, Change)>,
+}
+
+impl Chmod {
+    /// Add the rules of `spec` after the existing ones. Nothing is added if
+    /// one of them is invalid
+    pub fn add(&mut self, spec: &str) -> Result<(), String> {
+        let mut rules = vec![];
+        for clause in spec.split(',') {
+            let (target, rest) = match clause.chars().next() {
+                Some('D') => (Target::Dirs, &clause[1..]),
+                Some('F') => (Target::Files, &clause[1..]),
+                _ => (Target::All, clause),
+            };
+            let change = parse_change(rest).ok_or_else(|| format!("Invalid rule '{}'", clause))?;
+            rules.push((target, change));
+        }
+        self.rules.extend(rules);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The permission bits `mode` becomes, for a directory if `is_dir`
+    pub fn apply(&self, mode: u32, is_dir: bool) -> u32 {
+        let mut mode = mode & 0o7777;
+        for (target, change) in &self.rules {
+            match (target, is_dir) {
+                (Target::Dirs, false) | (Target::Files, true) => continue,
+                _ => (),
+            }
+            match change {
+                Change::Octal(bits) => mode = *bits,
+                Change::Symbolic(who, ops) => {
+                    for (op, perms) in ops {
+                        let bits = perm_bits(perms, mode, is_dir) & who;
+                        match op {
+                            '+' => mode |= bits,
+                            '-' => mode &= !bits,
+                            _ => mode = (mode & !who) | bits,
+                        }
+                    }
+                }
+            }
+        }
+        mode
+    }
+}
+
+fn parse_change(change: &str) -> Option<Change> {
+    if !change.is_empty() && change.chars().all(|c| c.is_digit(8)) {
+        return match u32::from_str_radix(change, 8) {
+            Ok(bits) if bits <= 0o7777 => Some(Change::Octal(bits)),
+            _ => None,
+        };
+    }
+    let ops_start = change.find(['+', '-', '='])?;
+    let mut who = 0;
+    for c in change[..ops_start].chars() {
+        who |= match c {
+            'u' => 0o4700,
+            'g' => 0o2070,
+            'o' => 0o1007,
+            'a' => 0o7777,
+            _ => return None,
+        };
+    }
+    if who == 0 {
+        who = 0o7777;
+    }
+    let mut ops: Vec<(char, String)> = vec![];
+    for c in change[ops_start..].chars() {
+        match c {
+            '+' | '-' | '=' => ops.push((c, String::new())),
+            'r' | 'w' | 'x' | 'X' | 's' | 't' => ops.last_mut()?.1.push(c),
+            _ => return None,
+        }
+    }
+    Some(Change::Symbolic(who, ops))
+}
+
+fn perm_bits(perms: &str, mode: u32, is_dir: bool) -> u32 {
+    perms
+        .chars()
+        .map(|c| match c {
+            'r' => 0o444,
+            'w' => 0o222,
+            'x' => 0o111,
+            // Execute only for directories, and files that some can execute
+            'X' if is_dir || mode & 0o111 != 0 => 0o111,
+            's' => 0o6000,
+            't' => 0o1000,
+            _ => 0,
+        })
+        .fold(0, |bits, bit| bits | bit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chmod(spec: &str) -> Chmod {
+        let mut chmod = Chmod::default();
+        chmod.add(spec).unwrap();
+        chmod
+    }
+
+    #[test]
+    fn test_octal() {
+        let rules = chmod("D755,F644");
+        assert_eq!(rules.apply(0o700, true), 0o755);
+        assert_eq!(rules.apply(0o100600, false), 0o644);
+        assert_eq!(chmod("4711").apply(0o644, false), 0o4711);
+    }
+
+    #[test]
+    fn test_symbolic() {
+        assert_eq!(chmod("go-w").apply(0o666, false), 0o644);
+        assert_eq!(chmod("u=rw,go=r").apply(0o777, false), 0o644);
+        assert_eq!(chmod("a+X").apply(0o600, true), 0o711);
+        assert_eq!(chmod("a+X").apply(0o600, false), 0o600);
+        assert_eq!(chmod("a+X").apply(0o700, false), 0o711);
+        assert_eq!(chmod("+r").apply(0o200, false), 0o644);
+        assert_eq!(chmod("u+rw-x").apply(0o700, false), 0o600);
+        assert_eq!(chmod("Fg+s").apply(0o755, true), 0o755);
+        assert_eq!(chmod("Dg+s").apply(0o755, true), 0o2755);
+        assert_eq!(chmod("+t").apply(0o777, true), 0o1777);
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let mut rules = Chmod::default();
+        assert!(rules.add("D755,F64x").is_err());
+        assert!(rules.add("D").is_err());
+        assert!(rules.add("F888").is_err());
+        assert!(rules.add("77777").is_err());
+        assert!(rules.add("u+q").is_err());
+        assert!(rules.add("z+r").is_err());
+        assert!(rules.add("u").is_err());
+        assert!(rules.add("").is_err());
+        assert!(rules.is_empty());
+    }
+}
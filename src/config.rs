@@ -0,0 +1,432 @@
+//! config
+//!
+//! Read default command line options from a configuration file, written in
+//! a subset of TOML: `key = value` lines grouped in `[tables]`, where values
+//! are strings, integers, booleans or arrays of those.
+//!
+//! Keys are the long names of the command line options, so that
+//! `workers = 4` is the same as `--workers 4`, `exclude = ["*.tmp"]` the
+//! same as `--exclude '*.tmp'` and `delete = true` the same as `--delete`.
+//!
+//! `[profile.NAME]` tables also contain a `source` (or an array of sources)
+//! and a `destination`, for `rusync --profile NAME`.
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Error};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+/// `key = value` pairs, in the order they appear in the file
+pub type Table = Vec<(String, Value)>;
+
+#[derive(Debug, Default)]
+pub struct Config {
+    /// Keys found before the first `[table]` header
+    pub root: Table,
+    /// Other tables, like `[profile.photos]`, by name
+    pub tables: Vec<(String, Table)>,
+}
+
+impl Config {
+    pub fn read(path: &Path) -> Result<Config, Error> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Could not read '{}'", path.display()))?;
+        Config::parse(&contents).map_err(|e| anyhow!("In '{}': {}", path.display(), e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut lines = contents.lines().enumerate();
+        while let Some((index, line)) = lines.next() {
+            let line_number = index + 1;
+            let mut line = strip_comment(line).trim().to_string();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                let name = line
+                    .strip_prefix('[')
+                    .and_then(|l| l.strip_suffix(']'))
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .ok_or_else(|| format!("line {}: invalid table header", line_number))?;
+                config.tables.push((name.to_string(), vec![]));
+                continue;
+            }
+            // Arrays may span several lines
+            while unclosed_brackets(&line) > 0 {
+                match lines.next() {
+                    Some((_, next)) => {
+                        line.push(' ');
+                        line.push_str(strip_comment(next).trim());
+                    }
+                    None => return Err(format!("line {}: unclosed array", line_number)),
+                }
+            }
+            let (key, value) = parse_key_value(&line)
+                .map_err(|details| format!("line {}: {}", line_number, details))?;
+            let table = match config.tables.last_mut() {
+                Some((_, table)) => table,
+                None => &mut config.root,
+            };
+            if table.iter().any(|(k, _)| *k == key) {
+                return Err(format!("line {}: duplicate key '{}'", line_number, key));
+            }
+            table.push((key, value));
+        }
+        Ok(config)
+    }
+
+    pub fn table(&self, name: &str) -> Option<&Table> {
+        self.tables
+            .iter()
+            .find(|(table_name, _)| table_name == name)
+            .map(|(_, table)| table)
+    }
+}
+
+pub struct Profile {
+    pub sources: Vec<PathBuf>,
+    pub destination: PathBuf,
+    /// The other keys of the profile, to be turned into options
+    pub options: Table,
+}
+
+impl Profile {
+    pub fn from_table(table: &Table) -> Result<Profile, String> {
+        let mut sources = vec![];
+        let mut destination = None;
+        let mut options = vec![];
+        for (key, value) in table {
+            match (key.as_str(), value) {
+                ("source", Value::String(source)) => sources.push(PathBuf::from(source)),
+                ("source", Value::Array(values)) => {
+                    for value in values {
+                        match value {
+                            Value::String(source) => sources.push(PathBuf::from(source)),
+                            _ => return Err("'source' must contain strings".to_string()),
+                        }
+                    }
+                }
+                ("destination", Value::String(dest)) => destination = Some(PathBuf::from(dest)),
+                ("source", _) | ("destination", _) => {
+                    return Err(format!("'{}' must be a string", key))
+                }
+                _ => options.push((key.clone(), value.clone())),
+            }
+        }
+        if sources.is_empty() {
+            return Err("missing 'source'".to_string());
+        }
+        let destination = destination.ok_or("missing 'destination'")?;
+        Ok(Profile {
+            sources,
+            destination,
+            options,
+        })
+    }
+}
+
+/// `$XDG_CONFIG_HOME/rusync/config.toml`, or `~/.config/rusync/config.toml`
+pub fn default_path() -> Option<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("rusync").join("config.toml"))
+}
+
+/// Turn the keys of `table` into command line options. Keys set to `false`
+/// are left out, since flags cannot be negated
+pub fn to_args(table: &Table) -> Vec<OsString> {
+    let mut args = vec![];
+    for (key, value) in table {
+        let option = format!("--{}", key);
+        match value {
+            Value::Boolean(true) => args.push(option.into()),
+            Value::Boolean(false) => {}
+            Value::Array(values) => {
+                for value in values {
+                    args.push(option.clone().into());
+                    args.push(to_arg(value).into());
+                }
+            }
+            value => {
+                args.push(option.into());
+                args.push(to_arg(value).into());
+            }
+        }
+    }
+    args
+}
+
+fn to_arg(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Integer(i) => i.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        // Nested arrays are rejected when parsing
+        Value::Array(_) => unreachable!(),
+    }
+}
+
+// Comments start with a `#` outside of strings
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' => return &line[..i],
+            None => {}
+        }
+    }
+    line
+}
+
+// Brackets outside of strings that are still open at the end of the line
+fn unclosed_brackets(line: &str) -> i32 {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for c in line.chars() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '[' => depth += 1,
+            None if c == ']' => depth -= 1,
+            None => {}
+        }
+    }
+    depth
+}
+
+fn parse_key_value(line: &str) -> Result<(String, Value), String> {
+    let equal = line.find('=').ok_or("expected 'key = value'")?;
+    let key = line[..equal].trim();
+    let is_bare_key = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !is_bare_key {
+        return Err(format!("invalid key '{}'", key));
+    }
+    let mut parser = ValueParser {
+        input: line[equal + 1..].trim(),
+    };
+    let value = parser.value(true)?;
+    if !parser.input.trim().is_empty() {
+        return Err(format!("unexpected '{}' after value", parser.input.trim()));
+    }
+    Ok((key.to_string(), value))
+}
+
+struct ValueParser<'a> {
+    input: &'a str,
+}
+
+impl<'a> ValueParser<'a> {
+    fn value(&mut self, allow_array: bool) -> Result<Value, String> {
+        self.input = self.input.trim_start();
+        let c = self.input.chars().next().ok_or("missing value")?;
+        match c {
+            '"' => self.basic_string(),
+            '\'' => self.literal_string(),
+            '[' if allow_array => self.array(),
+            '[' => Err("nested arrays are not supported".to_string()),
+            _ => self.scalar(),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<Value, String> {
+        let mut s = String::new();
+        let mut chars = self.input[1..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.input = &self.input[i + 2..];
+                    return Ok(Value::String(s));
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some(c) => return Err(format!("unsupported escape sequence '\\{}'", c)),
+                    None => break,
+                },
+                c => s.push(c),
+            }
+        }
+        Err("unclosed string".to_string())
+    }
+
+    fn literal_string(&mut self) -> Result<Value, String> {
+        match self.input[1..].find('\'') {
+            Some(end) => {
+                let s = self.input[1..end + 1].to_string();
+                self.input = &self.input[end + 2..];
+                Ok(Value::String(s))
+            }
+            None => Err("unclosed string".to_string()),
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.input = &self.input[1..];
+        let mut values = vec![];
+        loop {
+            self.input = self.input.trim_start();
+            if let Some(rest) = self.input.strip_prefix(']') {
+                self.input = rest;
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value(false)?);
+            self.input = self.input.trim_start();
+            if let Some(rest) = self.input.strip_prefix(',') {
+                self.input = rest;
+            } else if !self.input.starts_with(']') {
+                return Err("expected ',' or ']' in array".to_string());
+            }
+        }
+    }
+
+    fn scalar(&mut self) -> Result<Value, String> {
+        let end = self
+            .input
+            .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+            .unwrap_or(self.input.len());
+        let token = &self.input[..end];
+        self.input = &self.input[end..];
+        match token {
+            "true" => Ok(Value::Boolean(true)),
+            "false" => Ok(Value::Boolean(false)),
+            _ => token
+                .replace('_', "")
+                .parse()
+                .map(Value::Integer)
+                .map_err(|_| format!("invalid value '{}' (strings must be quoted)", token)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+# Defaults
+workers = 4
+bwlimit = "1M"  # shared by all workers
+delete = true
+exclude = [
+    "*.tmp",  # editors
+    'C:\cache',
+]
+
+[profile.photos]
+source = "/home/me/Pictures"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.root,
+            vec![
+                ("workers".to_string(), Value::Integer(4)),
+                ("bwlimit".to_string(), Value::String("1M".to_string())),
+                ("delete".to_string(), Value::Boolean(true)),
+                (
+                    "exclude".to_string(),
+                    Value::Array(vec![
+                        Value::String("*.tmp".to_string()),
+                        Value::String("C:\\cache".to_string())
+                    ])
+                ),
+            ]
+        );
+        let photos = config.table("profile.photos").unwrap();
+        assert_eq!(
+            photos[0],
+            (
+                "source".to_string(),
+                Value::String("/home/me/Pictures".to_string())
+            )
+        );
+        assert!(config.table("profile.music").is_none());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Config::parse("workers = four").unwrap_err(),
+            "line 1: invalid value 'four' (strings must be quoted)"
+        );
+        assert_eq!(
+            Config::parse("a = 1\na = 2").unwrap_err(),
+            "line 2: duplicate key 'a'"
+        );
+        assert!(Config::parse("exclude = [\"a\"").is_err());
+        assert!(Config::parse("[]").is_err());
+        assert!(Config::parse("bwlimit = \"1M").is_err());
+    }
+
+    #[test]
+    fn test_profile() {
+        let config = Config::parse(
+            "[profile.photos]\nsource = [\"a\", \"b\"]\ndestination = \"c\"\ndelete = true",
+        )
+        .unwrap();
+        let profile = Profile::from_table(config.table("profile.photos").unwrap()).unwrap();
+        assert_eq!(
+            profile.sources,
+            vec![PathBuf::from("a"), PathBuf::from("b")]
+        );
+        assert_eq!(profile.destination, PathBuf::from("c"));
+        assert_eq!(
+            profile.options,
+            vec![("delete".to_string(), Value::Boolean(true))]
+        );
+
+        let config = Config::parse("[profile.photos]\nsource = \"a\"").unwrap();
+        let error = Profile::from_table(&config.tables[0].1).err();
+        assert_eq!(error.as_deref(), Some("missing 'destination'"));
+    }
+
+    #[test]
+    fn test_to_args() {
+        let config =
+            Config::parse("workers = 4\ndelete = true\nsparse = false\nexclude = [\"a\", \"b\"]")
+                .unwrap();
+        let args: Vec<OsString> = [
+            "--workers",
+            "4",
+            "--delete",
+            "--exclude",
+            "a",
+            "--exclude",
+            "b",
+        ]
+        .iter()
+        .map(OsString::from)
+        .collect();
+        assert_eq!(to_args(&config.root), args);
+    }
+}
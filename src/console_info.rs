@@ -2,84 +2,331 @@
 //!
 //! Display transfer progress to the command line
 
-use crate::progress::{Progress, ProgressInfo};
+use crate::itemize;
+use crate::progress::{Change, Progress, ProgressInfo};
+use crate::report::Report;
 use crate::sync;
 use anyhow::{Context, Error};
-use colored::Colorize;
+use colored::{Color, Colorize};
 use humansize::{file_size_opts as options, FileSize};
+use std::env;
 use std::fs::OpenOptions;
 use std::io;
-use std::io::Write;
+use std::io::{IsTerminal, Write};
 use std::path::Path;
+use std::time::{Duration, Instant};
+
+// How many errors to list in the summary
+const MAX_ERRORS_SHOWN: usize = 10;
+
+// Width of the overall progress bar, brackets included
+const BAR_WIDTH: usize = 22;
+
+// How often to print a status line when not drawing in a terminal
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How to show the progress, see `ConsoleProgressInfo::progress_mode()`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ProgressMode {
+    /// Draw the progress in place, even if stdout is not a terminal
+    Always,
+    /// Draw the progress in place if stdout is a terminal, and print a
+    /// status line from time to time otherwise
+    Auto,
+    /// Do not show the progress, only the errors and the summary
+    Never,
+}
+
+impl std::str::FromStr for ProgressMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(ProgressMode::Always),
+            "auto" => Ok(ProgressMode::Auto),
+            "never" => Ok(ProgressMode::Never),
+            _ => Err(format!(
+                "Invalid progress mode '{}', expected 'always', 'auto' or 'never'",
+                s
+            )),
+        }
+    }
+}
+
+/// When to use colors, see `ConsoleProgressInfo::color_mode()`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColorMode {
+    Always,
+    /// Only when stdout is a terminal and the `NO_COLOR` environment
+    /// variable is not set
+    Auto,
+    Never,
+}
+
+impl std::str::FromStr for ColorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(ColorMode::Always),
+            "auto" => Ok(ColorMode::Auto),
+            "never" => Ok(ColorMode::Never),
+            _ => Err(format!(
+                "Invalid color mode '{}', expected 'always', 'auto' or 'never'",
+                s
+            )),
+        }
+    }
+}
+
+/// The colors used for each kind of message, see
+/// `ConsoleProgressInfo::theme()`
+///
+/// Parsed from a list like `success=cyan,error=bright-magenta`. The kinds
+/// are `info`, `success`, `warning` and `error`, and the colors are the
+/// 8 standard terminal colors and their `bright-` variants. Kinds that
+/// are not listed keep their default color.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Theme {
+    info: Color,
+    success: Color,
+    warning: Color,
+    error: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            info: Color::Blue,
+            success: Color::Green,
+            warning: Color::Yellow,
+            error: Color::Red,
+        }
+    }
+}
+
+impl std::str::FromStr for Theme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut theme = Theme::default();
+        for item in s.split(',').filter(|item| !item.is_empty()) {
+            let (kind, color) = item
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid theme entry '{}', expected 'kind=color'", item))?;
+            let color: Color = color
+                .trim()
+                .replace('-', " ")
+                .parse()
+                .map_err(|_| format!("Invalid color '{}'", color))?;
+            match kind.trim() {
+                "info" => theme.info = color,
+                "success" => theme.success = color,
+                "warning" => theme.warning = color,
+                "error" => theme.error = color,
+                _ => {
+                    return Err(format!(
+                        "Invalid theme entry '{}', expected info, success, warning or error",
+                        kind
+                    ))
+                }
+            }
+        }
+        Ok(theme)
+    }
+}
 
 #[derive(Debug)]
 pub struct ConsoleProgressInfo {
     err_file: Option<std::fs::File>,
+    itemize_changes: bool,
+    report: Option<Report>,
+    show_progress: bool,
+    // Wether to draw the progress in place, on two lines
+    tty: bool,
+    // Wether the two progress lines are currently shown
+    drawn: bool,
+    // When the last status line was printed, when not drawing in place
+    last_status: Option<Instant>,
+    colors: bool,
+    theme: Theme,
 }
 
 impl ConsoleProgressInfo {
     pub fn new() -> Self {
-        Self { err_file: None }
+        Self {
+            err_file: None,
+            itemize_changes: false,
+            report: None,
+            show_progress: true,
+            tty: io::stdout().is_terminal(),
+            drawn: false,
+            last_status: None,
+            colors: colors_by_default(),
+            theme: Theme::default(),
+        }
+    }
+
+    /// Wether to use colors. Defaults to `ColorMode::Auto`
+    pub fn color_mode(&mut self, mode: ColorMode) {
+        self.colors = match mode {
+            ColorMode::Always => true,
+            ColorMode::Auto => colors_by_default(),
+            ColorMode::Never => false,
+        };
+    }
+
+    /// Which colors to use, when colors are used at all
+    pub fn theme(&mut self, theme: Theme) {
+        self.theme = theme;
+    }
+
+    /// Wether to draw the progress in place, print status lines or nothing.
+    /// Defaults to `ProgressMode::Auto`
+    pub fn progress_mode(&mut self, mode: ProgressMode) {
+        self.show_progress = mode != ProgressMode::Never;
+        self.tty = match mode {
+            ProgressMode::Always => true,
+            ProgressMode::Auto => io::stdout().is_terminal(),
+            ProgressMode::Never => false,
+        };
+    }
+
+    /// Print a line for each entry that changed, like `rsync --itemize-changes`
+    pub fn itemize_changes(&mut self, itemize_changes: bool) {
+        self.itemize_changes = itemize_changes;
+    }
+
+    /// When using `SyncOptions.dry_run`, print the new, changed and deleted
+    /// entries at the end, with the total size of each group
+    pub fn report(&mut self, report: bool) {
+        self.report = if report {
+            Some(Report::default())
+        } else {
+            None
+        };
     }
 
     pub fn with_error_list_path(error_list_path: &Path) -> Result<Self, Error> {
         let err_file = OpenOptions::new()
             .create(true)
             .write(true)
+            .truncate(true)
             .open(error_list_path)
             .with_context(|| {
                 format!("Could not open errfile at '{}'", error_list_path.display())
             })?;
         Ok(Self {
             err_file: Some(err_file),
+            ..Self::new()
         })
     }
+
+    fn paint(&self, text: &str, color: Color) -> String {
+        if self.colors {
+            text.color(color).to_string()
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn bold(&self, text: &str) -> String {
+        if self.colors {
+            text.bold().to_string()
+        } else {
+            text.to_string()
+        }
+    }
+
+    // Make room for other output, the progress is drawn again on the next
+    // call to `progress()`
+    fn erase(&mut self) {
+        if self.drawn {
+            // Back to the start of the first line, then clear both
+            print!("\r\x1b[J");
+            let _ = io::stdout().flush();
+            self.drawn = false;
+        }
+    }
 }
 
 impl ProgressInfo for ConsoleProgressInfo {
-    fn done_syncing(&mut self) {
-        erase_line();
+    fn done_syncing(&mut self, _name: &str) {
+        self.erase();
     }
 
     fn start(&mut self, source: &str, destination: &str) {
         println!(
             "{} Syncing from {} to {} …",
-            "::".color("blue"),
-            source.bold(),
-            destination.bold()
+            self.paint("::", self.theme.info),
+            self.bold(source),
+            self.bold(destination)
         )
     }
 
     fn new_file(&mut self, _name: &str) {}
 
+    fn scanning(&mut self, entries_found: u64, bytes_found: u64) {
+        if !self.show_progress {
+            return;
+        }
+        let line = format_scanning(entries_found, bytes_found);
+        if !self.tty {
+            let now = Instant::now();
+            if self
+                .last_status
+                .is_none_or(|last| now - last >= STATUS_INTERVAL)
+            {
+                println!("{}", line);
+                self.last_status = Some(now);
+            }
+            return;
+        }
+        let line = truncate_lossy(&line, get_terminal_width().saturating_sub(1));
+        print!("\r{}\x1b[K", line);
+        let _ = io::stdout().flush();
+        self.drawn = true;
+    }
+
+    fn itemized(&mut self, name: &str, changes: &str) {
+        if self.itemize_changes && itemize::has_changes(changes) {
+            self.erase();
+            println!("{} {}", changes, name);
+        }
+    }
+
+    fn planned(&mut self, name: &str, change: Change, size: u64) {
+        if let Some(report) = &mut self.report {
+            report.add(name, change, size);
+        }
+    }
+
     fn progress(&mut self, progress: &Progress) {
-        let eta_str = human_seconds(progress.eta);
-        let percent_width = 3;
-        let eta_width = eta_str.len();
-        let index = progress.index;
-        let index_width = index.to_string().len();
-        let num_files = progress.num_files;
-        let num_files_width = num_files.to_string().len();
-        let widgets_width = percent_width + index_width + num_files_width + eta_width;
-        let num_separators = 5;
-        let line_width = get_terminal_width();
-        let file_width = line_width - widgets_width - num_separators - 1;
-        let current_file = progress.current_file.clone();
-        let current_file = truncate_lossy(&current_file, file_width as usize);
-        let current_file = format!(
-            "{filename:<pad$}",
-            pad = file_width as usize,
-            filename = current_file
-        );
-        let file_percent = ((progress.file_done * 100) as usize) / progress.file_size;
-        print!(
-            "{:>3}% {}/{} {} {:<}\r",
-            file_percent, index, num_files, current_file, eta_str
-        );
+        if !self.show_progress {
+            return;
+        }
+        if !self.tty {
+            // Log files and pipes get a plain line from time to time
+            let now = Instant::now();
+            if self
+                .last_status
+                .is_none_or(|last| now - last >= STATUS_INTERVAL)
+            {
+                println!("{}", format_status(progress));
+                self.last_status = Some(now);
+            }
+            return;
+        }
+        let (file_line, total_line) = format_lines(progress, get_terminal_width());
+        // Both lines are cleared to their end, then the cursor goes back to
+        // the start of the first one, so that the next call draws over them
+        print!("\r{}\x1b[K\n{}\x1b[K\x1b[1A\r", file_line, total_line);
         let _ = io::stdout().flush();
+        self.drawn = true;
     }
 
     fn error(&mut self, entry: &str, desc: &str) {
+        self.erase();
         eprintln!("Errror: {}", desc);
         if let Some(err_file) = &mut self.err_file {
             // Ignoring errrors when trying to log errors ...
@@ -89,17 +336,59 @@ impl ProgressInfo for ConsoleProgressInfo {
         }
     }
 
+    fn warning(&mut self, _entry: &str, desc: &str) {
+        self.erase();
+        eprintln!("Warning: {}", desc);
+    }
+
     fn end(&mut self, stats: &sync::Stats) {
-        println!(
-            "{} Synced {} files ({} up to date)",
-            " ✓".color("green"),
-            stats.num_synced,
-            stats.up_to_date
-        );
+        if self.drawn {
+            self.erase();
+        }
+        if self.report.is_some() {
+            self.erase();
+        }
+        if let Some(report) = &mut self.report {
+            print!("{}", report.format());
+            // Start again from scratch when syncing several times
+            *report = Report::default();
+        }
+        if stats.cancelled {
+            self.erase();
+            println!("{} Sync cancelled", self.paint(" !", self.theme.warning));
+        }
+        if stats.errors == 0 {
+            println!(
+                "{} Synced {} files ({} up to date)",
+                self.paint(" ✓", self.theme.success),
+                stats.num_synced,
+                stats.up_to_date
+            );
+        } else {
+            println!(
+                "{} Synced {} files ({} up to date), {} failed",
+                self.paint(" ✗", self.theme.error),
+                stats.num_synced,
+                stats.up_to_date,
+                stats.errors
+            );
+        }
         println!(
             "{} files copied, {} symlinks created, {} symlinks updated",
             stats.copied, stats.symlink_created, stats.symlink_updated
         );
+        if stats.cloned != 0 {
+            println!("{} files cloned", stats.cloned);
+        }
+        if stats.symlinks_skipped != 0 {
+            println!("{} symlinks skipped", stats.symlinks_skipped);
+        }
+        if stats.junctions_created != 0 || stats.junctions_skipped != 0 {
+            println!(
+                "{} junctions created, {} junctions skipped",
+                stats.junctions_created, stats.junctions_skipped
+            );
+        }
         let transfered = stats.total_transfered;
         // We know transfered cannot be negative
         let transfered = transfered.file_size(options::DECIMAL).unwrap();
@@ -107,9 +396,61 @@ impl ProgressInfo for ConsoleProgressInfo {
         // Truncate below 1 second
         let duration = std::time::Duration::from_secs(duration.as_secs());
         let duration = humantime::format_duration(duration);
-        println!("{} copied in {}", transfered, duration);
+        if stats.specials_created != 0 || stats.specials_skipped != 0 {
+            println!(
+                "{} special files created, {} skipped",
+                stats.specials_created, stats.specials_skipped
+            );
+        }
+        if stats.size_skipped != 0 {
+            println!("{} files skipped because of their size", stats.size_skipped);
+        }
+        if stats.missing_skipped != 0 {
+            println!(
+                "{} entries skipped because they are not in the destination",
+                stats.missing_skipped
+            );
+        }
+        if stats.conflicts_skipped != 0 {
+            println!(
+                "{} files kept because they changed in the destination too",
+                stats.conflicts_skipped
+            );
+        }
+        if stats.hardlinks_created != 0 {
+            println!("{} hard links created", stats.hardlinks_created);
+        }
+        if stats.files_linked != 0 {
+            println!("{} unchanged files linked", stats.files_linked);
+        }
+        if stats.bytes_saved != 0 {
+            let saved = stats.bytes_saved.file_size(options::DECIMAL).unwrap();
+            println!("{} saved by in-place updates", saved);
+        }
+        if stats.deleted != 0 {
+            println!("{} entries deleted", stats.deleted);
+        }
+        let throughput = (stats.avg_throughput() as u64)
+            .file_size(options::DECIMAL)
+            .unwrap();
+        println!("{} copied in {} ({}/s)", transfered, duration, throughput);
+        if let Some(speedup) = stats.speedup() {
+            let total = stats.bytes_total.file_size(options::DECIMAL).unwrap();
+            println!("total size is {}, speedup is {:.2}", total, speedup);
+        }
+        if stats.warnings != 0 {
+            eprintln!("{} warnings", stats.warnings);
+        }
         if stats.errors != 0 {
-            eprintln!("{} errors occurred", stats.errors);
+            eprintln!("{} errors occurred:", stats.errors);
+            // Errors were already printed during the sync, but they are
+            // easy to miss in a long transfer
+            for (path, error) in stats.error_list.iter().take(MAX_ERRORS_SHOWN) {
+                eprintln!("  {}: {}", path.display(), error);
+            }
+            if stats.error_list.len() > MAX_ERRORS_SHOWN {
+                eprintln!("  … and {} more", stats.error_list.len() - MAX_ERRORS_SHOWN);
+            }
         }
     }
 }
@@ -120,6 +461,88 @@ impl Default for ConsoleProgressInfo {
     }
 }
 
+// The current file on the first line, and the overall progress on the
+// second one
+fn format_lines(progress: &Progress, line_width: usize) -> (String, String) {
+    let percent = format!("{:>3}% ", file_percent(progress));
+    let file_width = line_width.saturating_sub(percent.len() + 1);
+    let file_line = format!(
+        "{}{}",
+        percent,
+        truncate_lossy(&progress.current_file, file_width)
+    );
+    let done = progress.total_done.file_size(options::BINARY).unwrap();
+    let mut total_line = if progress.totals_known {
+        format!(
+            "{} {:>3}% {}/{} files, {}/{}",
+            progress_bar(total_percent(progress), BAR_WIDTH),
+            total_percent(progress),
+            progress.index,
+            progress.num_files,
+            done,
+            progress.total_size.file_size(options::BINARY).unwrap()
+        )
+    } else {
+        format!("{}/{} files, {}", progress.index, progress.num_files, done)
+    };
+    total_line.push_str(&format!(
+        " {}/s ETA {}",
+        progress.speed.file_size(options::BINARY).unwrap(),
+        human_seconds(progress.eta)
+    ));
+    let total_line = truncate_lossy(&total_line, line_width.saturating_sub(1));
+    (file_line, total_line)
+}
+
+// Like the second line of `format_lines()`, without the bar
+fn format_status(progress: &Progress) -> String {
+    let done = progress.total_done.file_size(options::BINARY).unwrap();
+    let total = if progress.totals_known {
+        format!(
+            "{}/{} ({}%)",
+            done,
+            progress.total_size.file_size(options::BINARY).unwrap(),
+            total_percent(progress)
+        )
+    } else {
+        done
+    };
+    format!(
+        "{}/{} files, {}, {}/s, ETA {}",
+        progress.index,
+        progress.num_files,
+        total,
+        progress.speed.file_size(options::BINARY).unwrap(),
+        human_seconds(progress.eta)
+    )
+}
+
+fn format_scanning(entries_found: u64, bytes_found: u64) -> String {
+    format!(
+        "Scanning: {} files, {} found",
+        entries_found,
+        bytes_found.file_size(options::BINARY).unwrap()
+    )
+}
+
+fn file_percent(progress: &Progress) -> usize {
+    (progress.file_done * 100)
+        .checked_div(progress.file_size)
+        .unwrap_or(100)
+}
+
+fn total_percent(progress: &Progress) -> usize {
+    (progress.total_done * 100)
+        .checked_div(progress.total_size)
+        .unwrap_or(100)
+}
+
+// See https://no-color.org
+fn colors_by_default() -> bool {
+    let disabled = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    io::stdout().is_terminal() && !disabled
+}
+
 fn get_terminal_width() -> usize {
     if let Some((w, _)) = term_size::dimensions() {
         return w;
@@ -129,19 +552,23 @@ fn get_terminal_width() -> usize {
     80
 }
 
-fn erase_line() {
-    let line_width = get_terminal_width();
-    let line = vec![32 as u8; line_width as usize];
-    // We're calling from_utf8 on a string containing only spaces,
-    // so calling unwrap() is safe
-    print!("{}\r", String::from_utf8(line).unwrap());
-}
-
 fn human_seconds(s: usize) -> String {
     let hours = s / 3600;
     let minutes = (s / 60) % 60;
     let seconds = s % 60;
-    return format!("{:02}:{:02}:{:02}", hours, minutes, seconds);
+    format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
+}
+
+// Like `[=======>      ]`
+fn progress_bar(percent: usize, width: usize) -> String {
+    let inner = width.saturating_sub(2);
+    let filled = std::cmp::min(percent, 100) * inner / 100;
+    let bar = if filled == inner {
+        "=".repeat(inner)
+    } else {
+        format!("{}>{}", "=".repeat(filled), " ".repeat(inner - filled - 1))
+    };
+    format!("[{}]", bar)
 }
 
 fn truncate_lossy(text: &str, maxsize: usize) -> String {
@@ -166,6 +593,89 @@ mod test {
         assert_eq!(new_text, "é");
     }
 
+    #[test]
+    fn test_progress_bar() {
+        assert_eq!(progress_bar(0, 7), "[>    ]");
+        assert_eq!(progress_bar(50, 7), "[==>  ]");
+        assert_eq!(progress_bar(100, 7), "[=====]");
+    }
+
+    #[test]
+    fn test_format_lines() {
+        let progress = Progress {
+            current_file: "a_dir/one.txt".to_string(),
+            file_done: 50,
+            file_size: 200,
+            total_done: 1024,
+            total_size: 4096,
+            index: 2,
+            num_files: 5,
+            totals_known: true,
+            eta: 65,
+            speed: 2048,
+            avg_speed: 2048,
+        };
+        let (file_line, total_line) = format_lines(&progress, 80);
+        assert_eq!(file_line, " 25% a_dir/one.txt");
+        assert_eq!(
+            total_line,
+            "[=====>              ]  25% 2/5 files, 1 KiB/4 KiB 2 KiB/s ETA 00:01:05"
+        );
+
+        let progress = Progress {
+            totals_known: false,
+            ..progress
+        };
+        let (_, total_line) = format_lines(&progress, 30);
+        assert_eq!(total_line, "2/5 files, 1 KiB 2 KiB/s ETA ");
+    }
+
+    #[test]
+    fn test_format_status() {
+        let progress = Progress {
+            current_file: "a_dir/one.txt".to_string(),
+            file_done: 50,
+            file_size: 200,
+            total_done: 1024,
+            total_size: 4096,
+            index: 2,
+            num_files: 5,
+            totals_known: true,
+            eta: 65,
+            speed: 2048,
+            avg_speed: 2048,
+        };
+        assert_eq!(
+            format_status(&progress),
+            "2/5 files, 1 KiB/4 KiB (25%), 2 KiB/s, ETA 00:01:05"
+        );
+    }
+
+    #[test]
+    fn test_format_scanning() {
+        assert_eq!(
+            format_scanning(1200, 3 * 1024 * 1024),
+            "Scanning: 1200 files, 3 MiB found"
+        );
+    }
+
+    #[test]
+    fn test_parse_theme() {
+        let theme: Theme = "success=cyan, error=bright-magenta".parse().unwrap();
+        assert_eq!(
+            theme,
+            Theme {
+                success: Color::Cyan,
+                error: Color::BrightMagenta,
+                ..Theme::default()
+            }
+        );
+        assert_eq!("".parse::<Theme>().unwrap(), Theme::default());
+        assert!("success=pink".parse::<Theme>().is_err());
+        assert!("failure=red".parse::<Theme>().is_err());
+        assert!("red".parse::<Theme>().is_err());
+    }
+
     #[test]
     fn test_human_seconds() {
         assert_eq!("00:00:05", human_seconds(5));
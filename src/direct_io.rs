@@ -0,0 +1,120 @@
+//! direct_io
+//!
+//! Copies bypassing the page cache (O_DIRECT), see `SyncOptions.direct_io`.
+//!
+//! Reads and writes then have to use buffers, offsets and lengths aligned on
+//! the block size of the device. `ALIGNMENT` covers the usual 512 bytes and
+//! 4 KiB blocks.
+use std::alloc::{self, Layout};
+use std::fs::File;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::ptr::NonNull;
+
+pub const ALIGNMENT: usize = 4096;
+
+/// A buffer of zeros starting at an address aligned on `ALIGNMENT`
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    /// The length is rounded up to a multiple of `ALIGNMENT`
+    pub fn new(len: usize) -> AlignedBuffer {
+        let len = len.max(1).div_ceil(ALIGNMENT) * ALIGNMENT;
+        let layout = Layout::from_size_align(len, ALIGNMENT).expect("invalid buffer size");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        match NonNull::new(ptr) {
+            Some(ptr) => AlignedBuffer { ptr, layout },
+            None => alloc::handle_alloc_error(layout),
+        }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+/// Open `path` for reading, bypassing the page cache. Fails on file systems
+/// that do not support it, like tmpfs, and on other platforms than Linux
+#[cfg(target_os = "linux")]
+pub fn open(path: &Path) -> io::Result<File> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open(_path: &Path) -> io::Result<File> {
+    Err(unsupported())
+}
+
+/// Start or stop bypassing the page cache for the reads and writes of `file`
+#[cfg(target_os = "linux")]
+pub fn set_direct(file: &File, direct: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if direct {
+        flags | libc::O_DIRECT
+    } else {
+        flags & !libc::O_DIRECT
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_direct(_file: &File, _direct: bool) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "direct I/O is only supported on Linux",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aligned_buffer() {
+        let buffer = AlignedBuffer::new(100 * 1000);
+        assert_eq!(buffer.len(), 25 * ALIGNMENT);
+        assert!((buffer.as_ptr() as usize).is_multiple_of(ALIGNMENT));
+        assert!(buffer.iter().all(|&b| b == 0));
+        assert_eq!(AlignedBuffer::new(0).len(), ALIGNMENT);
+    }
+}
@@ -2,7 +2,10 @@ use std::fs;
 use std::option::Option;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::SystemTime;
 
+/// A file, directory or symlink, along with its metadata as it was when the
+/// Entry was created
 #[derive(Debug, Clone)]
 pub struct Entry {
     description: String,
@@ -10,29 +13,52 @@ pub struct Entry {
     metadata: Option<fs::Metadata>,
     exists: bool,
     is_link: Option<bool>,
+    link_target: Option<PathBuf>,
 }
 
 impl Entry {
     pub fn new(description: &str, entry_path: &Path) -> Entry {
-        let mut metadata = fs::metadata(&entry_path).ok();
+        let mut metadata = fs::metadata(entry_path).ok();
         let is_link;
-        let symlink_metadata = fs::symlink_metadata(&entry_path);
+        let symlink_metadata = fs::symlink_metadata(entry_path);
         if let Ok(data) = symlink_metadata {
             is_link = Some(data.file_type().is_symlink());
             metadata = Some(data);
         } else {
             is_link = None;
         }
+        let link_target = if is_link == Some(true) {
+            fs::read_link(entry_path).ok()
+        } else {
+            None
+        };
+
+        Entry {
+            description: String::from(description),
+            metadata,
+            path: entry_path.to_path_buf(),
+            exists: entry_path.exists(),
+            is_link,
+            link_target,
+        }
+    }
 
+    /// Like `new()`, but if `entry_path` is a symlink, describe its target
+    /// instead of the link itself
+    pub fn dereferenced(description: &str, entry_path: &Path) -> Entry {
+        let metadata = fs::metadata(entry_path).ok();
+        let is_link = metadata.as_ref().map(|_| false);
         Entry {
             description: String::from(description),
             metadata,
             path: entry_path.to_path_buf(),
             exists: entry_path.exists(),
             is_link,
+            link_target: None,
         }
     }
 
+    /// The path relative to the source (or the destination)
     pub fn description(&self) -> &String {
         &self.description
     }
@@ -40,16 +66,37 @@ impl Entry {
     pub fn path(&self) -> &PathBuf {
         &self.path
     }
+    /// Of the link itself for symlinks, None if the entry does not exist
     pub fn metadata(&self) -> Option<&fs::Metadata> {
         self.metadata.as_ref()
     }
+    /// Note that this follows symlinks: false for broken ones
     pub fn exists(&self) -> bool {
         self.exists
     }
 
+    /// None if the entry does not exist
     pub fn is_link(&self) -> Option<bool> {
         self.is_link
     }
+
+    /// In bytes, 0 if the entry does not exist
+    pub fn size(&self) -> u64 {
+        self.metadata.as_ref().map_or(0, |m| m.len())
+    }
+
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.metadata.as_ref().and_then(|m| m.modified().ok())
+    }
+
+    pub fn file_type(&self) -> Option<fs::FileType> {
+        self.metadata.as_ref().map(|m| m.file_type())
+    }
+
+    /// What the symlink points to, None for other entries
+    pub fn link_target(&self) -> Option<&Path> {
+        self.link_target.as_deref()
+    }
 }
 
 #[cfg(test)]
@@ -61,16 +108,34 @@ mod tests {
     #[test]
     fn new_entry_with_non_existing_path() {
         let path = Path::new("/path/to/nosuch.txt");
-        let entry = Entry::new("nosuch", &path);
+        let entry = Entry::new("nosuch", path);
 
         assert!(!entry.exists());
         assert!(entry.metadata.is_none());
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn dereferenced_entry() {
+        let tmp_dir = tempdir::TempDir::new("test-rusync-entry").unwrap();
+        let link = tmp_dir.path().join("link");
+        let target = std::fs::canonicalize(file!()).unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let entry = Entry::new("link", &link);
+        assert_eq!(entry.link_target(), Some(target.as_path()));
+
+        let entry = Entry::dereferenced("link", &link);
+        assert_eq!(entry.is_link(), Some(false));
+        assert_eq!(entry.link_target(), None);
+        let size = entry.metadata().unwrap().len();
+        assert_eq!(size, std::fs::metadata(&target).unwrap().len());
+    }
+
     #[test]
     fn new_entry_with_existing_path() {
         let path = Path::new(file!());
-        let entry = Entry::new("entry.rs", &path);
+        let entry = Entry::new("entry.rs", path);
 
         assert!(entry.exists());
         assert!(entry.metadata.is_some());
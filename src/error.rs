@@ -0,0 +1,115 @@
+//! error
+//!
+//! Errors preventing the whole sync from completing. Errors affecting a
+//! single entry are reported to the ProgressInfo instead, and collected
+//! in Stats.error_list
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum SyncError {
+    /// `SyncerBuilder::build()` was called without any source
+    NoSource,
+    /// `SyncerBuilder::build()` was called without a destination
+    NoDestination,
+    /// A regular expression given to `SyncerBuilder::filters()` is invalid:
+    /// contains the pattern and the reason
+    InvalidPattern(String, String),
+    /// Both options were set, but they cannot be used together
+    ConflictingOptions(&'static str, &'static str),
+    /// The first option was set, but it only works along with the second one
+    MissingOption(&'static str, &'static str),
+    /// The source does not exist or is not a directory
+    InvalidSource(PathBuf),
+    /// One of the sources given to `Syncer::new_multi()` is inside another
+    /// one: contains both paths
+    NestedSources(PathBuf, PathBuf),
+    /// The destination exists but is not a directory
+    InvalidDestination(PathBuf),
+    /// The destination looks like `host:path`, and remote destinations
+    /// are not supported
+    RemoteDestination(PathBuf),
+    /// The source could not be walked
+    Walk(anyhow::Error),
+    /// Entries could not be synced, for a reason affecting the whole
+    /// transfer
+    Copy(anyhow::Error),
+    /// Extraneous entries could not be removed from the destination
+    Delete(anyhow::Error),
+    /// Changes in the source could not be watched
+    Watch(anyhow::Error),
+    /// The manifest of the destination could not be written
+    Manifest(anyhow::Error),
+    /// The checksum cache could not be saved
+    ChecksumCache(anyhow::Error),
+    /// The state used to detect conflicts could not be saved, see
+    /// `SyncOptions.conflicts`, or the state database, see
+    /// `Syncer::state_db()`
+    State(anyhow::Error),
+    /// The pre-sync or post-sync hook failed
+    Hook(anyhow::Error),
+    /// The snapshots of the sources could not be created, see `SyncOptions.vss`
+    Snapshot(anyhow::Error),
+    /// One of the worker threads panicked. Contains the name of the worker
+    Join(String),
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::NoSource => write!(f, "No source given"),
+            SyncError::NoDestination => write!(f, "No destination given"),
+            SyncError::InvalidPattern(pattern, reason) => {
+                write!(f, "Invalid pattern '{}': {}", pattern, reason)
+            }
+            SyncError::ConflictingOptions(first, second) => {
+                write!(f, "{} cannot be combined with {}", first, second)
+            }
+            SyncError::MissingOption(option, required) => {
+                write!(f, "{} requires {}", option, required)
+            }
+            SyncError::InvalidSource(path) => write!(f, "{} is not a directory", path.display()),
+            SyncError::NestedSources(inner, outer) => write!(
+                f,
+                "{} is inside {}, sources must not contain one another",
+                inner.display(),
+                outer.display()
+            ),
+            SyncError::InvalidDestination(path) => {
+                write!(f, "{} exists but is not a directory", path.display())
+            }
+            SyncError::RemoteDestination(path) => write!(
+                f,
+                "{} looks like a remote destination, which is not supported",
+                path.display()
+            ),
+            SyncError::Walk(e) => write!(f, "Could not walk source: {:#}", e),
+            SyncError::Copy(e) => write!(f, "Could not sync: {:#}", e),
+            SyncError::Delete(e) => write!(f, "Could not delete extraneous entries: {:#}", e),
+            SyncError::Watch(e) => write!(f, "Could not watch source: {:#}", e),
+            SyncError::Manifest(e) => write!(f, "Could not write manifest: {:#}", e),
+            SyncError::ChecksumCache(e) => write!(f, "Could not save checksum cache: {:#}", e),
+            SyncError::State(e) => write!(f, "Could not save sync state: {:#}", e),
+            SyncError::Hook(e) => write!(f, "Hook failed: {:#}", e),
+            SyncError::Snapshot(e) => write!(f, "Could not create snapshot: {:#}", e),
+            SyncError::Join(name) => write!(f, "Could not join {} thread", name),
+        }
+    }
+}
+
+impl std::error::Error for SyncError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SyncError::Walk(e)
+            | SyncError::Copy(e)
+            | SyncError::Delete(e)
+            | SyncError::Watch(e)
+            | SyncError::Manifest(e)
+            | SyncError::ChecksumCache(e)
+            | SyncError::State(e)
+            | SyncError::Hook(e)
+            | SyncError::Snapshot(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! filesystem
+//!
+//! Operations the sync pipeline performs on whole entries, behind a trait,
+//! so that the workers do not have to assume the entries live on a local
+//! file system.
+//!
+//! File contents are still read and written with `std::fs` in `fsops`,
+//! because sparse copies, reflinks and in-place updates all need real
+//! local files.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    /// FIFOs, sockets and device nodes
+    Special,
+}
+
+pub trait Filesystem: Send + Sync {
+    /// Paths of the entries of the `dir` directory, in no particular order
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Kind of the entry at `path`. Symlinks are not followed
+    fn stat(&self, path: &Path) -> io::Result<FileKind>;
+
+    /// Size of the entry at `path`, in bytes. Symlinks are not followed
+    fn size(&self, path: &Path) -> io::Result<u64>;
+
+    /// Create the `dir` directory, along with its missing parents
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+
+    /// Remove a file, a symlink or an empty directory
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    fn exists(&self, path: &Path) -> bool {
+        self.stat(path).is_ok()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.stat(path).ok() == Some(FileKind::Dir)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.stat(path).ok() == Some(FileKind::File)
+    }
+}
+
+pub struct LocalFilesystem {}
+
+impl Filesystem for LocalFilesystem {
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect()
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<FileKind> {
+        let file_type = fs::symlink_metadata(path)?.file_type();
+        let kind = if file_type.is_symlink() {
+            FileKind::Symlink
+        } else if file_type.is_dir() {
+            FileKind::Dir
+        } else if file_type.is_file() {
+            FileKind::File
+        } else {
+            FileKind::Special
+        };
+        Ok(kind)
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::symlink_metadata(path)?.len())
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        if self.stat(path)? == FileKind::Dir {
+            fs::remove_dir(path)
+        } else {
+            fs::remove_file(path)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+
+    /// Keeps the kind of each entry in memory, for tests
+    #[derive(Default)]
+    pub struct MemoryFilesystem {
+        entries: Mutex<BTreeMap<PathBuf, FileKind>>,
+    }
+
+    impl MemoryFilesystem {
+        pub fn add(&self, path: &str, kind: FileKind) {
+            let mut entries = self.entries.lock().unwrap();
+            entries.insert(PathBuf::from(path), kind);
+        }
+
+        pub fn paths(&self) -> Vec<String> {
+            let entries = self.entries.lock().unwrap();
+            entries
+                .keys()
+                .map(|p| p.to_string_lossy().to_string())
+                .collect()
+        }
+    }
+
+    impl Filesystem for MemoryFilesystem {
+        fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+            if !self.is_dir(dir) {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "no such directory"));
+            }
+            let entries = self.entries.lock().unwrap();
+            Ok(entries
+                .keys()
+                .filter(|p| p.parent() == Some(dir))
+                .cloned()
+                .collect())
+        }
+
+        fn stat(&self, path: &Path) -> io::Result<FileKind> {
+            let entries = self.entries.lock().unwrap();
+            entries
+                .get(path)
+                .cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such entry"))
+        }
+
+        // Only kinds are kept in memory
+        fn size(&self, path: &Path) -> io::Result<u64> {
+            self.stat(path).map(|_| 0)
+        }
+
+        fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+            let mut entries = self.entries.lock().unwrap();
+            for ancestor in dir.ancestors() {
+                entries.insert(ancestor.to_path_buf(), FileKind::Dir);
+            }
+            Ok(())
+        }
+
+        fn remove(&self, path: &Path) -> io::Result<()> {
+            if self.is_dir(path) && !self.list(path)?.is_empty() {
+                return Err(io::Error::other("directory not empty"));
+            }
+            let mut entries = self.entries.lock().unwrap();
+            entries.remove(path);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_local_filesystem() {
+        let tmp_dir = tempdir::TempDir::new("test-rusync-filesystem").unwrap();
+        let local = LocalFilesystem {};
+        let sub = tmp_dir.path().join("a/b");
+        local.create_dir_all(&sub).unwrap();
+        std::fs::write(sub.join("c.txt"), "c").unwrap();
+        assert_eq!(local.stat(&sub).unwrap(), FileKind::Dir);
+        assert!(local.is_file(&sub.join("c.txt")));
+        assert_eq!(local.list(&sub).unwrap(), vec![sub.join("c.txt")]);
+
+        assert!(local.remove(&sub).is_err());
+        local.remove(&sub.join("c.txt")).unwrap();
+        local.remove(&sub).unwrap();
+        assert!(!local.exists(&sub));
+    }
+}
@@ -0,0 +1,472 @@
+//! filters
+//!
+//! Include/exclude rules using rsync-like glob patterns:
+//!
+//! * `*` matches anything except `/`, `**` matches anything, `?` matches one
+//!   character except `/`, and `[a-z]` / `[!a-z]` match character classes
+//! * a pattern starting with `/` is anchored to the root of the source,
+//!   otherwise it is matched against the end of the relative path
+//! * a pattern ending with `/` only matches directories
+//!
+//! Rules are evaluated in order, and the first one that matches wins.
+//!
+//! This module also knows how to read `.gitignore` files, which use the same
+//! kind of patterns but where the *last* matching rule wins.
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::regex::Regex;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(char),
+    AnyChar,
+    Star,
+    DoubleStar,
+    // `**/`, which also matches zero directories
+    AnyDirs,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Glob {
+    tokens: Vec<Token>,
+    anchored: bool,
+    dir_only: bool,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Glob {
+        let mut pattern = pattern;
+        let anchored = pattern.starts_with('/');
+        if anchored {
+            pattern = &pattern[1..];
+        }
+        let dir_only = pattern.ends_with('/');
+        if dir_only {
+            pattern = &pattern[..pattern.len() - 1];
+        }
+        Glob {
+            tokens: parse(pattern),
+            anchored,
+            dir_only,
+        }
+    }
+
+    /// Returns true if `rel_path` (relative to the source) matches the pattern
+    pub fn is_match(&self, rel_path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let path: Vec<char> = rel_path
+            .to_string_lossy()
+            .replace('\\', "/")
+            .chars()
+            .collect();
+        let mut matcher = Matcher::new(&self.tokens, &path);
+        if self.anchored {
+            return matcher.matches(0, 0);
+        }
+        // Try every suffix of the path that starts on a component boundary
+        let mut start = 0;
+        loop {
+            if matcher.matches(0, start) {
+                return true;
+            }
+            match path[start..].iter().position(|&c| c == '/') {
+                Some(i) => start += i + 1,
+                None => return false,
+            }
+        }
+    }
+}
+
+fn parse(pattern: &str) -> Vec<Token> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '*' => {
+                if chars.get(i + 1) == Some(&'*') && chars.get(i + 2) == Some(&'/') {
+                    tokens.push(Token::AnyDirs);
+                    i += 2;
+                } else if chars.get(i + 1) == Some(&'*') {
+                    tokens.push(Token::DoubleStar);
+                    i += 1;
+                } else {
+                    tokens.push(Token::Star);
+                }
+            }
+            '?' => tokens.push(Token::AnyChar),
+            '[' => match parse_class(&chars[i + 1..]) {
+                Some((token, len)) => {
+                    tokens.push(token);
+                    i += len;
+                }
+                // Unterminated class: treat the bracket literally
+                None => tokens.push(Token::Literal('[')),
+            },
+            '\\' if i + 1 < chars.len() => {
+                tokens.push(Token::Literal(chars[i + 1]));
+                i += 1;
+            }
+            _ => tokens.push(Token::Literal(c)),
+        }
+        i += 1;
+    }
+    tokens
+}
+
+// Parse the inside of a [...] class. Returns the token and the number
+// of chars consumed, including the closing bracket
+fn parse_class(chars: &[char]) -> Option<(Token, usize)> {
+    let mut i = 0;
+    let negated = matches!(chars.first(), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+    let mut ranges = vec![];
+    let mut first = true;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == ']' && !first {
+            return Some((Token::Class { negated, ranges }, i + 1));
+        }
+        first = false;
+        if i + 2 < chars.len() && chars[i + 1] == '-' && chars[i + 2] != ']' {
+            ranges.push((c, chars[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((c, c));
+            i += 1;
+        }
+    }
+    None
+}
+
+// Matches tokens against the text, starting from given indices. Remembers
+// the outcome for each pair of indices, otherwise patterns with many stars
+// would take exponential time on long names that do not match
+struct Matcher<'a> {
+    tokens: &'a [Token],
+    text: &'a [char],
+    memo: Vec<Option<bool>>,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(tokens: &'a [Token], text: &'a [char]) -> Matcher<'a> {
+        Matcher {
+            tokens,
+            text,
+            memo: vec![None; (tokens.len() + 1) * (text.len() + 1)],
+        }
+    }
+
+    fn matches(&mut self, t: usize, i: usize) -> bool {
+        let key = t * (self.text.len() + 1) + i;
+        if let Some(known) = self.memo[key] {
+            return known;
+        }
+        let result = self.compute(t, i);
+        self.memo[key] = Some(result);
+        result
+    }
+
+    fn compute(&mut self, t: usize, i: usize) -> bool {
+        let text = self.text;
+        match self.tokens.get(t) {
+            None => i == text.len(),
+            Some(Token::Star) => {
+                for j in i..=text.len() {
+                    if self.matches(t + 1, j) {
+                        return true;
+                    }
+                    if j < text.len() && text[j] == '/' {
+                        return false;
+                    }
+                }
+                false
+            }
+            Some(Token::DoubleStar) => (i..=text.len()).any(|j| self.matches(t + 1, j)),
+            Some(Token::AnyDirs) => {
+                if self.matches(t + 1, i) {
+                    return true;
+                }
+                (i..text.len()).any(|j| text[j] == '/' && self.matches(t + 1, j + 1))
+            }
+            Some(token) => match text.get(i) {
+                None => false,
+                Some(&c) => {
+                    let ok = match token {
+                        Token::Literal(l) => *l == c,
+                        Token::AnyChar => c != '/',
+                        Token::Class { negated, ranges } => {
+                            let in_class = ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi);
+                            c != '/' && in_class != *negated
+                        }
+                        Token::Star | Token::DoubleStar | Token::AnyDirs => unreachable!(),
+                    };
+                    ok && self.matches(t + 1, i + 1)
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Rule {
+    Include(Glob),
+    Exclude(Glob),
+    IncludeRegex(Regex),
+    ExcludeRegex(Regex),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Filters {
+    rules: Vec<Rule>,
+}
+
+impl Filters {
+    pub fn new() -> Filters {
+        Filters { rules: vec![] }
+    }
+
+    pub fn add_include(&mut self, pattern: &str) {
+        self.rules.push(Rule::Include(Glob::new(pattern)));
+    }
+
+    pub fn add_exclude(&mut self, pattern: &str) {
+        self.rules.push(Rule::Exclude(Glob::new(pattern)));
+    }
+
+    pub fn add_include_regex(&mut self, regex: Regex) {
+        self.rules.push(Rule::IncludeRegex(regex));
+    }
+
+    pub fn add_exclude_regex(&mut self, regex: Regex) {
+        self.rules.push(Rule::ExcludeRegex(regex));
+    }
+
+    /// Exclude `pattern` whatever the other rules say
+    pub fn protect(&mut self, pattern: &str) {
+        self.rules.insert(0, Rule::Exclude(Glob::new(pattern)));
+    }
+
+    /// Returns true if the entry at `rel_path` should be left out of the sync
+    pub fn is_excluded(&self, rel_path: &Path, is_dir: bool) -> bool {
+        // Regexes are matched against the path with forward slashes
+        let text = rel_path.to_string_lossy().replace('\\', "/");
+        for rule in &self.rules {
+            match rule {
+                Rule::Include(glob) if glob.is_match(rel_path, is_dir) => return false,
+                Rule::Exclude(glob) if glob.is_match(rel_path, is_dir) => return true,
+                Rule::IncludeRegex(regex) if regex.is_match(&text) => return false,
+                Rule::ExcludeRegex(regex) if regex.is_match(&text) => return true,
+                _ => {}
+            }
+        }
+        false
+    }
+}
+
+/// Rules read from a `.gitignore` or `.ignore` file
+#[derive(Debug)]
+pub struct IgnoreFile {
+    // Directory containing the file, relative to the source
+    base: PathBuf,
+    // Each pattern, and whether it was negated with a leading `!`
+    rules: Vec<(Glob, bool)>,
+}
+
+impl IgnoreFile {
+    pub fn parse(base: &Path, contents: &str) -> IgnoreFile {
+        let mut rules = vec![];
+        for line in contents.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, pattern) = match line.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, line),
+            };
+            // Contrary to rsync, a slash in the middle of the pattern
+            // anchors it to the directory containing the .gitignore
+            let is_anchored = pattern.trim_end_matches('/').contains('/');
+            let glob = if is_anchored && !pattern.starts_with('/') {
+                Glob::new(&format!("/{}", pattern))
+            } else {
+                Glob::new(pattern)
+            };
+            rules.push((glob, negated));
+        }
+        IgnoreFile {
+            base: base.to_path_buf(),
+            rules,
+        }
+    }
+
+    // Returns Some(true) if the entry is ignored, Some(false) if it was
+    // re-included with a negated pattern, and None if no rule matched
+    fn matched(&self, rel_path: &Path, is_dir: bool) -> Option<bool> {
+        let rel_path = rel_path.strip_prefix(&self.base).ok()?;
+        for (glob, negated) in self.rules.iter().rev() {
+            if glob.is_match(rel_path, is_dir) {
+                return Some(!negated);
+            }
+        }
+        None
+    }
+}
+
+/// Returns true if the entry at `rel_path` is ignored, given all the ignore
+/// files found from the root of the source down to the entry's directory
+pub fn is_ignored(ignore_files: &[Rc<IgnoreFile>], rel_path: &Path, is_dir: bool) -> bool {
+    // Rules in deeper directories take precedence
+    for ignore_file in ignore_files.iter().rev() {
+        if let Some(ignored) = ignore_file.matched(rel_path, is_dir) {
+            return ignored;
+        }
+    }
+    false
+}
+
+/// Read one pattern per line from the file at `path`, skipping blank lines
+/// and comments
+pub fn read_patterns(path: &Path) -> io::Result<Vec<String>> {
+    let contents = fs::read_to_string(path)?;
+    Ok(parse_patterns(&contents))
+}
+
+fn parse_patterns(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+/// Returns true for dotfiles and dot-directories, like `.git` or `.DS_Store`
+pub fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_match(pattern: &str, path: &str) -> bool {
+        Glob::new(pattern).is_match(Path::new(path), false)
+    }
+
+    #[test]
+    fn test_basename_patterns() {
+        assert!(is_match("*.o", "foo.o"));
+        assert!(is_match("*.o", "src/foo.o"));
+        assert!(!is_match("*.o", "foo.c"));
+        assert!(is_match("foo?.txt", "a/foo1.txt"));
+        assert!(!is_match("foo?.txt", "a/foo12.txt"));
+    }
+
+    #[test]
+    fn test_star_does_not_cross_slashes() {
+        assert!(!is_match("/*.txt", "a_dir/one.txt"));
+        assert!(is_match("/*.txt", "top.txt"));
+        assert!(is_match("/**.txt", "a_dir/one.txt"));
+        assert!(is_match("a/**/c", "a/b/b/c"));
+    }
+
+    #[test]
+    fn test_many_stars_do_not_backtrack_forever() {
+        let name = "a".repeat(200);
+        assert!(!is_match("*a*a*a*a*a*a*a*a*b", &name));
+        assert!(!is_match("**a**a**a**a**a**a**b", &name));
+        assert!(is_match("*a*a*a*a*a*a*a*a*", &name));
+    }
+
+    #[test]
+    fn test_unanchored_patterns_match_on_component_boundaries() {
+        assert!(is_match("b/c.txt", "a/b/c.txt"));
+        assert!(!is_match("b/c.txt", "a/bb/c.txt"));
+        assert!(!is_match("/b/c.txt", "a/b/c.txt"));
+    }
+
+    #[test]
+    fn test_character_classes() {
+        assert!(is_match("file[0-9].log", "file3.log"));
+        assert!(!is_match("file[0-9].log", "filex.log"));
+        assert!(is_match("file[!0-9].log", "filex.log"));
+        assert!(is_match("[].txt", "[].txt"));
+    }
+
+    #[test]
+    fn test_dir_only_patterns() {
+        let glob = Glob::new("target/");
+        assert!(glob.is_match(Path::new("target"), true));
+        assert!(!glob.is_match(Path::new("target"), false));
+    }
+
+    #[test]
+    fn test_double_star_slash_matches_zero_dirs() {
+        assert!(is_match("a/**/c", "a/c"));
+        assert!(is_match("**/c", "c"));
+        assert!(!is_match("a/**/c", "a/bc"));
+    }
+
+    #[test]
+    fn test_gitignore_last_match_wins() {
+        let contents = "# build artifacts\n*.log\n!important.log\n\ntarget/\n";
+        let ignore_file = Rc::new(IgnoreFile::parse(Path::new(""), contents));
+        let ignore_files = vec![ignore_file];
+        assert!(is_ignored(&ignore_files, Path::new("a/debug.log"), false));
+        assert!(!is_ignored(
+            &ignore_files,
+            Path::new("a/important.log"),
+            false
+        ));
+        assert!(is_ignored(&ignore_files, Path::new("target"), true));
+        assert!(!is_ignored(&ignore_files, Path::new("target"), false));
+    }
+
+    #[test]
+    fn test_gitignore_patterns_are_relative_to_their_directory() {
+        let root = Rc::new(IgnoreFile::parse(Path::new(""), "*.tmp\n"));
+        let sub = Rc::new(IgnoreFile::parse(Path::new("sub"), "/build\n!keep.tmp\n"));
+        let ignore_files = vec![root, sub];
+        assert!(is_ignored(&ignore_files, Path::new("sub/build"), true));
+        assert!(!is_ignored(&ignore_files, Path::new("build"), true));
+        assert!(!is_ignored(
+            &ignore_files,
+            Path::new("sub/other/build"),
+            true
+        ));
+        assert!(!is_ignored(&ignore_files, Path::new("sub/keep.tmp"), false));
+        assert!(is_ignored(&ignore_files, Path::new("sub/other.tmp"), false));
+    }
+
+    #[test]
+    fn test_first_match_wins() {
+        let mut filters = Filters::new();
+        filters.add_include("keep.o");
+        filters.add_exclude("*.o");
+        assert!(!filters.is_excluded(Path::new("keep.o"), false));
+        assert!(filters.is_excluded(Path::new("other.o"), false));
+        assert!(!filters.is_excluded(Path::new("main.c"), false));
+    }
+
+    #[test]
+    fn test_parse_patterns_skips_comments_and_blank_lines() {
+        let contents = "# build outputs\n*.o\n\n  \ntarget/\r\n";
+        assert_eq!(parse_patterns(contents), vec!["*.o", "target/"]);
+    }
+}
@@ -1,6 +1,9 @@
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
 #[cfg(unix)]
 use std::os::unix;
@@ -11,25 +14,151 @@ use std::sync::mpsc;
 use anyhow::{bail, Context, Error};
 use filetime::FileTime;
 
+use crate::backup::Backup;
+use crate::checksum::{self, HashAlgorithm, Hasher};
+use crate::checksum_cache::ChecksumCache;
+use crate::chmod::Chmod;
+use crate::direct_io::{self, AlignedBuffer};
 use crate::entry::Entry;
+use crate::filters::Filters;
+use crate::id_map::IdMaps;
+use crate::in_place;
+use crate::junction;
+use crate::mmap;
 use crate::progress::ProgressMessage;
+use crate::sync::{JunctionPolicy, ReflinkMode, SymlinkPolicy, SyncOptions};
+use crate::throttle::Throttle;
 
-const BUFFER_SIZE: usize = 100 * 1024;
+/// How much of a file is read and written at once, see
+/// `SyncOptions.buffer_size`
+pub const DEFAULT_BUFFER_SIZE: usize = 100 * 1024;
+
+/// Appended to the name of files being written when using `SyncOptions.partial`
+pub const PART_SUFFIX: &str = ".rusync-part";
+
+/// Name of the directory holding the files being written when using
+/// `SyncOptions.partial_dir`, in the root of the destination
+pub const PARTIAL_DIR_NAME: &str = ".rusync-partial";
+
+/// Appended to the name of the temporary files written when using
+/// `SyncOptions.atomic`, after a leading dot
+pub const TMP_SUFFIX: &str = ".rusync-tmp";
 
 #[derive(PartialEq, Debug)]
 pub enum SyncOutcome {
     UpToDate,
-    FileCopied { size: u64 },
+    FileCopied {
+        size: u64,
+    },
+    SparseFileCopied {
+        size: u64,
+    },
+    FileCloned {
+        size: u64,
+    },
+    UpdatedInPlace {
+        size: u64,
+        saved: u64,
+    },
     SymlinkUpdated,
     SymlinkCreated,
+    SymlinkSkipped,
+    JunctionCreated,
+    JunctionSkipped,
+    HardLinkCreated,
+    FileLinked {
+        size: u64,
+    },
+    SpecialCreated,
+    SpecialSkipped,
+    /// The file is bigger than `SyncOptions.max_size`
+    SizeSkipped,
+    /// The entry does not exist in the destination, see `SyncOptions.existing`
+    MissingSkipped,
+    /// The file changed in the destination too, and was kept, see
+    /// `SyncOptions.conflicts`
+    ConflictSkipped,
+}
+
+impl SyncOutcome {
+    /// Returns true if the contents of the file were written
+    pub fn is_copy(&self) -> bool {
+        matches!(
+            self,
+            SyncOutcome::FileCopied { .. }
+                | SyncOutcome::SparseFileCopied { .. }
+                | SyncOutcome::FileCloned { .. }
+                | SyncOutcome::UpdatedInPlace { .. }
+        )
+    }
+}
+
+/// Returns the kind of the underlying io::Error, if any
+pub fn error_kind(error: &Error) -> io::ErrorKind {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<io::Error>())
+        .map_or(io::ErrorKind::Other, |io_error| io_error.kind())
 }
 
 pub fn get_rel_path(a: &Path, b: &Path) -> PathBuf {
-    pathdiff::diff_paths(&a, &b)
+    pathdiff::diff_paths(a, b)
         .expect("called get_rel_path on two absolute paths '{}' and '{}', a, b")
 }
 
-fn is_more_recent_than(src: &Entry, dest: &Entry) -> bool {
+/// Turns `path` into its extended-length form (`\\?\C:\...`), so that
+/// paths longer than 260 characters can be used on Windows.
+///
+/// Such paths are not normalized by Windows, so `path` is made absolute
+/// and `.` and `..` are resolved here
+#[cfg(windows)]
+pub fn extended_length_path(path: &Path) -> PathBuf {
+    use std::ffi::OsString;
+    use std::path::{Component, Prefix};
+
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        match std::env::current_dir() {
+            Ok(dir) => dir.join(path),
+            Err(_) => return path.to_path_buf(),
+        }
+    };
+    let mut components = absolute.components();
+    let mut res = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(letter) => PathBuf::from(format!(r"\\?\{}:\", letter as char)),
+            Prefix::UNC(server, share) => {
+                let mut res = OsString::from(r"\\?\UNC\");
+                res.push(server);
+                res.push(r"\");
+                res.push(share);
+                res.push(r"\");
+                PathBuf::from(res)
+            }
+            // Already in extended-length form, or a device path
+            _ => return path.to_path_buf(),
+        },
+        _ => return path.to_path_buf(),
+    };
+    for component in components {
+        match component {
+            Component::Normal(name) => res.push(name),
+            Component::ParentDir => {
+                res.pop();
+            }
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+    res
+}
+
+#[cfg(not(windows))]
+pub fn extended_length_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+fn is_more_recent_than(src: &Entry, dest: &Entry, opts: SyncOptions) -> bool {
     if !dest.exists() {
         return true;
     }
@@ -40,14 +169,56 @@ fn is_more_recent_than(src: &Entry, dest: &Entry) -> bool {
     let src_meta = &src_meta.expect("src_meta was None");
     let dest_meta = &dest_meta.expect("dest_meta was None");
 
-    let src_mtime = FileTime::from_last_modification_time(&src_meta);
-    let dest_mtime = FileTime::from_last_modification_time(&dest_meta);
+    mtime_nanos(src_meta) > mtime_nanos(dest_meta) + opts.modify_window.as_nanos()
+}
 
-    let src_precise = src_mtime.seconds() * 1000 * 1000 * 1000 + u64::from(src_mtime.nanoseconds());
-    let dest_precise =
-        dest_mtime.seconds() * 1000 * 1000 * 1000 + u64::from(dest_mtime.nanoseconds());
+/// Wether the modification times of `a` and `b` are equal, give or take
+/// `SyncOptions.modify_window`
+pub fn same_mtime(a: &fs::Metadata, b: &fs::Metadata, opts: SyncOptions) -> bool {
+    mtime_nanos(a).abs_diff(mtime_nanos(b)) <= opts.modify_window.as_nanos()
+}
 
-    src_precise > dest_precise
+fn mtime_nanos(metadata: &fs::Metadata) -> u128 {
+    let mtime = FileTime::from_last_modification_time(metadata);
+    u128::from(mtime.seconds()) * 1_000_000_000 + u128::from(mtime.nanoseconds())
+}
+
+#[cfg(unix)]
+pub fn copy_ownership(
+    src: &Entry,
+    dest: &Entry,
+    preserve_owner: bool,
+    preserve_group: bool,
+    id_maps: &IdMaps,
+) -> Result<(), Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let src_meta = src.metadata().expect("src_meta should not be None");
+    // dest was just written, so re-read its metadata
+    let dest_meta = fs::symlink_metadata(dest.path()).with_context(|| {
+        format!(
+            "Could not read metadata of '{}' while copying ownership",
+            dest.description()
+        )
+    })?;
+    let src_uid = id_maps.users.map(src_meta.uid());
+    let src_gid = id_maps.groups.map(src_meta.gid());
+    let uid = if preserve_owner && src_uid != dest_meta.uid() {
+        Some(src_uid)
+    } else {
+        None
+    };
+    let gid = if preserve_group && src_gid != dest_meta.gid() {
+        Some(src_gid)
+    } else {
+        None
+    };
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+    unix::fs::lchown(dest.path(), uid, gid)
+        .with_context(|| format!("Could not set ownership of {}", dest.description()))?;
+    Ok(())
 }
 
 #[cfg(unix)]
@@ -65,19 +236,373 @@ pub fn copy_permissions(src: &Entry, dest: &Entry) -> Result<(), Error> {
     // and we checked that right above:
     let src_meta = &src_meta.unwrap_or_else(|| panic!("src_meta was None for {:#?}", src));
     let permissions = src_meta.permissions();
-    let dest_file = File::open(dest.path()).with_context(|| {
+    // Note: do not open dest here - opening a FIFO would block
+    fs::set_permissions(dest.path(), permissions)
+        .with_context(|| format!("Could not set permissions for {}", dest.description()))?;
+    Ok(())
+}
+
+/// Change the permissions of `dest`, once written, with the `chmod` rules
+#[cfg(unix)]
+pub fn apply_chmod(dest: &Entry, chmod: &Chmod) -> Result<(), Error> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let dest_meta = fs::symlink_metadata(dest.path()).with_context(|| {
         format!(
-            "Could not open '{}' while copying permissions",
+            "Could not read metadata of '{}' while changing permissions",
             dest.description()
         )
     })?;
-    dest_file
-        .set_permissions(permissions)
+    // Symlinks have no permissions of their own
+    if dest_meta.file_type().is_symlink() {
+        return Ok(());
+    }
+    let mode = chmod.apply(dest_meta.mode(), dest_meta.is_dir());
+    if mode == dest_meta.mode() & 0o7777 {
+        return Ok(());
+    }
+    fs::set_permissions(dest.path(), fs::Permissions::from_mode(mode))
         .with_context(|| format!("Could not set permissions for {}", dest.description()))?;
     Ok(())
 }
 
-fn copy_link(src: &Entry, dest: &Entry) -> Result<SyncOutcome, Error> {
+/// On Windows, permissions are the read-only, hidden, system, archive and
+/// not-indexed attributes
+#[cfg(windows)]
+pub fn copy_permissions(src: &Entry, dest: &Entry) -> Result<(), Error> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::fs::MetadataExt;
+
+    const FILE_ATTRIBUTE_READONLY: u32 = 0x1;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+    const FILE_ATTRIBUTE_ARCHIVE: u32 = 0x20;
+    const FILE_ATTRIBUTE_NORMAL: u32 = 0x80;
+    const FILE_ATTRIBUTE_NOT_CONTENT_INDEXED: u32 = 0x2000;
+    const PRESERVED: u32 = FILE_ATTRIBUTE_READONLY
+        | FILE_ATTRIBUTE_HIDDEN
+        | FILE_ATTRIBUTE_SYSTEM
+        | FILE_ATTRIBUTE_ARCHIVE
+        | FILE_ATTRIBUTE_NOT_CONTENT_INDEXED;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetFileAttributesW(name: *const u16, attributes: u32) -> i32;
+    }
+
+    // Setting attributes would follow the link
+    if src.is_link() != Some(false) {
+        return Ok(());
+    }
+    let src_meta = src.metadata().expect("src_meta should not be None");
+    let mut attributes = src_meta.file_attributes() & PRESERVED;
+    if attributes == 0 {
+        // Only valid alone, and clears the other attributes
+        attributes = FILE_ATTRIBUTE_NORMAL;
+    }
+    let name: Vec<u16> = dest
+        .path()
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+    if unsafe { SetFileAttributesW(name.as_ptr(), attributes) } == 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Could not set attributes for {}", dest.description()));
+    }
+    Ok(())
+}
+
+pub fn copy_times(src: &Entry, dest: &Entry) -> Result<(), Error> {
+    let src_meta = src.metadata().expect("src_meta should not be None");
+    let atime = FileTime::from_last_access_time(src_meta);
+    let mtime = FileTime::from_last_modification_time(src_meta);
+    filetime::set_file_times(dest.path(), atime, mtime)
+        .with_context(|| format!("Could not set modification time for {}", dest.description()))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// Make `dest` a hard link to `target`, which was synced earlier
+/// from another path to the same source inode
+pub fn create_hard_link(
+    target: &Path,
+    dest: &Entry,
+    backup: Option<&Backup>,
+) -> Result<SyncOutcome, Error> {
+    match dest.is_link() {
+        None => (),
+        Some(_) if is_same_file(target, dest.path()) => return Ok(SyncOutcome::UpToDate),
+        Some(_) => {
+            if dest.path().is_dir() {
+                bail!(
+                    "Refusing to replace existing directory {} by hard link",
+                    dest.description()
+                );
+            }
+            replace(dest, backup)
+                .with_context(|| format!("While creating hard link {}", dest.description()))?;
+        }
+    }
+    fs::hard_link(target, dest.path()).with_context(|| {
+        format!(
+            "Could not create hard link from {} to {}",
+            dest.description(),
+            target.display()
+        )
+    })?;
+    Ok(SyncOutcome::HardLinkCreated)
+}
+
+/// If `previous`, the same entry in an older copy of the destination, has
+/// the same contents and attributes as `src`, make `dest` a hard link to it
+/// instead of copying `src`. Returns None if `src` needs to be copied
+#[allow(clippy::too_many_arguments)]
+pub fn link_unchanged(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    src: &Entry,
+    dest: &Entry,
+    previous: &Entry,
+    checksums: Option<&ChecksumCache>,
+    opts: SyncOptions,
+    id_maps: &IdMaps,
+    chmod: &Chmod,
+) -> Result<Option<SyncOutcome>, Error> {
+    let is_file = |entry: &Entry| entry.metadata().is_some_and(|m| m.is_file());
+    if dest.exists() || !is_file(src) || !is_file(previous) {
+        return Ok(None);
+    }
+    let src_meta = src.metadata().expect("src_meta should not be None");
+    let previous_meta = previous
+        .metadata()
+        .expect("previous_meta should not be None");
+    if src_meta.len() != previous_meta.len() {
+        return Ok(None);
+    }
+    if opts.checksum {
+        if has_different_contents(src, previous, checksums, opts.hash)? {
+            return Ok(None);
+        }
+    } else if !opts.size_only && !same_mtime(src_meta, previous_meta, opts) {
+        return Ok(None);
+    }
+    // The link shares the attributes of `previous`, which must not change
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let mode = if opts.preserve_permissions {
+            src_meta.mode()
+        } else {
+            previous_meta.mode()
+        };
+        if chmod.apply(mode, false) != previous_meta.mode() & 0o7777
+            || (opts.preserve_owner && id_maps.users.map(src_meta.uid()) != previous_meta.uid())
+            || (opts.preserve_group && id_maps.groups.map(src_meta.gid()) != previous_meta.gid())
+        {
+            return Ok(None);
+        }
+    }
+    let _ = progress_sender.send(ProgressMessage::StartSync(Box::new(src.clone())));
+    fs::hard_link(previous.path(), dest.path()).with_context(|| {
+        format!(
+            "Could not create hard link from {} to {}",
+            dest.description(),
+            previous.path().display()
+        )
+    })?;
+    skip_file(progress_sender, src);
+    Ok(Some(SyncOutcome::FileLinked {
+        size: src_meta.len(),
+    }))
+}
+
+/// Remove `src` once it has been synced to `dest`, after checking that
+/// `dest` has the same size (and contents, with `SyncOptions.checksum`)
+pub fn remove_source(
+    src: &Entry,
+    dest: &Path,
+    checksums: Option<&ChecksumCache>,
+    opts: SyncOptions,
+) -> Result<(), Error> {
+    let src_meta = src.metadata().expect("src_meta should not be None");
+    if src_meta.is_file() {
+        let dest = Entry::new(src.description(), dest);
+        if has_different_size(src, &dest) {
+            bail!(
+                "Not removing source file {}: destination has a different size",
+                src.description()
+            );
+        }
+        if opts.checksum && has_different_contents(src, &dest, checksums, opts.hash)? {
+            bail!(
+                "Not removing source file {}: destination has different contents",
+                src.description()
+            );
+        }
+    }
+    fs::remove_file(src.path())
+        .with_context(|| format!("Could not remove source file {}", src.description()))
+}
+
+/// Remove the empty directories found in `dir`, including the ones that
+/// only contain empty directories, but not `dir` itself. Excluded
+/// directories are left alone. With `report_removals`, each removed
+/// directory is sent as `ProgressMessage::Deleted`. Returns true if `dir` is
+/// now empty
+pub fn remove_empty_dirs(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    dir: &Path,
+    root: &Path,
+    filters: &Filters,
+    report_removals: bool,
+) -> bool {
+    let report = |path: &Path, details: String, kind: io::ErrorKind| {
+        let _ = progress_sender.send(ProgressMessage::SyncError {
+            entry: get_rel_path(path, root).to_string_lossy().to_string(),
+            details,
+            kind,
+        });
+    };
+    let mut entries =
+        match fs::read_dir(dir).and_then(|entries| entries.collect::<Result<Vec<_>, _>>()) {
+            Ok(entries) => entries,
+            Err(e) => {
+                report(
+                    dir,
+                    format!("Could not read '{}': {}", dir.display(), e),
+                    e.kind(),
+                );
+                return false;
+            }
+        };
+    // So that errors are reported in the same order on every run
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut is_empty = true;
+    for entry in entries {
+        let path = entry.path();
+        // Never follow symlinks
+        let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+        if !is_dir || filters.is_excluded(&get_rel_path(&path, root), true) {
+            is_empty = false;
+            continue;
+        }
+        if !remove_empty_dirs(progress_sender, &path, root, filters, report_removals) {
+            is_empty = false;
+            continue;
+        }
+        match fs::remove_dir(&path) {
+            Ok(()) if report_removals => {
+                let rel_path = get_rel_path(&path, root).to_string_lossy().to_string();
+                let _ = progress_sender.send(ProgressMessage::Deleted(rel_path));
+            }
+            Ok(()) => (),
+            Err(e) => {
+                report(
+                    &path,
+                    format!("Could not remove '{}': {}", path.display(), e),
+                    e.kind(),
+                );
+                is_empty = false;
+            }
+        }
+    }
+    is_empty
+}
+
+/// Returns true for FIFOs, sockets and device nodes
+#[cfg(unix)]
+pub fn is_special(entry: &Entry) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    match entry.metadata() {
+        None => false,
+        Some(metadata) => {
+            let file_type = metadata.file_type();
+            file_type.is_fifo()
+                || file_type.is_socket()
+                || file_type.is_block_device()
+                || file_type.is_char_device()
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn is_special(_entry: &Entry) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn copy_special(src: &Entry, dest: &Entry, backup: Option<&Backup>) -> Result<SyncOutcome, Error> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let src_meta = src.metadata().expect("src_meta should not be None");
+    if let Some(dest_meta) = dest.metadata() {
+        if dest_meta.is_dir() {
+            bail!(
+                "Refusing to replace existing directory {} by special file",
+                dest.description()
+            );
+        }
+        // mode_t is not u32 on every platform
+        #[allow(clippy::unnecessary_cast)]
+        let file_type_mask = libc::S_IFMT as u32;
+        let same_type = dest_meta.mode() & file_type_mask == src_meta.mode() & file_type_mask;
+        if same_type && dest_meta.rdev() == src_meta.rdev() {
+            return Ok(SyncOutcome::UpToDate);
+        }
+        replace(dest, backup)
+            .with_context(|| format!("While creating special file {}", dest.description()))?;
+    }
+
+    let dest_path = CString::new(dest.path().as_os_str().as_bytes())
+        .with_context(|| format!("Invalid path: {}", dest.description()))?;
+    let res = if src_meta.file_type().is_fifo() {
+        let mode = src_meta.mode() & 0o7777;
+        unsafe { libc::mkfifo(dest_path.as_ptr(), mode as libc::mode_t) }
+    } else {
+        // mode contains the type of the node
+        let mode = src_meta.mode();
+        let rdev = src_meta.rdev();
+        unsafe {
+            libc::mknod(
+                dest_path.as_ptr(),
+                mode as libc::mode_t,
+                rdev as libc::dev_t,
+            )
+        }
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Could not create special file {}", dest.description()));
+    }
+    Ok(SyncOutcome::SpecialCreated)
+}
+
+#[cfg(not(unix))]
+fn copy_special(
+    _src: &Entry,
+    _dest: &Entry,
+    _backup: Option<&Backup>,
+) -> Result<SyncOutcome, Error> {
+    unreachable!("special files only exist on Unix")
+}
+
+fn copy_link(src: &Entry, dest: &Entry, backup: Option<&Backup>) -> Result<SyncOutcome, Error> {
     let src_target = std::fs::read_link(src.path())
         .with_context(|| format!("While copying source link '{}'", src.description()))?;
 
@@ -88,12 +613,8 @@ fn copy_link(src: &Entry, dest: &Entry) -> Result<SyncOutcome, Error> {
             let dest_target = std::fs::read_link(dest.path())
                 .with_context(|| format!("While creating target link: {}", dest.description()))?;
             if dest_target != src_target {
-                fs::remove_file(dest.path()).with_context(|| {
-                    format!(
-                        "Could not remove {} while updating link",
-                        dest.description()
-                    )
-                })?;
+                replace(dest, backup)
+                    .with_context(|| format!("While updating link {}", dest.description()))?;
                 outcome = SyncOutcome::SymlinkUpdated;
             } else {
                 return Ok(SyncOutcome::UpToDate);
@@ -113,7 +634,7 @@ fn copy_link(src: &Entry, dest: &Entry) -> Result<SyncOutcome, Error> {
     }
     #[cfg(unix)]
     {
-        unix::fs::symlink(&src_target, &dest.path()).with_context(|| {
+        unix::fs::symlink(&src_target, dest.path()).with_context(|| {
             format!(
                 "Could not create link from {} to {}",
                 dest.description(),
@@ -129,20 +650,604 @@ fn copy_link(src: &Entry, dest: &Entry) -> Result<SyncOutcome, Error> {
     }
 }
 
+fn copy_junction(
+    src: &Entry,
+    dest: &Entry,
+    backup: Option<&Backup>,
+    opts: SyncOptions,
+) -> Result<SyncOutcome, Error> {
+    // Dereferenced junctions are walked like directories
+    if opts.junctions == JunctionPolicy::Skip {
+        return Ok(SyncOutcome::JunctionSkipped);
+    }
+    let target = fs::read_link(src.path())
+        .with_context(|| format!("Could not read junction '{}'", src.description()))?;
+    match dest.is_link() {
+        Some(true) => {
+            if fs::read_link(dest.path()).ok().as_ref() == Some(&target) {
+                return Ok(SyncOutcome::UpToDate);
+            }
+            match backup {
+                Some(backup) => backup.save(dest.path(), Path::new(dest.description()))?,
+                // Junctions are removed like directories
+                None => fs::remove_dir(dest.path())
+                    .with_context(|| format!("Could not remove {}", dest.description()))?,
+            }
+        }
+        Some(false) => bail!(
+            "Refusing to replace existing path {} by junction",
+            dest.description()
+        ),
+        None => {}
+    }
+    junction::create(&target, dest.path())
+        .with_context(|| format!("Could not create junction {}", dest.description()))?;
+    Ok(SyncOutcome::JunctionCreated)
+}
+
+fn plan_junction(src: &Entry, dest: &Entry, opts: SyncOptions) -> Result<SyncOutcome, Error> {
+    if opts.junctions == JunctionPolicy::Skip {
+        return Ok(SyncOutcome::JunctionSkipped);
+    }
+    match dest.is_link() {
+        Some(false) => bail!(
+            "Refusing to replace existing path {} by junction",
+            dest.description()
+        ),
+        Some(true) if fs::read_link(src.path())? == fs::read_link(dest.path())? => {
+            Ok(SyncOutcome::UpToDate)
+        }
+        _ => Ok(SyncOutcome::JunctionCreated),
+    }
+}
+
+// Make dest_path a copy-on-write clone of src. Returns Ok(false) if this is
+// not supported, for instance because the file system does not know how to do
+// it or because src and dest are on different file systems
+#[cfg(target_os = "linux")]
+fn clone_file(src: &Entry, dest_path: &Path) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    // _IOW(0x94, 9, int), from linux/fs.h
+    const FICLONE: libc::c_ulong = 0x4004_9409;
+    let src_file = File::open(src.path())?;
+    let dest_file = File::create(dest_path)?;
+    let res = unsafe { libc::ioctl(dest_file.as_raw_fd(), FICLONE as _, src_file.as_raw_fd()) };
+    if res == 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::EXDEV) | Some(libc::EINVAL) | Some(libc::ENOTTY) => {
+            Ok(false)
+        }
+        _ => Err(error),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn clone_file(src: &Entry, dest_path: &Path) -> io::Result<bool> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    extern "C" {
+        fn clonefile(src: *const libc::c_char, dst: *const libc::c_char, flags: u32)
+            -> libc::c_int;
+    }
+
+    let to_cstring = |path: &Path| {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let c_src_path = to_cstring(src.path())?;
+    let c_dest_path = to_cstring(dest_path)?;
+    // clonefile() refuses to overwrite existing files
+    if dest_path.exists() {
+        fs::remove_file(dest_path)?;
+    }
+    let res = unsafe { clonefile(c_src_path.as_ptr(), c_dest_path.as_ptr(), 0) };
+    if res == 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::ENOTSUP) | Some(libc::EXDEV) => Ok(false),
+        _ => Err(error),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn clone_file(_src: &Entry, _dest_path: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+// Reserve the space for `size` bytes, without changing the size of the file.
+// Does nothing on file systems that do not support it
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, size: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let res = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            0,
+            size as libc::off_t,
+        )
+    };
+    if res == 0 {
+        return Ok(());
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(()),
+        _ => Err(error),
+    }
+}
+
+#[cfg(windows)]
+fn preallocate(file: &File, size: u64) -> io::Result<()> {
+    use std::ffi::c_void;
+    use std::os::windows::io::AsRawHandle;
+
+    const FILE_ALLOCATION_INFO: i32 = 5;
+    const ERROR_DISK_FULL: i32 = 112;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetFileInformationByHandle(
+            file: *mut c_void,
+            class: i32,
+            info: *mut c_void,
+            size: u32,
+        ) -> i32;
+    }
+
+    // FILE_ALLOCATION_INFO only contains the allocation size
+    let mut allocation_size = size as i64;
+    let res = unsafe {
+        SetFileInformationByHandle(
+            file.as_raw_handle() as *mut c_void,
+            FILE_ALLOCATION_INFO,
+            &mut allocation_size as *mut i64 as *mut c_void,
+            std::mem::size_of::<i64>() as u32,
+        )
+    };
+    if res != 0 {
+        return Ok(());
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(ERROR_DISK_FULL) => Err(error),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn preallocate(_file: &File, _size: u64) -> io::Result<()> {
+    Ok(())
+}
+
+// Remove dest so that it can be replaced, or move it to the backup dir
+fn replace(dest: &Entry, backup: Option<&Backup>) -> Result<(), Error> {
+    match backup {
+        Some(backup) => backup.save(dest.path(), Path::new(dest.description())),
+        None => fs::remove_file(dest.path())
+            .with_context(|| format!("Could not remove {}", dest.description())),
+    }
+}
+
+// Returns true if dest is a file with other hard links to it
+#[cfg(unix)]
+fn is_hard_linked(dest: &Entry) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    dest.metadata()
+        .is_some_and(|m| m.is_file() && m.nlink() > 1)
+}
+
+#[cfg(not(unix))]
+fn is_hard_linked(_dest: &Entry) -> bool {
+    false
+}
+
+/// Copy src to dest. With `SyncOptions.verify_copies`, also returns the
+/// checksum of the source
 pub fn copy_entry(
     progress_sender: &mpsc::Sender<ProgressMessage>,
+    throttle: &Throttle,
+    backup: Option<&Backup>,
     src: &Entry,
     dest: &Entry,
-) -> Result<SyncOutcome, Error> {
-    let src_path = src.path();
-    let mut src_file = File::open(src_path)
+    opts: SyncOptions,
+) -> Result<(SyncOutcome, Option<Vec<u8>>), Error> {
+    let dest_is_file = dest.metadata().is_some_and(|m| m.is_file() && m.len() > 0);
+    // In-place updates leave nothing to back up, and would change the other
+    // hard links too
+    if opts.in_place && dest_is_file && !opts.atomic && backup.is_none() && !is_hard_linked(dest) {
+        // Cloning would throw away the existing destination contents
+        let outcome = update_in_place(progress_sender, throttle, src, dest)?;
+        let digest = if opts.verify_copies {
+            Some(verify_copy(src, dest.path(), None, opts)?)
+        } else {
+            None
+        };
+        return Ok((outcome, digest));
+    }
+    if !opts.partial && !opts.atomic {
+        if let (Some(backup), true) = (backup, dest.exists()) {
+            backup.save(dest.path(), Path::new(dest.description()))?;
+        } else if is_hard_linked(dest) {
+            // Writing to it would also change the other links, for instance
+            // the files of a `link_dest` snapshot
+            replace(dest, None)?;
+        }
+        let (outcome, src_digest) =
+            copy_to(progress_sender, throttle, src, dest, dest.path(), opts)?;
+        let digest = if opts.verify_copies && !matches!(outcome, SyncOutcome::FileCloned { .. }) {
+            Some(verify_copy(src, dest.path(), src_digest, opts)?)
+        } else {
+            None
+        };
+        return Ok((outcome, digest));
+    }
+
+    // Write to a temporary file next to the destination, and rename it once
+    // it is complete. With `partial`, the file is kept if something goes
+    // wrong so that the transfer can be resumed later on
+    let tmp_path = if opts.partial {
+        part_path(dest, opts)
+    } else {
+        tmp_path(dest.path())
+    };
+    if opts.partial_dir {
+        let parent = tmp_path.parent().expect("part path should have a parent");
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Could not create '{}'", parent.display()))?;
+    }
+    let outcome = copy_to(progress_sender, throttle, src, dest, &tmp_path, opts);
+    if outcome.is_err() && !opts.partial {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    let (outcome, src_digest) = outcome?;
+    let mut digest = None;
+    if opts.verify_copies && !matches!(outcome, SyncOutcome::FileCloned { .. }) {
+        // The destination is left alone, and corrupted .part files must
+        // not be resumed
+        match verify_copy(src, &tmp_path, src_digest, opts) {
+            Ok(verified) => digest = Some(verified),
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+        }
+    }
+    if opts.fsync {
+        // Or the rename could reach the disk before the contents
+        sync_contents(&tmp_path)
+            .with_context(|| format!("Could not flush '{}' to disk", tmp_path.display()))?;
+    }
+    if let (Some(backup), true) = (backup, dest.exists()) {
+        backup.save(dest.path(), Path::new(dest.description()))?;
+    }
+    fs::rename(&tmp_path, dest.path()).with_context(|| {
+        format!(
+            "Could not rename '{}' to '{}'",
+            tmp_path.display(),
+            dest.description()
+        )
+    })?;
+    Ok((outcome, digest))
+}
+
+// Clone or copy src to dest_path, which is either the path of dest or the
+// path of a temporary file. With `opts.verify_copies`, also returns the
+// checksum of the source when it could be computed while copying
+fn copy_to(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    throttle: &Throttle,
+    src: &Entry,
+    dest: &Entry,
+    dest_path: &Path,
+    opts: SyncOptions,
+) -> Result<(SyncOutcome, Option<Vec<u8>>), Error> {
+    let src_size = src.metadata().expect("src_meta should not be None").len();
+    let resume_from = if opts.partial && !opts.sparse {
+        resumable_len(src, dest_path, opts.hash)?
+    } else {
+        0
+    };
+    // Cloning would truncate the partial file first
+    if opts.reflink != ReflinkMode::Never && resume_from == 0 {
+        let cloned = clone_file(src, dest_path)
+            .with_context(|| format!("Could not clone '{}'", src.description()))?;
+        if cloned {
+            let progress = ProgressMessage::Syncing {
+                description: src.description().clone(),
+                size: src_size as usize,
+                done: src_size as usize,
+                transfered: false,
+            };
+            let _ = progress_sender.send(progress);
+            return Ok((SyncOutcome::FileCloned { size: src_size }, None));
+        }
+        if opts.reflink == ReflinkMode::Always {
+            bail!(
+                "Could not clone '{}': not supported by the destination file system",
+                src.description()
+            );
+        }
+    }
+
+    let mut src_file = File::open(src.path())
         .with_context(|| format!("Could not open '{}' for reading", src.description()))?;
-    let src_meta = src.metadata().expect("src_meta should not be None");
-    let src_size = src_meta.len();
-    let dest_path = dest.path();
-    let mut dest_file = File::create(dest_path)
+    let write_error = || format!("Could not write to '{}'", dest_path.display());
+    let mut dest_file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(resume_from == 0)
+        .open(dest_path)
         .with_context(|| format!("Could not open '{}' for writing", dest.description()))?;
-    let mut buffer = vec![0; BUFFER_SIZE];
+    // Holes would be filled
+    if opts.preallocate && !opts.sparse {
+        preallocate(&dest_file, src_size).with_context(|| {
+            format!(
+                "Could not allocate {} bytes for '{}'",
+                src_size,
+                dest.description()
+            )
+        })?;
+    }
+    if resume_from > 0 {
+        src_file
+            .seek(SeekFrom::Start(resume_from))
+            .with_context(|| format!("Could not read from '{}'", src.description()))?;
+        dest_file
+            .seek(SeekFrom::Start(resume_from))
+            .with_context(write_error)?;
+        let progress = ProgressMessage::Syncing {
+            description: src.description().clone(),
+            size: src_size as usize,
+            done: resume_from as usize,
+            transfered: false,
+        };
+        let _ = progress_sender.send(progress);
+    }
+    // Sparse copies skip the holes, and resumed ones what was already
+    // copied: the source is hashed again after them instead
+    let mut hasher = if opts.verify_copies && !opts.sparse && resume_from == 0 {
+        Some(Hasher::new(opts.hash))
+    } else {
+        None
+    };
+    let outcome = write_contents(
+        progress_sender,
+        throttle,
+        src,
+        dest,
+        &mut src_file,
+        &mut dest_file,
+        hasher.as_mut(),
+        opts,
+    )?;
+    Ok((outcome, hasher.map(Hasher::finish)))
+}
+
+// Read back the copy of src at copy_path, and compare its checksum with
+// the one of the source, which is returned
+fn verify_copy(
+    src: &Entry,
+    copy_path: &Path,
+    src_digest: Option<Vec<u8>>,
+    opts: SyncOptions,
+) -> Result<Vec<u8>, Error> {
+    let src_digest = match src_digest {
+        Some(src_digest) => src_digest,
+        None => checksum::hash_file(src.path(), opts.hash)
+            .with_context(|| format!("Could not compute checksum of '{}'", src.description()))?,
+    };
+    drop_cached_pages(copy_path)
+        .with_context(|| format!("Could not flush '{}' to disk", copy_path.display()))?;
+    let copy_digest = checksum::hash_file(copy_path, opts.hash)?;
+    if copy_digest != src_digest {
+        bail!(
+            "Verification failed: the copy of {} in '{}' has different contents",
+            src.description(),
+            copy_path.display()
+        );
+    }
+    Ok(src_digest)
+}
+
+// So that the file is read back from the disk, and not from memory
+#[cfg(target_os = "linux")]
+fn drop_cached_pages(path: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = File::open(path)?;
+    // Dirty pages cannot be dropped
+    file.sync_data()?;
+    // Only a hint: the pages may be kept, but the fd is valid
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn drop_cached_pages(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Make sure `path`, and its entry in the parent directory, are on disk.
+/// With `contents`, its contents are flushed too
+pub fn sync_to_disk(path: &Path, contents: bool) -> io::Result<()> {
+    // Opening FIFOs would block, and symlinks would be followed
+    if contents && fs::symlink_metadata(path)?.is_file() {
+        sync_contents(path)?;
+    }
+    sync_dir(path.parent().expect("dest path should have a parent"))
+}
+
+#[cfg(unix)]
+fn sync_contents(path: &Path) -> io::Result<()> {
+    // No need to open it for writing, which permissions may not allow
+    File::open(path)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_contents(path: &Path) -> io::Result<()> {
+    fs::OpenOptions::new().write(true).open(path)?.sync_all()
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    // Directories cannot be flushed there, NTFS journals their entries
+    Ok(())
+}
+
+// With `partial_dir`, at the same relative path in the partial dir
+fn part_path(dest: &Entry, opts: SyncOptions) -> PathBuf {
+    let dest_path = dest.path();
+    if opts.partial_dir {
+        let depth = Path::new(dest.description()).components().count();
+        let root = dest_path
+            .ancestors()
+            .nth(depth)
+            .expect("dest path should be in the destination");
+        let rel_path = get_rel_path(dest_path, root);
+        return root.join(PARTIAL_DIR_NAME).join(rel_path);
+    }
+    let mut file_name = dest_path
+        .file_name()
+        .expect("dest path should have a file name")
+        .to_os_string();
+    file_name.push(PART_SUFFIX);
+    dest_path.with_file_name(file_name)
+}
+
+/// Remove the directories left empty in the partial dir of `destination`
+/// once the files they contained were renamed, see `SyncOptions.partial_dir`
+pub fn prune_partial_dir(destination: &Path) {
+    fn prune(dir: &Path) {
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                    prune(&entry.path());
+                    let _ = fs::remove_dir(entry.path());
+                }
+            }
+        }
+    }
+    prune(&destination.join(PARTIAL_DIR_NAME));
+}
+
+// Hidden, so that it is less likely to be picked up by readers of the
+// destination
+pub fn tmp_path(dest_path: &Path) -> PathBuf {
+    let mut file_name = std::ffi::OsString::from(".");
+    file_name.push(
+        dest_path
+            .file_name()
+            .expect("dest path should have a file name"),
+    );
+    file_name.push(TMP_SUFFIX);
+    dest_path.with_file_name(file_name)
+}
+
+// Returns the number of bytes of an interrupted transfer that can be kept,
+// that is the size of the .part file if it matches the start of the source
+fn resumable_len(src: &Entry, part_path: &Path, algorithm: HashAlgorithm) -> Result<u64, Error> {
+    let part_len = match fs::metadata(part_path) {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        _ => return Ok(0),
+    };
+    let src_size = src.metadata().expect("src_meta should not be None").len();
+    if part_len == 0 || part_len > src_size {
+        return Ok(0);
+    }
+    let src_digest = checksum::hash_prefix(src.path(), part_len, algorithm)
+        .with_context(|| format!("Could not compute checksum of '{}'", src.description()))?;
+    let part_digest = checksum::hash_prefix(part_path, part_len, algorithm)?;
+    if src_digest == part_digest {
+        Ok(part_len)
+    } else {
+        Ok(0)
+    }
+}
+
+// Copy what is left to read from src_file to dest_file (which may be a .part
+// file), feeding the hasher with what is copied, if any. Sparse copies do
+// not use it
+#[allow(clippy::too_many_arguments)]
+fn write_contents(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    throttle: &Throttle,
+    src: &Entry,
+    dest: &Entry,
+    src_file: &mut File,
+    dest_file: &mut File,
+    mut hasher: Option<&mut Hasher>,
+    opts: SyncOptions,
+) -> Result<SyncOutcome, Error> {
+    let src_size = src.metadata().expect("src_meta should not be None").len();
+    let buffer_size = buffer_size(opts);
+    if opts.sparse {
+        return copy_sparse(
+            progress_sender,
+            throttle,
+            src,
+            dest,
+            src_file,
+            dest_file,
+            buffer_size,
+        );
+    }
+    if opts
+        .mmap_threshold
+        .is_some_and(|threshold| src_size >= threshold)
+    {
+        // Otherwise, copy it like any other file
+        if let Ok(mapping) = mmap::map(src_file) {
+            // Not 0 when resuming a transfer
+            let start = src_file
+                .stream_position()
+                .with_context(|| format!("Could not read from '{}'", src.description()))?;
+            let remaining = mapping.get(start as usize..).unwrap_or_default();
+            return copy_mapped(
+                progress_sender,
+                throttle,
+                src,
+                dest,
+                remaining,
+                dest_file,
+                hasher,
+                buffer_size,
+            );
+        }
+    }
+    // Only from the start: resumed transfers would read and write at
+    // offsets that are not aligned
+    if opts.direct_io && src_file.stream_position().ok() == Some(0) {
+        // Otherwise, for instance on tmpfs, copy it like any other file
+        if let Ok(direct_src) = direct_io::open(src.path()) {
+            if direct_io::set_direct(dest_file, true).is_ok() {
+                let files = (direct_src, &mut *dest_file);
+                return copy_direct(
+                    progress_sender,
+                    throttle,
+                    src,
+                    dest,
+                    files,
+                    hasher,
+                    buffer_size,
+                );
+            }
+        }
+    }
+    let mut buffer = vec![0; buffer_size];
     loop {
         let num_read = src_file
             .read(&mut buffer)
@@ -150,45 +1255,466 @@ pub fn copy_entry(
         if num_read == 0 {
             break;
         }
+        throttle.consume(num_read);
         dest_file
             .write_all(&buffer[0..num_read])
             .with_context(|| format!("Could not write to '{}'", dest.description()))?;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&buffer[0..num_read]);
+        }
+        let progress = ProgressMessage::Syncing {
+            description: src.description().clone(),
+            size: src_size as usize,
+            done: num_read,
+            transfered: true,
+        };
+        let _ = progress_sender.send(progress);
+    }
+    Ok(SyncOutcome::FileCopied { size: src_size })
+}
+
+// Like write_contents(), bypassing the page cache. dest_file was opened
+// normally, then switched to O_DIRECT
+fn copy_direct(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    throttle: &Throttle,
+    src: &Entry,
+    dest: &Entry,
+    (mut src_file, dest_file): (File, &mut File),
+    mut hasher: Option<&mut Hasher>,
+    buffer_size: usize,
+) -> Result<SyncOutcome, Error> {
+    let src_size = src.metadata().expect("src_meta should not be None").len();
+    let write_error = || format!("Could not write to '{}'", dest.description());
+    let mut buffer = AlignedBuffer::new(buffer_size);
+    loop {
+        // Always the whole buffer on regular files, except at the end
+        let num_read = src_file
+            .read(&mut buffer)
+            .with_context(|| format!("Could not read from '{}'", src.description()))?;
+        if num_read == 0 {
+            break;
+        }
+        throttle.consume(num_read);
+        if !num_read.is_multiple_of(direct_io::ALIGNMENT) {
+            // The last block of the file, which is only partly used, is
+            // written through the page cache
+            direct_io::set_direct(dest_file, false).with_context(write_error)?;
+        }
+        dest_file
+            .write_all(&buffer[0..num_read])
+            .with_context(write_error)?;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&buffer[0..num_read]);
+        }
         let progress = ProgressMessage::Syncing {
             description: src.description().clone(),
             size: src_size as usize,
             done: num_read,
+            transfered: true,
+        };
+        let _ = progress_sender.send(progress);
+    }
+    Ok(SyncOutcome::FileCopied { size: src_size })
+}
+
+// Reading into an empty buffer would never end
+fn buffer_size(opts: SyncOptions) -> usize {
+    if opts.buffer_size == 0 {
+        DEFAULT_BUFFER_SIZE
+    } else {
+        opts.buffer_size
+    }
+}
+
+// Like write_contents(), writing what is left to copy from the mapped source
+// instead of a buffer
+#[allow(clippy::too_many_arguments)]
+fn copy_mapped(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    throttle: &Throttle,
+    src: &Entry,
+    dest: &Entry,
+    remaining: &[u8],
+    dest_file: &mut File,
+    mut hasher: Option<&mut Hasher>,
+    buffer_size: usize,
+) -> Result<SyncOutcome, Error> {
+    let src_size = src.metadata().expect("src_meta should not be None").len();
+    // In chunks, for the throttle and the progress
+    for chunk in remaining.chunks(buffer_size) {
+        throttle.consume(chunk.len());
+        dest_file
+            .write_all(chunk)
+            .with_context(|| format!("Could not write to '{}'", dest.description()))?;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(chunk);
+        }
+        let progress = ProgressMessage::Syncing {
+            description: src.description().clone(),
+            size: src_size as usize,
+            done: chunk.len(),
+            transfered: true,
         };
         let _ = progress_sender.send(progress);
     }
     Ok(SyncOutcome::FileCopied { size: src_size })
 }
 
+// Only write the parts of the destination that differ from the source
+fn update_in_place(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    throttle: &Throttle,
+    src: &Entry,
+    dest: &Entry,
+) -> Result<SyncOutcome, Error> {
+    let src_size = src.metadata().expect("src_meta should not be None").len();
+    let mut src_file = File::open(src.path())
+        .with_context(|| format!("Could not open '{}' for reading", src.description()))?;
+    let mut dest_file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(dest.path())
+        .with_context(|| format!("Could not open '{}' for writing", dest.description()))?;
+    let saved = in_place::update(&mut src_file, &mut dest_file, |num_read| {
+        throttle.consume(num_read);
+        let progress = ProgressMessage::Syncing {
+            description: src.description().clone(),
+            size: src_size as usize,
+            done: num_read,
+            transfered: true,
+        };
+        let _ = progress_sender.send(progress);
+    })
+    .with_context(|| format!("Could not update '{}'", dest.description()))?;
+    Ok(SyncOutcome::UpdatedInPlace {
+        size: src_size,
+        saved,
+    })
+}
+
+// Returns the (start, end) offsets of the parts of the file containing data
+#[cfg(target_os = "linux")]
+fn data_segments(file: &File, size: u64) -> io::Result<Vec<(u64, u64)>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut segments = vec![];
+    let mut offset = 0;
+    while offset < size {
+        let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            let error = io::Error::last_os_error();
+            match error.raw_os_error() {
+                // No more data after offset
+                Some(libc::ENXIO) => break,
+                // SEEK_DATA not supported by the file system
+                Some(libc::EINVAL) => return Ok(vec![(0, size)]),
+                _ => return Err(error),
+            }
+        }
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        segments.push((start as u64, end as u64));
+        offset = end as u64;
+    }
+    Ok(segments)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn data_segments(_file: &File, size: u64) -> io::Result<Vec<(u64, u64)>> {
+    Ok(vec![(0, size)])
+}
+
+// Copy the data segments of the source, and seek over holes and blocks
+// containing only zeros, so that the destination file is sparse too
+fn copy_sparse(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    throttle: &Throttle,
+    src: &Entry,
+    dest: &Entry,
+    src_file: &mut File,
+    dest_file: &mut File,
+    buffer_size: usize,
+) -> Result<SyncOutcome, Error> {
+    let src_size = src.metadata().expect("src_meta should not be None").len();
+    let read_error = || format!("Could not read from '{}'", src.description());
+    let write_error = || format!("Could not write to '{}'", dest.description());
+    let send_progress = |done: u64, transfered: bool| {
+        let progress = ProgressMessage::Syncing {
+            description: src.description().clone(),
+            size: src_size as usize,
+            done: done as usize,
+            transfered,
+        };
+        let _ = progress_sender.send(progress);
+    };
+
+    let segments = data_segments(src_file, src_size).with_context(read_error)?;
+    let mut buffer = vec![0; buffer_size];
+    let mut made_holes = false;
+    let mut position = 0;
+    for (start, end) in segments {
+        if start > position {
+            made_holes = true;
+            send_progress(start - position, false);
+        }
+        src_file
+            .seek(SeekFrom::Start(start))
+            .with_context(read_error)?;
+        dest_file
+            .seek(SeekFrom::Start(start))
+            .with_context(write_error)?;
+        let mut remaining = end - start;
+        while remaining > 0 {
+            let to_read = std::cmp::min(remaining, buffer_size as u64) as usize;
+            let num_read = src_file
+                .read(&mut buffer[0..to_read])
+                .with_context(read_error)?;
+            if num_read == 0 {
+                break;
+            }
+            throttle.consume(num_read);
+            if buffer[0..num_read].iter().all(|&b| b == 0) {
+                made_holes = true;
+                dest_file
+                    .seek(SeekFrom::Current(num_read as i64))
+                    .with_context(write_error)?;
+            } else {
+                dest_file
+                    .write_all(&buffer[0..num_read])
+                    .with_context(write_error)?;
+            }
+            remaining -= num_read as u64;
+            send_progress(num_read as u64, true);
+        }
+        position = end;
+    }
+    if position < src_size {
+        made_holes = true;
+        send_progress(src_size - position, false);
+    }
+    // Seeking past the end does not change the size of the file, so
+    // trailing holes need this
+    dest_file.set_len(src_size).with_context(write_error)?;
+    if made_holes {
+        Ok(SyncOutcome::SparseFileCopied { size: src_size })
+    } else {
+        Ok(SyncOutcome::FileCopied { size: src_size })
+    }
+}
+
 fn has_different_size(src: &Entry, dest: &Entry) -> bool {
     let src_meta = src.metadata().expect("src_meta should not be None");
     let dest_meta = dest.metadata();
     match dest_meta {
         None => true,
-        Some(dest_meta) => (dest_meta.len() != src_meta.len()),
+        Some(dest_meta) => dest_meta.len() != src_meta.len(),
     }
 }
 
+pub fn has_different_contents(
+    src: &Entry,
+    dest: &Entry,
+    checksums: Option<&ChecksumCache>,
+    algorithm: HashAlgorithm,
+) -> Result<bool, Error> {
+    let hash_file = |path: &Path| match checksums {
+        Some(checksums) => checksums.hash_file(path),
+        None => checksum::hash_file(path, algorithm),
+    };
+    let src_digest = hash_file(src.path())
+        .with_context(|| format!("Could not compute checksum of '{}'", src.description()))?;
+    let dest_digest = hash_file(dest.path())
+        .with_context(|| format!("Could not compute checksum of '{}'", dest.description()))?;
+    Ok(src_digest != dest_digest)
+}
+
+/// Sync src to dest. Also returns the checksum of the source file when it
+/// was computed by the copy, see `copy_entry()`
 pub fn sync_entries(
     progress_sender: &mpsc::Sender<ProgressMessage>,
+    throttle: &Throttle,
+    backup: Option<&Backup>,
     src: &Entry,
     dest: &Entry,
-) -> Result<SyncOutcome, Error> {
-    let _ = progress_sender.send(ProgressMessage::StartSync(src.description().to_string()));
+    checksums: Option<&ChecksumCache>,
+    opts: SyncOptions,
+) -> Result<(SyncOutcome, Option<Vec<u8>>), Error> {
+    let _ = progress_sender.send(ProgressMessage::StartSync(Box::new(src.clone())));
     let is_link = src.is_link().expect("src.is_link should not be None");
     if is_link {
-        return copy_link(&src, &dest);
+        if junction::is_junction(src.path()) {
+            return Ok((copy_junction(src, dest, backup, opts)?, None));
+        }
+        // Note: dereferenced symlinks are not links as far as Entry is concerned
+        if opts.symlinks == SymlinkPolicy::Skip {
+            return Ok((SyncOutcome::SymlinkSkipped, None));
+        }
+        return Ok((copy_link(src, dest, backup)?, None));
+    }
+    if is_special(src) {
+        if opts.copy_specials {
+            return Ok((copy_special(src, dest, backup)?, None));
+        }
+        let _ = progress_sender.send(ProgressMessage::SyncWarning {
+            entry: src.description().to_string(),
+            details: format!("Skipping special file {}", src.description()),
+        });
+        return Ok((SyncOutcome::SpecialSkipped, None));
+    }
+    if needs_copy(src, dest, checksums, opts)? {
+        return copy_entry(progress_sender, throttle, backup, src, dest, opts);
+    }
+    Ok((skip_file(progress_sender, src), None))
+}
+
+fn needs_copy(
+    src: &Entry,
+    dest: &Entry,
+    checksums: Option<&ChecksumCache>,
+    opts: SyncOptions,
+) -> Result<bool, Error> {
+    let different_size = has_different_size(src, dest);
+    if opts.checksum {
+        // Timestamps are not trusted at all in this mode
+        return Ok(different_size || has_different_contents(src, dest, checksums, opts.hash)?);
+    }
+    if opts.size_only {
+        return Ok(different_size);
     }
-    let different_size = has_different_size(&src, &dest);
-    let more_recent = is_more_recent_than(&src, &dest);
     // TODO: check if files really are different ?
-    if more_recent || different_size {
-        return copy_entry(&progress_sender, &src, &dest);
+    Ok(is_more_recent_than(src, dest, opts) || different_size)
+}
+
+/// Like `sync_entries()`, but only tells what would be done, without
+/// writing anything. Files that need to be copied are reported as copied
+pub fn plan_entries(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    src: &Entry,
+    dest: &Entry,
+    checksums: Option<&ChecksumCache>,
+    opts: SyncOptions,
+) -> Result<SyncOutcome, Error> {
+    let _ = progress_sender.send(ProgressMessage::StartSync(Box::new(src.clone())));
+    let is_link = src.is_link().expect("src.is_link should not be None");
+    if is_link {
+        if junction::is_junction(src.path()) {
+            return plan_junction(src, dest, opts);
+        }
+        if opts.symlinks == SymlinkPolicy::Skip {
+            return Ok(SyncOutcome::SymlinkSkipped);
+        }
+        return match dest.is_link() {
+            None => Ok(SyncOutcome::SymlinkCreated),
+            Some(false) => bail!(
+                "Refusing to replace existing path {} by symlink",
+                dest.description()
+            ),
+            Some(true) if fs::read_link(src.path())? == fs::read_link(dest.path())? => {
+                Ok(SyncOutcome::UpToDate)
+            }
+            Some(true) => Ok(SyncOutcome::SymlinkUpdated),
+        };
+    }
+    if is_special(src) {
+        if !opts.copy_specials {
+            return Ok(SyncOutcome::SpecialSkipped);
+        }
+        if dest.exists() {
+            return Ok(SyncOutcome::UpToDate);
+        }
+        return Ok(SyncOutcome::SpecialCreated);
+    }
+    if !needs_copy(src, dest, checksums, opts)? {
+        return Ok(skip_file(progress_sender, src));
+    }
+    let src_size = src.metadata().map_or(0, |m| m.len());
+    let _ = progress_sender.send(ProgressMessage::Syncing {
+        description: src.description().clone(),
+        size: src_size as usize,
+        done: src_size as usize,
+        transfered: true,
+    });
+    Ok(SyncOutcome::FileCopied { size: src_size })
+}
+
+/// Returns `SizeSkipped` if `src` is a file bigger than `SyncOptions.max_size`
+pub fn skip_too_large(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    src: &Entry,
+    opts: SyncOptions,
+) -> Option<SyncOutcome> {
+    let max_size = opts.max_size?;
+    let src_meta = src.metadata().expect("src_meta should not be None");
+    if !src_meta.is_file() || src_meta.len() <= max_size {
+        return None;
+    }
+    let _ = progress_sender.send(ProgressMessage::StartSync(Box::new(src.clone())));
+    skip_file(progress_sender, src);
+    Some(SyncOutcome::SizeSkipped)
+}
+
+/// Returns `MissingSkipped` if nothing exists at `dest_path` yet
+pub fn skip_missing(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    src: &Entry,
+    dest_path: &Path,
+) -> Option<SyncOutcome> {
+    if fs::symlink_metadata(dest_path).is_ok() {
+        return None;
     }
-    Ok(SyncOutcome::UpToDate)
+    let _ = progress_sender.send(ProgressMessage::StartSync(Box::new(src.clone())));
+    skip_file(progress_sender, src);
+    Some(SyncOutcome::MissingSkipped)
+}
+
+/// Where to keep the destination file at `path` when it conflicts with
+/// the source: like notes.txt.conflict, or notes.txt.conflict2 if that
+/// exists already
+pub fn conflict_path(path: &Path) -> PathBuf {
+    let mut attempt = 1;
+    loop {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(".conflict");
+        if attempt > 1 {
+            name.push(attempt.to_string());
+        }
+        let candidate = PathBuf::from(name);
+        if fs::symlink_metadata(&candidate).is_err() {
+            return candidate;
+        }
+        attempt += 1;
+    }
+}
+
+/// Returns `UpToDate`, for a file known to be up to date
+pub fn skip_unchanged(progress_sender: &mpsc::Sender<ProgressMessage>, src: &Entry) -> SyncOutcome {
+    let _ = progress_sender.send(ProgressMessage::StartSync(Box::new(src.clone())));
+    skip_file(progress_sender, src)
+}
+
+/// Returns `ConflictSkipped`, for a file kept because of a conflict
+pub fn skip_conflict(progress_sender: &mpsc::Sender<ProgressMessage>, src: &Entry) -> SyncOutcome {
+    let _ = progress_sender.send(ProgressMessage::StartSync(Box::new(src.clone())));
+    skip_file(progress_sender, src);
+    SyncOutcome::ConflictSkipped
+}
+
+// Account for the contents of an up to date file, so that the total
+// progress still reaches the total size
+fn skip_file(progress_sender: &mpsc::Sender<ProgressMessage>, src: &Entry) -> SyncOutcome {
+    let src_size = src.metadata().map_or(0, |m| m.len());
+    let progress = ProgressMessage::Syncing {
+        description: src.description().clone(),
+        size: src_size as usize,
+        done: src_size as usize,
+        transfered: false,
+    };
+    let _ = progress_sender.send(progress);
+    SyncOutcome::UpToDate
 }
 
 #[cfg(test)]
@@ -203,15 +1729,24 @@ mod tests {
         let tmp_path = tmp_dir.path();
         let src = &tmp_path.join("src.txt");
         let contents = "some contents";
-        std::fs::write(&src, &contents)?;
-        let src_entry = Entry::new("src.txt", &src);
+        std::fs::write(src, contents)?;
+        let src_entry = Entry::new("src.txt", src);
         let dest = &tmp_path.join("dest.txt");
-        let dest_entry = Entry::new("dest.txt", &dest);
+        let dest_entry = Entry::new("dest.txt", dest);
 
         let (progress_output, _) = channel::<ProgressMessage>();
-        sync_entries(&progress_output, &src_entry, &dest_entry).unwrap();
+        sync_entries(
+            &progress_output,
+            &Throttle::new(None),
+            None,
+            &src_entry,
+            &dest_entry,
+            None,
+            SyncOptions::default(),
+        )
+        .unwrap();
 
-        let actual = std::fs::read_to_string(&dest)?;
+        let actual = std::fs::read_to_string(dest)?;
         assert_eq!(actual, contents);
         Ok(())
     }
@@ -222,20 +1757,64 @@ mod tests {
         let tmp_path = tmp_dir.path();
         let src = &tmp_path.join("src.txt");
         let new_contents = "new and shiny";
-        std::fs::write(&src, &new_contents)?;
-        let src_entry = Entry::new("src.txt", &src);
+        std::fs::write(src, new_contents)?;
+        let src_entry = Entry::new("src.txt", src);
         let dest = &tmp_path.join("dest.txt");
         let old_contents = "old";
-        let dest_entry = Entry::new("dest.txt", &dest);
-        std::fs::write(&dest, &old_contents)?;
+        let dest_entry = Entry::new("dest.txt", dest);
+        std::fs::write(dest, old_contents)?;
 
         let (progress_output, _) = channel::<ProgressMessage>();
-        sync_entries(&progress_output, &src_entry, &dest_entry).unwrap();
+        sync_entries(
+            &progress_output,
+            &Throttle::new(None),
+            None,
+            &src_entry,
+            &dest_entry,
+            None,
+            SyncOptions::default(),
+        )
+        .unwrap();
 
-        let actual = std::fs::read_to_string(&dest)?;
+        let actual = std::fs::read_to_string(dest)?;
         assert_eq!(actual, new_contents);
         Ok(())
     }
+
+    #[test]
+    fn verify_copies() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-fsops")?;
+        let tmp_path = tmp_dir.path();
+        let src = &tmp_path.join("src.txt");
+        std::fs::write(src, "some contents")?;
+        let src_entry = Entry::new("src.txt", src);
+        let dest = &tmp_path.join("dest.txt");
+        let dest_entry = Entry::new("dest.txt", dest);
+        let opts = SyncOptions {
+            verify_copies: true,
+            atomic: true,
+            ..Default::default()
+        };
+
+        let (progress_output, _) = channel::<ProgressMessage>();
+        let outcome = copy_entry(
+            &progress_output,
+            &Throttle::new(None),
+            None,
+            &src_entry,
+            &dest_entry,
+            opts,
+        );
+        assert!(outcome.is_ok());
+        assert!(verify_copy(&src_entry, dest, None, opts).is_ok());
+
+        // As if the copy got corrupted on its way to the disk
+        std::fs::write(dest, "some_contents")?;
+        let digest = checksum::hash_file(src, opts.hash).unwrap();
+        let error = verify_copy(&src_entry, dest, Some(digest), opts).unwrap_err();
+        assert!(error.to_string().starts_with("Verification failed"));
+        Ok(())
+    }
 }
 
 #[cfg(unix)]
@@ -248,7 +1827,7 @@ mod symlink_tests {
     use tempdir::TempDir;
 
     fn create_link(src: &str, dest: &Path) -> Result<(), std::io::Error> {
-        unix::fs::symlink(&src, &dest)
+        unix::fs::symlink(src, dest)
     }
 
     fn create_file(path: &Path) -> Result<(), std::io::Error> {
@@ -264,17 +1843,17 @@ mod symlink_tests {
 
     fn setup_sync_link_test(tmp_path: &Path) -> Result<PathBuf, std::io::Error> {
         let src = &tmp_path.join("src");
-        create_file(&src)?;
+        create_file(src)?;
         let src_link = &tmp_path.join("src_link");
-        create_link("src", &src_link)?;
+        create_link("src", src_link)?;
         Ok(src_link.to_path_buf())
     }
 
     fn sync_src_link(tmp_path: &Path, src_link: &Path, dest: &str) -> Result<SyncOutcome, Error> {
-        let src_entry = Entry::new("src", &src_link);
-        let dest_path = &tmp_path.join(&dest);
-        let dest_entry = Entry::new(&dest, dest_path);
-        copy_link(&src_entry, &dest_entry)
+        let src_entry = Entry::new("src", src_link);
+        let dest_path = &tmp_path.join(dest);
+        let dest_entry = Entry::new(dest, dest_path);
+        copy_link(&src_entry, &dest_entry, None)
     }
 
     #[test]
@@ -283,9 +1862,9 @@ mod symlink_tests {
         let tmp_path = tmp_dir.path();
         let src_link = setup_sync_link_test(tmp_path)?;
 
-        let outcome = sync_src_link(&tmp_path, &src_link, "new");
+        let outcome = sync_src_link(tmp_path, &src_link, "new");
         assert_eq!(outcome.unwrap(), SyncOutcome::SymlinkCreated);
-        assert_links_to(&tmp_path, "new", "src");
+        assert_links_to(tmp_path, "new", "src");
         Ok(())
     }
 
@@ -296,10 +1875,10 @@ mod symlink_tests {
         let src_link = setup_sync_link_test(tmp_path)?;
 
         let broken_link = &tmp_path.join("broken");
-        create_link("no-such-file", &broken_link)?;
-        let outcome = sync_src_link(&tmp_path, &src_link, "broken");
+        create_link("no-such-file", broken_link)?;
+        let outcome = sync_src_link(tmp_path, &src_link, "broken");
         assert_eq!(outcome.unwrap(), SyncOutcome::SymlinkUpdated);
-        assert_links_to(&tmp_path, "broken", "src");
+        assert_links_to(tmp_path, "broken", "src");
         Ok(())
     }
 
@@ -310,12 +1889,12 @@ mod symlink_tests {
         let src_link = setup_sync_link_test(tmp_path)?;
 
         let old_dest = &tmp_path.join("old");
-        create_file(&old_dest)?;
+        create_file(old_dest)?;
         let existing_link = tmp_path.join("existing_link");
         create_link("old", &existing_link)?;
-        let outcome = sync_src_link(&tmp_path, &src_link, "existing_link");
+        let outcome = sync_src_link(tmp_path, &src_link, "existing_link");
         assert_eq!(outcome.unwrap(), SyncOutcome::SymlinkUpdated);
-        assert_links_to(&tmp_path, "existing_link", "src");
+        assert_links_to(tmp_path, "existing_link", "src");
         Ok(())
     }
 
@@ -327,7 +1906,7 @@ mod symlink_tests {
 
         let existing_file = tmp_path.join("existing");
         create_file(&existing_file)?;
-        let outcome = sync_src_link(&tmp_path, &src_link, "existing");
+        let outcome = sync_src_link(tmp_path, &src_link, "existing");
         assert!(outcome.is_err());
         let err = outcome.err().unwrap();
         let desc = err.to_string();
@@ -0,0 +1,69 @@
+//! hooks
+//!
+//! Run shell commands before and after syncing, see `Syncer::pre_hook()`
+//! and `Syncer::post_hook()`
+use std::process::Command;
+
+use anyhow::{bail, Context, Error};
+
+use crate::error::SyncError;
+use crate::sync::Stats;
+
+/// Run `command` with the system shell, and fail unless it exits with 0
+pub fn run(command: &str, env: &[(&str, String)]) -> Result<(), Error> {
+    let mut shell = shell_command(command);
+    shell.envs(env.iter().map(|(key, value)| (key, value)));
+    let status = shell
+        .status()
+        .with_context(|| format!("Could not run '{}'", command))?;
+    if !status.success() {
+        bail!("'{}' failed ({})", command, status);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Describe the outcome of a sync for the post-sync hook
+pub fn outcome_env(result: &Result<Stats, SyncError>) -> Vec<(&'static str, String)> {
+    let stats = match result {
+        Err(e) => {
+            return vec![
+                ("RUSYNC_STATUS", "failed".to_string()),
+                ("RUSYNC_ERROR", e.to_string()),
+            ]
+        }
+        Ok(stats) => stats,
+    };
+    let status = if stats.cancelled {
+        "cancelled"
+    } else if stats.errors != 0 {
+        "partial"
+    } else {
+        "success"
+    };
+    vec![
+        ("RUSYNC_STATUS", status.to_string()),
+        ("RUSYNC_FILES_TOTAL", stats.num_files.to_string()),
+        ("RUSYNC_FILES_COPIED", stats.copied.to_string()),
+        ("RUSYNC_FILES_UP_TO_DATE", stats.up_to_date.to_string()),
+        ("RUSYNC_FILES_DELETED", stats.deleted.to_string()),
+        ("RUSYNC_BYTES_TOTAL", stats.bytes_total.to_string()),
+        ("RUSYNC_BYTES_COPIED", stats.bytes_copied.to_string()),
+        ("RUSYNC_ERRORS", stats.errors.to_string()),
+        ("RUSYNC_WARNINGS", stats.warnings.to_string()),
+        ("RUSYNC_DURATION", stats.duration.as_secs_f64().to_string()),
+    ]
+}
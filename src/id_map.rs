@@ -0,0 +1,152 @@
+//! id_map
+//!
+//! Translate the owners and groups of the source entries when preserving
+//! them, see `Syncer::map_users()` and `Syncer::map_groups()`.
+//!
+//! Maps use the syntax of `rsync --usermap`: comma-separated `FROM:TO`
+//! pairs, where FROM is an id, a range of ids like `1000-1999`, a name or
+//! `*`, and TO an id or a name. The first pair matching an id wins, and ids
+//! matching none are kept as they are. Names are looked up in the user and
+//! group databases of this host.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Database {
+    Users,
+    Groups,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IdMap {
+    // (lowest, highest, translated) ids
+    rules: Vec<(u32, u32, u32)>,
+}
+
+/// The maps used by the sync workers
+#[derive(Clone, Debug, Default)]
+pub struct IdMaps {
+    pub users: IdMap,
+    pub groups: IdMap,
+}
+
+impl IdMap {
+    /// With `numeric_ids`, names are rejected instead of being looked up
+    pub fn parse(spec: &str, database: Database, numeric_ids: bool) -> Result<IdMap, String> {
+        let mut rules = vec![];
+        for pair in spec.split(',') {
+            let (from, to) = pair
+                .split_once(':')
+                .ok_or_else(|| format!("'{}' is not of the form FROM:TO", pair))?;
+            let (lowest, highest) = match parse_range(from) {
+                Some(range) => range,
+                None => {
+                    let id = resolve(from, database, numeric_ids)?;
+                    (id, id)
+                }
+            };
+            rules.push((lowest, highest, resolve(to, database, numeric_ids)?));
+        }
+        Ok(IdMap { rules })
+    }
+
+    pub fn map(&self, id: u32) -> u32 {
+        self.rules
+            .iter()
+            .find(|(lowest, highest, _)| (*lowest..=*highest).contains(&id))
+            .map_or(id, |(_, _, translated)| *translated)
+    }
+}
+
+// `*` or `low-high`. Names may contain dashes too, like www-data
+fn parse_range(from: &str) -> Option<(u32, u32)> {
+    if from == "*" {
+        return Some((0, u32::MAX));
+    }
+    let (lowest, highest) = from.split_once('-')?;
+    match (lowest.parse(), highest.parse()) {
+        (Ok(lowest), Ok(highest)) if lowest <= highest => Some((lowest, highest)),
+        _ => None,
+    }
+}
+
+fn resolve(value: &str, database: Database, numeric_ids: bool) -> Result<u32, String> {
+    if let Ok(id) = value.parse() {
+        return Ok(id);
+    }
+    let kind = match database {
+        Database::Users => "user",
+        Database::Groups => "group",
+    };
+    if value.is_empty() {
+        return Err(format!("Missing {}", kind));
+    }
+    if numeric_ids {
+        return Err(format!("'{}' is not a numeric {} id", value, kind));
+    }
+    lookup(value, database).ok_or_else(|| format!("No {} named '{}'", kind, value))
+}
+
+#[cfg(unix)]
+fn lookup(name: &str, database: Database) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // getpwnam() and getgrnam() are not reentrant, but maps are parsed
+    // before the workers start
+    unsafe {
+        match database {
+            Database::Users => {
+                let entry = libc::getpwnam(name.as_ptr());
+                if entry.is_null() {
+                    None
+                } else {
+                    Some((*entry).pw_uid)
+                }
+            }
+            Database::Groups => {
+                let entry = libc::getgrnam(name.as_ptr());
+                if entry.is_null() {
+                    None
+                } else {
+                    Some((*entry).gr_gid)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lookup(_name: &str, _database: Database) -> Option<u32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map() {
+        let map = IdMap::parse("1000:2000,1000-1999:3000,*:65534", Database::Users, true).unwrap();
+        assert_eq!(map.map(1000), 2000);
+        assert_eq!(map.map(1500), 3000);
+        assert_eq!(map.map(0), 65534);
+
+        let map = IdMap::parse("0:1", Database::Groups, true).unwrap();
+        assert_eq!(map.map(0), 1);
+        assert_eq!(map.map(42), 42);
+        assert_eq!(IdMap::default().map(42), 42);
+    }
+
+    #[test]
+    fn test_invalid_map() {
+        assert!(IdMap::parse("1000", Database::Users, true).is_err());
+        assert!(IdMap::parse("1000:", Database::Users, true).is_err());
+        assert!(IdMap::parse("root:0", Database::Users, true).is_err());
+        assert!(IdMap::parse("no-such-user-here:0", Database::Users, false).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_names() {
+        let map = IdMap::parse("root:1000,1000:root", Database::Users, false).unwrap();
+        assert_eq!(map.map(0), 1000);
+        assert_eq!(map.map(1000), 0);
+    }
+}
@@ -0,0 +1,174 @@
+//! in_place
+//!
+//! In-place updates: compare the source and the destination file block by
+//! block, and only write the blocks that changed.
+//!
+//! Blocks are only compared at the same offset, since everything before the
+//! current position has already been overwritten: data shifted by an
+//! insertion or a deletion in the source is written again. This is not an
+//! rsync-like delta transfer, which would need a rolling checksum and a
+//! separate copy of the destination.
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const MIN_BLOCK_SIZE: usize = 700;
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+const READ_SIZE: usize = 1024 * 1024;
+
+// Same heuristic as rsync: the square root of the file size
+fn block_size(len: u64) -> usize {
+    let size = ((len as f64).sqrt() as usize) & !7;
+    size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buffer.len() {
+        let num_read = reader.read(&mut buffer[total..])?;
+        if num_read == 0 {
+            break;
+        }
+        total += num_read;
+    }
+    Ok(total)
+}
+
+/// Update `dest` in place so that it has the same contents as `src`.
+/// `on_read` is called with the number of bytes read from the source each
+/// time the source is read.
+///
+/// Returns the number of bytes that did not need to be written
+pub fn update<F: FnMut(usize)>(src: &mut File, dest: &mut File, mut on_read: F) -> io::Result<u64> {
+    let dest_size = dest.metadata()?.len();
+    let block_size = block_size(dest_size);
+    // Read whole blocks at a time
+    let chunk_size = std::cmp::max(READ_SIZE / block_size, 1) * block_size;
+    let mut src_buffer = vec![0; chunk_size];
+    let mut dest_buffer = vec![0; chunk_size];
+    let mut offset = 0;
+    let mut saved = 0;
+    loop {
+        let num_read = read_full(src, &mut src_buffer)?;
+        if num_read == 0 {
+            break;
+        }
+        on_read(num_read);
+        let dest_len = if offset < dest_size {
+            dest.seek(SeekFrom::Start(offset))?;
+            read_full(dest, &mut dest_buffer[0..num_read])?
+        } else {
+            0
+        };
+        // Consecutive blocks that changed are written at once
+        let mut changed_from = None;
+        for start in (0..num_read).step_by(block_size) {
+            let end = std::cmp::min(start + block_size, num_read);
+            if end <= dest_len && src_buffer[start..end] == dest_buffer[start..end] {
+                saved += (end - start) as u64;
+                if let Some(from) = changed_from.take() {
+                    write_at(dest, offset + from as u64, &src_buffer[from..start])?;
+                }
+            } else if changed_from.is_none() {
+                changed_from = Some(start);
+            }
+        }
+        if let Some(from) = changed_from {
+            write_at(dest, offset + from as u64, &src_buffer[from..num_read])?;
+        }
+        offset += num_read as u64;
+        if num_read < chunk_size {
+            break;
+        }
+    }
+    dest.set_len(offset)?;
+    Ok(saved)
+}
+
+fn write_at(dest: &mut File, offset: u64, data: &[u8]) -> io::Result<()> {
+    dest.seek(SeekFrom::Start(offset))?;
+    dest.write_all(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use tempdir::TempDir;
+
+    // Run an in-place update and return the number of bytes saved
+    fn run_update(old: &[u8], new: &[u8]) -> u64 {
+        let tmp_dir = TempDir::new("test-rusync-in-place").unwrap();
+        let src_path = tmp_dir.path().join("src");
+        let dest_path = tmp_dir.path().join("dest");
+        std::fs::write(&src_path, new).unwrap();
+        std::fs::write(&dest_path, old).unwrap();
+        let mut src = File::open(&src_path).unwrap();
+        let mut dest = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&dest_path)
+            .unwrap();
+        let mut num_read = 0;
+        let saved = update(&mut src, &mut dest, |n| num_read += n).unwrap();
+        assert_eq!(std::fs::read(&dest_path).unwrap(), new);
+        assert_eq!(num_read, new.len());
+        saved
+    }
+
+    fn pseudo_random(len: usize) -> Vec<u8> {
+        let mut x: u32 = 42;
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_identical_files() {
+        let data = pseudo_random(100_000);
+        assert_eq!(run_update(&data, &data), 100_000);
+    }
+
+    #[test]
+    fn test_change_in_the_middle() {
+        let old = pseudo_random(100_000);
+        let mut new = old.clone();
+        for x in &mut new[50_000..50_100] {
+            *x = !*x;
+        }
+        let saved = run_update(&old, &new);
+        assert!(saved >= 100_000 - 2 * block_size(100_000) as u64);
+    }
+
+    #[test]
+    fn test_append_and_truncate() {
+        let old = pseudo_random(100_000);
+        let mut longer = old.clone();
+        longer.extend_from_slice(b"some more data");
+        assert!(run_update(&old, &longer) > 0);
+        assert!(run_update(&longer, &old) > 0);
+    }
+
+    #[test]
+    fn test_scattered_changes() {
+        let old = pseudo_random(3_000_000);
+        let mut new = old.clone();
+        for offset in [10, 1_200_000, 2_999_999].iter() {
+            new[*offset] = !new[*offset];
+        }
+        let saved = run_update(&old, &new);
+        assert!(saved >= 3_000_000 - 3 * block_size(3_000_000) as u64);
+    }
+
+    #[test]
+    fn test_insertion_is_still_correct() {
+        let old = pseudo_random(10_000);
+        let mut new = b"inserted".to_vec();
+        new.extend_from_slice(&old);
+        // Blocks are only compared at the same offset
+        assert_eq!(run_update(&old, &new), 0);
+        assert_eq!(run_update(&old, b""), 0);
+    }
+}
@@ -0,0 +1,186 @@
+//! itemize
+//!
+//! Describe what changed for each synced entry, using the same format as
+//! `rsync --itemize-changes`: `YXcstpoguax`, where `Y` is the kind of
+//! update, `X` the kind of entry, and each of the other letters is a
+//! dot unless the corresponding attribute changed.
+//!
+//! Only `c` (checksum or symlink target), `s` (size), `t` (modification
+//! time), `p` (permissions), `o` (owner) and `g` (group) are ever set.
+//! New entries get `+` for all attributes.
+
+use filetime::FileTime;
+
+use crate::chmod::Chmod;
+use crate::entry::Entry;
+use crate::fsops::{self, SyncOutcome};
+use crate::id_map::IdMaps;
+use crate::sync::SyncOptions;
+
+/// Returns the itemized changes for `src`, `dest` being the state of the
+/// destination before the sync.
+/// Returns None when nothing was written, for instance for skipped symlinks
+pub fn itemize(
+    src: &Entry,
+    dest: &Entry,
+    outcome: &SyncOutcome,
+    opts: SyncOptions,
+    id_maps: &IdMaps,
+    chmod: &Chmod,
+) -> Option<String> {
+    let update = match outcome {
+        SyncOutcome::SymlinkSkipped
+        | SyncOutcome::JunctionSkipped
+        | SyncOutcome::SpecialSkipped
+        | SyncOutcome::SizeSkipped
+        | SyncOutcome::MissingSkipped
+        | SyncOutcome::ConflictSkipped => return None,
+        SyncOutcome::UpToDate => '.',
+        SyncOutcome::HardLinkCreated | SyncOutcome::FileLinked { .. } => 'h',
+        SyncOutcome::SymlinkCreated
+        | SyncOutcome::SymlinkUpdated
+        | SyncOutcome::JunctionCreated
+        | SyncOutcome::SpecialCreated => 'c',
+        _ => '>',
+    };
+    let file_type = if src.is_link() == Some(true) {
+        'L'
+    } else if fsops::is_special(src) {
+        special_type(src)
+    } else {
+        'f'
+    };
+    let src_meta = src.metadata()?;
+    let dest_meta = match dest.metadata() {
+        None => return Some(format!("{}{}+++++++++", update, file_type)),
+        Some(dest_meta) => dest_meta,
+    };
+    let mut changes = vec!['.'; 9];
+    if *outcome == SyncOutcome::SymlinkUpdated || (opts.checksum && outcome.is_copy()) {
+        changes[0] = 'c';
+    }
+    if file_type == 'f' && src_meta.len() != dest_meta.len() {
+        changes[1] = 's';
+    }
+    let times_differ = FileTime::from_last_modification_time(src_meta)
+        != FileTime::from_last_modification_time(dest_meta);
+    if file_type == 'f' && outcome.is_copy() && opts.preserve_times && times_differ {
+        changes[2] = 't';
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let mode = if opts.preserve_permissions {
+            src_meta.mode()
+        } else {
+            dest_meta.mode()
+        };
+        if file_type != 'L' && chmod.apply(mode, false) != dest_meta.mode() & 0o7777 {
+            changes[3] = 'p';
+        }
+        if opts.preserve_owner && id_maps.users.map(src_meta.uid()) != dest_meta.uid() {
+            changes[4] = 'o';
+        }
+        if opts.preserve_group && id_maps.groups.map(src_meta.gid()) != dest_meta.gid() {
+            changes[5] = 'g';
+        }
+    }
+    let changes: String = changes.into_iter().collect();
+    Some(format!("{}{}{}", update, file_type, changes))
+}
+
+/// Returns true if the itemized changes describe an entry that was modified
+/// in some way. This is what rsync displays by default
+pub fn has_changes(changes: &str) -> bool {
+    !changes.starts_with('.') || changes.chars().skip(2).any(|c| c != '.')
+}
+
+#[cfg(unix)]
+fn special_type(entry: &Entry) -> char {
+    use std::os::unix::fs::FileTypeExt;
+    match entry.metadata().map(|m| m.file_type()) {
+        Some(t) if t.is_block_device() || t.is_char_device() => 'D',
+        _ => 'S',
+    }
+}
+
+#[cfg(not(unix))]
+fn special_type(_entry: &Entry) -> char {
+    'S'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_new_file() {
+        let tmp_dir = TempDir::new("test-rusync-itemize").unwrap();
+        let src_path = tmp_dir.path().join("src");
+        fs::write(&src_path, "new").unwrap();
+        let src = Entry::new("src", &src_path);
+        let dest = Entry::new("dest", &tmp_dir.path().join("dest"));
+        let outcome = SyncOutcome::FileCopied { size: 3 };
+        let changes = itemize(
+            &src,
+            &dest,
+            &outcome,
+            SyncOptions::default(),
+            &IdMaps::default(),
+            &Chmod::default(),
+        )
+        .unwrap();
+        assert_eq!(changes, ">f+++++++++");
+    }
+
+    #[test]
+    fn test_changed_size() {
+        let tmp_dir = TempDir::new("test-rusync-itemize").unwrap();
+        let src_path = tmp_dir.path().join("src");
+        let dest_path = tmp_dir.path().join("dest");
+        fs::write(&src_path, "new contents").unwrap();
+        fs::write(&dest_path, "old").unwrap();
+        let src = Entry::new("src", &src_path);
+        let dest = Entry::new("dest", &dest_path);
+        let outcome = SyncOutcome::FileCopied { size: 12 };
+        let changes = itemize(
+            &src,
+            &dest,
+            &outcome,
+            SyncOptions::default(),
+            &IdMaps::default(),
+            &Chmod::default(),
+        )
+        .unwrap();
+        assert!(changes.starts_with(">f.s"), "{}", changes);
+        assert!(has_changes(&changes));
+    }
+
+    #[test]
+    fn test_skipped_entries_are_not_itemized() {
+        let src = Entry::new("src", Path::new(file!()));
+        let outcome = SyncOutcome::SymlinkSkipped;
+        assert_eq!(
+            itemize(
+                &src,
+                &src,
+                &outcome,
+                SyncOptions::default(),
+                &IdMaps::default(),
+                &Chmod::default()
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_has_changes() {
+        assert!(!has_changes(".f........."));
+        assert!(has_changes(".f...p....."));
+        assert!(has_changes(">f+++++++++"));
+        assert!(has_changes("cL+++++++++"));
+    }
+}
@@ -0,0 +1,226 @@
+//! json_info
+//!
+//! Display transfer progress as newline-delimited JSON, one object per event,
+//! so that it can be parsed by other programs
+//!
+//! Each object has an `event` field: `start`, `new_file`, `progress`,
+//! `itemized`, `planned` (for dry runs), `done_syncing`, `error`, `warning`, `deleted`
+//! and finally `end`, which contains the stats of the transfer.
+
+use crate::progress::{Change, Progress, ProgressInfo};
+use crate::sync::Stats;
+use std::io::Write;
+
+#[derive(Debug, Default)]
+pub struct JsonProgressInfo {}
+
+impl JsonProgressInfo {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    fn emit(&self, event: &str, fields: &[(&str, String)]) {
+        let mut line = format!("{{\"event\":{}", quote(event));
+        for (key, value) in fields {
+            line.push_str(&format!(",{}:{}", quote(key), value));
+        }
+        line.push('}');
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        // Nothing much we can do if stdout is closed
+        let _ = writeln!(stdout, "{}", line);
+        let _ = stdout.flush();
+    }
+}
+
+impl ProgressInfo for JsonProgressInfo {
+    fn start(&mut self, source: &str, destination: &str) {
+        self.emit(
+            "start",
+            &[
+                ("source", quote(source)),
+                ("destination", quote(destination)),
+            ],
+        );
+    }
+
+    fn new_file(&mut self, name: &str) {
+        self.emit("new_file", &[("name", quote(name))]);
+    }
+
+    fn itemized(&mut self, name: &str, changes: &str) {
+        self.emit(
+            "itemized",
+            &[("name", quote(name)), ("changes", quote(changes))],
+        );
+    }
+
+    fn planned(&mut self, name: &str, change: Change, size: u64) {
+        let change = match change {
+            Change::New => "new",
+            Change::Changed => "changed",
+            Change::Unchanged => "unchanged",
+            Change::Deleted => "deleted",
+        };
+        self.emit(
+            "planned",
+            &[
+                ("name", quote(name)),
+                ("change", quote(change)),
+                ("size", size.to_string()),
+            ],
+        );
+    }
+
+    fn scanning(&mut self, entries_found: u64, bytes_found: u64) {
+        self.emit(
+            "scanning",
+            &[
+                ("entries_found", entries_found.to_string()),
+                ("bytes_found", bytes_found.to_string()),
+            ],
+        );
+    }
+
+    fn done_syncing(&mut self, name: &str) {
+        self.emit("done_syncing", &[("name", quote(name))]);
+    }
+
+    fn progress(&mut self, progress: &Progress) {
+        self.emit(
+            "progress",
+            &[
+                ("current_file", quote(&progress.current_file)),
+                ("file_done", progress.file_done.to_string()),
+                ("file_size", progress.file_size.to_string()),
+                ("total_done", progress.total_done.to_string()),
+                ("total_size", progress.total_size.to_string()),
+                ("index", progress.index.to_string()),
+                ("num_files", progress.num_files.to_string()),
+                ("totals_known", progress.totals_known.to_string()),
+                ("eta", progress.eta.to_string()),
+                ("speed", progress.speed.to_string()),
+                ("avg_speed", progress.avg_speed.to_string()),
+            ],
+        );
+    }
+
+    fn error(&mut self, entry: &str, details: &str) {
+        self.emit(
+            "error",
+            &[("entry", quote(entry)), ("details", quote(details))],
+        );
+    }
+
+    fn warning(&mut self, entry: &str, details: &str) {
+        self.emit(
+            "warning",
+            &[("entry", quote(entry)), ("details", quote(details))],
+        );
+    }
+
+    fn deleted(&mut self, name: &str) {
+        self.emit("deleted", &[("name", quote(name))]);
+    }
+
+    fn end(&mut self, stats: &Stats) {
+        self.emit("end", &[("stats", stats_to_json(stats))]);
+    }
+}
+
+/// Serialize the stats as a JSON object
+pub fn stats_to_json(stats: &Stats) -> String {
+    let counters = [
+        ("num_files", stats.num_files),
+        ("total_size", stats.total_size as u64),
+        ("total_transfered", stats.total_transfered),
+        ("bytes_total", stats.bytes_total),
+        ("bytes_copied", stats.bytes_copied),
+        ("bytes_skipped", stats.bytes_skipped),
+        ("num_synced", stats.num_synced),
+        ("up_to_date", stats.up_to_date),
+        ("copied", stats.copied),
+        ("sparse_copied", stats.sparse_copied),
+        ("cloned", stats.cloned),
+        ("bytes_saved", stats.bytes_saved),
+        ("errors", stats.errors),
+        ("warnings", stats.warnings),
+        ("symlink_created", stats.symlink_created),
+        ("symlink_updated", stats.symlink_updated),
+        ("symlinks_skipped", stats.symlinks_skipped),
+        ("junctions_created", stats.junctions_created),
+        ("junctions_skipped", stats.junctions_skipped),
+        ("hardlinks_created", stats.hardlinks_created),
+        ("files_linked", stats.files_linked),
+        ("specials_created", stats.specials_created),
+        ("specials_skipped", stats.specials_skipped),
+        ("size_skipped", stats.size_skipped),
+        ("missing_skipped", stats.missing_skipped),
+        ("conflicts_skipped", stats.conflicts_skipped),
+        ("deleted", stats.deleted),
+    ];
+    let mut fields: Vec<String> = counters
+        .iter()
+        .map(|(key, value)| format!("{}:{}", quote(key), value))
+        .collect();
+    fields.push(format!("\"duration\":{}", stats.duration.as_secs_f64()));
+    fields.push(format!("\"avg_throughput\":{}", stats.avg_throughput()));
+    fields.push(format!("\"cancelled\":{}", stats.cancelled));
+    let error_list: Vec<String> = stats
+        .error_list
+        .iter()
+        .map(|(path, error)| {
+            format!(
+                "{{\"entry\":{},\"details\":{}}}",
+                quote(&path.to_string_lossy()),
+                quote(&error.to_string())
+            )
+        })
+        .collect();
+    fields.push(format!("\"error_list\":[{}]", error_list.join(",")));
+    format!("{{{}}}", fields.join(","))
+}
+
+// Returns the string as a JSON string literal
+pub(crate) fn quote(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("foo.txt"), r#""foo.txt""#);
+        assert_eq!(quote(r#"a "b" c\d"#), r#""a \"b\" c\\d""#);
+        assert_eq!(quote("line\nbreak\u{1}"), r#""line\nbreak\u0001""#);
+        assert_eq!(quote("café"), "\"café\"");
+    }
+
+    #[test]
+    fn test_stats_to_json() {
+        let mut stats = Stats::new();
+        stats.copied = 3;
+        stats.add_error("a/b", std::io::Error::other("oops"));
+        let json = stats_to_json(&stats);
+        assert!(json.starts_with("{\"num_files\":0,"));
+        assert!(json.contains("\"copied\":3,"));
+        assert!(json.contains("\"errors\":1,"));
+        assert!(json.ends_with("\"error_list\":[{\"entry\":\"a/b\",\"details\":\"oops\"}]}"));
+    }
+}
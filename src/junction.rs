@@ -0,0 +1,160 @@
+//! junction
+//!
+//! Windows junctions (mount point reparse points), see
+//! `SyncOptions.junctions`.
+//!
+//! The standard library treats them as symlinks to directories, but has no
+//! stable way to tell them apart from actual symlinks or to create them.
+use std::io;
+use std::path::Path;
+
+#[cfg(windows)]
+mod windows {
+    use std::ffi::c_void;
+    use std::fs::{self, File, OpenOptions};
+    use std::io;
+    use std::mem;
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::os::windows::io::AsRawHandle;
+    use std::path::Path;
+    use std::ptr;
+
+    const FILE_FLAG_OPEN_REPARSE_POINT: u32 = 0x0020_0000;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+    const FILE_ATTRIBUTE_TAG_INFO: i32 = 9;
+    const FSCTL_SET_REPARSE_POINT: u32 = 0x0009_00A4;
+    const GENERIC_WRITE: u32 = 0x4000_0000;
+    const IO_REPARSE_TAG_MOUNT_POINT: u32 = 0xA000_0003;
+
+    #[repr(C)]
+    struct FileAttributeTagInfo {
+        file_attributes: u32,
+        reparse_tag: u32,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetFileInformationByHandleEx(
+            file: *mut c_void,
+            class: i32,
+            info: *mut c_void,
+            size: u32,
+        ) -> i32;
+        fn DeviceIoControl(
+            device: *mut c_void,
+            code: u32,
+            in_buffer: *const c_void,
+            in_size: u32,
+            out_buffer: *mut c_void,
+            out_size: u32,
+            returned: *mut u32,
+            overlapped: *mut c_void,
+        ) -> i32;
+    }
+
+    // Open the reparse point itself rather than what it points to
+    fn open(path: &Path, access: u32) -> io::Result<File> {
+        OpenOptions::new()
+            .access_mode(access)
+            .custom_flags(FILE_FLAG_OPEN_REPARSE_POINT | FILE_FLAG_BACKUP_SEMANTICS)
+            .open(path)
+    }
+
+    pub fn is_junction(path: &Path) -> io::Result<bool> {
+        let file = open(path, 0)?;
+        let mut info = FileAttributeTagInfo {
+            file_attributes: 0,
+            reparse_tag: 0,
+        };
+        let res = unsafe {
+            GetFileInformationByHandleEx(
+                file.as_raw_handle() as *mut c_void,
+                FILE_ATTRIBUTE_TAG_INFO,
+                &mut info as *mut FileAttributeTagInfo as *mut c_void,
+                mem::size_of::<FileAttributeTagInfo>() as u32,
+            )
+        };
+        if res == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(info.reparse_tag == IO_REPARSE_TAG_MOUNT_POINT)
+    }
+
+    pub fn create(target: &Path, path: &Path) -> io::Result<()> {
+        // Junction targets are NT paths, like \??\C:\target
+        let target = target.as_os_str().to_string_lossy();
+        let target = target.strip_prefix(r"\\?\").unwrap_or(&target);
+        let substitute: Vec<u16> = format!(r"\??\{}", target).encode_utf16().collect();
+        let print: Vec<u16> = std::ffi::OsStr::new(target).encode_wide().collect();
+        let substitute_len = (substitute.len() * 2) as u16;
+        let print_len = (print.len() * 2) as u16;
+
+        // REPARSE_DATA_BUFFER for a mount point: a header, four offsets and
+        // lengths, then both names, each followed by a null character
+        let mut path_buffer = substitute;
+        path_buffer.push(0);
+        path_buffer.extend(print);
+        path_buffer.push(0);
+        let data_len = 8 + path_buffer.len() * 2;
+        let mut buffer: Vec<u8> = Vec::with_capacity(8 + data_len);
+        buffer.extend(IO_REPARSE_TAG_MOUNT_POINT.to_le_bytes());
+        buffer.extend((data_len as u16).to_le_bytes());
+        buffer.extend(0u16.to_le_bytes());
+        buffer.extend(0u16.to_le_bytes());
+        buffer.extend(substitute_len.to_le_bytes());
+        buffer.extend((substitute_len + 2).to_le_bytes());
+        buffer.extend(print_len.to_le_bytes());
+        for unit in path_buffer {
+            buffer.extend(unit.to_le_bytes());
+        }
+
+        fs::create_dir(path)?;
+        let file = open(path, GENERIC_WRITE)?;
+        let mut returned = 0;
+        let res = unsafe {
+            DeviceIoControl(
+                file.as_raw_handle() as *mut c_void,
+                FSCTL_SET_REPARSE_POINT,
+                buffer.as_ptr() as *const c_void,
+                buffer.len() as u32,
+                ptr::null_mut(),
+                0,
+                &mut returned,
+                ptr::null_mut(),
+            )
+        };
+        if res == 0 {
+            let error = io::Error::last_os_error();
+            drop(file);
+            let _ = fs::remove_dir(path);
+            return Err(error);
+        }
+        Ok(())
+    }
+}
+
+/// Wether `path` is a junction. Always false on other platforms
+#[cfg(windows)]
+pub fn is_junction(path: &Path) -> bool {
+    windows::is_junction(path).unwrap_or(false)
+}
+
+#[cfg(not(windows))]
+pub fn is_junction(_path: &Path) -> bool {
+    false
+}
+
+/// Create a junction at `path`, pointing to the `target` directory
+#[cfg(windows)]
+pub fn create(target: &Path, path: &Path) -> io::Result<()> {
+    windows::create(target, path)
+}
+
+#[cfg(not(windows))]
+pub fn create(_target: &Path, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "junctions only exist on Windows",
+    ))
+}
@@ -2,7 +2,9 @@
 //!
 //! Implements copy from one directory to an other
 //!
-//! To use rusync as a library, start with the [Syncer](sync/struct.Syncer.html) struct.
+//! To use rusync as a library, start with the [Syncer](sync/struct.Syncer.html) struct,
+//! or with [SyncerBuilder](struct.SyncerBuilder.html) to have the configuration checked
+//! before syncing.
 //!
 //! To customize its output, implement the [ProgressInfo](progress/trait.ProgressInfo.html) trait.
 
@@ -29,13 +31,72 @@
 //! }
 //! ```
 //!
+pub mod r#async;
+mod backup;
+mod bisync;
+mod blake3;
+mod builder;
+mod cancel;
+mod checksum;
+mod checksum_cache;
+mod chmod;
 pub mod console_info;
+mod direct_io;
 mod entry;
+pub mod error;
+mod filesystem;
+mod filters;
 mod fsops;
+mod hooks;
+mod id_map;
+mod in_place;
+mod itemize;
+pub mod json_info;
+mod junction;
+mod log_file;
+mod manifest;
+mod mmap;
+mod names;
 pub mod progress;
+mod regex;
+mod report;
+mod schedule;
+mod state;
+mod state_db;
 pub mod sync;
+mod throttle;
+mod trash;
+mod unicode;
+mod unicode_tables;
+mod verify;
+mod vss;
+mod watch;
 mod workers;
+mod xxh3;
+pub use crate::bisync::Bisync;
+pub use crate::builder::{Filter, SyncerBuilder};
+pub use crate::cancel::CancellationToken;
+pub use crate::checksum::HashAlgorithm;
+pub use crate::console_info::ColorMode;
 pub use crate::console_info::ConsoleProgressInfo;
+pub use crate::console_info::ProgressMode;
+pub use crate::console_info::Theme;
+pub use crate::entry::Entry;
+pub use crate::error::SyncError;
+pub use crate::json_info::JsonProgressInfo;
+pub use crate::manifest::ManifestFormat;
+pub use crate::sync::CollisionPolicy;
+pub use crate::sync::ConflictPolicy;
+pub use crate::sync::DeleteTiming;
+pub use crate::sync::JunctionPolicy;
+pub use crate::sync::MaxDelete;
+pub use crate::sync::Normalization;
+pub use crate::sync::ReflinkMode;
+pub use crate::sync::RetryPolicy;
 pub use crate::sync::Stats;
+pub use crate::sync::SymlinkPolicy;
 pub use crate::sync::SyncOptions;
 pub use crate::sync::Syncer;
+pub use crate::sync::WalkOrder;
+pub use crate::verify::Comparison;
+pub use crate::verify::Verification;
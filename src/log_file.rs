@@ -0,0 +1,91 @@
+//! log_file
+//!
+//! Append a timestamped line to a file for every entry that was synced,
+//! skipped, deleted or could not be synced, regardless of what the
+//! ProgressInfo displays.
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::fsops::SyncOutcome;
+
+pub struct LogFile {
+    file: File,
+    timestamps: bool,
+}
+
+impl LogFile {
+    pub fn open(path: &Path) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(LogFile {
+            file,
+            timestamps: true,
+        })
+    }
+
+    /// See `SyncOptions.deterministic`
+    pub fn set_timestamps(&mut self, timestamps: bool) {
+        self.timestamps = timestamps;
+    }
+
+    pub fn start(&mut self, source: &str, destination: &str) {
+        self.write(&format!("sync started from {} to {}", source, destination));
+    }
+
+    pub fn outcome(&mut self, entry: &str, outcome: &SyncOutcome) {
+        self.write(&format!("{}: {}", describe(outcome), entry));
+    }
+
+    pub fn error(&mut self, entry: &str, details: &str) {
+        self.write(&format!("error: {}: {}", entry, details));
+    }
+
+    pub fn warning(&mut self, entry: &str, details: &str) {
+        self.write(&format!("warning: {}: {}", entry, details));
+    }
+
+    pub fn deleted(&mut self, entry: &str) {
+        self.write(&format!("deleted: {}", entry));
+    }
+
+    pub fn end(&mut self, num_synced: u64, errors: u64) {
+        self.write(&format!(
+            "sync done, {} entries synced, {} errors",
+            num_synced, errors
+        ));
+    }
+
+    fn write(&mut self, message: &str) {
+        // Like for the error list, failing to log must not stop the sync
+        let _ = if self.timestamps {
+            let now = humantime::format_rfc3339_seconds(SystemTime::now());
+            writeln!(self.file, "{} {}", now, message)
+        } else {
+            writeln!(self.file, "{}", message)
+        };
+    }
+}
+
+fn describe(outcome: &SyncOutcome) -> &'static str {
+    match outcome {
+        SyncOutcome::UpToDate => "up to date",
+        SyncOutcome::FileCopied { .. } => "copied",
+        SyncOutcome::SparseFileCopied { .. } => "copied (sparse)",
+        SyncOutcome::FileCloned { .. } => "cloned",
+        SyncOutcome::UpdatedInPlace { .. } => "updated in place",
+        SyncOutcome::SymlinkUpdated => "symlink updated",
+        SyncOutcome::SymlinkCreated => "symlink created",
+        SyncOutcome::SymlinkSkipped => "symlink skipped",
+        SyncOutcome::JunctionCreated => "junction created",
+        SyncOutcome::JunctionSkipped => "junction skipped",
+        SyncOutcome::HardLinkCreated => "hard link created",
+        SyncOutcome::FileLinked { .. } => "linked from previous copy",
+        SyncOutcome::SpecialCreated => "special file created",
+        SyncOutcome::SpecialSkipped => "special file skipped",
+        SyncOutcome::SizeSkipped => "skipped (too large)",
+        SyncOutcome::MissingSkipped => "skipped (not in destination)",
+        SyncOutcome::ConflictSkipped => "skipped (conflict)",
+    }
+}
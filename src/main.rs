@@ -1,58 +1,1204 @@
-use anyhow::Error;
+use anyhow::{Context, Error};
 use rusync::console_info::ConsoleProgressInfo;
-use rusync::sync::SyncOptions;
-use rusync::Syncer;
-use std::path::PathBuf;
+use rusync::json_info::JsonProgressInfo;
+use rusync::progress::ProgressInfo;
+use rusync::sync::{
+    CollisionPolicy, ConflictPolicy, DeleteTiming, JunctionPolicy, MaxDelete, Normalization,
+    ReflinkMode, RetryPolicy, SymlinkPolicy, SyncOptions, WalkOrder,
+};
+use rusync::{
+    Bisync, CancellationToken, ColorMode, Comparison, HashAlgorithm, ManifestFormat, ProgressMode,
+    Stats, Syncer, Theme, Verification,
+};
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use structopt::clap::{AppSettings, ArgMatches};
 use structopt::StructOpt;
 
+mod config;
+
+// Exit codes of a sync
+const EXIT_SUCCESS: i32 = 0;
+// The sync could not start or was interrupted by an error
+const EXIT_FATAL: i32 = 1;
+// Done, but some entries could not be synced
+const EXIT_PARTIAL: i32 = 2;
+const EXIT_CANCELLED: i32 = 3;
+// With --check: the source and the destination differ
+const EXIT_DIFFERENCES: i32 = 4;
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "rusync")]
 struct Opt {
     #[structopt(
         long = "no-perms",
-        help = "Do not preserve permissions (no-op on Windows)"
+        help = "Do not preserve permissions (file attributes on Windows)"
     )]
     no_preserve_permissions: bool,
 
+    #[structopt(
+        long = "chmod",
+        help = "Change the permissions of the destination entries, like D755,F644 or go-w (no-op on Windows)",
+        raw(number_of_values = "1")
+    )]
+    chmod: Vec<String>,
+
+    #[structopt(
+        long = "owner",
+        help = "Preserve owner (requires root, no-op on Windows)"
+    )]
+    preserve_owner: bool,
+
+    #[structopt(long = "group", help = "Preserve group (no-op on Windows)")]
+    preserve_group: bool,
+
+    #[structopt(
+        long = "numeric-ids",
+        help = "Only accept numeric ids in --usermap and --groupmap, without looking up names"
+    )]
+    numeric_ids: bool,
+
+    #[structopt(
+        long = "usermap",
+        help = "Translate owners with comma-separated FROM:TO pairs, like 1000:1001,alice:bob (implies --owner)"
+    )]
+    usermap: Option<String>,
+
+    #[structopt(
+        long = "groupmap",
+        help = "Translate groups with comma-separated FROM:TO pairs, like 1000-1999:users (implies --group)"
+    )]
+    groupmap: Option<String>,
+
+    #[structopt(
+        long = "hard-links",
+        help = "Preserve hard links between source files (no-op on Windows)"
+    )]
+    preserve_hard_links: bool,
+
+    #[structopt(long = "no-times", help = "Do not preserve modification times")]
+    no_preserve_times: bool,
+
+    #[structopt(
+        long = "delete",
+        help = "Delete files in the destination that do not exist in the source"
+    )]
+    delete_extraneous: bool,
+
+    #[structopt(
+        long = "delete-before",
+        help = "Delete extraneous files before syncing, to free space first (implies --delete)",
+        raw(conflicts_with_all = r#"&["delete_during", "delete_after"]"#)
+    )]
+    delete_before: bool,
+
+    #[structopt(
+        long = "delete-during",
+        help = "Delete extraneous files while syncing (implies --delete)",
+        raw(conflicts_with = r#""delete_after""#)
+    )]
+    delete_during: bool,
+
+    #[structopt(
+        long = "delete-after",
+        help = "Delete extraneous files once everything is synced, the default (implies --delete)"
+    )]
+    delete_after: bool,
+
+    #[structopt(
+        long = "max-delete",
+        help = "Fail without deleting anything if more extraneous entries than this, or than this percentage like 50%, would be deleted"
+    )]
+    max_delete: Option<MaxDelete>,
+
+    #[structopt(
+        long = "delete-to-trash",
+        help = "Move extraneous files to the trash instead of removing them (implies --delete)"
+    )]
+    delete_to_trash: bool,
+
+    #[structopt(
+        long = "conflicts",
+        help = "Detect files changed in both the source and the destination since the previous sync, and resolve them with the given policy (source-wins, newer-wins, keep-both or report-only)"
+    )]
+    conflicts: Option<ConflictPolicy>,
+
+    #[structopt(
+        long = "remove-source-files",
+        help = "Remove source files once synced, and then empty source directories",
+        raw(
+            conflicts_with_all = r#"&["delete_extraneous", "delete_before", "delete_during", "delete_after", "delete_to_trash"]"#
+        )
+    )]
+    remove_source_files: bool,
+
+    #[structopt(
+        long = "prune-empty-dirs",
+        help = "Remove the empty directories of the destination once synced"
+    )]
+    prune_empty_dirs: bool,
+
+    #[structopt(
+        short = "n",
+        long = "dry-run",
+        help = "Only show what would be done, without changing anything"
+    )]
+    dry_run: bool,
+
+    #[structopt(
+        long = "report",
+        help = "With --dry-run: list new, changed and deleted entries at the end",
+        raw(requires = r#""dry_run""#)
+    )]
+    report: bool,
+
+    #[structopt(
+        long = "exclude",
+        help = "Skip entries matching the given pattern",
+        raw(number_of_values = "1")
+    )]
+    exclude: Vec<String>,
+
+    #[structopt(
+        long = "include",
+        help = "Do not skip entries matching the given pattern",
+        raw(number_of_values = "1")
+    )]
+    include: Vec<String>,
+
+    #[structopt(
+        long = "exclude-from",
+        help = "Skip entries matching the patterns found in the given file",
+        parse(from_os_str),
+        raw(number_of_values = "1")
+    )]
+    exclude_from: Vec<PathBuf>,
+
+    #[structopt(
+        long = "exclude-regex",
+        help = "Skip entries whose relative path matches the given regular expression",
+        raw(number_of_values = "1")
+    )]
+    exclude_regex: Vec<String>,
+
+    #[structopt(
+        long = "include-regex",
+        help = "Do not skip entries whose relative path matches the given regular expression",
+        raw(number_of_values = "1")
+    )]
+    include_regex: Vec<String>,
+
+    #[structopt(
+        long = "include-from",
+        help = "Do not skip entries matching the patterns found in the given file",
+        parse(from_os_str),
+        raw(number_of_values = "1")
+    )]
+    include_from: Vec<PathBuf>,
+
+    #[structopt(
+        long = "gitignore",
+        help = "Skip entries matching patterns in .gitignore and .ignore files"
+    )]
+    respect_gitignore: bool,
+
+    #[structopt(
+        long = "skip-hidden",
+        help = "Skip files and directories whose name starts with a dot"
+    )]
+    skip_hidden: bool,
+
+    #[structopt(
+        short = "x",
+        long = "one-file-system",
+        help = "Do not cross file system boundaries (no-op on Windows)"
+    )]
+    one_file_system: bool,
+
+    #[structopt(
+        long = "symlinks",
+        help = "What to do with symlinks: preserve, preserve-all, dereference or skip",
+        default_value = "preserve"
+    )]
+    symlinks: SymlinkPolicy,
+
+    #[structopt(
+        long = "junctions",
+        help = "What to do with junctions on Windows: recreate, dereference or skip",
+        default_value = "recreate"
+    )]
+    junctions: JunctionPolicy,
+
+    #[structopt(
+        long = "follow-dir-symlinks",
+        help = "Walk through symlinks to directories, and copy what they contain"
+    )]
+    follow_dir_symlinks: bool,
+
+    #[structopt(
+        long = "case-insensitive",
+        help = "The destination ignores case: report files only differing in case as errors"
+    )]
+    case_insensitive: bool,
+
+    #[structopt(
+        long = "normalize-names",
+        help = "How to compare and write names in different Unicode forms: none, compare, nfc or nfd",
+        default_value = "none"
+    )]
+    normalize_names: Normalization,
+
+    #[structopt(
+        long = "collisions",
+        help = "With several sources, what to do with files found in more than one: error, last-wins or skip",
+        default_value = "error"
+    )]
+    collisions: CollisionPolicy,
+
+    #[structopt(
+        long = "specials",
+        help = "Re-create FIFOs, sockets and device nodes instead of skipping them"
+    )]
+    copy_specials: bool,
+
+    #[structopt(long = "sparse", help = "Handle sparse files efficiently")]
+    sparse: bool,
+
+    #[structopt(
+        long = "checksum",
+        help = "Compare file contents instead of modification times"
+    )]
+    checksum: bool,
+
+    #[structopt(
+        long = "checksum-cache",
+        help = "With checksum: remember checksums in the destination between syncs",
+        raw(requires = r#""checksum""#)
+    )]
+    checksum_cache: bool,
+
+    #[structopt(
+        long = "hash",
+        help = "Hash function for checksums and manifests: sha256, blake3 or xxh3",
+        default_value = "sha256"
+    )]
+    hash: HashAlgorithm,
+
+    #[structopt(
+        long = "size-only",
+        help = "Only compare file sizes, not modification times",
+        raw(conflicts_with = r#""checksum""#)
+    )]
+    size_only: bool,
+
+    #[structopt(
+        long = "modify-window",
+        help = "Consider modification times within that many seconds of each other equal",
+        default_value = "0"
+    )]
+    modify_window: u64,
+
+    #[structopt(
+        long = "partial",
+        help = "Keep partially transfered files, and resume from them on the next sync"
+    )]
+    partial: bool,
+
+    #[structopt(
+        long = "partial-dir",
+        help = "Keep partially transfered files in .rusync-partial at the root of the destination, implies --partial"
+    )]
+    partial_dir: bool,
+
+    #[structopt(
+        long = "atomic",
+        help = "Write to temporary files, and rename them once complete"
+    )]
+    atomic: bool,
+
+    #[structopt(
+        long = "fsync",
+        help = "Flush each written file and its directory to disk before going on"
+    )]
+    fsync: bool,
+
+    #[structopt(
+        long = "verify",
+        help = "Read each copied file back, and report an error if its checksum differs from the source"
+    )]
+    verify_copies: bool,
+
+    #[structopt(
+        long = "in-place",
+        help = "Update existing files in place, only writing the blocks that changed"
+    )]
+    in_place: bool,
+
+    #[structopt(
+        long = "reflink",
+        help = "Clone files with copy-on-write: auto, always or never",
+        default_value = "auto"
+    )]
+    reflink: ReflinkMode,
+
+    #[structopt(
+        long = "retries",
+        help = "Retry entries failing with transient errors this many times",
+        default_value = "0"
+    )]
+    retries: u32,
+
+    #[structopt(
+        long = "retry-backoff",
+        help = "Time to wait before the first retry, doubled after each attempt",
+        default_value = "1s",
+        parse(try_from_str = "humantime::parse_duration")
+    )]
+    retry_backoff: Duration,
+
+    #[structopt(
+        long = "bwlimit",
+        help = "Limit transfer rate, in KiB/s or with a K, M or G suffix",
+        parse(try_from_str = "parse_rate")
+    )]
+    bwlimit: Option<u64>,
+
+    #[structopt(
+        long = "max-size",
+        help = "Skip files bigger than this, in bytes or with a K, M or G suffix",
+        parse(try_from_str = "parse_size")
+    )]
+    max_size: Option<u64>,
+
+    #[structopt(
+        long = "buffer-size",
+        help = "How much to read and write at once when copying files, in bytes or with a K, M or G suffix",
+        default_value = "100K",
+        parse(try_from_str = "parse_buffer_size")
+    )]
+    buffer_size: usize,
+
+    #[structopt(
+        long = "preallocate",
+        help = "Reserve the space for each file in the destination before copying it"
+    )]
+    preallocate: bool,
+
+    #[structopt(
+        long = "vss",
+        help = "On Windows, sync from a Volume Shadow Copy snapshot of the sources, to copy files locked by other programs",
+        raw(conflicts_with = r#""remove_source_files""#)
+    )]
+    vss: bool,
+
+    #[structopt(
+        long = "direct-io",
+        help = "Bypass the page cache when copying files (Linux only)"
+    )]
+    direct_io: bool,
+
+    #[structopt(
+        long = "mmap",
+        help = "Copy files at least this big through a memory mapping, in bytes or with a K, M or G suffix",
+        parse(try_from_str = "parse_size")
+    )]
+    mmap_threshold: Option<u64>,
+
+    #[structopt(
+        long = "existing",
+        help = "Only update files that already exist in the destination"
+    )]
+    existing: bool,
+
+    #[structopt(
+        long = "min-size",
+        help = "Ignore files smaller than this, in bytes or with a K, M or G suffix",
+        parse(try_from_str = "parse_size")
+    )]
+    min_size: Option<u64>,
+
+    #[structopt(
+        long = "newer-than",
+        help = "Ignore files modified before the given time, or longer ago than the given duration",
+        parse(try_from_str = "parse_time")
+    )]
+    newer_than: Option<SystemTime>,
+
+    #[structopt(
+        long = "older-than",
+        help = "Ignore files modified after the given time, or more recently than the given duration",
+        parse(try_from_str = "parse_time")
+    )]
+    older_than: Option<SystemTime>,
+
+    #[structopt(
+        long = "workers",
+        help = "Number of files to sync in parallel",
+        default_value = "1"
+    )]
+    num_workers: usize,
+
     #[structopt(long = "err-list", help = "Write errors to the given file")]
     error_list_path: Option<PathBuf>,
 
-    #[structopt(parse(from_os_str))]
-    source: PathBuf,
+    #[structopt(
+        long = "backup-dir",
+        help = "Move overwritten and deleted entries to the given directory",
+        parse(from_os_str)
+    )]
+    backup_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "backup",
+        help = "Rename overwritten and deleted entries by adding the suffix to their name"
+    )]
+    backup: bool,
+
+    #[structopt(
+        long = "suffix",
+        help = "Suffix of the backups (default: ~, or nothing with --backup-dir)"
+    )]
+    backup_suffix: Option<String>,
+
+    #[structopt(
+        long = "link-dest",
+        help = "Hard link unchanged files from the given directory instead of copying them",
+        parse(from_os_str)
+    )]
+    link_dest: Option<PathBuf>,
+
+    #[structopt(
+        long = "bisync",
+        help = "Sync two directories both ways, propagating changes made on either side"
+    )]
+    bisync: bool,
+
+    #[structopt(
+        long = "check",
+        help = "Compare the source and the destination without changing anything"
+    )]
+    check: bool,
+
+    #[structopt(
+        long = "compare",
+        help = "With --check: how to compare files, size, mtime or checksum",
+        default_value = "mtime"
+    )]
+    compare: Comparison,
+
+    #[structopt(
+        long = "manifest",
+        help = "Once done, list the files of the destination with their checksums in the given file",
+        parse(from_os_str)
+    )]
+    manifest: Option<PathBuf>,
 
-    #[structopt(parse(from_os_str))]
-    destination: PathBuf,
+    #[structopt(
+        long = "state-db",
+        help = "Record the synced entries in the given file, to skip unchanged files next time. With --checksum or --verify, also skip files rewritten with the same contents",
+        parse(from_os_str)
+    )]
+    state_db: Option<PathBuf>,
+
+    #[structopt(
+        long = "stats-json",
+        help = "Once done, write the stats of the sync as JSON to the given file",
+        parse(from_os_str),
+        raw(conflicts_with_all = r#"&["watch", "every"]"#)
+    )]
+    stats_json: Option<PathBuf>,
+
+    #[structopt(
+        long = "manifest-format",
+        help = "Format of the manifest: json or csv",
+        default_value = "json"
+    )]
+    manifest_format: ManifestFormat,
+
+    #[structopt(
+        long = "pre-hook",
+        help = "Shell command to run before syncing. The sync does not start if it fails"
+    )]
+    pre_hook: Option<String>,
+
+    #[structopt(
+        long = "post-hook",
+        help = "Shell command to run after syncing, with the stats in RUSYNC_* environment variables"
+    )]
+    post_hook: Option<String>,
+
+    #[structopt(
+        long = "config",
+        help = "Read default options from the given file, after ~/.config/rusync/config.toml",
+        parse(from_os_str)
+    )]
+    // Read before parsing the command line, see config_args()
+    #[allow(dead_code)]
+    config: Option<PathBuf>,
+
+    #[structopt(
+        long = "profile",
+        help = "Sync what the [profile.NAME] table of the configuration describes"
+    )]
+    // Read before parsing the command line, see config_args()
+    #[allow(dead_code)]
+    profile: Option<String>,
+
+    #[structopt(
+        long = "log-file",
+        help = "Append what was done for each entry to the given file",
+        parse(from_os_str)
+    )]
+    log_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "json",
+        help = "Print progress as newline-delimited JSON",
+        raw(conflicts_with = r#""error_list_path""#)
+    )]
+    json: bool,
+
+    #[structopt(
+        long = "progress",
+        help = "When to draw the progress in place: always, auto (in a terminal) or never",
+        default_value = "auto"
+    )]
+    progress: ProgressMode,
+
+    #[structopt(
+        long = "color",
+        help = "When to use colors: always, auto (in a terminal, unless NO_COLOR is set) or never",
+        default_value = "auto"
+    )]
+    color: ColorMode,
+
+    #[structopt(
+        long = "theme",
+        help = "Colors to use, like success=cyan,error=bright-magenta (kinds: info, success, warning, error)",
+        default_value = ""
+    )]
+    theme: Theme,
+
+    #[structopt(
+        long = "prescan",
+        help = "Count files and bytes before syncing, to show the overall progress"
+    )]
+    prescan: bool,
+
+    #[structopt(
+        long = "order",
+        help = "In which order to walk the source: depth-first, sorted or breadth-first",
+        default_value = "depth-first"
+    )]
+    order: WalkOrder,
+
+    #[structopt(
+        long = "deterministic",
+        help = "Report the same things in the same order on every run: implies --order sorted, --prescan and a single worker"
+    )]
+    deterministic: bool,
+
+    #[structopt(
+        short = "q",
+        long = "quiet",
+        help = "Do not print anything, except fatal errors",
+        raw(conflicts_with_all = r#"&["json", "error_list_path"]"#)
+    )]
+    quiet: bool,
+
+    #[structopt(
+        short = "i",
+        long = "itemize-changes",
+        help = "Print a line describing the changes made to each entry",
+        raw(conflicts_with = r#""quiet""#)
+    )]
+    itemize_changes: bool,
+
+    #[structopt(
+        long = "watch",
+        help = "Keep running, and sync again each time the source changes"
+    )]
+    watch: bool,
+
+    #[structopt(
+        long = "every",
+        help = "Keep running, and sync again after the given duration, like 15m",
+        parse(try_from_str = "humantime::parse_duration"),
+        raw(conflicts_with = r#""watch""#)
+    )]
+    every: Option<Duration>,
+
+    #[structopt(
+        name = "PATH",
+        help = "One or more source directories, followed by the destination",
+        parse(from_os_str),
+        raw(required = "true", min_values = "2")
+    )]
+    paths: Vec<PathBuf>,
+}
+
+// Parse a rate like rsync does: 1024-based K, M and G suffixes, and KiB
+// when there is no suffix
+fn parse_rate(value: &str) -> Result<u64, String> {
+    parse_with_suffix(value, 1024).map_err(|_| format!("Invalid rate: '{}'", value))
+}
+
+// Parse a size with the same suffixes as rates, but in bytes when there is
+// no suffix
+fn parse_size(value: &str) -> Result<u64, String> {
+    parse_with_suffix(value, 1).map_err(|_| format!("Invalid size: '{}'", value))
+}
+
+fn parse_buffer_size(value: &str) -> Result<usize, String> {
+    match parse_size(value)? {
+        0 => Err("The buffer size cannot be 0".to_string()),
+        size => usize::try_from(size).map_err(|_| format!("Buffer size too large: '{}'", value)),
+    }
+}
+
+// Either a duration like `7days`, counted back from now, or a timestamp like
+// `2024-01-31`, `2024-01-31 12:00:00` or `2024-01-31T12:00:00Z` (in UTC)
+fn parse_time(value: &str) -> Result<SystemTime, String> {
+    if let Ok(duration) = humantime::parse_duration(value) {
+        return SystemTime::now()
+            .checked_sub(duration)
+            .ok_or_else(|| format!("Duration too long: '{}'", value));
+    }
+    let timestamp = if value.len() == "2024-01-31".len() {
+        format!("{} 00:00:00", value)
+    } else {
+        value.to_string()
+    };
+    humantime::parse_rfc3339_weak(&timestamp)
+        .map_err(|_| format!("Invalid duration or timestamp: '{}'", value))
+}
+
+fn parse_with_suffix(value: &str, default_multiplier: u64) -> Result<u64, ()> {
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1024),
+        Some('M') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('G') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, default_multiplier),
+    };
+    let number: f64 = number.parse().map_err(|_| ())?;
+    if number < 0.0 {
+        return Err(());
+    }
+    Ok((number * multiplier as f64) as u64)
+}
+
+enum FilterRule<'a> {
+    Include(&'a str),
+    Exclude(&'a str),
+    IncludeFrom(&'a Path),
+    ExcludeFrom(&'a Path),
+    IncludeRegex(&'a str),
+    ExcludeRegex(&'a str),
+}
+
+// Filter rules are evaluated in order, so interleave the values of all the
+// filter options using their positions on the command line
+fn add_filters(syncer: &mut Syncer, opt: &Opt, matches: &ArgMatches) -> Result<(), Error> {
+    let mut rules = vec![];
+    if let Some(indices) = matches.indices_of("include") {
+        rules.extend(indices.zip(opt.include.iter().map(|p| FilterRule::Include(p))));
+    }
+    if let Some(indices) = matches.indices_of("exclude") {
+        rules.extend(indices.zip(opt.exclude.iter().map(|p| FilterRule::Exclude(p))));
+    }
+    if let Some(indices) = matches.indices_of("include_from") {
+        rules.extend(indices.zip(opt.include_from.iter().map(|p| FilterRule::IncludeFrom(p))));
+    }
+    if let Some(indices) = matches.indices_of("exclude_from") {
+        rules.extend(indices.zip(opt.exclude_from.iter().map(|p| FilterRule::ExcludeFrom(p))));
+    }
+    if let Some(indices) = matches.indices_of("include_regex") {
+        rules.extend(
+            indices.zip(
+                opt.include_regex
+                    .iter()
+                    .map(|p| FilterRule::IncludeRegex(p)),
+            ),
+        );
+    }
+    if let Some(indices) = matches.indices_of("exclude_regex") {
+        rules.extend(
+            indices.zip(
+                opt.exclude_regex
+                    .iter()
+                    .map(|p| FilterRule::ExcludeRegex(p)),
+            ),
+        );
+    }
+    rules.sort_by_key(|(index, _)| *index);
+    for (_, rule) in rules {
+        match rule {
+            FilterRule::Include(pattern) => syncer.include(pattern),
+            FilterRule::Exclude(pattern) => syncer.exclude(pattern),
+            FilterRule::IncludeFrom(path) => syncer
+                .include_from(path)
+                .with_context(|| format!("Could not read '{}'", path.display()))?,
+            FilterRule::ExcludeFrom(path) => syncer
+                .exclude_from(path)
+                .with_context(|| format!("Could not read '{}'", path.display()))?,
+            FilterRule::IncludeRegex(pattern) => syncer
+                .include_regex(pattern)
+                .map_err(|e| anyhow::anyhow!("Invalid regex '{}': {}", pattern, e))?,
+            FilterRule::ExcludeRegex(pattern) => syncer
+                .exclude_regex(pattern)
+                .map_err(|e| anyhow::anyhow!("Invalid regex '{}': {}", pattern, e))?,
+        }
+    }
+    Ok(())
+}
+
+// The values given to the option `name` on the command line
+fn option_values(args: &[OsString], name: &str) -> Vec<OsString> {
+    let mut values = vec![];
+    let prefix = format!("{}=", name);
+    for (i, arg) in args.iter().enumerate() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            break;
+        }
+        if arg == name {
+            if let Some(value) = args.get(i + 1) {
+                values.push(value.clone());
+            }
+        } else if let Some(value) = arg.strip_prefix(&prefix) {
+            values.push(value.into());
+        }
+    }
+    values
+}
+
+// Returns true if `option`, like `--delete`, is an option without a value
+fn is_flag(option: &str) -> bool {
+    Opt::clap()
+        .setting(AppSettings::ColorNever)
+        .get_matches_from_safe(["rusync", option, "--", ".", "."])
+        .is_ok()
+}
+
+// Flags set in the configuration are turned off by `--no-FLAG` on the command
+// line. Removes such options from `args`, and returns the names of the flags
+fn negated_flags(args: &mut Vec<OsString>) -> Vec<String> {
+    let mut negated = vec![];
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].to_string_lossy().to_string();
+        if arg == "--" {
+            break;
+        }
+        match arg.strip_prefix("--no-") {
+            // Like --no-perms
+            Some(name) if !is_flag(&arg) && is_flag(&format!("--{}", name)) => {
+                negated.push(name.to_string());
+                args.remove(i);
+            }
+            _ => i += 1,
+        }
+    }
+    negated
+}
+
+// Options found in the configuration files, in the order they must be given
+// to clap: later ones override earlier ones, and the actual command line
+// overrides them all. With a `profile`, its options come last, and its
+// sources and destination are returned separately. Flags in `negated` are
+// left out
+fn config_args(
+    args: &[OsString],
+    negated: &[String],
+) -> Result<(Vec<OsString>, Vec<OsString>), Error> {
+    let mut paths = vec![];
+    if let Some(default_path) = config::default_path().filter(|path| path.is_file()) {
+        paths.push(default_path);
+    }
+    // Looked for before parsing, since the configuration must be parsed too
+    paths.extend(
+        option_values(args, "--config")
+            .into_iter()
+            .map(PathBuf::from),
+    );
+    let profile = option_values(args, "--profile")
+        .pop()
+        .map(|name| name.to_string_lossy().to_string());
+    let profile = profile.as_deref();
+    let mut config_args = vec![];
+    // The last file defining the profile wins
+    let mut profile_table = None;
+    for path in paths {
+        let config = config::Config::read(&path)?;
+        config_args.extend(checked_args(&path, &config.root, negated)?);
+        if let Some(name) = profile {
+            if let Some(table) = config.table(&format!("profile.{}", name)) {
+                profile_table = Some((path.clone(), table.clone()));
+            }
+        }
+    }
+    let name = match profile {
+        None => return Ok((config_args, vec![])),
+        Some(name) => name,
+    };
+    let (path, table) = profile_table
+        .ok_or_else(|| anyhow::anyhow!("No profile named '{}' in the configuration", name))?;
+    let profile = config::Profile::from_table(&table)
+        .map_err(|e| anyhow::anyhow!("In '{}': profile '{}': {}", path.display(), name, e))?;
+    config_args.extend(checked_args(&path, &profile.options, negated)?);
+    let mut paths: Vec<OsString> = profile.sources.into_iter().map(OsString::from).collect();
+    paths.push(profile.destination.into());
+    Ok((config_args, paths))
+}
+
+// Check the options now, so that errors mention the file
+fn checked_args(
+    path: &Path,
+    table: &config::Table,
+    negated: &[String],
+) -> Result<Vec<OsString>, Error> {
+    let table: config::Table = table
+        .iter()
+        .filter(|(key, value)| !(negated.contains(key) && *value == config::Value::Boolean(true)))
+        .cloned()
+        .collect();
+    let args = config::to_args(&table);
+    let mut check_args = vec![OsString::from("rusync")];
+    check_args.extend(args.iter().cloned());
+    check_args.extend([OsString::from("."), OsString::from(".")]);
+    if let Err(e) = Opt::clap()
+        .setting(AppSettings::AllArgsOverrideSelf)
+        .setting(AppSettings::ColorNever)
+        .get_matches_from_safe(check_args)
+    {
+        let details = e.message.lines().next().unwrap_or_default();
+        let details = details.trim_start_matches("error: ");
+        anyhow::bail!("In '{}': {}", path.display(), details);
+    }
+    Ok(args)
+}
+
+fn exit_code(stats: &Stats) -> i32 {
+    if stats.cancelled {
+        EXIT_CANCELLED
+    } else if stats.errors > 0 {
+        EXIT_PARTIAL
+    } else {
+        EXIT_SUCCESS
+    }
+}
+
+// Cancel the sync on the first Ctrl-C, so that it stops after the entries
+// being synced. The second one kills rusync as usual
+#[cfg(unix)]
+fn cancel_on_interrupt(token: CancellationToken) {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn on_interrupt(_signal: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+
+    let handler: extern "C" fn(libc::c_int) = on_interrupt;
+    let installed = unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESETHAND;
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut()) == 0
+    };
+    if !installed {
+        return;
+    }
+    // Only async-signal-safe code may run in the handler itself
+    thread::spawn(move || loop {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            token.cancel();
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    });
+}
+
+#[cfg(not(unix))]
+fn cancel_on_interrupt(_token: CancellationToken) {}
+
+fn print_verification(verification: &Verification) {
+    for path in &verification.missing {
+        println!("missing: {}", path.display());
+    }
+    for path in &verification.extra {
+        println!("extra: {}", path.display());
+    }
+    for (path, how) in &verification.differing {
+        println!("differs: {} ({})", path.display(), how);
+    }
+    for (path, error) in &verification.errors {
+        eprintln!("error: {}: {}", path.display(), error);
+    }
+    if verification.is_identical() {
+        println!("Source and destination are identical");
+    } else {
+        println!(
+            "{} missing, {} extra, {} differing, {} errors",
+            verification.missing.len(),
+            verification.extra.len(),
+            verification.differing.len(),
+            verification.errors.len()
+        );
+    }
+}
+
+fn print_bisync(report: &Bisync) {
+    for path in &report.to_destination {
+        println!("copied to destination: {}", path.display());
+    }
+    for path in &report.to_source {
+        println!("copied to source: {}", path.display());
+    }
+    for path in &report.deleted_in_destination {
+        println!("deleted in destination: {}", path.display());
+    }
+    for path in &report.deleted_in_source {
+        println!("deleted in source: {}", path.display());
+    }
+    for (path, resolution) in &report.conflicts {
+        println!("conflict: {} ({})", path.display(), resolution);
+    }
+    for (path, error) in &report.errors {
+        eprintln!("error: {}: {}", path.display(), error);
+    }
+    println!(
+        "{} copied to destination, {} copied to source, {} deleted, {} conflicts, {} errors",
+        report.to_destination.len(),
+        report.to_source.len(),
+        report.deleted_in_destination.len() + report.deleted_in_source.len(),
+        report.conflicts.len(),
+        report.errors.len()
+    );
 }
 
 fn main() -> Result<(), Error> {
-    let opt = Opt::from_args();
-    let source = &opt.source;
-    if !source.is_dir() {
-        eprintln!("{} is not a directory", source.to_string_lossy());
-        process::exit(1);
+    let mut args: Vec<_> = std::env::args_os().collect();
+    let negated = negated_flags(&mut args);
+    let (config_args, profile_paths) = config_args(&args[1..], &negated)?;
+    args.splice(1..1, config_args);
+    args.extend(profile_paths);
+    // So that the command line can override the configuration
+    let matches = Opt::clap()
+        .setting(AppSettings::AllArgsOverrideSelf)
+        .get_matches_from(args);
+    let opt = Opt::from_clap(&matches);
+    let (destination, sources) = opt.paths.split_last().expect("at least 2 paths");
+    for source in sources {
+        if !source.is_dir() {
+            eprintln!("{} is not a directory", source.to_string_lossy());
+            process::exit(1);
+        }
     }
-    let destination = &opt.destination;
 
-    let console_info = match opt.error_list_path {
-        Some(err_file) => ConsoleProgressInfo::with_error_list_path(&err_file)?,
-        None => ConsoleProgressInfo::new(),
+    let progress_info: Box<dyn ProgressInfo + Send> = if opt.json {
+        Box::new(JsonProgressInfo::new())
+    } else {
+        let mut console_info = match &opt.error_list_path {
+            Some(err_file) => ConsoleProgressInfo::with_error_list_path(err_file)?,
+            None => ConsoleProgressInfo::new(),
+        };
+        console_info.itemize_changes(opt.itemize_changes);
+        console_info.report(opt.report);
+        console_info.progress_mode(opt.progress);
+        console_info.color_mode(opt.color);
+        console_info.theme(opt.theme);
+        Box::new(console_info)
     };
     let options = SyncOptions {
         preserve_permissions: !opt.no_preserve_permissions,
+        preserve_owner: opt.preserve_owner || opt.usermap.is_some(),
+        preserve_group: opt.preserve_group || opt.groupmap.is_some(),
+        numeric_ids: opt.numeric_ids,
+        preserve_hard_links: opt.preserve_hard_links,
+        preserve_times: !opt.no_preserve_times,
+        delete_extraneous: opt.delete_extraneous
+            || opt.delete_before
+            || opt.delete_during
+            || opt.delete_after
+            || opt.delete_to_trash,
+        delete_timing: if opt.delete_before {
+            DeleteTiming::Before
+        } else if opt.delete_during {
+            DeleteTiming::During
+        } else {
+            DeleteTiming::After
+        },
+        respect_gitignore: opt.respect_gitignore,
+        skip_hidden: opt.skip_hidden,
+        one_file_system: opt.one_file_system,
+        checksum: opt.checksum,
+        checksum_cache: opt.checksum_cache,
+        hash: opt.hash,
+        size_only: opt.size_only,
+        modify_window: Duration::from_secs(opt.modify_window),
+        sparse: opt.sparse,
+        copy_specials: opt.copy_specials,
+        symlinks: opt.symlinks,
+        junctions: opt.junctions,
+        follow_dir_symlinks: opt.follow_dir_symlinks,
+        collisions: opt.collisions,
+        case_insensitive: opt.case_insensitive,
+        normalization: opt.normalize_names,
+        bwlimit: opt.bwlimit,
+        max_size: opt.max_size,
+        mmap_threshold: opt.mmap_threshold,
+        direct_io: opt.direct_io,
+        preallocate: opt.preallocate,
+        vss: opt.vss,
+        buffer_size: opt.buffer_size,
+        existing: opt.existing,
+        min_size: opt.min_size,
+        newer_than: opt.newer_than,
+        older_than: opt.older_than,
+        reflink: opt.reflink,
+        in_place: opt.in_place,
+        partial: opt.partial || opt.partial_dir,
+        partial_dir: opt.partial_dir,
+        atomic: opt.atomic,
+        fsync: opt.fsync,
+        verify_copies: opt.verify_copies,
+        retries: RetryPolicy {
+            count: opt.retries,
+            backoff: opt.retry_backoff,
+        },
+        remove_source_files: opt.remove_source_files,
+        prune_empty_dirs: opt.prune_empty_dirs,
+        max_delete: opt.max_delete,
+        delete_to_trash: opt.delete_to_trash,
+        conflicts: opt.conflicts,
+        dry_run: opt.dry_run,
+        prescan: opt.prescan,
+        walk_order: opt.order,
+        deterministic: opt.deterministic,
+        quiet: opt.quiet,
     };
-    let syncer = Syncer::new(&source, &destination, options, Box::new(console_info));
-    let stats = syncer.sync();
-    match stats {
+    let mut syncer = Syncer::new_multi(sources, destination, options, progress_info);
+    add_filters(&mut syncer, &opt, &matches)?;
+    syncer.num_workers(opt.num_workers);
+    for rules in &opt.chmod {
+        syncer
+            .chmod(rules)
+            .map_err(|e| anyhow::anyhow!("Invalid chmod rules '{}': {}", rules, e))?;
+    }
+    if let Some(usermap) = &opt.usermap {
+        syncer
+            .map_users(usermap)
+            .map_err(|e| anyhow::anyhow!("Invalid user map '{}': {}", usermap, e))?;
+    }
+    if let Some(groupmap) = &opt.groupmap {
+        syncer
+            .map_groups(groupmap)
+            .map_err(|e| anyhow::anyhow!("Invalid group map '{}': {}", groupmap, e))?;
+    }
+    if let Some(link_dest) = &opt.link_dest {
+        syncer.link_dest(link_dest);
+    }
+    if let Some(backup_dir) = &opt.backup_dir {
+        syncer.backup_dir(backup_dir);
+    }
+    if opt.backup || opt.backup_suffix.is_some() {
+        let default_suffix = if opt.backup_dir.is_some() { "" } else { "~" };
+        syncer.backup_suffix(opt.backup_suffix.as_deref().unwrap_or(default_suffix));
+    }
+    if opt.check {
+        let comparison = if opt.checksum {
+            Comparison::Checksum
+        } else {
+            opt.compare
+        };
+        match syncer.verify(comparison) {
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(EXIT_FATAL);
+            }
+            Ok(verification) => {
+                print_verification(&verification);
+                process::exit(if verification.is_identical() {
+                    EXIT_SUCCESS
+                } else {
+                    EXIT_DIFFERENCES
+                });
+            }
+        }
+    }
+    if opt.bisync {
+        if sources.len() != 1 {
+            eprintln!("--bisync takes exactly two directories");
+            process::exit(EXIT_FATAL);
+        }
+        cancel_on_interrupt(syncer.cancellation_token());
+        match syncer.bisync() {
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(EXIT_FATAL);
+            }
+            Ok(report) => {
+                print_bisync(&report);
+                process::exit(if report.errors.is_empty() {
+                    EXIT_SUCCESS
+                } else {
+                    EXIT_PARTIAL
+                });
+            }
+        }
+    }
+    if let Some(manifest) = &opt.manifest {
+        syncer.manifest(manifest, opt.manifest_format);
+    }
+    if let Some(state_db) = &opt.state_db {
+        syncer.state_db(state_db);
+    }
+    if let Some(pre_hook) = &opt.pre_hook {
+        syncer.pre_hook(pre_hook);
+    }
+    if let Some(post_hook) = &opt.post_hook {
+        syncer.post_hook(post_hook);
+    }
+    if let Some(log_file) = &opt.log_file {
+        syncer
+            .log_file(log_file)
+            .with_context(|| format!("Could not open log file '{}'", log_file.display()))?;
+    }
+    cancel_on_interrupt(syncer.cancellation_token());
+    if opt.watch || opt.every.is_some() {
+        // Only returns on fatal errors, or once cancelled
+        let result = match opt.every {
+            Some(interval) => syncer.every(interval),
+            None => syncer.watch(),
+        };
+        match result {
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(EXIT_FATAL);
+            }
+            Ok(()) => process::exit(EXIT_CANCELLED),
+        }
+    }
+    match syncer.sync() {
         Err(err) => {
             eprintln!("{}", err);
-            process::exit(1);
-        }
-        Ok(stats) if stats.errors > 0 => {
-            process::exit(1);
+            process::exit(EXIT_FATAL);
         }
-        _ => {
-            process::exit(0);
+        Ok(stats) => {
+            if let Some(path) = &opt.stats_json {
+                let json = format!("{}\n", rusync::json_info::stats_to_json(&stats));
+                if let Err(err) = std::fs::write(path, json) {
+                    eprintln!("Could not write stats to '{}': {}", path.display(), err);
+                    process::exit(EXIT_FATAL);
+                }
+            }
+            process::exit(exit_code(&stats));
         }
     }
 }
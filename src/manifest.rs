@@ -0,0 +1,234 @@
+//! manifest
+//!
+//! List the files of a directory along with their size, modification time
+//! and checksum, so that they can be checked later on. The checksums are
+//! named after their algorithm, see `SyncOptions.hash`
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{Context, Error};
+
+use crate::checksum::{self, HashAlgorithm};
+use crate::fsops;
+use crate::json_info;
+
+/// How to write the manifest
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ManifestFormat {
+    /// A JSON object with a `files` array
+    Json,
+    /// A header line followed by one line per file
+    Csv,
+}
+
+impl std::str::FromStr for ManifestFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ManifestFormat::Json),
+            "csv" => Ok(ManifestFormat::Csv),
+            _ => Err(format!(
+                "Invalid manifest format '{}', expected 'json' or 'csv'",
+                s
+            )),
+        }
+    }
+}
+
+pub struct ManifestEntry {
+    /// Relative to the root of the manifest
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+    /// Lowercase hexadecimal digest of the contents
+    pub checksum: String,
+}
+
+/// Collect the regular files found in `root`, sorted by path, hashing them
+/// with `algorithm`. Symlinks are not followed, and entries for which `skip`
+/// returns true are left out
+pub fn collect(
+    root: &Path,
+    skip: &dyn Fn(&Path) -> bool,
+    algorithm: HashAlgorithm,
+) -> Result<Vec<ManifestEntry>, Error> {
+    let mut entries = vec![];
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let read_dir =
+            fs::read_dir(&dir).with_context(|| format!("Could not read '{}'", dir.display()))?;
+        for entry in read_dir {
+            let path = entry
+                .with_context(|| format!("Could not read '{}'", dir.display()))?
+                .path();
+            if skip(&path) {
+                continue;
+            }
+            let metadata = fs::symlink_metadata(&path)
+                .with_context(|| format!("Could not stat '{}'", path.display()))?;
+            if metadata.is_dir() {
+                dirs.push(path);
+            } else if metadata.is_file() {
+                let digest = checksum::hash_file(&path, algorithm)?;
+                entries.push(ManifestEntry {
+                    path: fsops::get_rel_path(&path, root),
+                    size: metadata.len(),
+                    modified: metadata
+                        .modified()
+                        .with_context(|| format!("Could not stat '{}'", path.display()))?,
+                    checksum: to_hex(&digest),
+                });
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// `algorithm` is the one the entries were collected with
+pub fn write(
+    path: &Path,
+    format: ManifestFormat,
+    algorithm: HashAlgorithm,
+    entries: &[ManifestEntry],
+) -> Result<(), Error> {
+    write_to(path, format, algorithm, entries)
+        .with_context(|| format!("Could not write manifest to '{}'", path.display()))
+}
+
+fn write_to(
+    path: &Path,
+    format: ManifestFormat,
+    algorithm: HashAlgorithm,
+    entries: &[ManifestEntry],
+) -> io::Result<()> {
+    let mut out = io::BufWriter::new(fs::File::create(path)?);
+    match format {
+        ManifestFormat::Json => {
+            write!(out, "{{\"files\":[")?;
+            for (i, entry) in entries.iter().enumerate() {
+                if i != 0 {
+                    write!(out, ",")?;
+                }
+                write!(
+                    out,
+                    "\n{{\"path\":{},\"size\":{},\"modified\":{},\"{}\":\"{}\"}}",
+                    json_info::quote(&entry.path.to_string_lossy()),
+                    entry.size,
+                    json_info::quote(&format_time(entry.modified)),
+                    algorithm,
+                    entry.checksum
+                )?;
+            }
+            writeln!(out, "\n]}}")?;
+        }
+        ManifestFormat::Csv => {
+            writeln!(out, "path,size,modified,{}", algorithm)?;
+            for entry in entries {
+                writeln!(
+                    out,
+                    "{},{},{},{}",
+                    csv_field(&entry.path.to_string_lossy()),
+                    entry.size,
+                    format_time(entry.modified),
+                    entry.checksum
+                )?;
+            }
+        }
+    }
+    out.flush()
+}
+
+fn format_time(time: SystemTime) -> String {
+    humantime::format_rfc3339_nanos(time).to_string()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Quote the field if needed, doubling the quotes it contains
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("a/b.txt"), "a/b.txt");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn test_collect_and_write() {
+        let tmp_dir = TempDir::new("test-rusync-manifest").unwrap();
+        let root = tmp_dir.path().join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/b.txt"), "").unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("skipped.txt"), "").unwrap();
+
+        let skip = |path: &Path| path.ends_with("skipped.txt");
+        let entries = collect(&root, &skip, HashAlgorithm::Sha256).unwrap();
+        let paths: Vec<_> = entries.iter().map(|e| e.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![PathBuf::from("a.txt"), PathBuf::from("sub/b.txt")]
+        );
+        assert_eq!(
+            entries[1].checksum,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+
+        let csv_path = tmp_dir.path().join("manifest.csv");
+        write(
+            &csv_path,
+            ManifestFormat::Csv,
+            HashAlgorithm::Sha256,
+            &entries,
+        )
+        .unwrap();
+        let csv = fs::read_to_string(&csv_path).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("a.txt,1,"));
+
+        let json_path = tmp_dir.path().join("manifest.json");
+        write(
+            &json_path,
+            ManifestFormat::Json,
+            HashAlgorithm::Sha256,
+            &entries,
+        )
+        .unwrap();
+        let json = fs::read_to_string(&json_path).unwrap();
+        assert!(json.starts_with("{\"files\":["));
+        assert!(json.contains("{\"path\":\"sub/b.txt\",\"size\":0,"));
+        assert!(json.contains(",\"sha256\":\"e3b0c442"));
+
+        let entries = collect(&root, &skip, HashAlgorithm::Xxh3).unwrap();
+        assert_eq!(entries[1].checksum, "2d06800538d394c2");
+        write(
+            &csv_path,
+            ManifestFormat::Csv,
+            HashAlgorithm::Xxh3,
+            &entries,
+        )
+        .unwrap();
+        let csv = fs::read_to_string(&csv_path).unwrap();
+        assert!(csv.starts_with("path,size,modified,xxh3\n"));
+    }
+}
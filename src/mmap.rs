@@ -0,0 +1,132 @@
+//! mmap
+//!
+//! Read-only memory mappings of source files, see
+//! `SyncOptions.mmap_threshold`.
+//!
+//! The destination is written straight from the mapped pages, instead of
+//! reading the source into a buffer first.
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+
+#[cfg(unix)]
+mod unix {
+    use std::convert::TryFrom;
+    use std::fs::File;
+    use std::io;
+    use std::ops::Deref;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+    use std::slice;
+
+    pub struct Mapping {
+        ptr: *mut libc::c_void,
+        len: usize,
+    }
+
+    pub fn map(file: &File) -> io::Result<Mapping> {
+        // The size of the open file, not the one seen by the walker: it may
+        // have changed since
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::other("file too large to be mapped"))?;
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty files cannot be mapped",
+            ));
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        #[cfg(target_os = "linux")]
+        unsafe {
+            // Only a hint for the readahead, failing is harmless
+            libc::madvise(ptr, len, libc::MADV_SEQUENTIAL);
+        }
+        Ok(Mapping { ptr, len })
+    }
+
+    impl Deref for Mapping {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+        }
+    }
+
+    impl Drop for Mapping {
+        fn drop(&mut self) {
+            unsafe {
+                libc::munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
+
+/// The whole contents of a file, mapped in memory
+#[cfg(unix)]
+pub struct Mapping(unix::Mapping);
+
+#[cfg(not(unix))]
+pub struct Mapping(std::convert::Infallible);
+
+impl Deref for Mapping {
+    type Target = [u8];
+
+    #[cfg(unix)]
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+
+    #[cfg(not(unix))]
+    fn deref(&self) -> &[u8] {
+        match self.0 {}
+    }
+}
+
+/// Map `file` in memory. Fails for empty files and on file systems that do
+/// not support it, in which case it should be read as usual
+#[cfg(unix)]
+pub fn map(file: &File) -> io::Result<Mapping> {
+    unix::map(file).map(Mapping)
+}
+
+#[cfg(not(unix))]
+pub fn map(_file: &File) -> io::Result<Mapping> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "memory mapped copies are only supported on Unix",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_map() {
+        let tmp_dir = tempdir::TempDir::new("test-rusync-mmap").unwrap();
+        let path = tmp_dir.path().join("mapped.txt");
+        File::create(&path)
+            .unwrap()
+            .write_all(b"mapped contents")
+            .unwrap();
+        let mapping = map(&File::open(&path).unwrap()).unwrap();
+        assert_eq!(&mapping[..], b"mapped contents");
+
+        File::create(&path).unwrap();
+        assert!(map(&File::open(&path).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+//! names
+//!
+//! Compare entry names the way the destination does, see
+//! `SyncOptions.case_insensitive` and `SyncOptions.normalization`
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+use crate::filesystem::Filesystem;
+use crate::sync::{Normalization, SyncOptions};
+use crate::unicode;
+
+/// Wether names that are not equal may still refer to the same entry
+pub fn loose(opts: SyncOptions) -> bool {
+    opts.case_insensitive || opts.normalization != Normalization::None
+}
+
+/// The same for all the names referring to the same entry
+pub fn key(name: &str, opts: SyncOptions) -> String {
+    let name = if opts.normalization == Normalization::None {
+        Cow::Borrowed(name)
+    } else {
+        unicode::nfc(name)
+    };
+    if opts.case_insensitive {
+        name.to_lowercase()
+    } else {
+        name.into_owned()
+    }
+}
+
+/// Where `rel_path` is in `base`: the entries that already exist are looked
+/// up by key, whatever their actual name, and the missing ones are named
+/// according to `SyncOptions.normalization`
+pub fn resolve(fs: &dyn Filesystem, base: &Path, rel_path: &Path, opts: SyncOptions) -> PathBuf {
+    let mut path = base.to_path_buf();
+    let mut missing = false;
+    for component in rel_path.components() {
+        let name = component.as_os_str();
+        if !missing {
+            let exact = path.join(name);
+            if fs.exists(&exact) {
+                path = exact;
+                continue;
+            }
+            if let Some(found) = find(fs, &path, name, opts) {
+                path = found;
+                continue;
+            }
+            // So are all the entries below
+            missing = true;
+        }
+        path.push(written(name, opts));
+    }
+    path
+}
+
+fn find(fs: &dyn Filesystem, dir: &Path, name: &OsStr, opts: SyncOptions) -> Option<PathBuf> {
+    if !opts.case_insensitive && name.is_ascii() {
+        // Only its exact name has the same key
+        return None;
+    }
+    // Names that are not valid UTF-8 are only compared as they are
+    let key = key(name.to_str()?, opts);
+    fs.list(dir).ok()?.into_iter().find(|entry| {
+        entry
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| self::key(n, opts) == key)
+    })
+}
+
+fn written(name: &OsStr, opts: SyncOptions) -> OsString {
+    let converted = name.to_str().and_then(|name| match opts.normalization {
+        Normalization::Nfc => Some(unicode::nfc(name)),
+        Normalization::Nfd => Some(unicode::nfd(name)),
+        Normalization::None | Normalization::Compare => None,
+    });
+    match converted {
+        Some(converted) => OsString::from(converted.into_owned()),
+        None => name.to_os_string(),
+    }
+}
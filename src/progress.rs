@@ -1,25 +1,70 @@
+use crate::entry::Entry;
 use crate::fsops::SyncOutcome;
 use crate::sync::Stats;
 
 #[doc(hidden)]
 pub enum ProgressMessage {
-    DoneSyncing(SyncOutcome),
-    StartSync(String),
+    DoneSyncing {
+        entry: String,
+        outcome: SyncOutcome,
+    },
+    StartSync(Box<Entry>),
+    Itemized {
+        entry: String,
+        changes: String,
+    },
     Todo {
         num_files: u64,
         total_size: usize,
+        // True once the whole source was walked, with SyncOptions.prescan
+        complete: bool,
+    },
+    // What the walker found so far, with SyncOptions.prescan
+    Scanning {
+        entries_found: u64,
+        bytes_found: u64,
     },
     Syncing {
         description: String,
         size: usize,
         done: usize,
+        // False when the bytes did not have to be copied: up to date
+        // files, clones, resumed transfers and holes in sparse files
+        transfered: bool,
     },
     SyncError {
         entry: String,
         details: String,
+        kind: std::io::ErrorKind,
+    },
+    SyncWarning {
+        entry: String,
+        details: String,
+    },
+    Deleted(String),
+    Planned {
+        entry: String,
+        change: Change,
+        size: u64,
     },
+    // The sync was cancelled before every entry could be synced
+    Cancelled,
+}
+
+/// What a dry run would do to an entry of the destination
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Change {
+    /// The entry does not exist yet
+    New,
+    /// The entry exists but would be written again
+    Changed,
+    /// The entry is up to date
+    Unchanged,
+    /// The entry would be deleted
+    Deleted,
 }
 
+#[derive(Clone, Debug)]
 pub struct Progress {
     /// Name of the file being transferred
     pub current_file: String,
@@ -35,24 +80,56 @@ pub struct Progress {
     pub index: usize,
     /// Total number of files to transfer
     pub num_files: usize,
+    /// Wether `total_size` and `num_files` are final, instead of what was found
+    /// so far, see `SyncOptions.prescan`
+    pub totals_known: bool,
     /// Estimated time remaining for the transfer, in seconds
     pub eta: usize,
+    /// Transfer speed over the last few seconds, in bytes per second
+    pub speed: usize,
+    /// Average transfer speed since the start, in bytes per second
+    pub avg_speed: usize,
 }
 
 /// Trait for implementing rusync progress details
+///
+/// All the methods are called from the same thread: `start()` first, then
+/// `new_file()`, `progress()` and `done_syncing()` (or `error()`) for each
+/// entry, and `end()` last. When using several workers, callbacks for
+/// different entries may be interleaved, which is why they all get the
+/// name of the entry.
+///
+/// ConsoleProgressInfo is the implementation used by the command line.
 pub trait ProgressInfo {
     /// A new transfer has begun from the `source` directory to the `destination`
     /// directory
     #[allow(unused_variables)]
     fn start(&mut self, source: &str, destination: &str) {}
 
+    /// With `SyncOptions.prescan`, the walker found `entries_found` files
+    /// totalling `bytes_found` bytes so far. Called from time to time while
+    /// walking, and once the walk is done, before anything is synced
+    #[allow(unused_variables)]
+    fn scanning(&mut self, entries_found: u64, bytes_found: u64) {}
+
     /// A new file named `name` is being transfered
     #[allow(unused_variables)]
     fn new_file(&mut self, name: &str) {}
 
-    /// The file transfer is done
+    /// Called right after `new_file()`, with the source entry and its
+    /// metadata, like its size or its modification time
+    #[allow(unused_variables)]
+    fn new_entry(&mut self, entry: &Entry) {}
+
+    /// The transfer of the file named `name` is done
+    #[allow(unused_variables)]
+    fn done_syncing(&mut self, name: &str) {}
+
+    /// What changed for the entry, in the same format as
+    /// `rsync --itemize-changes`, for instance `>f.st......`. Called
+    /// right before `done_syncing()`, except for skipped entries
     #[allow(unused_variables)]
-    fn done_syncing(&mut self) {}
+    fn itemized(&mut self, name: &str, changes: &str) {}
 
     /// Callback for the detailed progress
     #[allow(unused_variables)]
@@ -66,4 +143,20 @@ pub trait ProgressInfo {
     /// The entry could not be synced
     #[allow(unused_variables)]
     fn error(&mut self, entry: &str, details: &str) {}
+
+    /// The entry was synced, but something went wrong, for instance its owner
+    /// could not be preserved
+    #[allow(unused_variables)]
+    fn warning(&mut self, entry: &str, details: &str) {}
+
+    /// The entry named `name` was removed from the destination because it
+    /// no longer exists in the source
+    #[allow(unused_variables)]
+    fn deleted(&mut self, name: &str) {}
+
+    /// Only called when using `SyncOptions.dry_run`: what would be done to
+    /// the entry named `name`, of `size` bytes. Called right before
+    /// `done_syncing()`, or `deleted()` for deletions
+    #[allow(unused_variables)]
+    fn planned(&mut self, name: &str, change: Change, size: u64) {}
 }
@@ -0,0 +1,399 @@
+//! regex
+//!
+//! A small backtracking regular expression matcher, for filter rules that
+//! cannot be expressed as globs. Supports:
+//!
+//! * `.`, character classes like `[a-z]` or `[^0-9]`, and `\d`, `\w`, `\s`
+//!   (and their negations `\D`, `\W`, `\S`), inside or outside classes
+//! * `^` and `$` anchors
+//! * groups with `(...)` or `(?:...)`, and alternation with `|`
+//! * the `*`, `+`, `?`, `{n}`, `{n,}` and `{n,m}` quantifiers. Lazy
+//!   quantifiers are accepted, but only whether a path matches matters here
+//!
+//! Like with most regex engines, a pattern matches if it matches anywhere
+//! in the text, unless anchored.
+#[derive(Debug, Clone)]
+enum Node {
+    Literal(char),
+    Any,
+    Class {
+        negated: bool,
+        items: Vec<ClassItem>,
+    },
+    Start,
+    End,
+    // Each alternative is a sequence of nodes
+    Alt(Vec<Vec<Node>>),
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum ClassItem {
+    Range(char, char),
+    Perl(Perl, bool),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Perl {
+    Digit,
+    Word,
+    Space,
+}
+
+impl Perl {
+    fn matches(self, c: char) -> bool {
+        match self {
+            Perl::Digit => c.is_ascii_digit(),
+            Perl::Word => c.is_alphanumeric() || c == '_',
+            Perl::Space => c.is_whitespace(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Regex {
+    nodes: Vec<Node>,
+}
+
+impl Regex {
+    /// Returns an error describing what is wrong with `pattern` if it is
+    /// not a valid regular expression
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut parser = Parser { chars, pos: 0 };
+        let branches = parser.parse_alt()?;
+        if parser.pos < parser.chars.len() {
+            // Only a closing parenthesis stops parse_alt() early
+            return Err(format!("unmatched ')' in '{}'", pattern));
+        }
+        Ok(Regex {
+            nodes: vec![Node::Alt(branches)],
+        })
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        (0..=text.len()).any(|start| match_here(&self.nodes, &text, start, &mut |_| true))
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn parse_alt(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut branches = vec![self.parse_seq()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            branches.push(self.parse_seq()?);
+        }
+        Ok(branches)
+    }
+
+    fn parse_seq(&mut self) -> Result<Vec<Node>, String> {
+        let mut nodes = vec![];
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.parse_atom()?;
+            nodes.push(self.parse_quantifier(atom)?);
+        }
+        Ok(nodes)
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, String> {
+        let c = self.chars[self.pos];
+        self.pos += 1;
+        let node = match c {
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '(' => {
+                if self.chars[self.pos..].starts_with(&['?', ':']) {
+                    self.pos += 2;
+                }
+                let branches = self.parse_alt()?;
+                if self.peek() != Some(')') {
+                    return Err("unclosed '('".to_string());
+                }
+                self.pos += 1;
+                Node::Alt(branches)
+            }
+            '[' => self.parse_class()?,
+            '\\' => match self.parse_escape()? {
+                ClassItem::Range(c, _) => Node::Literal(c),
+                item => Node::Class {
+                    negated: false,
+                    items: vec![item],
+                },
+            },
+            '*' | '+' | '?' => return Err(format!("nothing to repeat before '{}'", c)),
+            c => Node::Literal(c),
+        };
+        Ok(node)
+    }
+
+    fn parse_quantifier(&mut self, node: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => match self.parse_bounds() {
+                Some(bounds) => bounds,
+                // Not a valid quantifier: the brace is a literal
+                None => return Ok(node),
+            },
+            _ => return Ok(node),
+        };
+        if let Some(max) = max {
+            if max < min {
+                return Err(format!("invalid repetition {{{},{}}}", min, max));
+            }
+        }
+        self.pos += 1;
+        if self.peek() == Some('?') {
+            // Lazy quantifier, which does not change whether there is a match
+            self.pos += 1;
+        }
+        if matches!(node, Node::Start | Node::End | Node::Repeat { .. }) {
+            return Err("nothing to repeat".to_string());
+        }
+        Ok(Node::Repeat {
+            node: Box::new(node),
+            min,
+            max,
+        })
+    }
+
+    // Parse `{n}`, `{n,}` or `{n,m}`, leaving `pos` on the closing brace
+    fn parse_bounds(&mut self) -> Option<(u32, Option<u32>)> {
+        let rest: String = self.chars[self.pos + 1..].iter().collect();
+        let end = rest.find('}')?;
+        let inside = &rest[..end];
+        let bounds = match inside.split_once(',') {
+            None => {
+                let n = inside.parse().ok()?;
+                (n, Some(n))
+            }
+            Some((min, "")) => (min.parse().ok()?, None),
+            Some((min, max)) => (min.parse().ok()?, Some(max.parse().ok()?)),
+        };
+        self.pos += 1 + inside.chars().count();
+        Some(bounds)
+    }
+
+    fn parse_class(&mut self) -> Result<Node, String> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut items = vec![];
+        loop {
+            let c = match self.peek() {
+                None => return Err("unclosed '['".to_string()),
+                Some(c) => c,
+            };
+            self.pos += 1;
+            // A closing bracket right after the opening one is a literal
+            if c == ']' && !items.is_empty() {
+                break;
+            }
+            let item = if c == '\\' {
+                self.parse_escape()?
+            } else {
+                ClassItem::Range(c, c)
+            };
+            let is_range =
+                self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']');
+            match item {
+                ClassItem::Range(start, _) if is_range => {
+                    self.pos += 1;
+                    let end = match self.chars[self.pos] {
+                        '\\' => {
+                            self.pos += 1;
+                            match self.parse_escape()? {
+                                ClassItem::Range(end, _) => end,
+                                _ => return Err("invalid range in class".to_string()),
+                            }
+                        }
+                        end => {
+                            self.pos += 1;
+                            end
+                        }
+                    };
+                    if end < start {
+                        return Err(format!("invalid range {}-{}", start, end));
+                    }
+                    items.push(ClassItem::Range(start, end));
+                }
+                item => items.push(item),
+            }
+        }
+        Ok(Node::Class { negated, items })
+    }
+
+    // Parse what follows a backslash: either a Perl class or an escaped
+    // character, returned as a range of one character
+    fn parse_escape(&mut self) -> Result<ClassItem, String> {
+        let c = match self.peek() {
+            None => return Err("trailing backslash".to_string()),
+            Some(c) => c,
+        };
+        self.pos += 1;
+        let item = match c {
+            'd' => ClassItem::Perl(Perl::Digit, false),
+            'D' => ClassItem::Perl(Perl::Digit, true),
+            'w' => ClassItem::Perl(Perl::Word, false),
+            'W' => ClassItem::Perl(Perl::Word, true),
+            's' => ClassItem::Perl(Perl::Space, false),
+            'S' => ClassItem::Perl(Perl::Space, true),
+            'n' => ClassItem::Range('\n', '\n'),
+            't' => ClassItem::Range('\t', '\t'),
+            c if c.is_alphanumeric() => return Err(format!("unsupported escape '\\{}'", c)),
+            c => ClassItem::Range(c, c),
+        };
+        Ok(item)
+    }
+}
+
+// Match `nodes` at `pos`, and then call `k` with the position after the
+// match. Backtracks until `k` returns true or there is nothing left to try
+fn match_here(nodes: &[Node], text: &[char], pos: usize, k: &mut dyn FnMut(usize) -> bool) -> bool {
+    let (node, rest) = match nodes.split_first() {
+        None => return k(pos),
+        Some(split) => split,
+    };
+    match node {
+        Node::Alt(branches) => branches
+            .iter()
+            .any(|branch| match_here(branch, text, pos, &mut |p| match_here(rest, text, p, k))),
+        Node::Repeat { node, min, max } => repeat(node, *min, *max, 0, text, pos, &mut |p| {
+            match_here(rest, text, p, k)
+        }),
+        Node::Start => pos == 0 && match_here(rest, text, pos, k),
+        Node::End => pos == text.len() && match_here(rest, text, pos, k),
+        node => match text.get(pos) {
+            Some(&c) if matches_char(node, c) => match_here(rest, text, pos + 1, k),
+            _ => false,
+        },
+    }
+}
+
+// Greedily match `node` as many times as allowed, giving back one
+// repetition at a time
+fn repeat(
+    node: &Node,
+    min: u32,
+    max: Option<u32>,
+    count: u32,
+    text: &[char],
+    pos: usize,
+    k: &mut dyn FnMut(usize) -> bool,
+) -> bool {
+    if max.is_none_or(|max| count < max) {
+        let once = std::slice::from_ref(node);
+        // Stop repeating empty matches once `min` is reached, otherwise
+        // patterns like `(a*)*` would never end
+        let matched = match_here(once, text, pos, &mut |p| {
+            (p != pos || count < min) && repeat(node, min, max, count + 1, text, p, k)
+        });
+        if matched {
+            return true;
+        }
+    }
+    count >= min && k(pos)
+}
+
+fn matches_char(node: &Node, c: char) -> bool {
+    match node {
+        Node::Literal(literal) => *literal == c,
+        Node::Any => c != '\n',
+        Node::Class { negated, items } => {
+            let in_class = items.iter().any(|item| match item {
+                ClassItem::Range(start, end) => *start <= c && c <= *end,
+                ClassItem::Perl(perl, negated) => perl.matches(c) != *negated,
+            });
+            in_class != *negated
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_match(pattern: &str, text: &str) -> bool {
+        Regex::new(pattern).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn test_literals_and_anchors() {
+        assert!(is_match("foo", "a/foo.txt"));
+        assert!(!is_match("^foo", "a/foo.txt"));
+        assert!(is_match("^a/", "a/foo.txt"));
+        assert!(is_match(r"\.txt$", "a/foo.txt"));
+        assert!(!is_match(r"\.txt$", "a/foo.txt.bak"));
+        assert!(!is_match(r"a\.b", "axb"));
+        assert!(is_match("a.b", "axb"));
+    }
+
+    #[test]
+    fn test_classes() {
+        assert!(is_match("^img[0-9]+$", "img042"));
+        assert!(!is_match("^img[^0-9]", "img042"));
+        assert!(is_match(r"^\d{4}-\d{2}$", "2024-01"));
+        assert!(!is_match(r"^\d{4}-\d{2}$", "2024-1"));
+        assert!(is_match(r"[\w-]+\.log", "my-app.log"));
+        assert!(is_match("[]a]", "]"));
+        assert!(is_match("[a-]", "-"));
+    }
+
+    #[test]
+    fn test_groups_and_quantifiers() {
+        assert!(is_match("^(foo|bar)/", "bar/baz"));
+        assert!(!is_match("^(foo|bar)/", "baz/bar"));
+        assert!(is_match("^(?:ab)+$", "ababab"));
+        assert!(!is_match("^(?:ab)+$", "ababa"));
+        assert!(is_match("^a{2,3}$", "aaa"));
+        assert!(!is_match("^a{2,3}$", "aaaa"));
+        assert!(is_match("^a{2,}$", "aaaa"));
+        assert!(is_match("^colou?r$", "color"));
+        assert!(is_match("^(a*)*b$", "aaab"));
+        assert!(!is_match("^(a*)*b$", "aaac"));
+        assert!(is_match("^.*?x", "abx"));
+        // Not a quantifier
+        assert!(is_match("a{b", "a{b"));
+    }
+
+    #[test]
+    fn test_numeric_range_in_file_names() {
+        // Frames 100 to 199, which globs cannot express
+        let regex = Regex::new(r"frame_1\d\d\.png$").unwrap();
+        assert!(regex.is_match("shots/frame_150.png"));
+        assert!(!regex.is_match("shots/frame_250.png"));
+        assert!(!regex.is_match("shots/frame_1500.png"));
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        for pattern in &["(a", "a)", "[a", "*a", r"\", "a{3,2}", r"\b", "[z-a]"] {
+            assert!(
+                Regex::new(pattern).is_err(),
+                "{} should be invalid",
+                pattern
+            );
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! report
+//!
+//! Group the entries of a dry run by what would be done to them, for
+//! ConsoleProgressInfo
+use humansize::{file_size_opts as options, FileSize};
+
+use crate::progress::Change;
+
+#[derive(Debug, Default)]
+struct Group {
+    entries: Vec<(String, u64)>,
+    count: u64,
+    size: u64,
+}
+
+impl Group {
+    fn add(&mut self, name: &str, size: u64, keep_name: bool) {
+        if keep_name {
+            self.entries.push((name.to_string(), size));
+        }
+        self.count += 1;
+        self.size += size;
+    }
+
+    fn format(&mut self, title: &str, out: &mut String) {
+        out.push_str(&format!(
+            "{}: {} {}, {}\n",
+            title,
+            self.count,
+            if self.count == 1 { "entry" } else { "entries" },
+            human_size(self.size)
+        ));
+        self.entries.sort();
+        for (name, size) in &self.entries {
+            out.push_str(&format!("  {} ({})\n", name, human_size(*size)));
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    new: Group,
+    changed: Group,
+    deleted: Group,
+    // Only counted: listing them would hide what matters
+    unchanged: Group,
+}
+
+impl Report {
+    pub fn add(&mut self, name: &str, change: Change, size: u64) {
+        match change {
+            Change::New => self.new.add(name, size, true),
+            Change::Changed => self.changed.add(name, size, true),
+            Change::Deleted => self.deleted.add(name, size, true),
+            Change::Unchanged => self.unchanged.add(name, size, false),
+        }
+    }
+
+    pub fn format(&mut self) -> String {
+        let mut out = String::new();
+        self.new.format("New", &mut out);
+        self.changed.format("Changed", &mut out);
+        self.deleted.format("Deleted", &mut out);
+        self.unchanged.format("Unchanged", &mut out);
+        out
+    }
+}
+
+fn human_size(size: u64) -> String {
+    // Sizes cannot be negative
+    size.file_size(options::DECIMAL).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_report() {
+        let mut report = Report::default();
+        report.add("b.txt", Change::New, 2000);
+        report.add("a.txt", Change::New, 10);
+        report.add("old.txt", Change::Deleted, 5);
+        report.add("same.txt", Change::Unchanged, 100);
+        report.add("same2.txt", Change::Unchanged, 100);
+        assert_eq!(
+            report.format(),
+            "New: 2 entries, 2.01 KB\n  a.txt (10 B)\n  b.txt (2 KB)\n\
+             Changed: 0 entries, 0 B\n\
+             Deleted: 1 entry, 5 B\n  old.txt (5 B)\n\
+             Unchanged: 2 entries, 200 B\n"
+        );
+    }
+}
@@ -0,0 +1,65 @@
+//! schedule
+//!
+//! Compute when to start each sync when syncing at a fixed interval
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub struct Schedule {
+    interval: Duration,
+    // When the current cycle was supposed to start, without jitter
+    cycle_start: Instant,
+}
+
+impl Schedule {
+    pub fn new(interval: Duration, now: Instant) -> Schedule {
+        Schedule {
+            interval,
+            cycle_start: now,
+        }
+    }
+
+    /// Move to the next cycle once the current one is done.
+    /// Returns when the next sync should start, and how many cycles were
+    /// skipped because the previous sync took longer than the interval
+    pub fn next(&mut self, now: Instant) -> (Instant, u32) {
+        let mut skipped = 0;
+        self.cycle_start += self.interval;
+        while self.cycle_start < now {
+            self.cycle_start += self.interval;
+            skipped += 1;
+        }
+        (self.cycle_start + jitter(self.interval), skipped)
+    }
+}
+
+// Up to a tenth of the interval, so that several hosts started at the same
+// time do not all sync at the same time. Does not need to be a good random
+// number
+fn jitter(interval: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    interval.mul_f64(f64::from(nanos % 1000) / 10_000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_cycle() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(60);
+        let mut schedule = Schedule::new(interval, start);
+
+        let (next, skipped) = schedule.next(start + Duration::from_secs(10));
+        assert_eq!(skipped, 0);
+        assert!(next >= start + interval);
+        assert!(next < start + interval + Duration::from_secs(6));
+
+        // A sync taking more than two intervals skips two cycles
+        let (next, skipped) = schedule.next(start + Duration::from_secs(200));
+        assert_eq!(skipped, 2);
+        assert!(next >= start + 4 * interval);
+    }
+}
@@ -0,0 +1,203 @@
+//! state
+//!
+//! Remember what the files of the source and of the destination looked
+//! like at the end of the previous sync, to detect the files that changed
+//! on both sides since then, see `SyncOptions.conflicts` and
+//! `Syncer::bisync()`.
+//!
+//! The state is a text file with one line per file: the size and the
+//! modification time (seconds and nanoseconds) of the source file, the same
+//! for the destination file, and the path relative to the destination.
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Error};
+use filetime::FileTime;
+
+/// Name of the state file, in the root of the destination
+pub const STATE_FILE_NAME: &str = ".rusync-state";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stamp {
+    size: u64,
+    mtime: (u64, u32),
+}
+
+impl Stamp {
+    pub fn new(metadata: &fs::Metadata) -> Stamp {
+        let mtime = FileTime::from_last_modification_time(metadata);
+        Stamp {
+            size: metadata.len(),
+            mtime: (mtime.seconds(), mtime.nanoseconds()),
+        }
+    }
+
+    pub fn is_newer_than(&self, other: Stamp) -> bool {
+        self.mtime > other.mtime
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Recorded {
+    src: Stamp,
+    dest: Stamp,
+}
+
+#[derive(Default)]
+struct Files {
+    previous: HashMap<PathBuf, Recorded>,
+    // The files seen during this sync
+    current: HashMap<PathBuf, Recorded>,
+}
+
+pub struct SyncState {
+    path: PathBuf,
+    files: Mutex<Files>,
+}
+
+impl SyncState {
+    /// Read the state at `path`. Missing or invalid states are just empty
+    pub fn load(path: &Path) -> SyncState {
+        let previous = fs::read_to_string(path)
+            .map(|contents| parse(&contents))
+            .unwrap_or_default();
+        SyncState {
+            path: path.to_path_buf(),
+            files: Mutex::new(Files {
+                previous,
+                current: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Returns true if both the source and the destination file at
+    /// `rel_path` changed since the previous sync. Files that were not
+    /// synced before never conflict
+    pub fn is_conflict(&self, rel_path: &Path, src: Stamp, dest: Stamp) -> bool {
+        self.lock()
+            .previous
+            .get(rel_path)
+            .is_some_and(|recorded| recorded.src != src && recorded.dest != dest)
+    }
+
+    /// What the source and the destination file at `rel_path` looked like
+    /// after the previous sync
+    pub fn previous(&self, rel_path: &Path) -> Option<(Stamp, Stamp)> {
+        self.lock()
+            .previous
+            .get(rel_path)
+            .map(|recorded| (recorded.src, recorded.dest))
+    }
+
+    /// The paths of the files recorded by the previous sync
+    pub fn previous_paths(&self) -> Vec<PathBuf> {
+        self.lock().previous.keys().cloned().collect()
+    }
+
+    /// Remember the files at `rel_path` once synced
+    pub fn record(&self, rel_path: &Path, src: Stamp, dest: Stamp) {
+        self.lock()
+            .current
+            .insert(rel_path.to_path_buf(), Recorded { src, dest });
+    }
+
+    /// Remember the files at `rel_path` as they were after the previous
+    /// sync, so that they still conflict next time
+    pub fn keep(&self, rel_path: &Path) {
+        let mut files = self.lock();
+        if let Some(recorded) = files.previous.get(rel_path).copied() {
+            files.current.insert(rel_path.to_path_buf(), recorded);
+        }
+    }
+
+    /// Write the state, replacing the previous one. Unless `complete`,
+    /// files not seen during this sync are kept
+    pub fn save(&self, complete: bool) -> Result<(), Error> {
+        let mut out = String::new();
+        let files = self.lock();
+        let mut saved: Vec<_> = files.current.iter().collect();
+        if !complete {
+            saved.extend(
+                files
+                    .previous
+                    .iter()
+                    .filter(|(path, _)| !files.current.contains_key(*path)),
+            );
+        }
+        saved.sort_by(|a, b| a.0.cmp(b.0));
+        for (path, recorded) in saved {
+            // Such paths would not be read back correctly
+            let path = match path.to_str() {
+                Some(path) if !path.contains('\n') => path,
+                _ => continue,
+            };
+            out.push_str(&format!(
+                "{} {}.{:09} {} {}.{:09} {}\n",
+                recorded.src.size,
+                recorded.src.mtime.0,
+                recorded.src.mtime.1,
+                recorded.dest.size,
+                recorded.dest.mtime.0,
+                recorded.dest.mtime.1,
+                path
+            ));
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, out)
+            .with_context(|| format!("Could not write '{}'", self.path.display()))?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Could not write '{}'", self.path.display()))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Files> {
+        self.files
+            .lock()
+            .expect("another sync worker panicked while holding the sync state")
+    }
+}
+
+fn parse(contents: &str) -> HashMap<PathBuf, Recorded> {
+    contents.lines().filter_map(parse_line).collect()
+}
+
+fn parse_line(line: &str) -> Option<(PathBuf, Recorded)> {
+    let mut fields = line.splitn(5, ' ');
+    let src = parse_stamp(fields.next()?, fields.next()?)?;
+    let dest = parse_stamp(fields.next()?, fields.next()?)?;
+    let path = PathBuf::from(fields.next()?);
+    Some((path, Recorded { src, dest }))
+}
+
+fn parse_stamp(size: &str, mtime: &str) -> Option<Stamp> {
+    let (seconds, nanoseconds) = mtime.split_once('.')?;
+    Some(Stamp {
+        size: size.parse().ok()?,
+        mtime: (seconds.parse().ok()?, nanoseconds.parse().ok()?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        let (path, recorded) =
+            parse_line("42 1600000000.000000123 43 1600000001.000000000 dir/with space.txt")
+                .unwrap();
+        assert_eq!(path, PathBuf::from("dir/with space.txt"));
+        assert_eq!(
+            recorded.src,
+            Stamp {
+                size: 42,
+                mtime: (1_600_000_000, 123),
+            }
+        );
+        assert_eq!(recorded.dest.size, 43);
+        assert!(parse_line("not a state line").is_none());
+        assert!(parse_line("42 1.0 x 1.0 foo").is_none());
+    }
+}
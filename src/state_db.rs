@@ -0,0 +1,393 @@
+//! state_db
+//!
+//! Remember the entries synced by the previous run, to make the next ones
+//! faster, see `Syncer::state_db()`.
+//!
+//! The database is a text file with one line per entry: its kind (`f` for
+//! regular files, `d` for directories, `o` for the other ones), then for
+//! files the size, the modification time (seconds and nanoseconds), the
+//! device, the inode and the status change time of the source file, the
+//! size and the modification time of the destination file, the hex digest
+//! of the source file, or `-` when no checksum was computed, and finally
+//! the path relative to the destination.
+//!
+//! Source files whose size, modification time, device, inode and status
+//! change time did not change since they were recorded are considered up
+//! to date when the size and modification time of the destination file did
+//! not change either, without comparing them, and extraneous entries are
+//! looked for among the recorded ones instead of walking the whole
+//! destination. The status change time also changes when a file is
+//! touched, has new permissions or is replaced by another one: such files
+//! go through the usual checks, and when their contents are the same as
+//! the recorded digest, they are not copied again.
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Error};
+use filetime::FileTime;
+
+use crate::checksum_cache::{from_hex, to_hex};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    File,
+    Dir,
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Record {
+    kind: Kind,
+    size: u64,
+    mtime: (u64, u32),
+    device: u64,
+    inode: u64,
+    ctime: (i64, u32),
+    dest_size: u64,
+    dest_mtime: (u64, u32),
+    digest: Option<Vec<u8>>,
+}
+
+impl Record {
+    fn new(
+        kind: Kind,
+        metadata: &fs::Metadata,
+        dest_metadata: Option<&fs::Metadata>,
+        digest: Option<Vec<u8>>,
+    ) -> Record {
+        if kind != Kind::File {
+            return Record {
+                kind,
+                size: 0,
+                mtime: (0, 0),
+                device: 0,
+                inode: 0,
+                ctime: (0, 0),
+                dest_size: 0,
+                dest_mtime: (0, 0),
+                digest: None,
+            };
+        }
+        let (dest_size, dest_mtime) = match dest_metadata {
+            Some(dest_metadata) => (dest_metadata.len(), mtime(dest_metadata)),
+            None => (0, (0, 0)),
+        };
+        Record {
+            kind,
+            size: metadata.len(),
+            mtime: mtime(metadata),
+            device: device(metadata),
+            inode: inode(metadata),
+            ctime: ctime(metadata),
+            dest_size,
+            dest_mtime,
+            digest,
+        }
+    }
+
+    // The destination file is still the one written by rusync
+    fn has_same_dest(&self, dest_metadata: Option<&fs::Metadata>) -> bool {
+        dest_metadata.is_some_and(|m| {
+            m.is_file() && m.len() == self.dest_size && mtime(m) == self.dest_mtime
+        })
+    }
+}
+
+fn mtime(metadata: &fs::Metadata) -> (u64, u32) {
+    let mtime = FileTime::from_last_modification_time(metadata);
+    (mtime.seconds(), mtime.nanoseconds())
+}
+
+#[derive(Default)]
+struct Entries {
+    previous: HashMap<PathBuf, Record>,
+    // The entries seen during this sync
+    current: HashMap<PathBuf, Record>,
+}
+
+pub struct StateDb {
+    path: PathBuf,
+    entries: Mutex<Entries>,
+}
+
+impl StateDb {
+    /// Read the database at `path`. Missing or invalid databases are just
+    /// empty
+    pub fn load(path: &Path) -> StateDb {
+        let previous = fs::read_to_string(path)
+            .map(|contents| parse(&contents))
+            .unwrap_or_default();
+        StateDb {
+            path: path.to_path_buf(),
+            entries: Mutex::new(Entries {
+                previous,
+                current: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Returns false until a sync was recorded. The destination must be
+    /// walked then
+    pub fn has_previous(&self) -> bool {
+        !self.lock().previous.is_empty()
+    }
+
+    /// Returns true if neither the source file at `rel_path` nor its copy
+    /// in the destination, given by `dest_metadata`, changed since they
+    /// were recorded
+    pub fn is_unchanged(
+        &self,
+        rel_path: &Path,
+        metadata: &fs::Metadata,
+        dest_metadata: Option<&fs::Metadata>,
+    ) -> bool {
+        let current = Record::new(Kind::File, metadata, None, None);
+        self.lock().previous.get(rel_path).is_some_and(|recorded| {
+            recorded.kind == Kind::File
+                && recorded.size == current.size
+                && recorded.mtime == current.mtime
+                && recorded.device == current.device
+                && recorded.inode == current.inode
+                && recorded.ctime == current.ctime
+                && recorded.has_same_dest(dest_metadata)
+        })
+    }
+
+    /// The digest recorded for the source file at `rel_path`, if it still
+    /// has the same size and its copy in the destination, given by
+    /// `dest_metadata`, did not change. When the source file changed in
+    /// another way, comparing its contents with this digest tells whether
+    /// it must be copied again
+    pub fn previous_digest(
+        &self,
+        rel_path: &Path,
+        metadata: &fs::Metadata,
+        dest_metadata: Option<&fs::Metadata>,
+    ) -> Option<Vec<u8>> {
+        self.lock()
+            .previous
+            .get(rel_path)
+            .filter(|recorded| {
+                recorded.kind == Kind::File
+                    && recorded.size == metadata.len()
+                    && recorded.has_same_dest(dest_metadata)
+            })
+            .and_then(|recorded| recorded.digest.clone())
+    }
+
+    /// The entries recorded by the previous sync, sorted by path so that
+    /// directories come right before what they contain
+    pub fn previous_entries(&self) -> Vec<(PathBuf, Kind)> {
+        let mut entries: Vec<_> = self
+            .lock()
+            .previous
+            .iter()
+            .map(|(path, record)| (path.clone(), record.kind))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    /// Remember the entry at `rel_path` as the source and the destination
+    /// had it once synced, with the digest of the source file if it was
+    /// computed
+    pub fn record(
+        &self,
+        rel_path: &Path,
+        kind: Kind,
+        metadata: &fs::Metadata,
+        dest_metadata: Option<&fs::Metadata>,
+        digest: Option<Vec<u8>>,
+    ) {
+        let record = Record::new(kind, metadata, dest_metadata, digest);
+        self.lock().current.insert(rel_path.to_path_buf(), record);
+    }
+
+    /// Keep the previous record of `rel_path`
+    pub fn keep(&self, rel_path: &Path) {
+        let mut entries = self.lock();
+        if let Some(recorded) = entries.previous.get(rel_path).cloned() {
+            entries.current.insert(rel_path.to_path_buf(), recorded);
+        }
+    }
+
+    /// Write the database, replacing the previous one. Unless `complete`,
+    /// entries not seen during this sync are kept
+    pub fn save(&self, complete: bool) -> Result<(), Error> {
+        let mut out = String::new();
+        let entries = self.lock();
+        let mut saved: Vec<_> = entries.current.iter().collect();
+        if !complete {
+            saved.extend(
+                entries
+                    .previous
+                    .iter()
+                    .filter(|(path, _)| !entries.current.contains_key(*path)),
+            );
+        }
+        saved.sort_by(|a, b| a.0.cmp(b.0));
+        for (path, record) in saved {
+            // Such paths would not be read back correctly
+            let path = match path.to_str() {
+                Some(path) if !path.contains('\n') => path,
+                _ => continue,
+            };
+            let kind = match record.kind {
+                Kind::File => 'f',
+                Kind::Dir => 'd',
+                Kind::Other => 'o',
+            };
+            let digest = match &record.digest {
+                Some(digest) => to_hex(digest),
+                None => "-".to_string(),
+            };
+            out.push_str(&format!(
+                "{} {} {}.{:09} {} {} {}.{:09} {} {}.{:09} {} {}\n",
+                kind,
+                record.size,
+                record.mtime.0,
+                record.mtime.1,
+                record.device,
+                record.inode,
+                record.ctime.0,
+                record.ctime.1,
+                record.dest_size,
+                record.dest_mtime.0,
+                record.dest_mtime.1,
+                digest,
+                path
+            ));
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, out)
+            .with_context(|| format!("Could not write '{}'", self.path.display()))?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Could not write '{}'", self.path.display()))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        self.entries
+            .lock()
+            .expect("another sync worker panicked while holding the state database")
+    }
+}
+
+#[cfg(unix)]
+fn device(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.dev()
+}
+
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(unix)]
+fn ctime(metadata: &fs::Metadata) -> (i64, u32) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.ctime(), metadata.ctime_nsec() as u32)
+}
+
+// Without a stable way to get them, only the size and the modification
+// time are compared
+#[cfg(not(unix))]
+fn device(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+#[cfg(not(unix))]
+fn ctime(_metadata: &fs::Metadata) -> (i64, u32) {
+    (0, 0)
+}
+
+fn parse(contents: &str) -> HashMap<PathBuf, Record> {
+    contents.lines().filter_map(parse_line).collect()
+}
+
+fn parse_line(line: &str) -> Option<(PathBuf, Record)> {
+    let mut fields = line.splitn(10, ' ');
+    let kind = match fields.next()? {
+        "f" => Kind::File,
+        "d" => Kind::Dir,
+        "o" => Kind::Other,
+        _ => return None,
+    };
+    let size = fields.next()?.parse().ok()?;
+    let mtime = parse_time(fields.next()?)?;
+    let device = fields.next()?.parse().ok()?;
+    let inode = fields.next()?.parse().ok()?;
+    let ctime = parse_time(fields.next()?)?;
+    let dest_size = fields.next()?.parse().ok()?;
+    let dest_mtime = parse_time(fields.next()?)?;
+    let digest = match fields.next()? {
+        "-" => None,
+        hex => Some(from_hex(hex)?),
+    };
+    let path = PathBuf::from(fields.next()?);
+    Some((
+        path,
+        Record {
+            kind,
+            size,
+            mtime,
+            device,
+            inode,
+            ctime,
+            dest_size,
+            dest_mtime,
+            digest,
+        },
+    ))
+}
+
+fn parse_time<T: std::str::FromStr>(text: &str) -> Option<(T, u32)> {
+    let (seconds, nanoseconds) = text.split_once('.')?;
+    Some((seconds.parse().ok()?, nanoseconds.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        let (path, record) = parse_line(
+            "f 42 1600000000.000000123 2049 7 1600000001.000000456 42 1600000000.000000123 00ff \
+             dir/with space.txt",
+        )
+        .unwrap();
+        assert_eq!(path, PathBuf::from("dir/with space.txt"));
+        assert_eq!(
+            record,
+            Record {
+                kind: Kind::File,
+                size: 42,
+                mtime: (1_600_000_000, 123),
+                device: 2049,
+                inode: 7,
+                ctime: (1_600_000_001, 456),
+                dest_size: 42,
+                dest_mtime: (1_600_000_000, 123),
+                digest: Some(vec![0, 255]),
+            }
+        );
+        let (_, record) =
+            parse_line("d 0 0.000000000 0 0 0.000000000 0 0.000000000 - dir").unwrap();
+        assert_eq!(record.kind, Kind::Dir);
+        assert_eq!(record.digest, None);
+        assert!(parse_line("x 42 1.0 1 7 1.0 42 1.0 - foo").is_none());
+        assert!(parse_line("f 42 1.0 1 7 1.0 42 1.0 0g foo").is_none());
+        // Written before the destination was recorded
+        assert!(parse_line("f 42 1.0 1 7 1.0 - foo").is_none());
+    }
+}
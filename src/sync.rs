@@ -1,14 +1,41 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
-use anyhow::{anyhow, Error};
-
+use crate::backup::Backup;
+use crate::bisync::{self, Bisync};
+use crate::builder::SyncerBuilder;
+use crate::cancel::CancellationToken;
+use crate::checksum::HashAlgorithm;
+use crate::checksum_cache::{ChecksumCache, CACHE_FILE_NAME};
+use crate::chmod::Chmod;
 use crate::entry::Entry;
+use crate::error::SyncError;
+use crate::filesystem::{Filesystem, LocalFilesystem};
+use crate::filters::{self, Filters};
 use crate::fsops;
 use crate::fsops::SyncOutcome::*;
+use crate::hooks;
+use crate::id_map::{Database, IdMap, IdMaps};
+use crate::log_file::LogFile;
+use crate::manifest;
+use crate::manifest::ManifestFormat;
 use crate::progress::{ProgressInfo, ProgressMessage};
+use crate::regex::Regex;
+use crate::schedule::Schedule;
+use crate::state::{SyncState, STATE_FILE_NAME};
+use crate::state_db::StateDb;
+use crate::throttle::Throttle;
+use crate::verify;
+use crate::verify::{Comparison, Verification};
+use crate::vss;
+use crate::watch::Watcher;
+use crate::workers::DeleteWorker;
 use crate::workers::ProgressWorker;
 use crate::workers::SyncWorker;
 use crate::workers::WalkWorker;
@@ -21,6 +48,14 @@ pub struct Stats {
     pub total_size: usize,
     /// Sum of the sizes of all the files that were synced
     pub total_transfered: u64,
+    /// Sum of the sizes of all the files in the source, in bytes
+    pub bytes_total: u64,
+    /// Number of bytes read from the source and written to the destination.
+    /// Includes what was copied during failed attempts, if any
+    pub bytes_copied: u64,
+    /// Number of bytes that did not have to be copied: contents of up to
+    /// date and cloned files, resumed transfers and holes in sparse files
+    pub bytes_skipped: u64,
 
     /// Number of files transfered (should match `num_files`
     /// if no error)
@@ -29,13 +64,54 @@ pub struct Stats {
     pub up_to_date: u64,
     /// Number of files that were copied
     pub copied: u64,
+    /// Number of files that were copied sparsely (included in `copied`)
+    pub sparse_copied: u64,
+    /// Number of files that were cloned with copy-on-write (included in `copied`)
+    pub cloned: u64,
+    /// Number of bytes that did not need to be written thanks to in-place updates
+    pub bytes_saved: u64,
     /// Number of errors
     pub errors: u64,
+    /// Number of warnings
+    pub warnings: u64,
+    /// Entries that could not be synced (relative to the source or to the
+    /// destination for deletions), and what went wrong
+    pub error_list: Vec<(PathBuf, io::Error)>,
 
     /// Number of symlink created in the destination folder
     pub symlink_created: u64,
     /// Number of symlinks updated in the destination folder
     pub symlink_updated: u64,
+    /// Number of symlinks left out because of `SymlinkPolicy::Skip`
+    pub symlinks_skipped: u64,
+    /// Number of junctions created in the destination folder (Windows only)
+    pub junctions_created: u64,
+    /// Number of junctions left out because of `JunctionPolicy::Skip`
+    pub junctions_skipped: u64,
+    /// Number of hard links created in the destination folder
+    pub hardlinks_created: u64,
+    /// Number of unchanged files hard linked from `Syncer::link_dest()`
+    /// instead of being copied
+    pub files_linked: u64,
+    /// Number of FIFOs, sockets and device nodes created in the destination folder
+    pub specials_created: u64,
+    /// Number of FIFOs, sockets and device nodes that were skipped
+    pub specials_skipped: u64,
+    /// Number of files skipped because of `SyncOptions.max_size`
+    pub size_skipped: u64,
+    /// Number of entries skipped because they do not exist in the destination, with
+    /// `SyncOptions.existing`
+    pub missing_skipped: u64,
+    /// Number of files kept in the destination because they changed there too, see
+    /// `SyncOptions.conflicts`
+    pub conflicts_skipped: u64,
+
+    /// Number of entries removed from the destination folder
+    pub deleted: u64,
+
+    /// Whether the sync was stopped with a `CancellationToken` before every
+    /// entry was synced. Extraneous entries are not deleted in that case
+    pub cancelled: bool,
 
     /// Duration of the transfer
     pub duration: std::time::Duration,
@@ -50,14 +126,35 @@ impl Stats {
             num_files: 0,
             total_size: 0,
             total_transfered: 0,
+            bytes_total: 0,
+            bytes_copied: 0,
+            bytes_skipped: 0,
 
             num_synced: 0,
             up_to_date: 0,
             copied: 0,
+            sparse_copied: 0,
+            cloned: 0,
+            bytes_saved: 0,
             errors: 0,
+            warnings: 0,
+            error_list: vec![],
 
             symlink_created: 0,
             symlink_updated: 0,
+            symlinks_skipped: 0,
+            junctions_created: 0,
+            junctions_skipped: 0,
+            hardlinks_created: 0,
+            files_linked: 0,
+            specials_created: 0,
+            specials_skipped: 0,
+            size_skipped: 0,
+            missing_skipped: 0,
+            conflicts_skipped: 0,
+
+            deleted: 0,
+            cancelled: false,
             start: std::time::Instant::now(),
             duration: std::time::Duration::new(0, 0),
         }
@@ -76,8 +173,36 @@ impl Stats {
         self.duration
     }
 
-    pub fn add_error(&mut self) {
+    /// Average number of bytes copied per second during the transfer
+    pub fn avg_throughput(&self) -> f64 {
+        let secs = self.duration.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes_copied as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Ratio between the total size of the source and the number of bytes
+    /// that were actually copied, like rsync's "speedup"
+    pub fn speedup(&self) -> Option<f64> {
+        if self.bytes_copied == 0 {
+            return None;
+        }
+        Some(self.bytes_total as f64 / self.bytes_copied as f64)
+    }
+
+    pub fn add_error(&mut self, entry: &str, error: io::Error) {
         self.errors += 1;
+        self.error_list.push((PathBuf::from(entry), error));
+    }
+
+    pub fn add_warning(&mut self) {
+        self.warnings += 1;
+    }
+
+    pub fn add_deleted(&mut self) {
+        self.deleted += 1;
     }
 
     #[doc(hidden)]
@@ -88,9 +213,330 @@ impl Stats {
                 self.copied += 1;
                 self.total_transfered += size;
             }
+            SparseFileCopied { size } => {
+                self.copied += 1;
+                self.sparse_copied += 1;
+                self.total_transfered += size;
+            }
+            FileCloned { size } => {
+                self.copied += 1;
+                self.cloned += 1;
+                self.total_transfered += size;
+            }
+            UpdatedInPlace { size, saved } => {
+                self.copied += 1;
+                self.bytes_saved += saved;
+                self.total_transfered += size;
+            }
             UpToDate => self.up_to_date += 1,
             SymlinkUpdated => self.symlink_updated += 1,
             SymlinkCreated => self.symlink_created += 1,
+            SymlinkSkipped => self.symlinks_skipped += 1,
+            JunctionCreated => self.junctions_created += 1,
+            JunctionSkipped => self.junctions_skipped += 1,
+            HardLinkCreated => self.hardlinks_created += 1,
+            FileLinked { .. } => self.files_linked += 1,
+            SpecialCreated => self.specials_created += 1,
+            SpecialSkipped => self.specials_skipped += 1,
+            SizeSkipped => self.size_skipped += 1,
+            MissingSkipped => self.missing_skipped += 1,
+            ConflictSkipped => self.conflicts_skipped += 1,
+        }
+    }
+}
+
+/// What to do with symlinks found in the source
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Re-create the symlinks to files in the destination, and walk through
+    /// the symlinks to directories as if they were directories
+    Preserve,
+    /// Re-create all the symlinks in the destination, including the ones to
+    /// directories
+    PreserveAll,
+    /// Copy whatever the symlinks point to
+    Dereference,
+    /// Leave symlinks out of the sync
+    Skip,
+}
+
+impl std::str::FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(SymlinkPolicy::Preserve),
+            "preserve-all" => Ok(SymlinkPolicy::PreserveAll),
+            "dereference" => Ok(SymlinkPolicy::Dereference),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            _ => Err(format!(
+                "invalid symlink policy: '{}' (expected preserve, preserve-all, dereference or skip)",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do with junctions found in the source (Windows only)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum JunctionPolicy {
+    /// Create junctions with the same target in the destination
+    Recreate,
+    /// Copy the directories they point to
+    Dereference,
+    /// Leave junctions out of the sync
+    Skip,
+}
+
+impl std::str::FromStr for JunctionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "recreate" => Ok(JunctionPolicy::Recreate),
+            "dereference" => Ok(JunctionPolicy::Dereference),
+            "skip" => Ok(JunctionPolicy::Skip),
+            _ => Err(format!(
+                "invalid junction policy: '{}' (expected recreate, dereference or skip)",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do when a file exists in several of the sources given to
+/// `Syncer::new_multi()`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CollisionPolicy {
+    /// Sync the file from the first source, and report an error for the
+    /// other ones
+    Error,
+    /// Sync the file from the last source
+    LastWins,
+    /// Sync the file from the first source, and silently skip the other ones
+    Skip,
+}
+
+impl std::str::FromStr for CollisionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(CollisionPolicy::Error),
+            "last-wins" => Ok(CollisionPolicy::LastWins),
+            "skip" => Ok(CollisionPolicy::Skip),
+            _ => Err(format!(
+                "invalid collision policy: '{}' (expected error, last-wins or skip)",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do with a file that changed both in the source and in the
+/// destination since the previous sync, see `SyncOptions.conflicts`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    /// Overwrite the destination file, like without conflict detection
+    SourceWins,
+    /// Keep the file modified last
+    NewerWins,
+    /// Rename the destination file, like `notes.txt.conflict`, before
+    /// syncing the source file
+    KeepBoth,
+    /// Keep the destination file, so that the conflict is reported again
+    /// next time
+    ReportOnly,
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "source-wins" => Ok(ConflictPolicy::SourceWins),
+            "newer-wins" => Ok(ConflictPolicy::NewerWins),
+            "keep-both" => Ok(ConflictPolicy::KeepBoth),
+            "report-only" => Ok(ConflictPolicy::ReportOnly),
+            _ => Err(format!(
+                "invalid conflict policy: '{}' (expected source-wins, newer-wins, keep-both or report-only)",
+                s
+            )),
+        }
+    }
+}
+
+/// In which order the source entries are walked, and sent to the sync
+/// workers. With several workers, they may still finish in another order
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WalkOrder {
+    /// The order in which the file system lists the entries of each
+    /// directory. The files of a directory come first, then each of its
+    /// subdirectories is walked entirely before the next one
+    DepthFirst,
+    /// Like `DepthFirst`, with the files and the subdirectories of each
+    /// directory sorted by name, so that the order is the same on every run
+    Sorted,
+    /// Every entry of a directory before the entries of its subdirectories,
+    /// so that the shallow parts of the tree are synced first
+    BreadthFirst,
+}
+
+impl std::str::FromStr for WalkOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "depth-first" => Ok(WalkOrder::DepthFirst),
+            "sorted" => Ok(WalkOrder::Sorted),
+            "breadth-first" => Ok(WalkOrder::BreadthFirst),
+            _ => Err(format!(
+                "invalid walk order: '{}' (expected depth-first, sorted or breadth-first)",
+                s
+            )),
+        }
+    }
+}
+
+/// When to delete the extraneous entries of the destination, see
+/// `SyncOptions.delete_extraneous`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DeleteTiming {
+    /// Before syncing anything, to free space in the destination first
+    Before,
+    /// While the entries are synced
+    During,
+    /// Once every entry is synced, and only if the source could be walked
+    /// entirely and nothing failed. The safest one
+    After,
+}
+
+/// The most entries `SyncOptions.delete_extraneous` may delete
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MaxDelete {
+    /// A number of entries
+    Entries(u64),
+    /// A percentage of the entries of the destination
+    Percent(f64),
+}
+
+impl MaxDelete {
+    /// Wether deleting `count` of the `total` entries of the destination
+    /// is more than allowed
+    pub fn is_exceeded(self, count: u64, total: u64) -> bool {
+        match self {
+            MaxDelete::Entries(max) => count > max,
+            MaxDelete::Percent(max) => count as f64 > total as f64 * max / 100.0,
+        }
+    }
+}
+
+impl std::str::FromStr for MaxDelete {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid deletion limit: '{}' (expected a number of entries, or a percentage like 50%)",
+                s
+            )
+        };
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.parse::<f64>() {
+                Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(MaxDelete::Percent(percent)),
+                _ => Err(invalid()),
+            },
+            None => s.parse().map(MaxDelete::Entries).map_err(|_| invalid()),
+        }
+    }
+}
+
+impl fmt::Display for MaxDelete {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MaxDelete::Entries(max) => write!(f, "{} entries", max),
+            MaxDelete::Percent(max) => write!(f, "{}% of the destination", max),
+        }
+    }
+}
+
+/// How to compare file names that may be in different Unicode forms, like
+/// names written on macOS (decomposed, NFD) and on Linux (usually composed,
+/// NFC)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Normalization {
+    /// Compare names as they are
+    None,
+    /// Compare names in the same form, so that an existing destination entry
+    /// is updated whatever its form. New entries are named like in the source
+    Compare,
+    /// Like `Compare`, and name new entries in composed form
+    Nfc,
+    /// Like `Compare`, and name new entries in decomposed form
+    Nfd,
+}
+
+impl std::str::FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Normalization::None),
+            "compare" => Ok(Normalization::Compare),
+            "nfc" => Ok(Normalization::Nfc),
+            "nfd" => Ok(Normalization::Nfd),
+            _ => Err(format!(
+                "invalid normalization: '{}' (expected none, compare, nfc or nfd)",
+                s
+            )),
+        }
+    }
+}
+
+/// Whether to clone files using copy-on-write instead of copying their
+/// contents (Linux file systems supporting FICLONE, like btrfs and XFS,
+/// and APFS on macOS)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ReflinkMode {
+    /// Clone when the file system supports it, copy otherwise
+    Auto,
+    /// Always clone, and fail if that is not possible
+    Always,
+    /// Always copy
+    Never,
+}
+
+impl std::str::FromStr for ReflinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ReflinkMode::Auto),
+            "always" => Ok(ReflinkMode::Always),
+            "never" => Ok(ReflinkMode::Never),
+            _ => Err(format!(
+                "invalid reflink mode: '{}' (expected auto, always or never)",
+                s
+            )),
+        }
+    }
+}
+
+/// How many times to retry entries failing with errors that may be transient,
+/// like EIO or EAGAIN on network file systems
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Number of retries (0 means failing on the first error)
+    pub count: u32,
+    /// Time to wait before the first retry. It doubles after each attempt
+    pub backoff: std::time::Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            backoff: std::time::Duration::from_secs(1),
         }
     }
 }
@@ -98,72 +544,1104 @@ impl Stats {
 #[derive(Copy, Clone)]
 pub struct SyncOptions {
     /// Wether to preserve permissions of the source file after the destination is written.
+    /// On Windows, the read-only, hidden, system, archive and not-indexed attributes are preserved.
     pub preserve_permissions: bool,
+    /// Wether to preserve the owner of the source file (no-op on Windows).
+    /// This usually requires to run as root.
+    pub preserve_owner: bool,
+    /// Wether to preserve the group of the source file (no-op on Windows).
+    pub preserve_group: bool,
+    /// Wether to reject user and group names in `Syncer::map_users()` and `Syncer::map_groups()`,
+    /// instead of looking them up in the databases of this host. Owners and groups are always
+    /// preserved as numeric ids.
+    pub numeric_ids: bool,
+    /// Wether to re-create hard links between source files in the destination (no-op on Windows).
+    pub preserve_hard_links: bool,
+    /// Wether to preserve modification times of the source file after the destination is written.
+    pub preserve_times: bool,
+    /// Wether to remove entries in the destination that do not exist in the source.
+    pub delete_extraneous: bool,
+    /// When to remove them with `delete_extraneous`. Before and during the sync, the entries of
+    /// source directories that cannot be read are not deleted, but other failures do not
+    /// prevent deleting.
+    pub delete_timing: DeleteTiming,
+    /// Make the sync fail without deleting anything when `delete_extraneous` would delete more
+    /// entries than this, for instance because the source is not the expected directory. The
+    /// contents of extraneous directories count too. None means no limit.
+    pub max_delete: Option<MaxDelete>,
+    /// Wether `delete_extraneous` moves the entries to the trash of the desktop (the XDG trash,
+    /// or the Recycle Bin on Windows) instead of removing them, so that they can be restored.
+    /// Ignored with backups (see `Syncer::backup_dir()` and `Syncer::backup_suffix()`), which
+    /// are made instead.
+    pub delete_to_trash: bool,
+    /// Detect the files that changed both in the source and in the destination since the
+    /// previous sync, and resolve these conflicts with the given policy. Each conflict is
+    /// reported as a warning. What the files looked like after each sync is recorded in a
+    /// `.rusync-state` file, in the root of the destination. None disables the detection.
+    pub conflicts: Option<ConflictPolicy>,
+    /// Wether to skip entries matching the patterns found in `.gitignore` and `.ignore` files.
+    pub respect_gitignore: bool,
+    /// Wether to skip entries whose name starts with a dot, and the contents of such directories.
+    /// Like excluded entries, they are never deleted from the destination.
+    pub skip_hidden: bool,
+    /// Wether to leave out directories that are on a different file system than
+    /// their source, such as `/proc` when syncing `/` (no-op on Windows).
+    pub one_file_system: bool,
+    /// What to do with symlinks
+    pub symlinks: SymlinkPolicy,
+    /// What to do with junctions, instead of treating them like symlinks (no-op outside of Windows)
+    pub junctions: JunctionPolicy,
+    /// Wether to walk through symlinks to directories, creating real directories in the
+    /// destination, whatever `symlinks` says. Other symlinks are handled according to `symlinks`.
+    pub follow_dir_symlinks: bool,
+    /// What to do with files found in several sources, see `Syncer::new_multi()`
+    pub collisions: CollisionPolicy,
+    /// Wether the destination file system ignores case, like on macOS and Windows. Entries whose
+    /// path only differs in case from one already synced are then reported as errors instead of
+    /// overwriting it, and `delete_extraneous` compares names ignoring case.
+    pub case_insensitive: bool,
+    /// How to compare and write names that may be in different Unicode forms
+    pub normalization: Normalization,
+    /// Wether to re-create FIFOs, sockets and device nodes instead of skipping them (no-op on Windows).
+    /// Creating device nodes usually requires to run as root.
+    pub copy_specials: bool,
+    /// Wether to turn holes and blocks of zeros into holes in the destination.
+    pub sparse: bool,
+    /// Wether to update existing destination files in place, only writing
+    /// the blocks that differ from the source at the same offsets. Data
+    /// shifted by an insertion or a deletion is written again
+    pub in_place: bool,
+    /// Wether to write to `<name>.rusync-part` files and rename them once done,
+    /// so that interrupted transfers can be resumed on the next sync (except
+    /// when using `sparse`)
+    pub partial: bool,
+    /// With `partial`, wether to write to files at the same relative paths
+    /// in a `.rusync-partial` directory at the root of the destination
+    /// instead, so that readers never see incomplete files at their final
+    /// path. The directory itself is never deleted
+    pub partial_dir: bool,
+    /// Wether to write to a temporary file in the destination directory and
+    /// rename it once done, so that readers never see half-written files.
+    /// The destination files get new inodes, and `in_place` is ignored
+    pub atomic: bool,
+    /// Wether to flush each written file and its parent directory to disk before counting it as
+    /// synced, so that a power loss right after the sync cannot leave the destination incomplete.
+    /// Slower, especially with many small files.
+    pub fsync: bool,
+    /// Whether to clone files instead of copying them
+    pub reflink: ReflinkMode,
+    /// What to do with entries failing with transient errors
+    pub retries: RetryPolicy,
+    /// Skip files bigger than this many bytes. None means no limit.
+    pub max_size: Option<u64>,
+    /// Copy files of at least this many bytes from a memory mapping of the
+    /// source, instead of reading them into a buffer. Unix only, falls back to
+    /// a regular copy when the file cannot be mapped. None means never,
+    /// ignored with `sparse` and `in_place`.
+    pub mmap_threshold: Option<u64>,
+    /// Wether to bypass the page cache (O_DIRECT) when copying files, so that
+    /// large syncs do not evict what other programs keep in memory. Linux only,
+    /// falls back to a regular copy on file systems that do not support it.
+    /// Ignored with `sparse` and `in_place`, for resumed transfers and for files
+    /// copied with `mmap_threshold`.
+    pub direct_io: bool,
+    /// Wether to reserve the space for the whole file in the destination before copying it, to
+    /// reduce fragmentation and fail right away when there is not enough space left. Linux and
+    /// Windows only, ignored with `sparse`.
+    pub preallocate: bool,
+    /// Wether to sync from a Volume Shadow Copy snapshot of the volumes containing the sources,
+    /// so that files kept open and locked by other programs are copied in a consistent state.
+    /// Windows only, requires running as administrator. The snapshots are deleted after the sync.
+    pub vss: bool,
+    /// Wether to only update entries that already exist in the destination, and never create new
+    /// ones.
+    pub existing: bool,
+    /// Leave files smaller than this many bytes out of the sync entirely, like
+    /// excluded files. None means no limit.
+    pub min_size: Option<u64>,
+    /// Leave files last modified before this time out of the sync entirely.
+    pub newer_than: Option<std::time::SystemTime>,
+    /// Leave files last modified after this time out of the sync entirely.
+    pub older_than: Option<std::time::SystemTime>,
+    /// Maximum transfer rate in bytes per second, shared by all the workers.
+    /// None means no limit.
+    pub bwlimit: Option<u64>,
+    /// How many bytes to read and write at once when copying files. Each
+    /// chunk is reported by `ProgressInfo::progress()`, so smaller buffers
+    /// also mean more frequent progress. 0 means the default, 100 KiB.
+    pub buffer_size: usize,
+    /// Wether to compare the contents of files instead of their modification times.
+    pub checksum: bool,
+    /// With `checksum`, wether to keep the checksums of the files in `.rusync-checksums` in the
+    /// destination, so that files whose size and modification time did not change are not read
+    /// again on the next sync.
+    pub checksum_cache: bool,
+    /// Which hash function computes the checksums: for `checksum`, `verify_copies`,
+    /// `Syncer::verify()`, `Syncer::manifest()` and resuming partial transfers.
+    pub hash: HashAlgorithm,
+    /// Wether to read every copied file back from the destination, and compare its checksum
+    /// with the one of the source computed while copying. Mismatches are reported as errors.
+    /// On Linux, copies are flushed to disk first, so that they are read back from the disk.
+    pub verify_copies: bool,
+    /// Wether to consider files with the same size up to date, whatever their modification times.
+    pub size_only: bool,
+    /// Modification times closer than that are considered equal, for file systems storing them
+    /// with a coarse precision, like FAT (2 seconds).
+    pub modify_window: std::time::Duration,
+    /// Wether to remove source files once they have been synced, and then the
+    /// empty source directories. Entries that were skipped are left alone,
+    /// and `delete_extraneous` is ignored, since everything in the
+    /// destination would be extraneous by then
+    pub remove_source_files: bool,
+    /// Wether to remove the empty directories of the destination once everything is synced and
+    /// the extraneous entries are deleted, including the ones only containing empty
+    /// directories. Excluded directories are left alone. Ignored for dry runs.
+    pub prune_empty_dirs: bool,
+    /// Wether to only report what would be done, without changing anything in
+    /// the source or the destination. The ProgressInfo gets a `planned()`
+    /// call for each entry, and the Stats count what would have been done
+    pub dry_run: bool,
+    /// Wether to walk the whole source before syncing anything, so that the total number of files
+    /// and bytes is known from the start, see `Progress.totals_known`
+    pub prescan: bool,
+    /// In which order to walk the source
+    pub walk_order: WalkOrder,
+    /// Wether two syncs of the same tree should report the same things in
+    /// the same order, so that their log files and error lists are
+    /// identical. Implies a `Sorted` walk order, `prescan` and a single
+    /// worker, deletes `After` instead of `During` the sync, and leaves the
+    /// timestamps out of the log file
+    pub deterministic: bool,
+    /// Wether to leave the `ProgressInfo` out entirely: none of its methods are
+    /// called. Failures are still reported in the returned `Stats`
+    pub quiet: bool,
 }
 
 impl Default for SyncOptions {
     fn default() -> Self {
         Self {
             preserve_permissions: true,
+            preserve_owner: false,
+            preserve_group: false,
+            numeric_ids: false,
+            preserve_hard_links: false,
+            preserve_times: true,
+            delete_extraneous: false,
+            delete_timing: DeleteTiming::After,
+            max_delete: None,
+            delete_to_trash: false,
+            conflicts: None,
+            respect_gitignore: false,
+            skip_hidden: false,
+            one_file_system: false,
+            checksum: false,
+            checksum_cache: false,
+            hash: HashAlgorithm::Sha256,
+            verify_copies: false,
+            size_only: false,
+            modify_window: std::time::Duration::from_secs(0),
+            sparse: false,
+            bwlimit: None,
+            max_size: None,
+            mmap_threshold: None,
+            direct_io: false,
+            preallocate: false,
+            vss: false,
+            buffer_size: fsops::DEFAULT_BUFFER_SIZE,
+            existing: false,
+            min_size: None,
+            newer_than: None,
+            older_than: None,
+            reflink: ReflinkMode::Auto,
+            in_place: false,
+            partial: false,
+            partial_dir: false,
+            atomic: false,
+            fsync: false,
+            retries: RetryPolicy::default(),
+            copy_specials: false,
+            symlinks: SymlinkPolicy::Preserve,
+            junctions: JunctionPolicy::Recreate,
+            follow_dir_symlinks: false,
+            collisions: CollisionPolicy::Error,
+            case_insensitive: false,
+            normalization: Normalization::None,
+            remove_source_files: false,
+            prune_empty_dirs: false,
+            dry_run: false,
+            prescan: false,
+            walk_order: WalkOrder::DepthFirst,
+            deterministic: false,
+            quiet: false,
         }
     }
 }
 
+/// See `Syncer::filter_entries()`
+pub(crate) type EntryFilter = Arc<dyn Fn(&Entry) -> bool + Send + Sync>;
+
 pub struct Syncer {
-    source: PathBuf,
+    sources: Vec<PathBuf>,
     destination: PathBuf,
     options: SyncOptions,
+    filters: Filters,
+    entry_filter: Option<EntryFilter>,
+    num_workers: usize,
+    log_file: Option<LogFile>,
+    backup_dir: Option<PathBuf>,
+    backup_suffix: String,
+    link_dest: Option<PathBuf>,
+    manifest: Option<(PathBuf, ManifestFormat)>,
+    state_db: Option<PathBuf>,
+    pre_hook: Option<String>,
+    post_hook: Option<String>,
+    id_maps: IdMaps,
+    chmod: Chmod,
+    cancel: CancellationToken,
     progress_info: Box<dyn ProgressInfo + Send>,
 }
 
 impl Syncer {
+    /// Configure a Syncer step by step, and check the configuration before
+    /// syncing anything, see `SyncerBuilder`
+    pub fn builder() -> SyncerBuilder {
+        SyncerBuilder::new()
+    }
+
     pub fn new(
         source: &Path,
         destination: &Path,
         options: SyncOptions,
         progress_info: Box<dyn ProgressInfo + Send>,
     ) -> Syncer {
+        Syncer::new_multi(&[source.to_path_buf()], destination, options, progress_info)
+    }
+
+    /// Merge several source directories into `destination`. Directories
+    /// found in several sources are merged, and `SyncOptions.collisions`
+    /// tells what to do with files found in several sources. Extraneous
+    /// entries are the ones that exist in none of the sources.
+    ///
+    /// Sources must not contain one another.
+    pub fn new_multi(
+        sources: &[PathBuf],
+        destination: &Path,
+        options: SyncOptions,
+        progress_info: Box<dyn ProgressInfo + Send>,
+    ) -> Syncer {
+        // Deep trees may not fit in the 260 characters Windows allows
+        // otherwise
         Syncer {
-            source: source.to_path_buf(),
-            destination: destination.to_path_buf(),
+            sources: sources
+                .iter()
+                .map(|source| fsops::extended_length_path(source))
+                .collect(),
+            destination: fsops::extended_length_path(destination),
             progress_info,
             options,
+            filters: Filters::new(),
+            entry_filter: None,
+            num_workers: 1,
+            log_file: None,
+            backup_dir: None,
+            backup_suffix: String::new(),
+            link_dest: None,
+            manifest: None,
+            state_db: None,
+            pre_hook: None,
+            post_hook: None,
+            id_maps: IdMaps::default(),
+            chmod: Chmod::default(),
+            cancel: CancellationToken::new(),
         }
     }
 
-    pub fn sync(self) -> Result<Stats, Error> {
-        let (walker_entry_output, syncer_input) = channel::<Entry>();
-        let (walker_stats_output, progress_input) = channel::<ProgressMessage>();
-        let progress_output = walker_stats_output.clone();
+    /// Skip entries matching the given glob pattern.
+    ///
+    /// Patterns are matched against the path relative to the source, and
+    /// rules added with `include()` and `exclude()` are evaluated in order:
+    /// the first matching rule wins.
+    pub fn exclude(&mut self, pattern: &str) {
+        self.filters.add_exclude(pattern);
+    }
 
-        let walk_worker = WalkWorker::new(&self.source, walker_entry_output, walker_stats_output);
-        let sync_worker = SyncWorker::new(
-            &self.source,
+    /// Always sync entries matching the given glob pattern, unless they are
+    /// excluded by an earlier rule. See `exclude()`.
+    pub fn include(&mut self, pattern: &str) {
+        self.filters.add_include(pattern);
+    }
+
+    /// Skip entries whose path relative to the source, with `/` as separator,
+    /// matches the given regular expression anywhere. Use `^` and `$` to
+    /// match the whole path. Evaluated in order with the other rules, see
+    /// `exclude()`.
+    ///
+    /// Returns an error if `pattern` is not a valid regular expression.
+    pub fn exclude_regex(&mut self, pattern: &str) -> Result<(), String> {
+        self.filters.add_exclude_regex(Regex::new(pattern)?);
+        Ok(())
+    }
+
+    /// Like `exclude_regex()`, but always syncs the matching entries unless
+    /// they are excluded by an earlier rule.
+    pub fn include_regex(&mut self, pattern: &str) -> Result<(), String> {
+        self.filters.add_include_regex(Regex::new(pattern)?);
+        Ok(())
+    }
+
+    /// Call `exclude()` for each pattern found in the file at `path`, one per
+    /// line. Blank lines and lines starting with `#` are ignored.
+    pub fn exclude_from(&mut self, path: &Path) -> io::Result<()> {
+        for pattern in filters::read_patterns(path)? {
+            self.filters.add_exclude(&pattern);
+        }
+        Ok(())
+    }
+
+    /// Like `exclude_from()`, but calls `include()` for each pattern.
+    pub fn include_from(&mut self, path: &Path) -> io::Result<()> {
+        for pattern in filters::read_patterns(path)? {
+            self.filters.add_include(&pattern);
+        }
+        Ok(())
+    }
+
+    /// Only sync the files for which `filter` returns true, once the other
+    /// rules were applied, for instance to decide on their size or on their
+    /// modification time. Directories are always walked, and the files left
+    /// out are neither synced nor counted in the stats.
+    pub fn filter_entries(&mut self, filter: impl Fn(&Entry) -> bool + Send + Sync + 'static) {
+        self.entry_filter = Some(Arc::new(filter));
+    }
+
+    /// Use `num_workers` threads to sync entries in parallel (default: 1).
+    ///
+    /// Several workers mostly help when syncing lots of small files, or
+    /// when the destination has a high latency.
+    pub fn num_workers(&mut self, num_workers: usize) {
+        self.num_workers = std::cmp::max(num_workers, 1);
+    }
+
+    /// Before overwriting or deleting an entry of the destination, move it
+    /// to `backup_dir`, at the same path relative to the destination.
+    /// A relative `backup_dir` is relative to the destination, and is left
+    /// alone when deleting extraneous entries.
+    ///
+    /// In-place updates (see `SyncOptions.in_place`) are disabled, since they
+    /// update files in place.
+    pub fn backup_dir(&mut self, backup_dir: &Path) {
+        self.backup_dir = Some(backup_dir.to_path_buf());
+    }
+
+    /// Before overwriting or deleting an entry of the destination, rename
+    /// it by adding `suffix` to its name, like `notes.txt.bak`. The entries
+    /// with this suffix are left alone when deleting extraneous entries.
+    ///
+    /// With `backup_dir`, the suffix is added to the names of the entries
+    /// moved there instead. An empty suffix only makes sense then.
+    pub fn backup_suffix(&mut self, suffix: &str) {
+        self.backup_suffix = suffix.to_string();
+    }
+
+    /// Compare files missing from the destination with the ones at the same
+    /// path in `link_dest`, for instance a previous backup, and hard link
+    /// them from there instead of copying them when they did not change.
+    /// A relative `link_dest` is relative to the destination.
+    ///
+    /// Files are considered unchanged when they have the same size,
+    /// modification time (or contents, with `SyncOptions.checksum`) and
+    /// the attributes that would be preserved.
+    pub fn link_dest(&mut self, link_dest: &Path) {
+        self.link_dest = Some(link_dest.to_path_buf());
+    }
+
+    /// Append a timestamped line to the file at `path` for every entry that
+    /// was synced, skipped, deleted or could not be synced.
+    ///
+    /// Unlike the ProgressInfo, the log file is written to even when using
+    /// `quiet`.
+    pub fn log_file(&mut self, path: &Path) -> io::Result<()> {
+        self.log_file = Some(LogFile::open(path)?);
+        Ok(())
+    }
+
+    /// Once the sync is done, write the list of the files in the destination
+    /// to `path`, with their size, modification time and checksum, computed
+    /// with `SyncOptions.hash` and named after it.
+    /// The manifest itself and the backup dir are left out of the list.
+    ///
+    /// Every file of the destination is read again, so this takes about as
+    /// long as copying everything. The manifest is written even when some
+    /// entries could not be synced, but not after a fatal error.
+    pub fn manifest(&mut self, path: &Path, format: ManifestFormat) {
+        self.manifest = Some((path.to_path_buf(), format));
+    }
+
+    /// Record the synced entries in the database at `path`, to make the
+    /// next syncs faster. Source files whose size, modification time,
+    /// device, inode and status change time did not change since they were
+    /// recorded are then considered up to date without computing checksums,
+    /// as long as the size and modification time of their destination did
+    /// not change either, and `SyncOptions.delete_extraneous` only looks for
+    /// extraneous entries among the recorded ones instead of walking the
+    /// whole destination. Files touched or rewritten with the same contents,
+    /// as compared with their recorded checksum, are not copied again.
+    ///
+    /// This assumes that only rusync adds entries to the destination:
+    /// extraneous entries that were never recorded are never deleted. The
+    /// checksums of the source files are only recorded with
+    /// `SyncOptions.checksum` or `SyncOptions.verify_copies`. Dry runs leave
+    /// the database alone.
+    pub fn state_db(&mut self, path: &Path) {
+        self.state_db = Some(path.to_path_buf());
+    }
+
+    /// Translate the owners of the source entries with `SyncOptions.preserve_owner`, for
+    /// instance when restoring files from another host. `map` is a comma-separated list of
+    /// `FROM:TO` pairs, like `rsync --usermap`: FROM is a uid, a range of uids like `1000-1999`,
+    /// a user name or `*`, and TO a uid or a user name. The first matching pair wins, and
+    /// owners matching none are kept. Replaces the map given before.
+    pub fn map_users(&mut self, map: &str) -> Result<(), String> {
+        self.id_maps.users = IdMap::parse(map, Database::Users, self.options.numeric_ids)?;
+        Ok(())
+    }
+
+    /// Like `map_users()`, for the groups of the source entries with
+    /// `SyncOptions.preserve_group`
+    pub fn map_groups(&mut self, map: &str) -> Result<(), String> {
+        self.id_maps.groups = IdMap::parse(map, Database::Groups, self.options.numeric_ids)?;
+        Ok(())
+    }
+
+    /// Change the permissions of the destination entries, after preserving the ones of the
+    /// source with `SyncOptions.preserve_permissions`, or else of the entries as they were
+    /// written. `rules` uses the syntax of `rsync --chmod`: comma-separated chmod(1) clauses,
+    /// like `go-w` or `644`, each optionally starting with `D` to only apply to directories or
+    /// `F` to only apply to files. Rules are applied in order, after the ones given before.
+    ///
+    /// Directory rules are applied once everything else is synced. No-op on Windows.
+    pub fn chmod(&mut self, rules: &str) -> Result<(), String> {
+        self.chmod.add(rules)
+    }
+
+    /// Run `command` with the system shell (`sh -c` or `cmd /C`) before each
+    /// sync, for instance to mount the destination. If it fails, the sync
+    /// does not start. `RUSYNC_SOURCE` and `RUSYNC_DESTINATION` are set in
+    /// its environment, as well as `RUSYNC_DRY_RUN` when using `dry_run`.
+    pub fn pre_hook(&mut self, command: &str) {
+        self.pre_hook = Some(command.to_string());
+    }
+
+    /// Run `command` like `pre_hook()` after each sync, even when it failed,
+    /// for instance to send a notification. Its environment also contains
+    /// `RUSYNC_STATUS` (`success`, `partial` when some entries could not be
+    /// synced, `cancelled` or `failed`) and the stats, like
+    /// `RUSYNC_FILES_COPIED`, `RUSYNC_BYTES_COPIED` and `RUSYNC_ERRORS`, or
+    /// `RUSYNC_ERROR` when the sync failed.
+    ///
+    /// If the command fails, the sync returns `SyncError::Hook`, unless it
+    /// failed already.
+    pub fn post_hook(&mut self, command: &str) {
+        self.post_hook = Some(command.to_string());
+    }
+
+    /// Returns a token that can be used from any thread to stop the sync,
+    /// for instance when the user clicks on a Cancel button.
+    ///
+    /// Once cancelled, the entries being synced are finished but no other
+    /// entry is started, and nothing is deleted from the destination.
+    /// `sync()` then returns the stats so far, with `Stats.cancelled` set.
+    /// `watch()` and `every()` return once the current sync is done.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    pub fn sync(mut self) -> Result<Stats, SyncError> {
+        self.sync_once()
+    }
+
+    /// Sync the source and the destination both ways, and return what was
+    /// done: the files created, modified or deleted on one side since the
+    /// previous call are created, modified or deleted on the other side too.
+    ///
+    /// Files modified on both sides are conflicts, resolved with
+    /// `SyncOptions.conflicts` (`ConflictPolicy::KeepBoth` by default), where
+    /// the source plays the source and the destination the destination.
+    /// Deleted entries go to the trash with `SyncOptions.delete_to_trash`,
+    /// `SyncOptions.max_delete` applies to the deletions of either side, and
+    /// nothing is changed with `SyncOptions.dry_run`. Filters apply to both
+    /// sides. The other options and the ProgressInfo are not used.
+    ///
+    /// Only the first source is synced, and the destination must exist.
+    pub fn bisync(self) -> Result<Bisync, SyncError> {
+        self.check_sources()?;
+        if !self.destination.is_dir() {
+            return Err(SyncError::InvalidDestination(self.destination));
+        }
+        bisync::bisync(
+            &self.sources[0],
             &self.destination,
-            syncer_input,
+            &self.filters,
+            self.options,
+            &self.cancel,
+        )
+    }
+
+    /// Compare the source and the destination without changing anything,
+    /// and return the differences.
+    ///
+    /// Entries are walked the same way as when syncing: filters, ignore
+    /// files and `SyncOptions.symlinks` apply, and the backup dir is never
+    /// reported as extraneous. `comparison` tells how to compare files that
+    /// exist on both sides. The ProgressInfo is not used.
+    pub fn verify(self, comparison: Comparison) -> Result<Verification, SyncError> {
+        self.check_sources()?;
+        if self.destination.exists() && !self.destination.is_dir() {
+            return Err(SyncError::InvalidDestination(self.destination));
+        }
+        let (entry_output, entry_input) = channel::<Entry>();
+        let (progress_output, progress_input) = channel::<ProgressMessage>();
+        let walk_worker = WalkWorker::new(
+            &self.sources,
+            self.filters.clone(),
+            self.entry_filter.clone(),
+            self.cancel.clone(),
+            entry_output,
+            None,
             progress_output,
         );
-        let progress_worker = ProgressWorker::new(progress_input, self.progress_info);
         let options = self.options;
+        let walker_thread = thread::spawn(move || walk_worker.start(options));
 
-        let walker_thread = thread::spawn(move || walk_worker.start());
-        let syncer_thread = thread::spawn(move || sync_worker.start(options));
-        let progress_thread = thread::spawn(|| progress_worker.start());
-
+        let mut verification = Verification::default();
+        for entry in entry_input {
+            verification.check(&entry, &self.destination, comparison, options);
+        }
         walker_thread
             .join()
-            .map_err(|e| anyhow!("Could not join walker thread: {:?}", e))?;
+            .map_err(|_| SyncError::Join("walker".to_string()))?
+            .map_err(SyncError::Walk)?;
+        // The walker reports broken symlinks as errors
+        for message in progress_input.try_iter() {
+            if let ProgressMessage::SyncError {
+                entry,
+                details,
+                kind,
+            } = message
+            {
+                let error = io::Error::new(kind, details);
+                verification.errors.push((PathBuf::from(entry), error));
+            }
+        }
+
+        let backup = self.backup();
+        verify::find_extra(
+            &mut verification,
+            &self.sources,
+            &self.destination,
+            &LocalFilesystem {},
+            &self.filters,
+            backup.as_ref(),
+            options,
+        );
+        verification.sort();
+        Ok(verification)
+    }
+
+    /// Sync, and then sync again each time something changes in the source,
+    /// once nothing changed for a second. Each sync is reported to the
+    /// ProgressInfo, from `start()` to `end()`.
+    ///
+    /// Only returns when an error prevents a sync from completing. Errors
+    /// affecting single entries are reported but do not stop watching.
+    ///
+    /// Changes are detected with inotify on Linux. On other platforms, the
+    /// source is synced again every few seconds instead.
+    pub fn watch(mut self) -> Result<(), SyncError> {
+        self.check_sources()?;
+        let sources = self.describe_sources();
+        let watch_error = |e: io::Error| {
+            SyncError::Watch(anyhow::Error::new(e).context(format!("While watching {}", sources)))
+        };
+        loop {
+            // Start watching before syncing, so that changes made during
+            // the sync trigger another one
+            let watcher = Watcher::new(&self.sources[0]).map_err(watch_error)?;
+            for source in &self.sources[1..] {
+                watcher.add(source).map_err(watch_error)?;
+            }
+            self.sync_once()?;
+            if self.cancel.is_cancelled() {
+                return Ok(());
+            }
+            watcher.wait(WATCH_DEBOUNCE).map_err(watch_error)?;
+        }
+    }
+
+    /// Sync every `interval`, with up to a tenth of the interval of random
+    /// delay. Each sync is reported to the ProgressInfo, from `start()` to
+    /// `end()`.
+    ///
+    /// When a sync takes longer than `interval`, the cycles that should have
+    /// started in the meantime are skipped, with a warning.
+    ///
+    /// Only returns when an error prevents a sync from completing, like
+    /// `watch()`.
+    pub fn every(mut self, interval: std::time::Duration) -> Result<(), SyncError> {
+        let mut schedule = Schedule::new(interval, std::time::Instant::now());
+        loop {
+            self.sync_once()?;
+            if self.cancel.is_cancelled() {
+                return Ok(());
+            }
+            let (next_start, skipped) = schedule.next(std::time::Instant::now());
+            if skipped != 0 && !self.options.quiet {
+                self.progress_info.warning(
+                    &self.describe_sources(),
+                    &format!("Sync took too long, skipped {} cycle(s)", skipped),
+                );
+            }
+            // Sleep in small steps, so that cancelling does not have to wait
+            // for the next cycle
+            loop {
+                let now = std::time::Instant::now();
+                if next_start <= now {
+                    break;
+                }
+                if self.cancel.is_cancelled() {
+                    return Ok(());
+                }
+                thread::sleep(std::cmp::min(next_start - now, CANCEL_POLL_INTERVAL));
+            }
+        }
+    }
+
+    // Does the actual work of sync(), but leaves the Syncer usable for
+    // the next run
+    fn sync_once(&mut self) -> Result<Stats, SyncError> {
+        let mut env = vec![
+            ("RUSYNC_SOURCE", self.describe_sources()),
+            (
+                "RUSYNC_DESTINATION",
+                self.destination.to_string_lossy().to_string(),
+            ),
+        ];
+        if self.options.dry_run {
+            env.push(("RUSYNC_DRY_RUN", "1".to_string()));
+        }
+        if let Some(command) = &self.pre_hook {
+            hooks::run(command, &env).map_err(SyncError::Hook)?;
+        }
+        let result = if self.options.vss {
+            self.run_from_snapshots()
+        } else {
+            self.run_workers()
+        };
+        if let Some(command) = &self.post_hook {
+            env.extend(hooks::outcome_env(&result));
+            let hook_result = hooks::run(command, &env);
+            if result.is_ok() {
+                hook_result.map_err(SyncError::Hook)?;
+            }
+        }
+        result
+    }
+
+    // Like run_workers(), reading the sources from their snapshots
+    fn run_from_snapshots(&mut self) -> Result<Stats, SyncError> {
+        self.check_sources()?;
+        // Deleted when going out of scope, after the workers are done
+        let (_snapshots, paths) = vss::snapshot(&self.sources).map_err(SyncError::Snapshot)?;
+        let sources = std::mem::replace(&mut self.sources, paths);
+        let result = self.run_workers();
+        self.sources = sources;
+        result
+    }
+
+    fn run_workers(&mut self) -> Result<Stats, SyncError> {
+        self.check_sources()?;
+        self.check_destination()?;
+        if self.options.deterministic {
+            // With the whole source walked first, the errors found while
+            // walking are always sent before the outcomes of the workers
+            self.options.walk_order = WalkOrder::Sorted;
+            self.options.prescan = true;
+            self.num_workers = 1;
+            if self.options.delete_timing == DeleteTiming::During {
+                self.options.delete_timing = DeleteTiming::After;
+            }
+        }
+        let mut progress_info = if self.options.quiet {
+            Box::new(QuietProgressInfo {})
+        } else {
+            std::mem::replace(&mut self.progress_info, Box::new(QuietProgressInfo {}))
+        };
+        let sources = self.describe_sources();
+        progress_info.start(&sources, &self.destination.to_string_lossy());
+        if let Some(log_file) = &mut self.log_file {
+            log_file.set_timestamps(!self.options.deterministic);
+            log_file.start(&sources, &self.destination.to_string_lossy());
+        }
+
+        let source_fs: Arc<dyn Filesystem> = Arc::new(LocalFilesystem {});
+        let dest_fs: Arc<dyn Filesystem> = Arc::new(LocalFilesystem {});
+        let backup = self.backup();
+        let link_dest = self
+            .link_dest
+            .as_ref()
+            .map(|dir| self.destination.join(dir));
+        let mut filters = self.filters.clone();
+        let checksums = if self.options.checksum && self.options.checksum_cache {
+            filters.protect(&format!("/{}", CACHE_FILE_NAME));
+            let path = self.destination.join(CACHE_FILE_NAME);
+            Some(Arc::new(ChecksumCache::load(&path, self.options.hash)))
+        } else {
+            None
+        };
+        let state = if self.options.conflicts.is_some() {
+            filters.protect(&format!("/{}", STATE_FILE_NAME));
+            let path = self.destination.join(STATE_FILE_NAME);
+            Some(Arc::new(SyncState::load(&path)))
+        } else {
+            None
+        };
+        if self.options.partial_dir {
+            filters.protect(&format!("/{}", fsops::PARTIAL_DIR_NAME));
+        }
+        let state_db = self.state_db.as_ref().map(|path| {
+            if let Some(in_destination) = located_in(path, &self.destination) {
+                let rel_path = fsops::get_rel_path(&in_destination, &self.destination);
+                let pattern = rel_path.to_string_lossy().replace('\\', "/");
+                filters.protect(&format!("/{}", pattern));
+            }
+            Arc::new(StateDb::load(path))
+        });
+        let (walker_entry_output, syncer_input) = channel::<Entry>();
+        let (walker_dir_output, dir_input) = channel::<Entry>();
+        let (walker_stats_output, progress_input) = channel::<ProgressMessage>();
+        let progress_output = walker_stats_output.clone();
+        let prune_output = walker_stats_output.clone();
+        let mut delete_worker =
+            if self.options.delete_extraneous && !self.options.remove_source_files {
+                let delete_output = walker_stats_output.clone();
+                Some(DeleteWorker::new(
+                    &self.sources,
+                    &self.destination,
+                    source_fs,
+                    dest_fs.clone(),
+                    backup.clone(),
+                    filters.clone(),
+                    state_db.clone(),
+                    self.cancel.clone(),
+                    delete_output,
+                ))
+            } else {
+                None
+            };
+
+        let syncer_input = Arc::new(Mutex::new(syncer_input));
+        let hard_links = Arc::new(Mutex::new(HashMap::new()));
+        let throttle = Arc::new(Throttle::new(self.options.bwlimit));
+        let id_maps = Arc::new(self.id_maps.clone());
+        let chmod = Arc::new(self.chmod.clone());
+        let new_sync_worker = || {
+            SyncWorker::new(
+                &self.sources,
+                &self.destination,
+                dest_fs.clone(),
+                backup.clone(),
+                link_dest.clone(),
+                hard_links.clone(),
+                checksums.clone(),
+                state.clone(),
+                state_db.clone(),
+                throttle.clone(),
+                id_maps.clone(),
+                chmod.clone(),
+                self.cancel.clone(),
+                syncer_input.clone(),
+                progress_output.clone(),
+            )
+        };
+        let sync_workers: Vec<SyncWorker> =
+            (0..self.num_workers).map(|_| new_sync_worker()).collect();
+        // Syncs the attributes of the directories, once the sync workers are
+        // done
+        let dir_worker = new_sync_worker();
+        // Make sure the progress worker stops once all sync workers are done
+        drop(progress_output);
+        let walk_worker = WalkWorker::new(
+            &self.sources,
+            filters,
+            self.entry_filter.clone(),
+            self.cancel.clone(),
+            walker_entry_output,
+            Some(walker_dir_output),
+            walker_stats_output,
+        );
+        let mut progress_worker =
+            ProgressWorker::new(progress_input, progress_info, self.log_file.take());
+        let options = self.options;
+
+        let progress_thread = thread::spawn(move || {
+            let stats = progress_worker.start();
+            (stats, progress_worker)
+        });
+        let mut delete_result = Ok(());
+        if options.delete_timing == DeleteTiming::Before {
+            if let Some(delete_worker) = delete_worker.take() {
+                delete_result = delete_worker.start(options).map_err(SyncError::Delete);
+            }
+        }
+        let delete_thread = delete_worker
+            .take_if(|_| options.delete_timing == DeleteTiming::During)
+            .map(|delete_worker| thread::spawn(move || delete_worker.start(options)));
+        let walker_thread = thread::spawn(move || walk_worker.start(options));
+        let syncer_threads: Vec<_> = sync_workers
+            .into_iter()
+            .map(|sync_worker| thread::spawn(move || sync_worker.start(options)))
+            .collect();
 
-        let syncer_result = syncer_thread
+        let walker_result = walker_thread
             .join()
-            .map_err(|e| anyhow!("Could not join syncer thread: {:?}", e))?;
+            .map_err(|_| SyncError::Join("walker".to_string()))?
+            .map_err(SyncError::Walk);
 
-        let progress_result = progress_thread
+        let mut syncer_result = Ok(());
+        for syncer_thread in syncer_threads {
+            let result = syncer_thread
+                .join()
+                .map_err(|_| SyncError::Join("syncer".to_string()))?;
+            if syncer_result.is_ok() {
+                syncer_result = result.map_err(SyncError::Copy);
+            }
+        }
+        if let Some(delete_thread) = delete_thread {
+            delete_result = delete_thread
+                .join()
+                .map_err(|_| SyncError::Join("delete".to_string()))?
+                .map_err(SyncError::Delete);
+        }
+
+        // Checked once, so that whatever happens next agrees with the
+        // stats
+        let cancelled = self.cancel.is_cancelled();
+        if cancelled {
+            // Cannot fail: the progress worker waits for this sender
+            let _ = prune_output.send(ProgressMessage::Cancelled);
+        }
+        if self.options.remove_source_files
+            && !self.options.dry_run
+            && !cancelled
+            && walker_result.is_ok()
+            && syncer_result.is_ok()
+        {
+            for source in &self.sources {
+                fsops::remove_empty_dirs(&prune_output, source, source, &self.filters, false);
+            }
+        }
+
+        // With DeleteTiming::After, only delete once every entry has been
+        // synced, and never after a fatal error or a cancellation
+        if let Some(delete_worker) = delete_worker {
+            if matches!(walker_result, Ok(false)) {
+                // The entries of the directories that could not be read
+                // would look extraneous: like rsync, do not delete anything
+                let _ = prune_output.send(ProgressMessage::SyncWarning {
+                    entry: self.destination.to_string_lossy().to_string(),
+                    details: "Some source directories could not be read, not deleting anything"
+                        .to_string(),
+                });
+            } else if walker_result.is_ok() && syncer_result.is_ok() && !cancelled {
+                delete_result = delete_worker.start(self.options).map_err(SyncError::Delete);
+            }
+        }
+
+        if self.options.prune_empty_dirs
+            && !self.options.dry_run
+            && !cancelled
+            && walker_result.is_ok()
+            && syncer_result.is_ok()
+            && self.destination.is_dir()
+        {
+            fsops::remove_empty_dirs(
+                &prune_output,
+                &self.destination,
+                &self.destination,
+                &self.filters,
+                true,
+            );
+        }
+
+        // Last, since syncing their contents and deleting extraneous
+        // entries both change their modification times
+        let mut dir_result = Ok(());
+        if !self.options.dry_run && !cancelled && walker_result.is_ok() && syncer_result.is_ok() {
+            // The walker sends a directory before the ones it contains
+            let mut dirs: Vec<Entry> = dir_input.try_iter().collect();
+            dirs.reverse();
+            dir_result = dir_worker
+                .sync_dirs(dirs, self.options)
+                .map_err(SyncError::Copy);
+        }
+        drop(dir_worker);
+        drop(prune_output);
+
+        let (stats, progress_worker) = progress_thread
             .join()
-            .map_err(|e| anyhow!("Could not join progress thread: {:?}", e))?;
+            .map_err(|_| SyncError::Join("progress".to_string()))?;
+        let (progress_info, log_file) = progress_worker.into_parts();
+        if !self.options.quiet {
+            self.progress_info = progress_info;
+        }
+        self.log_file = log_file;
+
+        if self.options.partial_dir && !self.options.dry_run {
+            fsops::prune_partial_dir(&self.destination);
+        }
 
+        // Otherwise the files that were not seen may still exist
+        let walk_complete = matches!(walker_result, Ok(true));
+        walker_result?;
         syncer_result?;
+        delete_result?;
+        dir_result?;
 
-        Ok(progress_result)
+        if let Some(checksums) = checksums.filter(|_| !self.options.dry_run && !cancelled) {
+            checksums.save().map_err(SyncError::ChecksumCache)?;
+        }
+        if let Some(state) = state.filter(|_| !self.options.dry_run) {
+            state
+                .save(walk_complete && !cancelled)
+                .map_err(SyncError::State)?;
+        }
+        if let Some(state_db) = state_db.filter(|_| !self.options.dry_run) {
+            state_db
+                .save(walk_complete && !cancelled)
+                .map_err(SyncError::State)?;
+        }
+
+        if let Some((path, format)) = self
+            .manifest
+            .as_ref()
+            .filter(|_| !self.options.dry_run && !cancelled)
+        {
+            let in_destination = located_in(path, &self.destination);
+            let state_db_path = self
+                .state_db
+                .as_ref()
+                .and_then(|path| located_in(path, &self.destination));
+            let cache_path = self.destination.join(CACHE_FILE_NAME);
+            let state_path = self.destination.join(STATE_FILE_NAME);
+            let partial_dir = self.destination.join(fsops::PARTIAL_DIR_NAME);
+            let skip = |entry: &Path| {
+                in_destination.as_deref() == Some(entry)
+                    || state_db_path.as_deref() == Some(entry)
+                    || (self.options.checksum_cache && entry == cache_path)
+                    || (self.options.conflicts.is_some() && entry == state_path)
+                    || (self.options.partial_dir && entry.starts_with(&partial_dir))
+                    || backup.as_ref().is_some_and(|b| b.contains(entry))
+            };
+            let entries = manifest::collect(&self.destination, &skip, self.options.hash)
+                .map_err(SyncError::Manifest)?;
+            manifest::write(path, *format, self.options.hash, &entries)
+                .map_err(SyncError::Manifest)?;
+        }
+
+        Ok(stats)
+    }
+
+    pub(crate) fn check_destination(&self) -> Result<(), SyncError> {
+        if self.destination.exists() && !self.destination.is_dir() {
+            return Err(SyncError::InvalidDestination(self.destination.clone()));
+        }
+        if !self.destination.exists() && is_remote(&self.destination) {
+            // Better than creating a local directory named after the host
+            return Err(SyncError::RemoteDestination(self.destination.clone()));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_sources(&self) -> Result<(), SyncError> {
+        for source in &self.sources {
+            if !source.is_dir() {
+                return Err(SyncError::InvalidSource(source.clone()));
+            }
+        }
+        if self.sources.len() > 1 {
+            let canonical: Vec<PathBuf> = self
+                .sources
+                .iter()
+                .map(|source| source.canonicalize().unwrap_or_else(|_| source.clone()))
+                .collect();
+            for (i, inner) in canonical.iter().enumerate() {
+                for (j, outer) in canonical.iter().enumerate() {
+                    if i != j && inner.starts_with(outer) {
+                        return Err(SyncError::NestedSources(
+                            self.sources[i].clone(),
+                            self.sources[j].clone(),
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn backup(&self) -> Option<Backup> {
+        match &self.backup_dir {
+            Some(dir) => Some(Backup::new(
+                &self.destination.join(dir),
+                &self.backup_suffix,
+            )),
+            None if !self.backup_suffix.is_empty() => Some(Backup::beside(&self.backup_suffix)),
+            None => None,
+        }
+    }
+
+    // Comma-separated, for the ProgressInfo and the log file
+    fn describe_sources(&self) -> String {
+        let sources: Vec<_> = self
+            .sources
+            .iter()
+            .map(|source| source.to_string_lossy())
+            .collect();
+        sources.join(", ")
     }
 }
+
+// How long to wait for more changes before syncing again
+const WATCH_DEBOUNCE: std::time::Duration = std::time::Duration::from_secs(1);
+
+// How often `every()` checks the CancellationToken between two syncs
+const CANCEL_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+// If `path` is inside `dir`, returns it as `dir` joined with the relative
+// path, whatever the path to `dir` looks like. `path` may not exist yet
+fn located_in(path: &Path, dir: &Path) -> Option<PathBuf> {
+    let parent = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent.canonicalize().ok()?,
+        _ => std::env::current_dir().ok()?,
+    };
+    let path = parent.join(path.file_name()?);
+    let rel_path = path.strip_prefix(dir.canonicalize().ok()?).ok()?;
+    Some(dir.join(rel_path))
+}
+
+// Like rsync: `host:path` and `user@host:path` are remote, but only if
+// there is no slash before the colon, and single letters are drive names
+fn is_remote(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    match path.find(':') {
+        None => false,
+        Some(colon) => {
+            let host = &path[..colon];
+            colon > 1 && !host.contains('/') && !host.contains('\\')
+        }
+    }
+}
+
+// Used instead of the ProgressInfo given to the Syncer when `quiet` is set
+pub(crate) struct QuietProgressInfo {}
+
+impl ProgressInfo for QuietProgressInfo {}
@@ -0,0 +1,86 @@
+//! throttle
+//!
+//! Limit the rate at which bytes are transfered. A single Throttle is
+//! shared by all the sync workers, so the limit applies to the whole
+//! transfer and not to each file.
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct Throttle {
+    // In bytes per second, None means no limit
+    rate: Option<u64>,
+    // When the bytes consumed so far will have been 'paid for'
+    next_free: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    pub fn new(rate: Option<u64>) -> Throttle {
+        Throttle {
+            rate: rate.filter(|&r| r > 0),
+            next_free: Mutex::new(None),
+        }
+    }
+
+    /// Account for `num_bytes` being transfered, sleeping for as long as
+    /// needed to stay under the rate limit
+    pub fn consume(&self, num_bytes: usize) {
+        let rate = match self.rate {
+            None => return,
+            Some(rate) => rate,
+        };
+        let cost = Duration::from_secs_f64(num_bytes as f64 / rate as f64);
+        let now = Instant::now();
+        let wait_until = {
+            let mut next_free = self
+                .next_free
+                .lock()
+                .expect("another sync worker panicked while holding the throttle");
+            // Do not let idle periods turn into bursts later on
+            let start = match *next_free {
+                Some(instant) if instant > now => instant,
+                _ => now,
+            };
+            let wait_until = start + cost;
+            *next_free = Some(wait_until);
+            wait_until
+        };
+        if wait_until > now {
+            thread::sleep(wait_until - now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlimited_does_not_sleep() {
+        let throttle = Throttle::new(None);
+        let start = Instant::now();
+        throttle.consume(1024 * 1024 * 1024);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_limit_is_shared() {
+        let throttle = std::sync::Arc::new(Throttle::new(Some(100 * 1024)));
+        let start = Instant::now();
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let throttle = throttle.clone();
+                thread::spawn(move || {
+                    for _ in 0..5 {
+                        throttle.consume(2 * 1024);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        // 20 KiB at 100 KiB/s
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+}
@@ -0,0 +1,307 @@
+//! trash
+//!
+//! Move deleted destination entries to the trash of the desktop instead of
+//! removing them, see `SyncOptions.delete_to_trash`.
+//!
+//! On Linux and the BSDs, this follows the FreeDesktop.org trash
+//! specification: entries go to the home trash when they are on the same
+//! filesystem, and to the `.Trash-$uid` directory at the top of their
+//! filesystem otherwise, so that file managers can restore them. On macOS,
+//! entries are moved to `~/.Trash`, and on Windows they are sent to the
+//! Recycle Bin through PowerShell.
+use std::path::Path;
+
+use anyhow::Error;
+
+/// Move the entry at `path` to the trash. Directories are moved with their
+/// contents
+pub fn trash(path: &Path) -> Result<(), Error> {
+    platform::trash(path)
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+mod platform {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::{self, Write};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+
+    use anyhow::{bail, Context, Error};
+
+    pub fn trash(path: &Path) -> Result<(), Error> {
+        let path = absolute(path)?;
+        let home_trash = home_trash()?;
+        let device = fs::symlink_metadata(&path)
+            .with_context(|| format!("Could not read '{}'", path.display()))?
+            .dev();
+        if device_of(&home_trash) == Some(device) {
+            return move_to_trash(&path, &home_trash, &path);
+        }
+        let top_dir = top_dir(&path, device);
+        // getuid() cannot fail
+        let uid = unsafe { libc::getuid() };
+        let trash_dir = top_dir.join(format!(".Trash-{}", uid));
+        // Paths in the trash of a filesystem are relative to its top
+        let rel_path = path.strip_prefix(&top_dir).unwrap_or(&path);
+        move_to_trash(&path, &trash_dir, rel_path)
+    }
+
+    // Without resolving the last component, which may be a symlink
+    fn absolute(path: &Path) -> Result<PathBuf, Error> {
+        let name = match path.file_name() {
+            Some(name) => name,
+            None => bail!("Cannot move '{}' to the trash", path.display()),
+        };
+        let parent = match path.parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        };
+        let parent = fs::canonicalize(parent)
+            .with_context(|| format!("Could not resolve '{}'", parent.display()))?;
+        Ok(parent.join(name))
+    }
+
+    fn home_trash() -> Result<PathBuf, Error> {
+        let data_home = match env::var_os("XDG_DATA_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => match env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(".local/share"),
+                None => bail!("Could not find the home trash: HOME is not set"),
+            },
+        };
+        Ok(data_home.join("Trash"))
+    }
+
+    // The device of `path`, or of its closest existing ancestor
+    fn device_of(path: &Path) -> Option<u64> {
+        path.ancestors()
+            .find_map(|dir| fs::metadata(dir).ok())
+            .map(|metadata| metadata.dev())
+    }
+
+    // The topmost ancestor of `path` still on `device`: its mount point
+    fn top_dir(path: &Path, device: u64) -> PathBuf {
+        let mut top_dir = path.parent().unwrap_or(path);
+        while let Some(parent) = top_dir.parent() {
+            match fs::metadata(parent) {
+                Ok(metadata) if metadata.dev() == device => top_dir = parent,
+                _ => break,
+            }
+        }
+        top_dir.to_path_buf()
+    }
+
+    // Move `path` to `trash_dir`, recording `original` as where it was
+    pub fn move_to_trash(path: &Path, trash_dir: &Path, original: &Path) -> Result<(), Error> {
+        let context = || {
+            format!(
+                "Could not move '{}' to the trash in '{}'",
+                path.display(),
+                trash_dir.display()
+            )
+        };
+        let files_dir = trash_dir.join("files");
+        let info_dir = trash_dir.join("info");
+        if !trash_dir.exists() {
+            fs::create_dir_all(trash_dir).with_context(context)?;
+            // Other users must not see what is trashed
+            fs::set_permissions(trash_dir, fs::Permissions::from_mode(0o700))
+                .with_context(context)?;
+        }
+        fs::create_dir_all(&files_dir).with_context(context)?;
+        fs::create_dir_all(&info_dir).with_context(context)?;
+
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let info = format!(
+            "[Trash Info]\nPath={}\nDeletionDate={}\n",
+            encode(original),
+            deletion_date()
+        );
+        for attempt in 1.. {
+            let trashed_name = if attempt == 1 {
+                name.to_string()
+            } else {
+                format!("{}.{}", name, attempt)
+            };
+            // Creating the info file first reserves the name
+            let info_path = info_dir.join(format!("{}.trashinfo", trashed_name));
+            let mut info_file = match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&info_path)
+            {
+                Ok(file) => file,
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e).with_context(context),
+            };
+            let trashed_path = files_dir.join(&trashed_name);
+            if trashed_path.exists() {
+                let _ = fs::remove_file(&info_path);
+                continue;
+            }
+            let moved = info_file
+                .write_all(info.as_bytes())
+                .and_then(|()| fs::rename(path, &trashed_path));
+            if let Err(e) = moved {
+                let _ = fs::remove_file(&info_path);
+                return Err(e).with_context(context);
+            }
+            return Ok(());
+        }
+        unreachable!()
+    }
+
+    // Percent-encode the path, as the specification requires
+    fn encode(path: &Path) -> String {
+        let mut encoded = String::new();
+        for &byte in path.as_os_str().as_bytes() {
+            if byte.is_ascii_alphanumeric() || b"/-_.~".contains(&byte) {
+                encoded.push(byte as char);
+            } else {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        }
+        encoded
+    }
+
+    // The current local time, like 2004-08-31T22:32:08
+    fn deletion_date() -> String {
+        // localtime_r() only writes to `tm`, and time() is given a
+        // null pointer
+        unsafe {
+            let now = libc::time(std::ptr::null_mut());
+            let mut tm: libc::tm = std::mem::zeroed();
+            if libc::localtime_r(&now, &mut tm).is_null() {
+                return String::new();
+            }
+            format!(
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+                tm.tm_year + 1900,
+                tm.tm_mon + 1,
+                tm.tm_mday,
+                tm.tm_hour,
+                tm.tm_min,
+                tm.tm_sec
+            )
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use tempdir::TempDir;
+
+        #[test]
+        fn test_move_to_trash() {
+            let tmp_dir = TempDir::new("test-rusync-trash").unwrap();
+            let trash_dir = tmp_dir.path().join("Trash");
+            let path = tmp_dir.path().join("my file.txt");
+            let original = Path::new("/dest/my file.txt");
+
+            fs::write(&path, "first").unwrap();
+            move_to_trash(&path, &trash_dir, original).unwrap();
+            fs::write(&path, "second").unwrap();
+            move_to_trash(&path, &trash_dir, original).unwrap();
+
+            assert!(!path.exists());
+            let files_dir = trash_dir.join("files");
+            assert_eq!(
+                fs::read_to_string(files_dir.join("my file.txt")).unwrap(),
+                "first"
+            );
+            assert_eq!(
+                fs::read_to_string(files_dir.join("my file.txt.2")).unwrap(),
+                "second"
+            );
+            let info = fs::read_to_string(trash_dir.join("info/my file.txt.2.trashinfo")).unwrap();
+            assert!(info.starts_with("[Trash Info]\nPath=/dest/my%20file.txt\nDeletionDate="));
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use anyhow::{bail, Context, Error};
+
+    pub fn trash(path: &Path) -> Result<(), Error> {
+        let trash_dir = match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".Trash"),
+            None => bail!("Could not find the trash: HOME is not set"),
+        };
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => bail!("Cannot move '{}' to the trash", path.display()),
+        };
+        // Like the Finder, add a number to the names already in the trash
+        let mut trashed_path = trash_dir.join(&name);
+        let mut attempt = 1;
+        while fs::symlink_metadata(&trashed_path).is_ok() {
+            attempt += 1;
+            trashed_path = trash_dir.join(format!("{} {}", name, attempt));
+        }
+        fs::rename(path, &trashed_path).with_context(|| {
+            format!(
+                "Could not move '{}' to '{}'",
+                path.display(),
+                trashed_path.display()
+            )
+        })
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::path::Path;
+    use std::process::Command;
+
+    use anyhow::{bail, Context, Error};
+
+    pub fn trash(path: &Path) -> Result<(), Error> {
+        let method = if path.is_dir() {
+            "DeleteDirectory"
+        } else {
+            "DeleteFile"
+        };
+        let path = std::fs::canonicalize(path)
+            .with_context(|| format!("Could not resolve '{}'", path.display()))?;
+        // Single quotes are escaped by doubling them in PowerShell
+        let quoted = path.to_string_lossy().replace('\'', "''");
+        let script = format!(
+            "Add-Type -AssemblyName Microsoft.VisualBasic; \
+             [Microsoft.VisualBasic.FileIO.FileSystem]::{}('{}', 'OnlyErrorDialogs', 'SendToRecycleBin')",
+            method, quoted
+        );
+        let output = Command::new("powershell")
+            .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+            .output()
+            .context("Could not run powershell")?;
+        if !output.status.success() {
+            bail!(
+                "Could not move '{}' to the Recycle Bin: {}",
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    use std::path::Path;
+
+    use anyhow::{bail, Error};
+
+    pub fn trash(path: &Path) -> Result<(), Error> {
+        bail!(
+            "Cannot move '{}' to the trash: not supported on this platform",
+            path.display()
+        )
+    }
+}
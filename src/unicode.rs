@@ -0,0 +1,180 @@
+//! unicode
+//!
+//! Canonical normalization of file names (NFC and NFD), see
+//! `SyncOptions.normalization`. The tables come from
+//! `tools/gen-unicode-tables.py`
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+use crate::unicode_tables::{COMBINING_CLASSES, COMPOSITIONS, DECOMPOSITIONS};
+
+// Hangul syllables are decomposed and composed algorithmically
+const S_BASE: u32 = 0xAC00;
+const L_BASE: u32 = 0x1100;
+const V_BASE: u32 = 0x1161;
+const T_BASE: u32 = 0x11A7;
+const L_COUNT: u32 = 19;
+const V_COUNT: u32 = 21;
+const T_COUNT: u32 = 28;
+const N_COUNT: u32 = V_COUNT * T_COUNT;
+const S_COUNT: u32 = L_COUNT * N_COUNT;
+
+/// Composed form, as used by Linux and Windows
+pub fn nfc(s: &str) -> Cow<'_, str> {
+    if s.is_ascii() {
+        return Cow::Borrowed(s);
+    }
+    Cow::Owned(compose(&decompose(s)))
+}
+
+/// Decomposed form, as used by macOS
+pub fn nfd(s: &str) -> Cow<'_, str> {
+    if s.is_ascii() {
+        return Cow::Borrowed(s);
+    }
+    Cow::Owned(decompose(s).into_iter().collect())
+}
+
+fn decompose(s: &str) -> Vec<char> {
+    let mut chars = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        decompose_char(c, &mut chars);
+    }
+    // Put combining marks in canonical order
+    let mut start = 0;
+    while start < chars.len() {
+        let end = start
+            + chars[start..]
+                .iter()
+                .take_while(|&&c| combining_class(c) != 0)
+                .count();
+        if end == start {
+            start += 1;
+        } else {
+            // Stable, so marks of the same class keep their order
+            chars[start..end].sort_by_key(|&c| combining_class(c));
+            start = end;
+        }
+    }
+    chars
+}
+
+fn decompose_char(c: char, out: &mut Vec<char>) {
+    let code = c as u32;
+    if (S_BASE..S_BASE + S_COUNT).contains(&code) {
+        let index = code - S_BASE;
+        out.push(to_char(L_BASE + index / N_COUNT));
+        out.push(to_char(V_BASE + (index % N_COUNT) / T_COUNT));
+        if !index.is_multiple_of(T_COUNT) {
+            out.push(to_char(T_BASE + index % T_COUNT));
+        }
+        return;
+    }
+    match DECOMPOSITIONS.binary_search_by_key(&c, |&(composed, _, _)| composed) {
+        Ok(i) => {
+            let (_, first, second) = DECOMPOSITIONS[i];
+            decompose_char(first, out);
+            if second != '\0' {
+                decompose_char(second, out);
+            }
+        }
+        Err(_) => out.push(c),
+    }
+}
+
+fn compose(chars: &[char]) -> String {
+    let mut out: Vec<char> = Vec::with_capacity(chars.len());
+    // Index in `out` of the last starter, and class of the last character
+    let mut starter: Option<usize> = None;
+    let mut last_class = 0;
+    for &c in chars {
+        let class = combining_class(c);
+        if let Some(starter) = starter {
+            // A character between the starter and this one blocks the
+            // composition if it is a starter or of the same class or higher
+            let blocked = out.len() - 1 != starter && (last_class == 0 || last_class >= class);
+            if !blocked {
+                if let Some(composed) = compose_pair(out[starter], c) {
+                    out[starter] = composed;
+                    continue;
+                }
+            }
+        }
+        if class == 0 {
+            starter = Some(out.len());
+        }
+        last_class = class;
+        out.push(c);
+    }
+    out.into_iter().collect()
+}
+
+fn compose_pair(first: char, second: char) -> Option<char> {
+    let (a, b) = (first as u32, second as u32);
+    if (L_BASE..L_BASE + L_COUNT).contains(&a) && (V_BASE..V_BASE + V_COUNT).contains(&b) {
+        let index = (a - L_BASE) * N_COUNT + (b - V_BASE) * T_COUNT;
+        return Some(to_char(S_BASE + index));
+    }
+    if (S_BASE..S_BASE + S_COUNT).contains(&a)
+        && (a - S_BASE).is_multiple_of(T_COUNT)
+        && (T_BASE + 1..T_BASE + T_COUNT).contains(&b)
+    {
+        return Some(to_char(a + b - T_BASE));
+    }
+    COMPOSITIONS
+        .binary_search_by_key(&(first, second), |&(a, b, _)| (a, b))
+        .ok()
+        .map(|i| COMPOSITIONS[i].2)
+}
+
+fn combining_class(c: char) -> u8 {
+    let found = COMBINING_CLASSES.binary_search_by(|&(start, end, _)| {
+        if end < c {
+            Ordering::Less
+        } else if start > c {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    });
+    found.map_or(0, |i| COMBINING_CLASSES[i].2)
+}
+
+fn to_char(code: u32) -> char {
+    // Only called with code points computed from the Hangul ranges
+    char::from_u32(code).expect("invalid Hangul code point")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latin() {
+        assert_eq!(nfc("Cafe\u{301}.txt"), "Caf\u{E9}.txt");
+        assert_eq!(nfd("Caf\u{E9}.txt"), "Cafe\u{301}.txt");
+        assert_eq!(nfc("plain.txt"), "plain.txt");
+    }
+
+    #[test]
+    fn test_canonical_order() {
+        // Dot below (class 220) goes before the circumflex (class 230)
+        assert_eq!(nfd("a\u{302}\u{323}"), "a\u{323}\u{302}");
+        assert_eq!(nfc("a\u{302}\u{323}"), "\u{1EAD}");
+        assert_eq!(nfc("\u{E2}\u{323}"), "\u{1EAD}");
+    }
+
+    #[test]
+    fn test_exclusions() {
+        // Singletons never come back, even in NFC
+        assert_eq!(nfc("\u{212B}"), "\u{C5}");
+        assert_eq!(nfc("\u{F900}"), "\u{8C48}");
+    }
+
+    #[test]
+    fn test_hangul() {
+        assert_eq!(nfd("\u{D55C}"), "\u{1112}\u{1161}\u{11AB}");
+        assert_eq!(nfc("\u{1112}\u{1161}\u{11AB}"), "\u{D55C}");
+        assert_eq!(nfc("\u{1112}\u{1161}"), "\u{D558}");
+    }
+}
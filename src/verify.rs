@@ -0,0 +1,206 @@
+//! verify
+//!
+//! Compare the source and the destination without changing anything
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+use filetime::FileTime;
+
+use crate::backup::Backup;
+use crate::entry::Entry;
+use crate::filesystem::{FileKind, Filesystem};
+use crate::filters::{self, Filters};
+use crate::fsops;
+use crate::sync::{SymlinkPolicy, SyncOptions};
+
+/// How to tell whether a file of the destination matches the source
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Comparison {
+    /// Only compare sizes
+    Size,
+    /// Compare sizes and modification times
+    ModificationTime,
+    /// Compare sizes and contents (using SHA-256)
+    Checksum,
+}
+
+impl std::str::FromStr for Comparison {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "size" => Ok(Comparison::Size),
+            "mtime" => Ok(Comparison::ModificationTime),
+            "checksum" => Ok(Comparison::Checksum),
+            _ => Err(format!(
+                "invalid comparison: '{}' (expected size, mtime or checksum)",
+                s
+            )),
+        }
+    }
+}
+
+/// Differences found by `Syncer::verify()`. All paths are relative to the
+/// source (or to the destination, for extraneous entries), and sorted
+#[derive(Debug, Default)]
+pub struct Verification {
+    /// Entries of the source missing from the destination
+    pub missing: Vec<PathBuf>,
+    /// Entries of the destination that do not exist in the source
+    pub extra: Vec<PathBuf>,
+    /// Entries that exist on both sides but differ, and how
+    pub differing: Vec<(PathBuf, String)>,
+    /// Entries that could not be compared, and what went wrong
+    pub errors: Vec<(PathBuf, io::Error)>,
+}
+
+impl Verification {
+    /// Returns true if no difference was found and every entry could be
+    /// compared
+    pub fn is_identical(&self) -> bool {
+        self.missing.is_empty()
+            && self.extra.is_empty()
+            && self.differing.is_empty()
+            && self.errors.is_empty()
+    }
+
+    pub(crate) fn sort(&mut self) {
+        self.missing.sort();
+        self.extra.sort();
+        self.differing.sort_by(|a, b| a.0.cmp(&b.0));
+        self.errors.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+
+    /// Compare an entry of the source, as sent by the walker, with the one
+    /// at the same path in the destination
+    pub(crate) fn check(
+        &mut self,
+        src: &Entry,
+        destination: &Path,
+        comparison: Comparison,
+        opts: SyncOptions,
+    ) {
+        let rel_path = PathBuf::from(src.description());
+        let dest = Entry::new(src.description(), &destination.join(&rel_path));
+        match compare(src, &dest, comparison, opts) {
+            Ok(Difference::None) => {}
+            Ok(Difference::Missing) => self.missing.push(rel_path),
+            Ok(Difference::Differs(how)) => self.differing.push((rel_path, how.to_string())),
+            Err(e) => {
+                let error = io::Error::new(fsops::error_kind(&e), format!("{:#}", e));
+                self.errors.push((rel_path, error));
+            }
+        }
+    }
+}
+
+enum Difference {
+    None,
+    Missing,
+    Differs(&'static str),
+}
+
+fn compare(
+    src: &Entry,
+    dest: &Entry,
+    comparison: Comparison,
+    opts: SyncOptions,
+) -> Result<Difference, Error> {
+    let src_meta = src.metadata().expect("src_meta should not be None");
+    // Entries that are not synced cannot be missing
+    if src.is_link() == Some(true) && opts.symlinks == SymlinkPolicy::Skip {
+        return Ok(Difference::None);
+    }
+    if fsops::is_special(src) && !opts.copy_specials {
+        return Ok(Difference::None);
+    }
+    let dest_meta = match dest.metadata() {
+        None => return Ok(Difference::Missing),
+        Some(dest_meta) => dest_meta,
+    };
+    if src_meta.file_type() != dest_meta.file_type() {
+        return Ok(Difference::Differs("type differs"));
+    }
+    if src.is_link() == Some(true) {
+        if std::fs::read_link(src.path())? != std::fs::read_link(dest.path())? {
+            return Ok(Difference::Differs("symlink target differs"));
+        }
+        return Ok(Difference::None);
+    }
+    if !src_meta.is_file() {
+        return Ok(Difference::None);
+    }
+    if src_meta.len() != dest_meta.len() {
+        return Ok(Difference::Differs("size differs"));
+    }
+    let difference = match comparison {
+        Comparison::Size => Difference::None,
+        Comparison::ModificationTime => {
+            if FileTime::from_last_modification_time(src_meta)
+                != FileTime::from_last_modification_time(dest_meta)
+            {
+                Difference::Differs("modification time differs")
+            } else {
+                Difference::None
+            }
+        }
+        Comparison::Checksum => {
+            if fsops::has_different_contents(src, dest, None, opts.hash)? {
+                Difference::Differs("contents differ")
+            } else {
+                Difference::None
+            }
+        }
+    };
+    Ok(difference)
+}
+
+/// Add the entries of `destination` that have no counterpart in any of the
+/// `sources` to `verification.extra`. Like when deleting extraneous entries, the
+/// contents of extraneous directories are not listed, and excluded
+/// entries and the backup dir are left alone
+pub(crate) fn find_extra(
+    verification: &mut Verification,
+    sources: &[PathBuf],
+    destination: &Path,
+    filesystem: &dyn Filesystem,
+    filters: &Filters,
+    backup: Option<&Backup>,
+    opts: SyncOptions,
+) {
+    if !filesystem.is_dir(destination) {
+        return;
+    }
+    let mut dirs = vec![destination.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match filesystem.list(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                let rel_path = fsops::get_rel_path(&dir, destination);
+                verification.errors.push((rel_path, e));
+                continue;
+            }
+        };
+        for dest_path in entries {
+            if backup.is_some_and(|b| b.contains(&dest_path)) {
+                continue;
+            }
+            let rel_path = fsops::get_rel_path(&dest_path, destination);
+            let is_dir = filesystem.stat(&dest_path).ok() == Some(FileKind::Dir);
+            if filters.is_excluded(&rel_path, is_dir)
+                || (opts.skip_hidden && filters::is_hidden(&dest_path))
+            {
+                continue;
+            }
+            if !sources
+                .iter()
+                .any(|source| filesystem.exists(&source.join(&rel_path)))
+            {
+                verification.extra.push(rel_path);
+            } else if is_dir {
+                dirs.push(dest_path);
+            }
+        }
+    }
+}
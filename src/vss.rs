@@ -0,0 +1,151 @@
+//! vss
+//!
+//! Volume Shadow Copy snapshots of the sources on Windows, see
+//! `SyncOptions.vss`.
+//!
+//! Snapshots are created and deleted with the `Win32_ShadowCopy` WMI class,
+//! through PowerShell, which requires running as administrator. Files kept
+//! open by other programs, like databases or Outlook PST files, are then
+//! read as they were when the snapshot was taken.
+use std::path::PathBuf;
+
+use anyhow::Error;
+
+#[cfg(windows)]
+mod windows {
+    use std::path::{Component, Path, PathBuf, Prefix};
+    use std::process::Command;
+
+    use anyhow::{bail, Context, Error};
+
+    pub struct Snapshot {
+        id: String,
+        volume: String,
+        // Like \\?\GLOBALROOT\Device\HarddiskVolumeShadowCopy1
+        device: String,
+    }
+
+    // Like C:\ for C:\Users\me, or \\?\C:\Users\me
+    pub fn volume(path: &Path) -> Result<String, Error> {
+        match path.components().next() {
+            Some(Component::Prefix(prefix)) => match prefix.kind() {
+                Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => {
+                    Ok(format!("{}:\\", letter as char))
+                }
+                _ => bail!(
+                    "{} is not on a local volume, which snapshots require",
+                    path.display()
+                ),
+            },
+            _ => bail!("{} is not an absolute path", path.display()),
+        }
+    }
+
+    fn powershell(script: &str) -> Result<String, Error> {
+        let output = Command::new("powershell")
+            .args(["-NoProfile", "-NonInteractive", "-Command", script])
+            .output()
+            .context("Could not run powershell")?;
+        if !output.status.success() {
+            bail!(
+                "powershell failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    pub fn create(volume: &str) -> Result<Snapshot, Error> {
+        let script = format!(
+            "$result = (Get-WmiObject -List Win32_ShadowCopy).Create('{}', 'ClientAccessible'); \
+             if ($result.ReturnValue -ne 0) {{ [Console]::Error.WriteLine(\"error $($result.ReturnValue)\"); exit 1 }}; \
+             $copy = Get-WmiObject Win32_ShadowCopy -Filter \"ID='$($result.ShadowID)'\"; \
+             Write-Output $copy.ID; Write-Output $copy.DeviceObject",
+            volume
+        );
+        let output = powershell(&script)
+            .with_context(|| format!("Could not create a snapshot of {}", volume))?;
+        let mut lines = output.lines().map(str::trim).filter(|l| !l.is_empty());
+        match (lines.next(), lines.next()) {
+            (Some(id), Some(device)) => Ok(Snapshot {
+                id: id.to_string(),
+                volume: volume.to_string(),
+                device: device.to_string(),
+            }),
+            _ => bail!("Unexpected output when creating a snapshot of {}", volume),
+        }
+    }
+
+    impl Snapshot {
+        pub fn volume(&self) -> &str {
+            &self.volume
+        }
+
+        // Where the canonical `path` is in the snapshot
+        pub fn translate(&self, path: &Path) -> PathBuf {
+            // The components after the prefix and the root
+            let rel_path: PathBuf = path
+                .components()
+                .filter(|c| matches!(c, Component::Normal(_)))
+                .collect();
+            PathBuf::from(format!("{}\\{}", self.device, rel_path.display()))
+        }
+    }
+
+    impl Drop for Snapshot {
+        fn drop(&mut self) {
+            let script = format!(
+                "Get-WmiObject Win32_ShadowCopy -Filter \"ID='{}'\" | ForEach-Object {{ $_.Delete() }}",
+                self.id
+            );
+            // Nothing to report it to: leftover snapshots can be removed
+            // with vssadmin
+            let _ = powershell(&script);
+        }
+    }
+}
+
+/// Snapshots of the volumes containing the sources, deleted when dropped
+#[cfg(windows)]
+pub struct Snapshots {
+    // Only kept to be dropped
+    _snapshots: Vec<windows::Snapshot>,
+}
+
+#[cfg(not(windows))]
+pub struct Snapshots(std::convert::Infallible);
+
+/// Take a snapshot of each volume containing one of the `sources`, and
+/// return where each source is in them
+#[cfg(windows)]
+pub fn snapshot(sources: &[PathBuf]) -> Result<(Snapshots, Vec<PathBuf>), Error> {
+    use anyhow::Context;
+
+    let mut snapshots: Vec<windows::Snapshot> = vec![];
+    let mut paths = vec![];
+    for source in sources {
+        let canonical = std::fs::canonicalize(source)
+            .with_context(|| format!("Could not resolve {}", source.display()))?;
+        let volume = windows::volume(&canonical)?;
+        let index = match snapshots.iter().position(|s| s.volume() == volume) {
+            Some(index) => index,
+            None => {
+                snapshots.push(windows::create(&volume)?);
+                snapshots.len() - 1
+            }
+        };
+        paths.push(snapshots[index].translate(&canonical));
+    }
+    Ok((
+        Snapshots {
+            _snapshots: snapshots,
+        },
+        paths,
+    ))
+}
+
+#[cfg(not(windows))]
+pub fn snapshot(_sources: &[PathBuf]) -> Result<(Snapshots, Vec<PathBuf>), Error> {
+    anyhow::bail!("Volume Shadow Copy snapshots are only available on Windows")
+}
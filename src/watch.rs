@@ -0,0 +1,202 @@
+//! watch
+//!
+//! Wait for changes in the source directory. On Linux this uses inotify,
+//! with one watch per directory. Elsewhere, we simply wait for a while,
+//! which turns watching into syncing at a fixed interval.
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+pub use self::inotify::Watcher;
+
+#[cfg(not(target_os = "linux"))]
+pub use self::polling::Watcher;
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use super::*;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+
+    extern "C" {
+        fn inotify_init1(flags: libc::c_int) -> libc::c_int;
+        fn inotify_add_watch(
+            fd: libc::c_int,
+            pathname: *const libc::c_char,
+            mask: u32,
+        ) -> libc::c_int;
+    }
+
+    // From sys/inotify.h
+    const IN_MODIFY: u32 = 0x0000_0002;
+    const IN_ATTRIB: u32 = 0x0000_0004;
+    const IN_CLOSE_WRITE: u32 = 0x0000_0008;
+    const IN_MOVED_FROM: u32 = 0x0000_0040;
+    const IN_MOVED_TO: u32 = 0x0000_0080;
+    const IN_CREATE: u32 = 0x0000_0100;
+    const IN_DELETE: u32 = 0x0000_0200;
+    const IN_DELETE_SELF: u32 = 0x0000_0400;
+    const IN_MOVE_SELF: u32 = 0x0000_0800;
+    const IN_ONLYDIR: u32 = 0x0100_0000;
+    const WATCH_MASK: u32 = IN_MODIFY
+        | IN_ATTRIB
+        | IN_CLOSE_WRITE
+        | IN_MOVED_FROM
+        | IN_MOVED_TO
+        | IN_CREATE
+        | IN_DELETE
+        | IN_DELETE_SELF
+        | IN_MOVE_SELF
+        | IN_ONLYDIR;
+
+    pub struct Watcher {
+        fd: libc::c_int,
+    }
+
+    impl Watcher {
+        /// Start watching `root` and all the directories it contains.
+        /// Directories created later on are not watched: create a new
+        /// Watcher after each sync
+        pub fn new(root: &Path) -> io::Result<Watcher> {
+            let fd = unsafe { inotify_init1(libc::O_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let watcher = Watcher { fd };
+            watcher.add_watches(root)?;
+            Ok(watcher)
+        }
+
+        /// Also watch `root` and all the directories it contains
+        pub fn add(&self, root: &Path) -> io::Result<()> {
+            self.add_watches(root)
+        }
+
+        fn add_watches(&self, dir: &Path) -> io::Result<()> {
+            let c_path = std::ffi::CString::new(dir.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let res = unsafe { inotify_add_watch(self.fd, c_path.as_ptr(), WATCH_MASK) };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                // Never follow symlinks, like the walker
+                if entry.file_type()?.is_dir() {
+                    // The directory may be gone already
+                    match self.add_watches(&entry.path()) {
+                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                        other => other?,
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        /// Block until something changes, and then until nothing changed
+        /// for `debounce`
+        pub fn wait(&self, debounce: Duration) -> io::Result<()> {
+            self.read_events(None)?;
+            while self.read_events(Some(debounce))? {}
+            Ok(())
+        }
+
+        // Returns false if nothing happened before the timeout
+        fn read_events(&self, timeout: Option<Duration>) -> io::Result<bool> {
+            let mut pollfd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let timeout = match timeout {
+                Some(timeout) => timeout.as_millis() as libc::c_int,
+                None => -1,
+            };
+            let res = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+            if res < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    return Ok(true);
+                }
+                return Err(error);
+            }
+            if res == 0 {
+                return Ok(false);
+            }
+            // The contents of the events do not matter, the whole source
+            // is synced again anyway
+            let mut buffer = [0_u8; 4096];
+            let num_read = unsafe {
+                libc::read(
+                    self.fd,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if num_read < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(true)
+        }
+    }
+
+    impl Drop for Watcher {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.fd);
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod polling {
+    use super::*;
+
+    const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+    pub struct Watcher {}
+
+    impl Watcher {
+        pub fn new(_root: &Path) -> io::Result<Watcher> {
+            Ok(Watcher {})
+        }
+
+        pub fn add(&self, _root: &Path) -> io::Result<()> {
+            Ok(())
+        }
+
+        pub fn wait(&self, debounce: Duration) -> io::Result<()> {
+            std::thread::sleep(POLL_INTERVAL + debounce);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_wait_for_changes_in_subdirs() {
+        let tmp_dir = TempDir::new("test-rusync-watch").unwrap();
+        let sub_dir = tmp_dir.path().join("a/b");
+        std::fs::create_dir_all(&sub_dir).unwrap();
+        let watcher = Watcher::new(tmp_dir.path()).unwrap();
+
+        let (done_output, done_input) = channel();
+        let waiter = thread::spawn(move || {
+            watcher.wait(Duration::from_millis(50)).unwrap();
+            done_output.send(()).unwrap();
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(done_input.try_recv().is_err());
+        std::fs::write(sub_dir.join("new.txt"), "new").unwrap();
+        waiter.join().unwrap();
+        assert!(done_input.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Error};
+
+use crate::backup::Backup;
+use crate::cancel::CancellationToken;
+use crate::filesystem::{FileKind, Filesystem};
+use crate::filters::{self, Filters};
+use crate::fsops;
+use crate::names;
+use crate::progress::{Change, ProgressMessage};
+use crate::state_db::{Kind, StateDb};
+use crate::sync::{DeleteTiming, SyncOptions, WalkOrder};
+use crate::trash;
+
+pub struct DeleteWorker {
+    output: Sender<ProgressMessage>,
+    sources: Vec<PathBuf>,
+    destination: PathBuf,
+    source_fs: Arc<dyn Filesystem>,
+    dest_fs: Arc<dyn Filesystem>,
+    backup: Option<Backup>,
+    filters: Filters,
+    state_db: Option<Arc<StateDb>>,
+    cancel: CancellationToken,
+}
+
+impl DeleteWorker {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        sources: &[PathBuf],
+        destination: &Path,
+        source_fs: Arc<dyn Filesystem>,
+        dest_fs: Arc<dyn Filesystem>,
+        backup: Option<Backup>,
+        filters: Filters,
+        state_db: Option<Arc<StateDb>>,
+        cancel: CancellationToken,
+        output: Sender<ProgressMessage>,
+    ) -> DeleteWorker {
+        DeleteWorker {
+            output,
+            sources: sources.to_vec(),
+            destination: destination.to_path_buf(),
+            source_fs,
+            dest_fs,
+            backup,
+            filters,
+            state_db,
+            cancel,
+        }
+    }
+
+    pub fn start(self, opts: SyncOptions) -> Result<(), Error> {
+        if !self.dest_fs.is_dir(&self.destination) {
+            return Ok(());
+        }
+        let mut extraneous = vec![];
+        let mut listed = 0;
+        match self.state_db.as_deref().filter(|db| db.has_previous()) {
+            Some(state_db) => self.find_recorded(state_db, &mut extraneous, &mut listed, opts)?,
+            None => self.find_extraneous(&self.destination, &mut extraneous, &mut listed, opts)?,
+        }
+        if let Some(max_delete) = opts.max_delete {
+            let count: u64 = extraneous.iter().map(|path| self.count_entries(path)).sum();
+            // The contents of extraneous directories were not listed
+            let total = listed - extraneous.len() as u64 + count;
+            if max_delete.is_exceeded(count, total) {
+                bail!(
+                    "Not deleting anything: {} entries are extraneous, more than the limit of {}",
+                    count,
+                    max_delete
+                );
+            }
+        }
+        // Backups take precedence over the trash
+        let to_trash = opts.delete_to_trash && self.backup.is_none();
+        for dest_path in extraneous {
+            if self.cancel.is_cancelled() {
+                break;
+            }
+            if opts.dry_run {
+                self.plan_removal(&dest_path, to_trash)?;
+            } else if to_trash {
+                self.send_outcome(&dest_path, trash::trash(&dest_path))?;
+            } else {
+                self.remove(&dest_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Find every entry of `dest_dir` that has no counterpart in any of the
+    // sources, and recurse into the directories that do. `listed` counts the
+    // entries of the directories walked
+    fn find_extraneous(
+        &self,
+        dest_dir: &Path,
+        extraneous: &mut Vec<PathBuf>,
+        listed: &mut u64,
+        opts: SyncOptions,
+    ) -> Result<(), Error> {
+        if opts.delete_timing != DeleteTiming::After && !self.sources_readable(dest_dir)? {
+            return Ok(());
+        }
+        let mut entries = match self.dest_fs.list(dest_dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                let details = format!("Could not read '{}': {}", dest_dir.display(), e);
+                return self.send_error(dest_dir, &details, e.kind());
+            }
+        };
+        if opts.walk_order == WalkOrder::Sorted {
+            entries.sort();
+        }
+        *listed += entries.len() as u64;
+        for dest_path in entries {
+            if self.cancel.is_cancelled() {
+                return Ok(());
+            }
+            let rel_path = fsops::get_rel_path(&dest_path, &self.destination);
+            let is_dir = self.dest_fs.is_dir(&dest_path);
+            if self.is_protected(&dest_path, &rel_path, is_dir, opts) {
+                continue;
+            }
+            if !self.in_sources(&rel_path, opts) {
+                extraneous.push(dest_path);
+            } else if is_dir {
+                self.find_extraneous(&dest_path, extraneous, listed, opts)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Like find_extraneous(), but only among the entries recorded in the
+    // state database by the previous sync, without walking the destination
+    fn find_recorded(
+        &self,
+        state_db: &StateDb,
+        extraneous: &mut Vec<PathBuf>,
+        listed: &mut u64,
+        opts: SyncOptions,
+    ) -> Result<(), Error> {
+        let entries = state_db.previous_entries();
+        *listed = entries.len() as u64;
+        let mut readable_dirs = HashMap::new();
+        for (rel_path, kind) in entries {
+            if self.cancel.is_cancelled() {
+                return Ok(());
+            }
+            let dest_path = self.destination.join(&rel_path);
+            // Removed with their parents
+            if extraneous
+                .last()
+                .is_some_and(|last| dest_path.starts_with(last))
+            {
+                continue;
+            }
+            if !self.dest_fs.exists(&dest_path) {
+                continue;
+            }
+            let dest_dir = dest_path.parent().unwrap_or(&self.destination);
+            if opts.delete_timing != DeleteTiming::After {
+                let readable = match readable_dirs.get(dest_dir) {
+                    Some(&readable) => readable,
+                    None => {
+                        let readable = self.sources_readable(dest_dir)?;
+                        readable_dirs.insert(dest_dir.to_path_buf(), readable);
+                        readable
+                    }
+                };
+                if !readable {
+                    continue;
+                }
+            }
+            if self.is_protected(&dest_path, &rel_path, kind == Kind::Dir, opts) {
+                continue;
+            }
+            if !self.in_sources(&rel_path, opts) {
+                extraneous.push(dest_path);
+            }
+        }
+        Ok(())
+    }
+
+    // Returns true if the destination entry must be kept even if it has no
+    // counterpart in the sources
+    fn is_protected(
+        &self,
+        dest_path: &Path,
+        rel_path: &Path,
+        is_dir: bool,
+        opts: SyncOptions,
+    ) -> bool {
+        if self.backup.as_ref().is_some_and(|b| b.contains(dest_path)) {
+            return true;
+        }
+        if self.filters.is_excluded(rel_path, is_dir)
+            || (opts.skip_hidden && filters::is_hidden(dest_path))
+        {
+            // Excluded entries are protected from deletion
+            return true;
+        }
+        let src_paths: Vec<PathBuf> = self.sources.iter().map(|s| s.join(rel_path)).collect();
+        if src_paths
+            .iter()
+            .any(|src_path| self.is_part_file_of(dest_path, src_path))
+        {
+            // Needed to resume an interrupted transfer
+            return true;
+        }
+        // Being written by a sync worker
+        opts.delete_timing == DeleteTiming::During
+            && src_paths
+                .iter()
+                .any(|src_path| self.is_tmp_file_of(dest_path, src_path))
+    }
+
+    // How many entries removing `dest_path` would delete
+    fn count_entries(&self, dest_path: &Path) -> u64 {
+        // Never follow symlinks
+        if self.dest_fs.stat(dest_path).ok() != Some(FileKind::Dir) {
+            return 1;
+        }
+        let entries = self.dest_fs.list(dest_path).unwrap_or_default();
+        1 + entries
+            .iter()
+            .map(|entry| self.count_entries(entry))
+            .sum::<u64>()
+    }
+
+    fn in_sources(&self, rel_path: &Path, opts: SyncOptions) -> bool {
+        self.sources.iter().any(|source| {
+            self.source_fs.exists(&source.join(rel_path))
+                || (names::loose(opts)
+                    && self.source_fs.exists(&names::resolve(
+                        &*self.source_fs,
+                        source,
+                        rel_path,
+                        opts,
+                    )))
+        })
+    }
+
+    fn remove(&self, dest_path: &Path) -> Result<(), Error> {
+        if let Some(backup) = &self.backup {
+            let rel_path = fsops::get_rel_path(dest_path, &self.destination);
+            let outcome = backup.save(dest_path, &rel_path);
+            return self.send_outcome(dest_path, outcome);
+        }
+        // Never follow symlinks when deleting things
+        if self.dest_fs.stat(dest_path).ok() == Some(FileKind::Dir) {
+            let entries = match self.dest_fs.list(dest_path) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    let details = format!("Could not read '{}': {}", dest_path.display(), e);
+                    return self.send_error(dest_path, &details, e.kind());
+                }
+            };
+            for entry in entries {
+                self.remove(&entry)?;
+            }
+        }
+        let outcome = self
+            .dest_fs
+            .remove(dest_path)
+            .with_context(|| format!("Could not remove '{}'", dest_path.display()));
+        self.send_outcome(dest_path, outcome)
+    }
+
+    // Report what remove() would delete, without deleting anything. Entries
+    // moved to the trash are moved whole, like backups
+    fn plan_removal(&self, dest_path: &Path, to_trash: bool) -> Result<(), Error> {
+        let is_dir = self.dest_fs.stat(dest_path).ok() == Some(FileKind::Dir);
+        if is_dir && self.backup.is_none() && !to_trash {
+            // Each entry would be removed before the directory itself
+            let entries = match self.dest_fs.list(dest_path) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    let details = format!("Could not read '{}': {}", dest_path.display(), e);
+                    return self.send_error(dest_path, &details, e.kind());
+                }
+            };
+            for entry in entries {
+                self.plan_removal(&entry, to_trash)?;
+            }
+        }
+        let size = if is_dir {
+            0
+        } else {
+            self.dest_fs.size(dest_path).unwrap_or(0)
+        };
+        self.output.send(ProgressMessage::Planned {
+            entry: self.description(dest_path),
+            change: Change::Deleted,
+            size,
+        })?;
+        self.send_outcome(dest_path, Ok(()))
+    }
+
+    fn send_outcome(&self, dest_path: &Path, outcome: Result<(), Error>) -> Result<(), Error> {
+        let desc = self.description(dest_path);
+        let progress_message = match outcome {
+            Ok(()) => ProgressMessage::Deleted(desc),
+            Err(e) => ProgressMessage::SyncError {
+                entry: desc,
+                details: format!("{:#}", e),
+                kind: fsops::error_kind(&e),
+            },
+        };
+        self.output.send(progress_message)?;
+        Ok(())
+    }
+
+    fn send_error(
+        &self,
+        dest_path: &Path,
+        details: &str,
+        kind: io::ErrorKind,
+    ) -> Result<(), Error> {
+        self.output.send(ProgressMessage::SyncError {
+            entry: self.description(dest_path),
+            details: details.to_string(),
+            kind,
+        })?;
+        Ok(())
+    }
+
+    fn description(&self, dest_path: &Path) -> String {
+        let rel_path = fsops::get_rel_path(dest_path, &self.destination);
+        rel_path.to_string_lossy().to_string()
+    }
+
+    // Before the whole source is walked, the entries of a source directory
+    // that cannot be read would look extraneous. Reports such directories
+    fn sources_readable(&self, dest_dir: &Path) -> Result<bool, Error> {
+        let rel_path = fsops::get_rel_path(dest_dir, &self.destination);
+        for source in &self.sources {
+            let src_dir = source.join(&rel_path);
+            if !self.source_fs.is_dir(&src_dir) {
+                continue;
+            }
+            if let Err(e) = self.source_fs.list(&src_dir) {
+                let details = format!(
+                    "Could not read '{}', not deleting its extraneous entries: {}",
+                    src_dir.display(),
+                    e
+                );
+                self.send_error(dest_dir, &details, e.kind())?;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // Returns true if dest_path is the temporary file written when
+    // transferring the file whose path would be src_path without the dot
+    // and the suffix
+    fn is_tmp_file_of(&self, dest_path: &Path, src_path: &Path) -> bool {
+        let name = match src_path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => return false,
+        };
+        let stripped = name
+            .strip_prefix('.')
+            .and_then(|name| name.strip_suffix(fsops::TMP_SUFFIX));
+        match stripped {
+            Some(stripped) => {
+                self.dest_fs.is_file(dest_path)
+                    && self.source_fs.is_file(&src_path.with_file_name(stripped))
+            }
+            None => false,
+        }
+    }
+
+    // Returns true if dest_path is the .part file written when transferring
+    // the file whose path would be src_path without the suffix
+    fn is_part_file_of(&self, dest_path: &Path, src_path: &Path) -> bool {
+        let name = match src_path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => return false,
+        };
+        match name.strip_suffix(fsops::PART_SUFFIX) {
+            Some(stripped) => {
+                self.dest_fs.is_file(dest_path)
+                    && self.source_fs.is_file(&src_path.with_file_name(stripped))
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::tests::MemoryFilesystem;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_delete_with_memory_filesystem() {
+        let source_fs = Arc::new(MemoryFilesystem::default());
+        source_fs.create_dir_all(Path::new("/src/kept")).unwrap();
+        source_fs.add("/src/kept/a.txt", FileKind::File);
+        let dest_fs = Arc::new(MemoryFilesystem::default());
+        dest_fs.create_dir_all(Path::new("/dest/kept")).unwrap();
+        dest_fs.create_dir_all(Path::new("/dest/gone/sub")).unwrap();
+        dest_fs.add("/dest/kept/a.txt", FileKind::File);
+        dest_fs.add("/dest/kept/b.txt", FileKind::File);
+        dest_fs.add("/dest/gone/sub/c.txt", FileKind::File);
+
+        let (output, input) = channel();
+        let delete_worker = DeleteWorker::new(
+            &[PathBuf::from("/src")],
+            Path::new("/dest"),
+            source_fs,
+            dest_fs.clone(),
+            None,
+            Filters::new(),
+            None,
+            CancellationToken::new(),
+            output,
+        );
+        delete_worker.start(SyncOptions::default()).unwrap();
+
+        assert_eq!(
+            dest_fs.paths(),
+            vec!["/", "/dest", "/dest/kept", "/dest/kept/a.txt"]
+        );
+        let deleted: Vec<String> = input
+            .iter()
+            .map(|m| match m {
+                ProgressMessage::Deleted(name) => name,
+                _ => panic!("unexpected message"),
+            })
+            .collect();
+        assert_eq!(deleted.len(), 4);
+        assert!(deleted.contains(&"gone".to_string()));
+    }
+}
@@ -1,61 +1,155 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
 use std::sync::mpsc::Receiver;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use crate::log_file::LogFile;
 use crate::progress::{Progress, ProgressInfo, ProgressMessage};
 use crate::sync::Stats;
 
 pub struct ProgressWorker {
     input: Receiver<ProgressMessage>,
     progress_info: Box<dyn ProgressInfo + Send>,
+    log_file: Option<LogFile>,
 }
 
 impl ProgressWorker {
     pub fn new(
         input: Receiver<ProgressMessage>,
         progress_info: Box<dyn ProgressInfo + Send>,
+        log_file: Option<LogFile>,
     ) -> ProgressWorker {
         ProgressWorker {
             input,
             progress_info,
+            log_file,
         }
     }
 
-    pub fn start(mut self) -> Stats {
+    /// Give back what was passed to `new()`, once `start()` is done
+    pub fn into_parts(self) -> (Box<dyn ProgressInfo + Send>, Option<LogFile>) {
+        (self.progress_info, self.log_file)
+    }
+
+    pub fn start(&mut self) -> Stats {
         let mut stats = Stats::new();
-        let mut file_done = 0;
-        let mut current_file = String::from("");
+        // Bytes done for each file being synced: there is more than one
+        // when using several sync workers
+        let mut files_done: HashMap<String, usize> = HashMap::new();
         let mut index = 0;
         let mut total_done = 0;
-        let now = Instant::now();
+        // Only includes the bytes that were actually copied, so that
+        // skipped files do not inflate the speed
+        let mut total_copied = 0;
+        let mut totals_known = false;
+        let mut speedometer = Speedometer::new();
+        let start = Instant::now();
         stats.start();
         for progress in self.input.iter() {
             match progress {
                 ProgressMessage::Todo {
                     num_files,
                     total_size,
+                    complete,
                 } => {
+                    totals_known = complete;
                     stats.num_files = num_files;
                     stats.total_size = total_size;
+                    stats.bytes_total = total_size as u64;
+                }
+                ProgressMessage::StartSync(entry) => {
+                    let x = entry.description();
+                    self.progress_info.new_file(x);
+                    self.progress_info.new_entry(&entry);
+                    match files_done.insert(x.clone(), 0) {
+                        // The entry is being retried: forget about
+                        // what was done during the failed attempt
+                        Some(done) => total_done -= done,
+                        None => index += 1,
+                    }
+                }
+                ProgressMessage::Scanning {
+                    entries_found,
+                    bytes_found,
+                } => {
+                    self.progress_info.scanning(entries_found, bytes_found);
                 }
-                ProgressMessage::StartSync(x) => {
-                    self.progress_info.new_file(&x);
-                    current_file = x;
-                    index += 1;
+                ProgressMessage::Itemized { entry, changes } => {
+                    self.progress_info.itemized(&entry, &changes);
                 }
-                ProgressMessage::DoneSyncing(x) => {
-                    self.progress_info.done_syncing();
-                    stats.add_outcome(&x);
-                    file_done = 0;
+                ProgressMessage::DoneSyncing { entry, outcome } => {
+                    self.progress_info.done_syncing(&entry);
+                    if let Some(log_file) = &mut self.log_file {
+                        log_file.outcome(&entry, &outcome);
+                    }
+                    stats.add_outcome(&outcome);
+                    files_done.remove(&entry);
                 }
-                ProgressMessage::SyncError { entry, details } => {
+                ProgressMessage::SyncError {
+                    entry,
+                    details,
+                    kind,
+                } => {
+                    files_done.remove(&entry);
                     self.progress_info.error(&entry, &details);
-                    stats.add_error();
+                    if let Some(log_file) = &mut self.log_file {
+                        log_file.error(&entry, &details);
+                    }
+                    stats.add_error(&entry, io::Error::new(kind, details));
+                }
+                ProgressMessage::SyncWarning { entry, details } => {
+                    self.progress_info.warning(&entry, &details);
+                    if let Some(log_file) = &mut self.log_file {
+                        log_file.warning(&entry, &details);
+                    }
+                    stats.add_warning();
+                }
+                ProgressMessage::Planned {
+                    entry,
+                    change,
+                    size,
+                } => {
+                    self.progress_info.planned(&entry, change, size);
+                }
+                ProgressMessage::Cancelled => stats.cancelled = true,
+                ProgressMessage::Deleted(x) => {
+                    self.progress_info.deleted(&x);
+                    if let Some(log_file) = &mut self.log_file {
+                        log_file.deleted(&x);
+                    }
+                    stats.add_deleted();
                 }
-                ProgressMessage::Syncing { done, size, .. } => {
-                    file_done += done;
+                ProgressMessage::Syncing {
+                    description,
+                    done,
+                    size,
+                    transfered,
+                } => {
+                    let file_done = files_done.entry(description.clone()).or_insert(0);
+                    *file_done += done;
+                    let file_done = *file_done;
                     total_done += done;
-                    let elapsed = now.elapsed().as_secs() as usize;
-                    let eta = ((elapsed * stats.total_size) / total_done) - elapsed;
+                    if transfered {
+                        total_copied += done;
+                        stats.bytes_copied += done as u64;
+                    } else {
+                        stats.bytes_skipped += done as u64;
+                    }
+                    let now = Instant::now();
+                    speedometer.record(now, total_copied);
+                    let elapsed = (now - start).as_secs_f64();
+                    let avg_speed = if elapsed > 0.0 {
+                        total_copied as f64 / elapsed
+                    } else {
+                        0.0
+                    };
+                    // total_size is whatever was found by the walker so far
+                    let remaining = stats.total_size.saturating_sub(total_done);
+                    let eta = if avg_speed > 0.0 {
+                        (remaining as f64 / avg_speed) as usize
+                    } else {
+                        0
+                    };
                     let detailed_progress = Progress {
                         file_done,
                         file_size: size,
@@ -63,8 +157,11 @@ impl ProgressWorker {
                         total_size: stats.total_size,
                         index,
                         num_files: stats.num_files as usize,
-                        current_file: current_file.clone(),
+                        totals_known,
+                        current_file: description,
                         eta,
+                        speed: speedometer.speed(),
+                        avg_speed: avg_speed as usize,
                     };
                     self.progress_info.progress(&detailed_progress);
                 }
@@ -72,6 +169,71 @@ impl ProgressWorker {
         }
         stats.stop();
         self.progress_info.end(&stats);
+        if let Some(log_file) = &mut self.log_file {
+            log_file.end(stats.num_synced, stats.errors);
+        }
         stats
     }
 }
+
+// How far back to look when computing the current speed
+const SPEED_WINDOW: Duration = Duration::from_secs(2);
+
+// Measures the transfer speed over the last few seconds
+struct Speedometer {
+    // When each progress message was received, and the total number of
+    // bytes done at that time
+    samples: VecDeque<(Instant, usize)>,
+}
+
+impl Speedometer {
+    fn new() -> Speedometer {
+        Speedometer {
+            samples: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, now: Instant, total_done: usize) {
+        self.samples.push_back((now, total_done));
+        while self.samples.len() > 2 {
+            let (oldest, _) = self.samples[0];
+            if now - oldest <= SPEED_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    // In bytes per second
+    fn speed(&self) -> usize {
+        let (first, last) = match (self.samples.front(), self.samples.back()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return 0,
+        };
+        let elapsed = (last.0 - first.0).as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0;
+        }
+        ((last.1 - first.1) as f64 / elapsed) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speed_only_uses_recent_samples() {
+        let start = Instant::now();
+        let mut speedometer = Speedometer::new();
+        assert_eq!(speedometer.speed(), 0);
+        // Slow at first ...
+        speedometer.record(start, 0);
+        speedometer.record(start + Duration::from_secs(10), 1000);
+        assert_eq!(speedometer.speed(), 100);
+        // ... and then much faster
+        speedometer.record(start + Duration::from_secs(11), 11_000);
+        speedometer.record(start + Duration::from_secs(12), 21_000);
+        assert_eq!(speedometer.speed(), 10_000);
+    }
+}
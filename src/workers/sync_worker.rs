@@ -1,46 +1,108 @@
-use std::fs;
+use std::collections::HashMap;
+use std::io;
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{Receiver, RecvError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 use anyhow::{Context, Error};
 
+use crate::backup::Backup;
+use crate::cancel::CancellationToken;
+use crate::checksum;
+use crate::checksum_cache::ChecksumCache;
+use crate::chmod::Chmod;
 use crate::entry::Entry;
+use crate::filesystem::Filesystem;
 use crate::fsops;
 use crate::fsops::SyncOutcome;
-use crate::progress::ProgressMessage;
-use crate::sync::SyncOptions;
+use crate::id_map::IdMaps;
+use crate::itemize;
+use crate::names;
+use crate::progress::{Change, ProgressMessage};
+use crate::state::{Stamp, SyncState};
+use crate::state_db::{self, StateDb};
+use crate::sync::{ConflictPolicy, SyncOptions};
+use crate::throttle::Throttle;
+
+/// Maps the (device, inode) pair of source files having several links
+/// to the first destination path they were synced to
+pub type HardLinks = Arc<Mutex<HashMap<(u64, u64), PathBuf>>>;
 
 pub struct SyncWorker {
-    input: Receiver<Entry>,
+    input: Arc<Mutex<Receiver<Entry>>>,
     output: Sender<ProgressMessage>,
-    source: PathBuf,
+    sources: Vec<PathBuf>,
     destination: PathBuf,
+    dest_fs: Arc<dyn Filesystem>,
+    backup: Option<Backup>,
+    link_dest: Option<PathBuf>,
+    hard_links: HardLinks,
+    checksums: Option<Arc<ChecksumCache>>,
+    state: Option<Arc<SyncState>>,
+    state_db: Option<Arc<StateDb>>,
+    throttle: Arc<Throttle>,
+    id_maps: Arc<IdMaps>,
+    chmod: Arc<Chmod>,
+    cancel: CancellationToken,
 }
 
 impl SyncWorker {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        source: &Path,
+        sources: &[PathBuf],
         destination: &Path,
-        input: Receiver<Entry>,
+        dest_fs: Arc<dyn Filesystem>,
+        backup: Option<Backup>,
+        link_dest: Option<PathBuf>,
+        hard_links: HardLinks,
+        checksums: Option<Arc<ChecksumCache>>,
+        state: Option<Arc<SyncState>>,
+        state_db: Option<Arc<StateDb>>,
+        throttle: Arc<Throttle>,
+        id_maps: Arc<IdMaps>,
+        chmod: Arc<Chmod>,
+        cancel: CancellationToken,
+        input: Arc<Mutex<Receiver<Entry>>>,
         output: Sender<ProgressMessage>,
     ) -> SyncWorker {
         SyncWorker {
-            source: source.to_path_buf(),
+            sources: sources.to_vec(),
             destination: destination.to_path_buf(),
+            dest_fs,
+            backup,
+            link_dest,
+            hard_links,
+            checksums,
+            state,
+            state_db,
+            throttle,
+            id_maps,
+            chmod,
+            cancel,
             input,
             output,
         }
     }
 
     pub fn start(self, opts: SyncOptions) -> Result<(), Error> {
-        for entry in self.input.iter() {
-            let sync_outcome = self.sync(&entry, opts);
+        // Several workers may share the same input, so only hold the
+        // lock while waiting for the next entry
+        while let Ok(entry) = self.next_entry() {
+            if self.cancel.is_cancelled() {
+                break;
+            }
+            let sync_outcome = self.sync_with_retries(&entry, opts);
             let progress_message = match sync_outcome {
-                Ok(s) => ProgressMessage::DoneSyncing(s),
+                Ok(outcome) => ProgressMessage::DoneSyncing {
+                    entry: entry.description().to_string(),
+                    outcome,
+                },
                 Err(e) => ProgressMessage::SyncError {
                     entry: entry.description().to_string(),
                     details: format!("{:#}", e),
+                    kind: fsops::error_kind(&e),
                 },
             };
             self.output.send(progress_message)?;
@@ -48,30 +110,554 @@ impl SyncWorker {
         Ok(())
     }
 
-    fn create_missing_dest_dirs(&self, rel_path: &Path) -> Result<(), Error> {
-        let parent_rel_path = rel_path
+    /// Apply the permissions, owners and modification times of the source
+    /// directories to the destination, once everything else is synced.
+    /// `dirs` must come after the directories they contain. Directories
+    /// missing in the destination are skipped
+    pub fn sync_dirs(&self, dirs: Vec<Entry>, opts: SyncOptions) -> Result<(), Error> {
+        for dir in dirs {
+            if self.cancel.is_cancelled() {
+                break;
+            }
+            if let Err(e) = self.sync_dir(&dir, opts) {
+                self.output.send(ProgressMessage::SyncError {
+                    entry: dir.description().to_string(),
+                    details: format!("{:#}", e),
+                    kind: fsops::error_kind(&e),
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn sync_dir(&self, src_entry: &Entry, opts: SyncOptions) -> Result<(), Error> {
+        let rel_path = self.rel_path(src_entry);
+        let dest_path = self.dest_path(&rel_path, opts);
+        let dest_entry = Entry::new(src_entry.description(), &dest_path);
+        if !dest_entry.metadata().is_some_and(|m| m.is_dir()) {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        {
+            if opts.preserve_owner || opts.preserve_group {
+                let copied = fsops::copy_ownership(
+                    src_entry,
+                    &dest_entry,
+                    opts.preserve_owner,
+                    opts.preserve_group,
+                    &self.id_maps,
+                );
+                if let Err(e) = copied {
+                    self.output.send(ProgressMessage::SyncWarning {
+                        entry: src_entry.description().to_string(),
+                        details: format!("{:#}", e),
+                    })?;
+                }
+            }
+        }
+        if opts.preserve_permissions {
+            fsops::copy_permissions(src_entry, &dest_entry)?;
+        }
+        #[cfg(unix)]
+        if !self.chmod.is_empty() {
+            fsops::apply_chmod(&dest_entry, &self.chmod)?;
+        }
+        if opts.preserve_times {
+            fsops::copy_times(src_entry, &dest_entry)?;
+        }
+        Ok(())
+    }
+
+    fn sync_with_retries(
+        &self,
+        src_entry: &Entry,
+        opts: SyncOptions,
+    ) -> Result<SyncOutcome, Error> {
+        let mut backoff = opts.retries.backoff;
+        let mut attempt = 0;
+        loop {
+            match self.sync(src_entry, opts) {
+                Err(e) if attempt < opts.retries.count && is_transient(&e) => {
+                    attempt += 1;
+                    self.output.send(ProgressMessage::SyncWarning {
+                        entry: src_entry.description().to_string(),
+                        details: format!(
+                            "{:#} (retrying in {}, attempt {}/{})",
+                            e,
+                            humantime::format_duration(backoff),
+                            attempt,
+                            opts.retries.count
+                        ),
+                    })?;
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                outcome => return outcome,
+            }
+        }
+    }
+
+    // Relative to the source the entry was found in. Sources never
+    // contain one another, so there is only one
+    fn rel_path(&self, src_entry: &Entry) -> PathBuf {
+        let source = self
+            .sources
+            .iter()
+            .find(|source| src_entry.path().starts_with(source))
+            .unwrap_or(&self.sources[0]);
+        fsops::get_rel_path(src_entry.path(), source)
+    }
+
+    fn next_entry(&self) -> Result<Entry, RecvError> {
+        self.input
+            .lock()
+            .expect("another sync worker panicked while waiting for an entry")
+            .recv()
+    }
+
+    fn dest_path(&self, rel_path: &Path, opts: SyncOptions) -> PathBuf {
+        if names::loose(opts) {
+            names::resolve(&*self.dest_fs, &self.destination, rel_path, opts)
+        } else {
+            self.destination.join(rel_path)
+        }
+    }
+
+    fn create_missing_dest_dirs(&self, dest_path: &Path) -> Result<(), Error> {
+        let to_create = dest_path
             .parent()
             .expect("dest directory should have a parent");
-        let to_create = self.destination.join(parent_rel_path);
-        fs::create_dir_all(&to_create)
+        self.dest_fs
+            .create_dir_all(to_create)
             .with_context(|| format!("Could not create '{}'", to_create.display()))?;
         Ok(())
     }
 
     fn sync(&self, src_entry: &Entry, opts: SyncOptions) -> Result<SyncOutcome, Error> {
-        let rel_path = fsops::get_rel_path(&src_entry.path(), &self.source);
-        self.create_missing_dest_dirs(&rel_path)?;
+        if let Some(outcome) = fsops::skip_too_large(&self.output, src_entry, opts) {
+            return Ok(outcome);
+        }
+        if opts.existing {
+            let dest_path = self.dest_path(&self.rel_path(src_entry), opts);
+            if let Some(outcome) = fsops::skip_missing(&self.output, src_entry, &dest_path) {
+                return Ok(outcome);
+            }
+        }
+        if opts.dry_run {
+            return self.plan(src_entry, opts);
+        }
+        let rel_path = self.rel_path(src_entry);
+        let dest_path = self.dest_path(&rel_path, opts);
+        if let Some(state_db) = &self.state_db {
+            // Only stat the destination, so that files changed or removed
+            // there by other programs are synced again
+            let is_unchanged = |src_meta: &std::fs::Metadata| {
+                let dest_meta = std::fs::symlink_metadata(&dest_path).ok();
+                state_db.is_unchanged(&rel_path, src_meta, dest_meta.as_ref())
+            };
+            if src_entry
+                .metadata()
+                .is_some_and(|m| m.is_file() && is_unchanged(m))
+            {
+                state_db.keep(&rel_path);
+                return Ok(fsops::skip_unchanged(&self.output, src_entry));
+            }
+        }
+        self.create_missing_dest_dirs(&dest_path)?;
         let desc = rel_path.to_string_lossy();
 
-        let dest_path = self.destination.join(&rel_path);
-        let dest_entry = Entry::new(&desc, &dest_path);
-        let outcome = fsops::sync_entries(&self.output, &src_entry, &dest_entry)?;
+        let mut dest_entry = Entry::new(&desc, &dest_path);
+        if let Some(policy) = opts.conflicts {
+            if self.resolve_conflict(src_entry, &dest_entry, &rel_path, policy)? {
+                return Ok(fsops::skip_conflict(&self.output, src_entry));
+            }
+            // The destination file may have been renamed
+            dest_entry = Entry::new(&desc, &dest_path);
+        }
+        let link_key = if opts.preserve_hard_links {
+            hard_link_key(src_entry)
+        } else {
+            None
+        };
+        let first_dest = link_key.and_then(|key| self.lock_hard_links().get(&key).cloned());
+        let mut digest = None;
+        let outcome = match first_dest {
+            Some(first_dest) => {
+                fsops::create_hard_link(&first_dest, &dest_entry, self.backup.as_ref())?
+            }
+            None => match self.link_unchanged(src_entry, &dest_entry, &rel_path, opts)? {
+                Some(outcome) => outcome,
+                None => match self.same_as_recorded(src_entry, &dest_entry, &rel_path, opts)? {
+                    Some(recorded) => {
+                        digest = Some(recorded);
+                        fsops::skip_unchanged(&self.output, src_entry)
+                    }
+                    None => {
+                        let (outcome, copied) = fsops::sync_entries(
+                            &self.output,
+                            &self.throttle,
+                            self.backup.as_ref(),
+                            src_entry,
+                            &dest_entry,
+                            self.checksums.as_deref(),
+                            opts,
+                        )?;
+                        digest = copied;
+                        outcome
+                    }
+                },
+            },
+        };
+        if let Some(key) = link_key {
+            self.lock_hard_links().entry(key).or_insert(dest_path);
+        }
+        if matches!(
+            outcome,
+            SyncOutcome::SpecialSkipped
+                | SyncOutcome::SymlinkSkipped
+                | SyncOutcome::JunctionSkipped
+        ) {
+            // Nothing was written
+            return Ok(outcome);
+        }
+        if opts.fsync && outcome != SyncOutcome::UpToDate {
+            // Before the attributes on Windows, which may make the file
+            // read-only
+            fsops::sync_to_disk(dest_entry.path(), outcome.is_copy())
+                .with_context(|| format!("Could not flush {} to disk", dest_entry.description()))?;
+        }
+        if outcome.is_copy() && opts.preserve_times {
+            fsops::copy_times(src_entry, &dest_entry)?;
+        }
         #[cfg(unix)]
         {
-            if opts.preserve_permissions {
-                fsops::copy_permissions(&src_entry, &dest_entry)?;
+            // chown() may clear setuid bits, so call it before chmod()
+            if opts.preserve_owner || opts.preserve_group {
+                let copied = fsops::copy_ownership(
+                    src_entry,
+                    &dest_entry,
+                    opts.preserve_owner,
+                    opts.preserve_group,
+                    &self.id_maps,
+                );
+                if let Err(e) = copied {
+                    // Not fatal: we probably just lack the privileges
+                    self.output.send(ProgressMessage::SyncWarning {
+                        entry: src_entry.description().to_string(),
+                        details: format!("{:#}", e),
+                    })?;
+                }
+            }
+        }
+        if opts.preserve_permissions {
+            fsops::copy_permissions(src_entry, &dest_entry)?;
+        }
+        #[cfg(unix)]
+        if !self.chmod.is_empty() {
+            fsops::apply_chmod(&dest_entry, &self.chmod)?;
+        }
+        // dest_entry still describes the destination before the sync
+        if let Some(changes) = itemize::itemize(
+            src_entry,
+            &dest_entry,
+            &outcome,
+            opts,
+            &self.id_maps,
+            &self.chmod,
+        ) {
+            self.output.send(ProgressMessage::Itemized {
+                entry: src_entry.description().to_string(),
+                changes,
+            })?;
+        }
+        if let (Some(state), Some(src_meta)) = (&self.state, src_entry.metadata()) {
+            if src_meta.is_file() {
+                let dest_meta = std::fs::symlink_metadata(dest_entry.path())
+                    .with_context(|| format!("Could not read '{}'", dest_entry.path().display()))?;
+                state.record(&rel_path, Stamp::new(src_meta), Stamp::new(&dest_meta));
             }
         }
+        if let Some(state_db) = &self.state_db {
+            self.record(state_db, src_entry, &dest_entry, &rel_path, digest, opts)?;
+        }
+        if opts.remove_source_files {
+            fsops::remove_source(
+                src_entry,
+                dest_entry.path(),
+                self.checksums.as_deref(),
+                opts,
+            )?;
+        }
         Ok(outcome)
     }
+
+    fn record(
+        &self,
+        state_db: &StateDb,
+        src_entry: &Entry,
+        dest_entry: &Entry,
+        rel_path: &Path,
+        digest: Option<Vec<u8>>,
+        opts: SyncOptions,
+    ) -> Result<(), Error> {
+        let src_meta = match src_entry.metadata() {
+            Some(src_meta) => src_meta,
+            None => return Ok(()),
+        };
+        let (kind, digest) = if src_meta.is_file() {
+            let digest = match digest {
+                None if opts.checksum => Some(self.hash_source(src_entry, opts)?),
+                digest => digest,
+            };
+            (state_db::Kind::File, digest)
+        } else if src_meta.is_dir() {
+            (state_db::Kind::Dir, None)
+        } else {
+            (state_db::Kind::Other, None)
+        };
+        // dest_entry still describes the destination before the sync
+        let dest_meta = std::fs::symlink_metadata(dest_entry.path()).ok();
+        state_db.record(rel_path, kind, src_meta, dest_meta.as_ref(), digest);
+        Ok(())
+    }
+
+    // A source file touched, or rewritten with the same contents, since it
+    // was recorded in the state database does not need to be copied again:
+    // only its modification time is applied. Returns the digest of its
+    // contents, if so
+    fn same_as_recorded(
+        &self,
+        src_entry: &Entry,
+        dest_entry: &Entry,
+        rel_path: &Path,
+        opts: SyncOptions,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let state_db = match &self.state_db {
+            Some(state_db) => state_db,
+            None => return Ok(None),
+        };
+        let src_meta = match src_entry.metadata() {
+            Some(src_meta) if src_meta.is_file() => src_meta,
+            _ => return Ok(None),
+        };
+        let recorded = match state_db.previous_digest(rel_path, src_meta, dest_entry.metadata()) {
+            Some(recorded) => recorded,
+            None => return Ok(None),
+        };
+        let digest = self.hash_source(src_entry, opts)?;
+        if digest != recorded {
+            return Ok(None);
+        }
+        if opts.preserve_times {
+            fsops::copy_times(src_entry, dest_entry)?;
+        }
+        Ok(Some(digest))
+    }
+
+    fn hash_source(&self, src_entry: &Entry, opts: SyncOptions) -> Result<Vec<u8>, Error> {
+        match &self.checksums {
+            Some(checksums) => checksums.hash_file(src_entry.path()),
+            None => checksum::hash_file(src_entry.path(), opts.hash),
+        }
+    }
+
+    // When the source and the destination file both changed since the
+    // previous sync, apply `policy`. Returns true if the destination file
+    // must be kept as it is
+    fn resolve_conflict(
+        &self,
+        src_entry: &Entry,
+        dest_entry: &Entry,
+        rel_path: &Path,
+        policy: ConflictPolicy,
+    ) -> Result<bool, Error> {
+        let state = match &self.state {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+        let (src_meta, dest_meta) = match (src_entry.metadata(), dest_entry.metadata()) {
+            (Some(src_meta), Some(dest_meta)) if src_meta.is_file() && dest_meta.is_file() => {
+                (src_meta, dest_meta)
+            }
+            _ => return Ok(false),
+        };
+        if !state.is_conflict(rel_path, Stamp::new(src_meta), Stamp::new(dest_meta)) {
+            return Ok(false);
+        }
+        let dest_is_newer = match (src_meta.modified(), dest_meta.modified()) {
+            (Ok(src_time), Ok(dest_time)) => dest_time > src_time,
+            _ => false,
+        };
+        let (keep, resolution) = match policy {
+            ConflictPolicy::SourceWins => (false, "overwriting the destination".to_string()),
+            ConflictPolicy::NewerWins if dest_is_newer => {
+                (true, "keeping the destination, which is newer".to_string())
+            }
+            ConflictPolicy::NewerWins => (false, "syncing the source, which is newer".to_string()),
+            ConflictPolicy::KeepBoth => {
+                let renamed = fsops::conflict_path(dest_entry.path());
+                std::fs::rename(dest_entry.path(), &renamed).with_context(|| {
+                    format!(
+                        "Could not rename '{}' to '{}'",
+                        dest_entry.path().display(),
+                        renamed.display()
+                    )
+                })?;
+                let name = renamed.file_name().unwrap_or_default().to_string_lossy();
+                (false, format!("keeping the destination as '{}'", name))
+            }
+            ConflictPolicy::ReportOnly => (true, "keeping the destination".to_string()),
+        };
+        if keep {
+            state.keep(rel_path);
+        }
+        self.output.send(ProgressMessage::SyncWarning {
+            entry: src_entry.description().to_string(),
+            details: format!(
+                "conflict: changed in both the source and the destination since the previous sync, {}",
+                resolution
+            ),
+        })?;
+        Ok(keep)
+    }
+
+    fn plan(&self, src_entry: &Entry, opts: SyncOptions) -> Result<SyncOutcome, Error> {
+        let rel_path = self.rel_path(src_entry);
+        let desc = rel_path.to_string_lossy();
+        let dest_entry = Entry::new(&desc, &self.dest_path(&rel_path, opts));
+        let outcome = fsops::plan_entries(
+            &self.output,
+            src_entry,
+            &dest_entry,
+            self.checksums.as_deref(),
+            opts,
+        )?;
+        if matches!(
+            outcome,
+            SyncOutcome::SpecialSkipped
+                | SyncOutcome::SymlinkSkipped
+                | SyncOutcome::JunctionSkipped
+        ) {
+            return Ok(outcome);
+        }
+        let change = if !dest_entry.exists() && dest_entry.is_link().is_none() {
+            Change::New
+        } else if outcome == SyncOutcome::UpToDate {
+            Change::Unchanged
+        } else {
+            Change::Changed
+        };
+        self.output.send(ProgressMessage::Planned {
+            entry: desc.to_string(),
+            change,
+            size: src_entry.metadata().map_or(0, |m| m.len()),
+        })?;
+        if let Some(changes) = itemize::itemize(
+            src_entry,
+            &dest_entry,
+            &outcome,
+            opts,
+            &self.id_maps,
+            &self.chmod,
+        ) {
+            self.output.send(ProgressMessage::Itemized {
+                entry: desc.to_string(),
+                changes,
+            })?;
+        }
+        Ok(outcome)
+    }
+
+    fn link_unchanged(
+        &self,
+        src_entry: &Entry,
+        dest_entry: &Entry,
+        rel_path: &Path,
+        opts: SyncOptions,
+    ) -> Result<Option<SyncOutcome>, Error> {
+        let link_dest = match &self.link_dest {
+            None => return Ok(None),
+            Some(link_dest) => link_dest,
+        };
+        let previous = Entry::new(dest_entry.description(), &link_dest.join(rel_path));
+        fsops::link_unchanged(
+            &self.output,
+            src_entry,
+            dest_entry,
+            &previous,
+            self.checksums.as_deref(),
+            opts,
+            &self.id_maps,
+            &self.chmod,
+        )
+    }
+
+    fn lock_hard_links(&self) -> std::sync::MutexGuard<'_, HashMap<(u64, u64), PathBuf>> {
+        self.hard_links
+            .lock()
+            .expect("another sync worker panicked while holding the hard links map")
+    }
+}
+
+// Returns true for errors that may go away if we try again, for instance
+// when a network file system hiccups
+fn is_transient(error: &Error) -> bool {
+    let io_error = error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<io::Error>());
+    let io_error = match io_error {
+        None => return false,
+        Some(io_error) => io_error,
+    };
+    match io_error.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+        #[cfg(unix)]
+        _ => matches!(
+            io_error.raw_os_error(),
+            Some(libc::EIO) | Some(libc::EBUSY) | Some(libc::ESTALE)
+        ),
+        #[cfg(not(unix))]
+        _ => false,
+    }
+}
+
+#[cfg(unix)]
+fn hard_link_key(src_entry: &Entry) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = src_entry.metadata()?;
+    if metadata.file_type().is_file() && metadata.nlink() > 1 {
+        Some((metadata.dev(), metadata.ino()))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn hard_link_key(_src_entry: &Entry) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_is_transient() {
+        let timed_out = Error::new(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+            .context("Could not write to 'foo'");
+        assert!(is_transient(&timed_out));
+
+        let not_found = Error::new(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+        assert!(!is_transient(&not_found));
+
+        assert!(!is_transient(&anyhow!("Refusing to replace existing path")));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_eio_is_transient() {
+        let eio = Error::new(io::Error::from_raw_os_error(libc::EIO));
+        assert!(is_transient(&eio));
+    }
 }
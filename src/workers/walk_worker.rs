@@ -1,91 +1,514 @@
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
 use std::fs::DirEntry;
+use std::io;
 use std::path::Path;
 use std::path::PathBuf;
+use std::rc::Rc;
 use std::sync::mpsc::Sender;
 
 use anyhow::{bail, Context, Error};
 
+use crate::cancel::CancellationToken;
 use crate::entry::Entry;
+use crate::filters::{self, Filters, IgnoreFile};
 use crate::fsops;
+use crate::junction;
+use crate::names;
 use crate::progress::ProgressMessage;
+use crate::sync::{
+    CollisionPolicy, EntryFilter, JunctionPolicy, SymlinkPolicy, SyncOptions, WalkOrder,
+};
+
+// With SyncOptions.prescan, how many files to find between two
+// ProgressMessage::Scanning
+const SCANNING_INTERVAL: u64 = 1000;
 
 pub struct WalkWorker {
     entry_output: Sender<Entry>,
+    // Where to send the directories, once their files are sent
+    dir_output: Option<Sender<Entry>>,
     progress_output: Sender<ProgressMessage>,
-    source: PathBuf,
+    sources: Vec<PathBuf>,
+    filters: Filters,
+    entry_filter: Option<EntryFilter>,
+    cancel: CancellationToken,
+    // Set when a directory or an entry could not be read
+    incomplete: Cell<bool>,
 }
 
 impl WalkWorker {
     pub fn new(
-        source: &Path,
+        sources: &[PathBuf],
+        filters: Filters,
+        entry_filter: Option<EntryFilter>,
+        cancel: CancellationToken,
         entry_output: Sender<Entry>,
+        dir_output: Option<Sender<Entry>>,
         progress_output: Sender<ProgressMessage>,
     ) -> WalkWorker {
         WalkWorker {
             entry_output,
+            dir_output,
             progress_output,
-            source: source.to_path_buf(),
+            sources: sources.to_vec(),
+            filters,
+            entry_filter,
+            cancel,
+            incomplete: Cell::new(false),
+        }
+    }
+
+    fn walk(&self, opts: SyncOptions) -> Result<(), Error> {
+        let mut totals = (0, 0);
+        // Maps the files found so far to the source they were found in,
+        // to detect collisions between sources
+        let mut seen: HashMap<PathBuf, PathBuf> = HashMap::new();
+        // Maps the paths found so far to their key, see names::key(), for
+        // destinations comparing names loosely
+        let mut names: HashMap<String, PathBuf> = HashMap::new();
+        // The directories sent so far: they may be found in several sources
+        let mut dirs: HashSet<PathBuf> = HashSet::new();
+        // With SyncOptions.prescan, the entries are only sent to the sync
+        // workers once the totals are known
+        let mut pending: Vec<Entry> = vec![];
+        let mut sources: Vec<&PathBuf> = self.sources.iter().collect();
+        if opts.collisions == CollisionPolicy::LastWins {
+            // Then the first source in which a file is found wins
+            sources.reverse();
         }
+        for source in sources {
+            self.walk_source(
+                source,
+                &mut seen,
+                &mut names,
+                &mut dirs,
+                &mut totals,
+                &mut pending,
+                opts,
+            )?;
+        }
+        if opts.prescan && !self.cancel.is_cancelled() {
+            self.send_scanning(totals)?;
+            self.send_todo(totals, true)?;
+            for entry in pending {
+                self.send_entry(entry)?;
+            }
+        }
+        Ok(())
     }
 
-    fn walk(&self) -> Result<(), Error> {
-        let mut num_files = 0;
-        let mut total_size = 0;
-        let mut subdirs: Vec<PathBuf> = vec![self.source.to_path_buf()];
-        while !subdirs.is_empty() {
-            // We just checked that subdirs is *not* empty, so calling pop() is safe
-            let subdir = subdirs.pop().unwrap();
-            let entries = fs::read_dir(&subdir).with_context(|| {
+    #[allow(clippy::too_many_arguments)]
+    fn walk_source(
+        &self,
+        source: &Path,
+        seen: &mut HashMap<PathBuf, PathBuf>,
+        names: &mut HashMap<String, PathBuf>,
+        dirs: &mut HashSet<PathBuf>,
+        (num_files, total_size): &mut (u64, u64),
+        pending: &mut Vec<Entry>,
+        opts: SyncOptions,
+    ) -> Result<(), Error> {
+        let root_id = fs::metadata(source).ok().and_then(|m| file_id(&m));
+        let root = root_id.map(|id| {
+            Rc::new(Ancestor {
+                id,
+                path: source.to_path_buf(),
+                parent: None,
+            })
+        });
+        // Each directory to visit comes with the ignore files that apply to
+        // it, and with the directories containing it
+        let mut subdirs: VecDeque<(PathBuf, Vec<Rc<IgnoreFile>>, Ancestors)> =
+            VecDeque::from([(source.to_path_buf(), vec![], root)]);
+        loop {
+            let next = if opts.walk_order == WalkOrder::BreadthFirst {
+                subdirs.pop_front()
+            } else {
+                subdirs.pop_back()
+            };
+            let (subdir, mut ignore_files, ancestors) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            if opts.respect_gitignore {
+                if let Err(error) = self.read_ignore_files(source, &subdir, &mut ignore_files) {
+                    // Its entries could be synced although they should be
+                    // ignored
+                    self.report(source, &subdir, error)?;
+                    continue;
+                }
+            }
+            let mut entries: Vec<_> = match fs::read_dir(&subdir).with_context(|| {
                 format!(
                     "While walking source, could not read directory '{}'",
                     subdir.display()
                 )
-            })?;
+            }) {
+                Ok(entries) => entries.collect(),
+                Err(error) => {
+                    self.report(source, &subdir, error)?;
+                    continue;
+                }
+            };
+            if opts.walk_order == WalkOrder::Sorted {
+                entries.sort_by_key(|entry| entry.as_ref().ok().map(|e| e.file_name()));
+            }
+            // Walked after the files of this directory, in the same order
+            let mut found = vec![];
             for entry in entries {
-                let entry = entry.with_context(|| {
+                if self.cancel.is_cancelled() {
+                    return Ok(());
+                }
+                let entry = match entry.with_context(|| {
                     format!(
                         "While walking source dir, could not read subdir: '{}'",
                         subdir.display()
                     )
-                })?;
+                }) {
+                    Ok(entry) => entry,
+                    Err(error) => {
+                        self.report(source, &subdir, error)?;
+                        continue;
+                    }
+                };
                 let path = entry.path();
-                if path.is_dir() {
-                    subdirs.push(path);
+                let file_type = match entry
+                    .file_type()
+                    .with_context(|| format!("While walking source dir, could not read {:?}", path))
+                {
+                    Ok(file_type) => file_type,
+                    Err(error) => {
+                        self.report(source, &path, error)?;
+                        continue;
+                    }
+                };
+                let dereference = if file_type.is_symlink() && junction::is_junction(&path) {
+                    opts.junctions == JunctionPolicy::Dereference
+                } else {
+                    file_type.is_symlink()
+                        && (opts.symlinks == SymlinkPolicy::Dereference
+                            || ((opts.symlinks == SymlinkPolicy::Preserve
+                                || opts.follow_dir_symlinks)
+                                && path.is_dir()))
+                };
+                let is_dir = if dereference {
+                    path.is_dir()
                 } else {
-                    let meta = self.process_file(&entry)?;
-                    num_files += 1;
-                    total_size += meta.len();
-                    let sent = self.progress_output.send(ProgressMessage::Todo {
-                        num_files,
-                        total_size: total_size as usize,
-                    });
-                    if sent.is_err() {
-                        bail!("stats output chan is closed");
+                    file_type.is_dir()
+                };
+                if opts.skip_hidden && filters::is_hidden(&path) {
+                    continue;
+                }
+                let rel_path = fsops::get_rel_path(&path, source);
+                if self.filters.is_excluded(&rel_path, is_dir) {
+                    // Skipping excluded directories here means we never
+                    // even read their contents
+                    continue;
+                }
+                if filters::is_ignored(&ignore_files, &rel_path, is_dir) {
+                    continue;
+                }
+                if is_dir {
+                    if names::loose(opts) && self.name_collision(names, &rel_path, opts)? {
+                        continue;
+                    }
+                    let id = fs::metadata(&path).ok().and_then(|m| file_id(&m));
+                    if opts.one_file_system
+                        && root_id.is_some()
+                        && id.map(|(dev, _)| dev) != root_id.map(|(dev, _)| dev)
+                    {
+                        // A mount point: leave it out, like rsync -x
+                        continue;
+                    }
+                    let ancestors = match id {
+                        Some(id) => {
+                            // Reached through a symlink or a bind mount:
+                            // walking it would never end
+                            if let Some(ancestor) = find_ancestor(&ancestors, id) {
+                                self.progress_output.send(ProgressMessage::SyncError {
+                                    entry: rel_path.to_string_lossy().to_string(),
+                                    details: format!(
+                                        "Filesystem loop: {:?} is the same directory as {:?}",
+                                        path, ancestor
+                                    ),
+                                    kind: io::ErrorKind::Other,
+                                })?;
+                                continue;
+                            }
+                            Some(Rc::new(Ancestor {
+                                id,
+                                path: path.clone(),
+                                parent: ancestors.clone(),
+                            }))
+                        }
+                        None => ancestors.clone(),
+                    };
+                    found.push((path, ignore_files.clone(), ancestors));
+                } else {
+                    if dereference && fs::metadata(&path).is_err() {
+                        self.progress_output.send(ProgressMessage::SyncError {
+                            entry: rel_path.to_string_lossy().to_string(),
+                            details: format!("Broken symlink: {:?}", path),
+                            kind: io::ErrorKind::NotFound,
+                        })?;
+                        continue;
+                    }
+                    if opts.min_size.is_some()
+                        || opts.newer_than.is_some()
+                        || opts.older_than.is_some()
+                    {
+                        let meta = if dereference {
+                            fs::metadata(&path)
+                        } else {
+                            entry.metadata()
+                        };
+                        // Such files are not even sent to the workers, and
+                        // do not count in the totals
+                        if meta.is_ok_and(|m| is_filtered_out(&m, opts)) {
+                            continue;
+                        }
+                    }
+                    let src_entry = match self.process_file(source, &entry, dereference) {
+                        Ok(src_entry) => src_entry,
+                        Err(error) => {
+                            self.report(source, &path, error)?;
+                            continue;
+                        }
+                    };
+                    if self.entry_filter.as_ref().is_some_and(|f| !f(&src_entry)) {
+                        continue;
+                    }
+                    if names::loose(opts) && self.name_collision(names, &rel_path, opts)? {
+                        continue;
+                    }
+                    if self.sources.len() > 1 {
+                        if let Some(first) = seen.get(&rel_path) {
+                            if opts.collisions == CollisionPolicy::Error {
+                                self.progress_output.send(ProgressMessage::SyncError {
+                                    entry: rel_path.to_string_lossy().to_string(),
+                                    details: format!(
+                                        "{:?} also exists in '{}'",
+                                        path,
+                                        first.display()
+                                    ),
+                                    kind: io::ErrorKind::AlreadyExists,
+                                })?;
+                            }
+                            continue;
+                        }
+                        seen.insert(rel_path.clone(), source.to_path_buf());
+                    }
+                    *num_files += 1;
+                    *total_size += src_entry.metadata().map_or(0, |m| m.len());
+                    if opts.prescan {
+                        pending.push(src_entry);
+                        if num_files.is_multiple_of(SCANNING_INTERVAL) {
+                            self.send_scanning((*num_files, *total_size))?;
+                        }
+                    } else {
+                        self.send_entry(src_entry)?;
+                        self.send_todo((*num_files, *total_size), false)?;
                     }
                 }
             }
+            if let Some(dir_output) = &self.dir_output {
+                let rel_path = fsops::get_rel_path(&subdir, source);
+                if dirs.insert(rel_path.clone()) {
+                    // Like the files, directories reached through a symlink
+                    // are described by their target
+                    let dir = Entry::dereferenced(&rel_path.to_string_lossy(), &subdir);
+                    dir_output
+                        .send(dir)
+                        .context("When walking source dir: could not send directory")?;
+                }
+            }
+            if opts.walk_order == WalkOrder::BreadthFirst {
+                subdirs.extend(found);
+            } else {
+                // The last one is walked first
+                subdirs.extend(found.into_iter().rev());
+            }
         }
         Ok(())
     }
 
-    fn process_file(&self, entry: &DirEntry) -> Result<fs::Metadata, Error> {
-        let rel_path = fsops::get_rel_path(&entry.path(), &self.source);
+    // Report entries whose name only differs from the one of an entry found
+    // before, and that would overwrite it in the destination
+    fn name_collision(
+        &self,
+        names: &mut HashMap<String, PathBuf>,
+        rel_path: &Path,
+        opts: SyncOptions,
+    ) -> Result<bool, Error> {
+        let key = names::key(&rel_path.to_string_lossy(), opts);
+        match names.get(&key) {
+            // The same entry, found in another source
+            Some(other) if other == rel_path => Ok(false),
+            Some(other) => {
+                self.progress_output.send(ProgressMessage::SyncError {
+                    entry: rel_path.to_string_lossy().to_string(),
+                    details: format!(
+                        "{:?} would be the same entry as {:?} in the destination",
+                        rel_path, other
+                    ),
+                    kind: io::ErrorKind::AlreadyExists,
+                })?;
+                Ok(true)
+            }
+            None => {
+                names.insert(key, rel_path.to_path_buf());
+                Ok(false)
+            }
+        }
+    }
+
+    fn read_ignore_files(
+        &self,
+        source: &Path,
+        dir: &Path,
+        ignore_files: &mut Vec<Rc<IgnoreFile>>,
+    ) -> Result<(), Error> {
+        let base = fsops::get_rel_path(dir, source);
+        for name in &[".gitignore", ".ignore"] {
+            let path = dir.join(name);
+            if !path.is_file() {
+                continue;
+            }
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Could not read '{}'", path.display()))?;
+            ignore_files.push(Rc::new(IgnoreFile::parse(&base, &contents)));
+        }
+        Ok(())
+    }
+
+    fn process_file(
+        &self,
+        source: &Path,
+        entry: &DirEntry,
+        dereference: bool,
+    ) -> Result<Entry, Error> {
+        let rel_path = fsops::get_rel_path(&entry.path(), source);
         let desc = rel_path.to_string_lossy();
-        let src_entry = Entry::new(&desc, &entry.path());
-        let metadata = src_entry
+        let src_entry = if dereference {
+            Entry::dereferenced(&desc, &entry.path())
+        } else {
+            Entry::new(&desc, &entry.path())
+        };
+        src_entry
             .metadata()
             .with_context(|| format!("Could not read metadata from {:?}", entry.path()))?;
+        Ok(src_entry)
+    }
+
+    // Send an error that only concerns `path` to the progress worker, so
+    // that the rest of the source is still walked
+    fn report(&self, source: &Path, path: &Path, error: Error) -> Result<(), Error> {
+        self.incomplete.set(true);
+        let rel_path = fsops::get_rel_path(path, source);
+        let entry = if rel_path.as_os_str().is_empty() {
+            path.to_string_lossy().to_string()
+        } else {
+            rel_path.to_string_lossy().to_string()
+        };
+        let kind = error
+            .downcast_ref::<io::Error>()
+            .map_or(io::ErrorKind::Other, |e| e.kind());
+        self.progress_output.send(ProgressMessage::SyncError {
+            entry,
+            details: format!("{:#}", error),
+            kind,
+        })?;
+        Ok(())
+    }
+
+    fn send_entry(&self, src_entry: Entry) -> Result<(), Error> {
         self.entry_output
-            .send(src_entry.clone())
-            .with_context(|| "When walking source dir: could not send entry to progress worker")?;
-        Ok(metadata.clone())
+            .send(src_entry)
+            .with_context(|| "When walking source dir: could not send entry to progress worker")
+    }
+
+    fn send_scanning(&self, (entries_found, bytes_found): (u64, u64)) -> Result<(), Error> {
+        self.progress_output.send(ProgressMessage::Scanning {
+            entries_found,
+            bytes_found,
+        })?;
+        Ok(())
+    }
+
+    fn send_todo(&self, (num_files, total_size): (u64, u64), complete: bool) -> Result<(), Error> {
+        let sent = self.progress_output.send(ProgressMessage::Todo {
+            num_files,
+            total_size: total_size as usize,
+            complete,
+        });
+        if sent.is_err() {
+            bail!("stats output chan is closed");
+        }
+        Ok(())
     }
 
-    pub fn start(&self) {
-        let outcome = &self.walk();
-        if outcome.is_err() {
-            // Send err to output
+    /// Walk the sources, sending the entries to sync. Entries and
+    /// directories that cannot be read are reported as errors, and the walk
+    /// goes on: the result is then `Ok(false)`
+    pub fn start(&self, opts: SyncOptions) -> Result<bool, Error> {
+        match self.walk(opts) {
+            // Most likely the sync workers stopped and could not be sent
+            // the next entry
+            Err(_) if self.cancel.is_cancelled() => Ok(!self.incomplete.get()),
+            result => result.map(|_| !self.incomplete.get()),
+        }
+    }
+}
+
+// A directory being walked, linked to the one containing it
+struct Ancestor {
+    id: (u64, u64),
+    path: PathBuf,
+    parent: Ancestors,
+}
+
+type Ancestors = Option<Rc<Ancestor>>;
+
+fn find_ancestor(ancestors: &Ancestors, id: (u64, u64)) -> Option<&Path> {
+    let mut ancestor = ancestors.as_ref();
+    while let Some(current) = ancestor {
+        if current.id == id {
+            return Some(&current.path);
         }
+        ancestor = current.parent.as_ref();
+    }
+    None
+}
+
+// The device and inode numbers
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+// Only regular files are filtered by size and modification time
+fn is_filtered_out(metadata: &fs::Metadata, opts: SyncOptions) -> bool {
+    if !metadata.is_file() {
+        return false;
+    }
+    if opts
+        .min_size
+        .is_some_and(|min_size| metadata.len() < min_size)
+    {
+        return true;
     }
+    let modified = match metadata.modified() {
+        Ok(modified) => modified,
+        Err(_) => return false,
+    };
+    opts.newer_than.is_some_and(|time| modified < time)
+        || opts.older_than.is_some_and(|time| modified > time)
 }
@@ -0,0 +1,290 @@
+//! xxh3
+//!
+//! Streaming XXH3 hasher, 64-bit variant with the default secret and a
+//! zero seed. Not cryptographic, but faster than the disks it reads from,
+//! see `HashAlgorithm::Xxh3`. The digest is big-endian, like `xxhsum -H3`
+//! prints it.
+
+const SECRET: [u8; 192] = [
+    0xb8, 0xfe, 0x6c, 0x39, 0x23, 0xa4, 0x4b, 0xbe, 0x7c, 0x01, 0x81, 0x2c, 0xf7, 0x21, 0xad, 0x1c,
+    0xde, 0xd4, 0x6d, 0xe9, 0x83, 0x90, 0x97, 0xdb, 0x72, 0x40, 0xa4, 0xa4, 0xb7, 0xb3, 0x67, 0x1f,
+    0xcb, 0x79, 0xe6, 0x4e, 0xcc, 0xc0, 0xe5, 0x78, 0x82, 0x5a, 0xd0, 0x7d, 0xcc, 0xff, 0x72, 0x21,
+    0xb8, 0x08, 0x46, 0x74, 0xf7, 0x43, 0x24, 0x8e, 0xe0, 0x35, 0x90, 0xe6, 0x81, 0x3a, 0x26, 0x4c,
+    0x3c, 0x28, 0x52, 0xbb, 0x91, 0xc3, 0x00, 0xcb, 0x88, 0xd0, 0x65, 0x8b, 0x1b, 0x53, 0x2e, 0xa3,
+    0x71, 0x64, 0x48, 0x97, 0xa2, 0x0d, 0xf9, 0x4e, 0x38, 0x19, 0xef, 0x46, 0xa9, 0xde, 0xac, 0xd8,
+    0xa8, 0xfa, 0x76, 0x3f, 0xe3, 0x9c, 0x34, 0x3f, 0xf9, 0xdc, 0xbb, 0xc7, 0xc7, 0x0b, 0x4f, 0x1d,
+    0x8a, 0x51, 0xe0, 0x4b, 0xcd, 0xb4, 0x59, 0x31, 0xc8, 0x9f, 0x7e, 0xc9, 0xd9, 0x78, 0x73, 0x64,
+    0xea, 0xc5, 0xac, 0x83, 0x34, 0xd3, 0xeb, 0xc3, 0xc5, 0x81, 0xa0, 0xff, 0xfa, 0x13, 0x63, 0xeb,
+    0x17, 0x0d, 0xdd, 0x51, 0xb7, 0xf0, 0xda, 0x49, 0xd3, 0x16, 0x55, 0x26, 0x29, 0xd4, 0x68, 0x9e,
+    0x2b, 0x16, 0xbe, 0x58, 0x7d, 0x47, 0xa1, 0xfc, 0x8f, 0xf8, 0xb8, 0xd1, 0x7a, 0xd0, 0x31, 0xce,
+    0x45, 0xcb, 0x3a, 0x8f, 0x95, 0x16, 0x04, 0x28, 0xaf, 0xd7, 0xfb, 0xca, 0xbb, 0x4b, 0x40, 0x7e,
+];
+
+const PRIME32_1: u64 = 0x9E3779B1;
+const PRIME32_2: u64 = 0x85EBCA77;
+const PRIME32_3: u64 = 0xC2B2AE3D;
+const PRIME64_1: u64 = 0x9E3779B185EBCA87;
+const PRIME64_2: u64 = 0xC2B2AE3D27D4EB4F;
+const PRIME64_3: u64 = 0x165667B19E3779F9;
+const PRIME64_4: u64 = 0x85EBCA77C2B2AE63;
+const PRIME64_5: u64 = 0x27D4EB2F165667C5;
+const PRIME_MX1: u64 = 0x165667919E3779F9;
+const PRIME_MX2: u64 = 0x9FB21C651E98DF25;
+
+const STRIPE_LEN: usize = 64;
+const STRIPES_PER_BLOCK: usize = (SECRET.len() - STRIPE_LEN) / 8;
+const BLOCK_LEN: usize = STRIPE_LEN * STRIPES_PER_BLOCK;
+// Longer inputs are hashed by stripes
+const MIDSIZE_MAX: usize = 240;
+
+const INITIAL_ACC: [u64; 8] = [
+    PRIME32_3, PRIME64_1, PRIME64_2, PRIME64_3, PRIME64_4, PRIME32_2, PRIME64_5, PRIME32_1,
+];
+
+fn read64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn read32(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes) as u64
+}
+
+fn mul128_fold64(a: u64, b: u64) -> u64 {
+    let product = (a as u128) * (b as u128);
+    (product as u64) ^ ((product >> 64) as u64)
+}
+
+fn xxh64_avalanche(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(PRIME64_2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(PRIME64_3);
+    h ^ (h >> 32)
+}
+
+fn avalanche(mut h: u64) -> u64 {
+    h ^= h >> 37;
+    h = h.wrapping_mul(PRIME_MX1);
+    h ^ (h >> 32)
+}
+
+fn rrmxmx(mut h: u64, len: u64) -> u64 {
+    h ^= h.rotate_left(49) ^ h.rotate_left(24);
+    h = h.wrapping_mul(PRIME_MX2);
+    h ^= (h >> 35).wrapping_add(len);
+    h = h.wrapping_mul(PRIME_MX2);
+    h ^ (h >> 28)
+}
+
+fn mix16(data: &[u8], offset: usize, secret_offset: usize) -> u64 {
+    mul128_fold64(
+        read64(data, offset) ^ read64(&SECRET, secret_offset),
+        read64(data, offset + 8) ^ read64(&SECRET, secret_offset + 8),
+    )
+}
+
+// Inputs of at most MIDSIZE_MAX bytes are hashed in one go
+fn hash_short(data: &[u8]) -> u64 {
+    let len = data.len();
+    let len64 = len as u64;
+    match len {
+        0 => xxh64_avalanche(read64(&SECRET, 56) ^ read64(&SECRET, 64)),
+        1..=3 => {
+            let combined = ((data[0] as u64) << 16)
+                | ((data[len >> 1] as u64) << 24)
+                | (data[len - 1] as u64)
+                | (len64 << 8);
+            xxh64_avalanche(combined ^ (read32(&SECRET, 0) ^ read32(&SECRET, 4)))
+        }
+        4..=8 => {
+            let input = read32(data, len - 4).wrapping_add(read32(data, 0) << 32);
+            rrmxmx(input ^ (read64(&SECRET, 8) ^ read64(&SECRET, 16)), len64)
+        }
+        9..=16 => {
+            let low = read64(data, 0) ^ (read64(&SECRET, 24) ^ read64(&SECRET, 32));
+            let high = read64(data, len - 8) ^ (read64(&SECRET, 40) ^ read64(&SECRET, 48));
+            avalanche(
+                len64
+                    .wrapping_add(low.swap_bytes())
+                    .wrapping_add(high)
+                    .wrapping_add(mul128_fold64(low, high)),
+            )
+        }
+        17..=128 => {
+            let mut acc = len64.wrapping_mul(PRIME64_1);
+            // Pairs of 16-byte lanes from both ends, as many as needed
+            let pairs = (len - 1) / 32;
+            for i in (0..=pairs).rev() {
+                acc = acc
+                    .wrapping_add(mix16(data, 16 * i, 32 * i))
+                    .wrapping_add(mix16(data, len - 16 * (i + 1), 32 * i + 16));
+            }
+            avalanche(acc)
+        }
+        _ => {
+            let mut acc = len64.wrapping_mul(PRIME64_1);
+            for i in 0..8 {
+                acc = acc.wrapping_add(mix16(data, 16 * i, 16 * i));
+            }
+            acc = avalanche(acc);
+            for i in 8..len / 16 {
+                acc = acc.wrapping_add(mix16(data, 16 * i, 16 * (i - 8) + 3));
+            }
+            avalanche(acc.wrapping_add(mix16(data, len - 16, 136 - 17)))
+        }
+    }
+}
+
+fn accumulate_stripe(acc: &mut [u64; 8], stripe: &[u8], secret_offset: usize) {
+    for i in 0..8 {
+        let value = read64(stripe, 8 * i);
+        let key = value ^ read64(&SECRET, secret_offset + 8 * i);
+        acc[i ^ 1] = acc[i ^ 1].wrapping_add(value);
+        acc[i] = acc[i].wrapping_add((key & 0xFFFF_FFFF).wrapping_mul(key >> 32));
+    }
+}
+
+fn scramble(acc: &mut [u64; 8]) {
+    for (i, lane) in acc.iter_mut().enumerate() {
+        let key = read64(&SECRET, SECRET.len() - STRIPE_LEN + 8 * i);
+        *lane = (*lane ^ (*lane >> 47) ^ key).wrapping_mul(PRIME32_1);
+    }
+}
+
+pub struct Xxh3 {
+    acc: [u64; 8],
+    // Not hashed yet: the last block may only be hashed once the end of
+    // the input is known
+    buffer: Vec<u8>,
+    // The end of the last hashed block, which the last stripe of the
+    // input may overlap
+    last_stripe: [u8; STRIPE_LEN],
+    total_len: u64,
+}
+
+impl Xxh3 {
+    pub fn new() -> Xxh3 {
+        Xxh3 {
+            acc: INITIAL_ACC,
+            buffer: Vec::with_capacity(2 * BLOCK_LEN),
+            last_stripe: [0; STRIPE_LEN],
+            total_len: 0,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+        self.total_len += data.len() as u64;
+        let mut start = 0;
+        while self.buffer.len() - start > BLOCK_LEN {
+            let block = &self.buffer[start..start + BLOCK_LEN];
+            for (i, stripe) in block.chunks(STRIPE_LEN).enumerate() {
+                accumulate_stripe(&mut self.acc, stripe, 8 * i);
+            }
+            scramble(&mut self.acc);
+            start += BLOCK_LEN;
+        }
+        if start != 0 {
+            self.last_stripe
+                .copy_from_slice(&self.buffer[start - STRIPE_LEN..start]);
+            self.buffer.drain(..start);
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let hash = if self.total_len <= MIDSIZE_MAX as u64 {
+            hash_short(&self.buffer)
+        } else {
+            self.hash_long()
+        };
+        hash.to_be_bytes().to_vec()
+    }
+
+    fn hash_long(&self) -> u64 {
+        let mut acc = self.acc;
+        let len = self.buffer.len();
+        let stripes = (len - 1) / STRIPE_LEN;
+        for i in 0..stripes {
+            let offset = i * STRIPE_LEN;
+            accumulate_stripe(&mut acc, &self.buffer[offset..offset + STRIPE_LEN], 8 * i);
+        }
+        let mut last_stripe = [0; STRIPE_LEN];
+        if len >= STRIPE_LEN {
+            last_stripe.copy_from_slice(&self.buffer[len - STRIPE_LEN..]);
+        } else {
+            last_stripe[..STRIPE_LEN - len].copy_from_slice(&self.last_stripe[len..]);
+            last_stripe[STRIPE_LEN - len..].copy_from_slice(&self.buffer);
+        }
+        accumulate_stripe(&mut acc, &last_stripe, SECRET.len() - STRIPE_LEN - 7);
+
+        let mut result = self.total_len.wrapping_mul(PRIME64_1);
+        for i in 0..4 {
+            result = result.wrapping_add(mul128_fold64(
+                acc[2 * i] ^ read64(&SECRET, 11 + 16 * i),
+                acc[2 * i + 1] ^ read64(&SECRET, 11 + 16 * i + 8),
+            ));
+        }
+        avalanche(result)
+    }
+}
+
+impl Default for Xxh3 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum_cache::to_hex;
+
+    fn xxh3_hex(data: &[u8]) -> String {
+        let mut hasher = Xxh3::new();
+        hasher.update(data);
+        to_hex(&hasher.finish())
+    }
+
+    #[test]
+    fn test_xxh3_known_vectors() {
+        assert_eq!(xxh3_hex(b""), "2d06800538d394c2");
+        assert_eq!(xxh3_hex(b"a"), "e6c632b61e964e1f");
+        assert_eq!(xxh3_hex(b"hello"), "9555e8555c62dcfd");
+        // One input in each size class
+        let vectors = [
+            (3, "5f4299fc161c9cbb"),
+            (8, "3a1c2d7c85af88f8"),
+            (16, "8355e3a6f61770db"),
+            (17, "9ef341a99de37328"),
+            (97, "e7220282dc4e14f4"),
+            (129, "ec7642b431ba3e5a"),
+            (240, "375a384d957fe865"),
+            (241, "02e8cd95421c6d02"),
+            (1024, "e5d78bafa45b2aa5"),
+            (1025, "e95c42288f28186e"),
+            (1088, "1a848d807034c403"),
+            (5000, "b418500fc42320ee"),
+            (102400, "1428e17f1cac2837"),
+        ];
+        for (len, expected) in vectors.iter() {
+            let data: Vec<u8> = (0..*len).map(|i| (i % 251) as u8).collect();
+            assert_eq!(xxh3_hex(&data), *expected, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn test_xxh3_streaming() {
+        let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        for chunk_len in [1, 37, 64, 1024, 1025].iter() {
+            let mut hasher = Xxh3::new();
+            for chunk in data.chunks(*chunk_len) {
+                hasher.update(chunk);
+            }
+            assert_eq!(to_hex(&hasher.finish()), xxh3_hex(&data));
+        }
+    }
+}
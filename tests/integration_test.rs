@@ -17,7 +17,7 @@ fn assert_same_contents(a: &Path, b: &Path) {
     assert!(a.exists(), "{:?} does not exist", a);
     assert!(b.exists(), "{:?} does not exist", b);
     let status = Command::new("diff")
-        .args(&[a, b])
+        .args([a, b])
         .status()
         .expect("Failed to execute process");
     assert!(status.success(), "{:?} and {:?} differ", a, b)
@@ -25,7 +25,7 @@ fn assert_same_contents(a: &Path, b: &Path) {
 
 #[cfg(unix)]
 fn is_executable(path: &Path) -> bool {
-    let metadata = std::fs::metadata(&path)
+    let metadata = std::fs::metadata(path)
         .unwrap_or_else(|e| panic!("Could not get metadata of {:?}: {}", path, e));
     let permissions = metadata.permissions();
     let mode = permissions.mode();
@@ -35,7 +35,7 @@ fn is_executable(path: &Path) -> bool {
 #[cfg(unix)]
 fn assert_executable(path: &Path) {
     assert!(
-        is_executable(&path),
+        is_executable(path),
         "{:?} does not appear to be executable",
         path
     );
@@ -43,14 +43,14 @@ fn assert_executable(path: &Path) {
 
 #[cfg(unix)]
 fn assert_not_executable(path: &Path) {
-    assert!(!is_executable(&path), "{:?} appears to be executable", path);
+    assert!(!is_executable(path), "{:?} appears to be executable", path);
 }
 
 fn setup_test(tmp_path: &Path) -> (PathBuf, PathBuf) {
     let src_path = tmp_path.join("src");
     let dest_path = tmp_path.join("dest");
     let status = Command::new("cp")
-        .args(&["-R", "tests/data", &src_path.to_string_lossy()])
+        .args(["-R", "tests/data", &src_path.to_string_lossy()])
         .status()
         .expect("Failed to start cp process");
     assert!(status.success(), "could not copy test data");
@@ -58,13 +58,13 @@ fn setup_test(tmp_path: &Path) -> (PathBuf, PathBuf) {
 }
 
 fn make_recent(path: &Path) -> io::Result<()> {
-    let metadata = fs::metadata(&path)?;
+    let metadata = fs::metadata(path)?;
     let atime = FileTime::from_last_access_time(&metadata);
     let mtime = FileTime::from_last_modification_time(&metadata);
     let mut epoch = mtime.seconds_relative_to_1970();
     epoch += 1;
     let mtime = FileTime::from_seconds_since_1970(epoch, 0);
-    filetime::set_file_times(&path, atime, mtime)?;
+    filetime::set_file_times(path, atime, mtime)?;
     Ok(())
 }
 
@@ -75,14 +75,15 @@ fn new_test_syncer(src: &Path, dest: &Path) -> rusync::Syncer {
     let dummy_progress_info = DummyProgressInfo {};
     let options = rusync::SyncOptions {
         preserve_permissions: true,
+        ..Default::default()
     };
-    rusync::Syncer::new(&src, &dest, options, Box::new(dummy_progress_info))
+    rusync::Syncer::new(src, dest, options, Box::new(dummy_progress_info))
 }
 
 #[test]
 fn fresh_copy() -> Result<(), std::io::Error> {
     let tmp_dir = TempDir::new("test-rusync")?;
-    let (src_path, dest_path) = setup_test(&tmp_dir.path());
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
     let syncer = new_test_syncer(&src_path, &dest_path);
     let outcome = syncer.sync();
     assert!(outcome.is_ok());
@@ -97,7 +98,7 @@ fn fresh_copy() -> Result<(), std::io::Error> {
 #[test]
 fn skip_up_to_date_files() -> Result<(), std::io::Error> {
     let tmp_dir = TempDir::new("test-rusync")?;
-    let (src_path, dest_path) = setup_test(&tmp_dir.path());
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
     let syncer = new_test_syncer(&src_path, &dest_path);
 
     let stats = syncer.sync().unwrap();
@@ -116,12 +117,12 @@ fn skip_up_to_date_files() -> Result<(), std::io::Error> {
 #[cfg(unix)]
 fn preserve_permissions() -> Result<(), std::io::Error> {
     let tmp_dir = TempDir::new("test-rusync")?;
-    let (src_path, dest_path) = setup_test(&tmp_dir.path());
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
     let syncer = new_test_syncer(&src_path, &dest_path);
     syncer.sync().unwrap();
 
     let dest_exe = &dest_path.join("a_dir/foo.exe");
-    assert_executable(&dest_exe);
+    assert_executable(dest_exe);
     Ok(())
 }
 
@@ -129,9 +130,10 @@ fn preserve_permissions() -> Result<(), std::io::Error> {
 #[cfg(unix)]
 fn do_not_preserve_permissions() -> Result<(), std::io::Error> {
     let tmp_dir = TempDir::new("test-rusync")?;
-    let (src_path, dest_path) = setup_test(&tmp_dir.path());
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
     let options = rusync::SyncOptions {
         preserve_permissions: false,
+        ..Default::default()
     };
     let syncer = rusync::Syncer::new(
         &src_path,
@@ -142,14 +144,14 @@ fn do_not_preserve_permissions() -> Result<(), std::io::Error> {
     syncer.sync().unwrap();
 
     let dest_exe = &dest_path.join("a_dir/foo.exe");
-    assert_not_executable(&dest_exe);
+    assert_not_executable(dest_exe);
     Ok(())
 }
 
 #[test]
 fn rewrite_partially_written_files() -> Result<(), std::io::Error> {
     let tmp_dir = TempDir::new("test-rusync")?;
-    let (src_path, dest_path) = setup_test(&tmp_dir.path());
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
     let src_top = src_path.join("top.txt");
     let expected = fs::read_to_string(&src_top)?;
 
@@ -169,7 +171,7 @@ fn rewrite_partially_written_files() -> Result<(), std::io::Error> {
 #[test]
 fn dest_read_only() -> Result<(), std::io::Error> {
     let tmp_dir = TempDir::new("test-rusync")?;
-    let (src_path, dest_path) = setup_test(&tmp_dir.path());
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
     fs::create_dir_all(&dest_path)?;
 
     let dest_top = dest_path.join("top.txt");
@@ -192,9 +194,9 @@ fn dest_read_only() -> Result<(), std::io::Error> {
 #[cfg(unix)]
 fn broken_link_in_src() -> Result<(), std::io::Error> {
     let tmp_dir = TempDir::new("test-rusync")?;
-    let (src_path, dest_path) = setup_test(&tmp_dir.path());
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
     let src_broken_link = &src_path.join("broken");
-    unix::fs::symlink("no-such", &src_broken_link)?;
+    unix::fs::symlink("no-such", src_broken_link)?;
 
     let syncer = new_test_syncer(&src_path, &dest_path);
     let result = syncer.sync();
@@ -205,3 +207,3005 @@ fn broken_link_in_src() -> Result<(), std::io::Error> {
     assert!(result.is_ok());
     Ok(())
 }
+
+#[test]
+fn delete_extraneous_files() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    let extra_file = dest_path.join("a_dir/extra.txt");
+    fs::write(&extra_file, "extra")?;
+    let extra_dir = dest_path.join("extra_dir");
+    fs::create_dir_all(&extra_dir)?;
+    fs::write(extra_dir.join("nested.txt"), "nested")?;
+
+    let options = rusync::SyncOptions {
+        delete_extraneous: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert!(!extra_file.exists());
+    assert!(!extra_dir.exists());
+    assert_eq!(stats.deleted, 3);
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    Ok(())
+}
+
+#[test]
+fn delete_timings() -> Result<(), std::io::Error> {
+    let timings = [
+        rusync::DeleteTiming::Before,
+        rusync::DeleteTiming::During,
+        rusync::DeleteTiming::After,
+    ];
+    for delete_timing in timings {
+        let tmp_dir = TempDir::new("test-rusync")?;
+        let (src_path, dest_path) = setup_test(tmp_dir.path());
+        fs::create_dir_all(dest_path.join("extra_dir"))?;
+        fs::write(dest_path.join("extra_dir/nested.txt"), "nested")?;
+        fs::create_dir_all(dest_path.join("a_dir"))?;
+        fs::write(dest_path.join("a_dir/extra.txt"), "extra")?;
+        let log_path = tmp_dir.path().join("rusync.log");
+
+        let options = rusync::SyncOptions {
+            delete_extraneous: true,
+            delete_timing,
+            atomic: true,
+            ..Default::default()
+        };
+        let mut syncer = rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options,
+            Box::new(DummyProgressInfo {}),
+        );
+        syncer.num_workers(4);
+        syncer.log_file(&log_path)?;
+        let stats = syncer.sync().unwrap();
+
+        assert_eq!(stats.errors, 0, "{:?}", delete_timing);
+        assert_eq!(stats.deleted, 3, "{:?}", delete_timing);
+        assert_eq!(stats.copied, 5, "{:?}", delete_timing);
+        assert!(!dest_path.join("extra_dir").exists());
+        assert!(!dest_path.join("a_dir/extra.txt").exists());
+        let log = fs::read_to_string(&log_path)?;
+        let first_deleted = log.lines().position(|l| l.contains(" deleted: "));
+        let first_copied = log.lines().position(|l| l.contains(" copied: "));
+        match delete_timing {
+            rusync::DeleteTiming::Before => assert!(first_deleted < first_copied),
+            rusync::DeleteTiming::After => assert!(first_deleted > first_copied),
+            rusync::DeleteTiming::During => (),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn max_delete() -> Result<(), std::io::Error> {
+    let limits = [
+        (rusync::MaxDelete::Entries(2), false),
+        (rusync::MaxDelete::Entries(3), true),
+        (rusync::MaxDelete::Percent(10.0), false),
+        (rusync::MaxDelete::Percent(50.0), true),
+    ];
+    assert_eq!("50%".parse(), Ok(rusync::MaxDelete::Percent(50.0)));
+    assert_eq!("3".parse(), Ok(rusync::MaxDelete::Entries(3)));
+    assert!("150%".parse::<rusync::MaxDelete>().is_err());
+    for (max_delete, allowed) in limits {
+        let tmp_dir = TempDir::new("test-rusync")?;
+        let (src_path, dest_path) = setup_test(tmp_dir.path());
+        fs::create_dir_all(dest_path.join("extra_dir"))?;
+        fs::write(dest_path.join("extra_dir/nested.txt"), "nested")?;
+        fs::create_dir_all(dest_path.join("a_dir"))?;
+        fs::write(dest_path.join("a_dir/extra.txt"), "extra")?;
+
+        let options = rusync::SyncOptions {
+            delete_extraneous: true,
+            max_delete: Some(max_delete),
+            ..Default::default()
+        };
+        let syncer = rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options,
+            Box::new(DummyProgressInfo {}),
+        );
+        let result = syncer.sync();
+
+        // 3 of the 11 entries of the destination are extraneous
+        if allowed {
+            assert_eq!(result.unwrap().deleted, 3, "{}", max_delete);
+            assert!(!dest_path.join("extra_dir").exists());
+        } else {
+            assert!(
+                matches!(result, Err(rusync::SyncError::Delete(_))),
+                "{}",
+                max_delete
+            );
+            assert!(dest_path.join("extra_dir/nested.txt").exists());
+            assert!(dest_path.join("a_dir/extra.txt").exists());
+        }
+    }
+    Ok(())
+}
+
+#[test]
+#[cfg(all(unix, not(target_os = "macos")))]
+fn delete_to_trash() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(dest_path.join("extra_dir"))?;
+    fs::write(dest_path.join("extra_dir/nested.txt"), "nested")?;
+    fs::write(dest_path.join("extra.txt"), "extra")?;
+    // Only this test reads the trash location
+    let data_home = tmp_dir.path().join("share");
+    std::env::set_var("XDG_DATA_HOME", &data_home);
+
+    let options = rusync::SyncOptions {
+        delete_extraneous: true,
+        delete_to_trash: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.deleted, 2);
+    assert!(!dest_path.join("extra_dir").exists());
+    assert!(!dest_path.join("extra.txt").exists());
+    let trash = data_home.join("Trash");
+    assert_eq!(
+        fs::read_to_string(trash.join("files/extra_dir/nested.txt"))?,
+        "nested"
+    );
+    let info = fs::read_to_string(trash.join("info/extra.txt.trashinfo"))?;
+    let original = fs::canonicalize(&dest_path)?.join("extra.txt");
+    assert!(info.contains(&format!("Path={}\n", original.display())));
+    Ok(())
+}
+
+#[test]
+fn keep_extraneous_files_by_default() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    let extra_file = dest_path.join("extra.txt");
+    fs::write(&extra_file, "extra")?;
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+
+    assert!(extra_file.exists());
+    assert_eq!(stats.deleted, 0);
+    Ok(())
+}
+
+#[test]
+fn exclude_patterns() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let mut syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.include("one.txt");
+    syncer.exclude("*.txt");
+    syncer.exclude("b_dir/");
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 2);
+    assert!(dest_path.join("a_dir/one.txt").exists());
+    assert!(dest_path.join("a_dir/foo.exe").exists());
+    assert!(!dest_path.join("a_dir/two.txt").exists());
+    assert!(!dest_path.join("top.txt").exists());
+    assert!(!dest_path.join("b_dir").exists());
+    Ok(())
+}
+
+#[test]
+fn excluded_files_are_not_deleted() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(&dest_path)?;
+    let protected = dest_path.join("notes.bak");
+    fs::write(&protected, "keep me")?;
+
+    let options = rusync::SyncOptions {
+        delete_extraneous: true,
+        ..Default::default()
+    };
+    let mut syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.exclude("*.bak");
+    let stats = syncer.sync().unwrap();
+
+    assert!(protected.exists());
+    assert_eq!(stats.deleted, 0);
+    Ok(())
+}
+
+#[test]
+fn respect_gitignore() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::write(src_path.join(".gitignore"), "b_dir/\n*.exe\n")?;
+    fs::write(src_path.join("a_dir/.ignore"), "two.txt\n")?;
+
+    let options = rusync::SyncOptions {
+        respect_gitignore: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+
+    assert!(dest_path.join(".gitignore").exists());
+    assert!(dest_path.join("top.txt").exists());
+    assert!(dest_path.join("a_dir/one.txt").exists());
+    assert!(!dest_path.join("a_dir/two.txt").exists());
+    assert!(!dest_path.join("a_dir/foo.exe").exists());
+    assert!(!dest_path.join("b_dir").exists());
+    Ok(())
+}
+
+#[test]
+fn checksum_mode_ignores_timestamps() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    // Same size, different contents, and a destination that looks newer
+    let src_top = src_path.join("top.txt");
+    let dest_top = dest_path.join("top.txt");
+    let expected = fs::read_to_string(&src_top)?;
+    fs::write(&dest_top, "x".repeat(expected.len()))?;
+    make_recent(&dest_top)?;
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.copied, 0);
+
+    let options = rusync::SyncOptions {
+        checksum: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.copied, 1);
+    assert_eq!(fs::read_to_string(&dest_top)?, expected);
+    Ok(())
+}
+
+#[test]
+fn size_only_ignores_timestamps() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    new_test_syncer(&src_path, &dest_path).sync().unwrap();
+
+    // Same size, but a source that looks newer
+    make_recent(&src_path.join("top.txt"))?;
+    fs::write(src_path.join("a_dir/one.txt"), "one has grown\n")?;
+    let options = rusync::SyncOptions {
+        size_only: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 1);
+    assert_eq!(stats.up_to_date, 4);
+    assert_same_contents(
+        &src_path.join("a_dir/one.txt"),
+        &dest_path.join("a_dir/one.txt"),
+    );
+    Ok(())
+}
+
+#[test]
+fn checksum_cache() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    new_test_syncer(&src_path, &dest_path).sync().unwrap();
+
+    // Recently modified files are not cached
+    let old = FileTime::from_seconds_since_1970(1_500_000_000, 0);
+    filetime::set_file_times(src_path.join("top.txt"), old, old)?;
+    filetime::set_file_times(dest_path.join("top.txt"), old, old)?;
+    let options = rusync::SyncOptions {
+        checksum: true,
+        checksum_cache: true,
+        delete_extraneous: true,
+        ..Default::default()
+    };
+    let sync = |options| {
+        rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options,
+            Box::new(DummyProgressInfo {}),
+        )
+        .sync()
+        .unwrap()
+    };
+    let stats = sync(options);
+    assert_eq!(stats.up_to_date, 5);
+    assert_eq!(stats.deleted, 0);
+    assert!(dest_path.join(".rusync-checksums").exists());
+
+    // Same size and modification time: the cached checksum is trusted
+    fs::write(dest_path.join("top.txt"), "not the top file")?;
+    filetime::set_file_times(dest_path.join("top.txt"), old, old)?;
+    let stats = sync(options);
+    assert_eq!(stats.up_to_date, 5);
+    assert!(dest_path.join(".rusync-checksums").exists());
+
+    let stats = sync(rusync::SyncOptions {
+        checksum_cache: false,
+        ..options
+    });
+    assert_eq!(stats.copied, 1);
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn exit_status_with_failed_entries() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let rusync = |src_path: &Path| {
+        Command::new(env!("CARGO_BIN_EXE_rusync"))
+            .args(["--quiet", "--symlinks", "dereference"])
+            .arg(src_path)
+            .arg(&dest_path)
+            .status()
+            .expect("Failed to start rusync")
+    };
+    assert_eq!(rusync(&src_path).code(), Some(0));
+
+    std::os::unix::fs::symlink("no-such-file", src_path.join("broken"))?;
+    assert_eq!(rusync(&src_path).code(), Some(2));
+
+    assert_eq!(rusync(&tmp_dir.path().join("missing")).code(), Some(1));
+    Ok(())
+}
+
+#[test]
+fn sources_named_like_commands() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    for name in ["verify", "bisync", "run"].iter() {
+        fs::create_dir(tmp_dir.path().join(name))?;
+        fs::write(tmp_dir.path().join(name).join(format!("{}.txt", name)), "")?;
+    }
+    let status = Command::new(env!("CARGO_BIN_EXE_rusync"))
+        .current_dir(tmp_dir.path())
+        .args(["--quiet", "verify", "bisync", "run", "dest"])
+        .status()
+        .expect("Failed to start rusync");
+
+    assert!(status.success());
+    assert!(tmp_dir.path().join("dest/verify.txt").exists());
+    assert!(tmp_dir.path().join("dest/bisync.txt").exists());
+    assert!(tmp_dir.path().join("dest/run.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn sync_profile() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let config_path = tmp_dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        format!(
+            "[profile.test]\nsource = {:?}\ndestination = {:?}\n",
+            src_path, dest_path
+        ),
+    )?;
+    let status = Command::new(env!("CARGO_BIN_EXE_rusync"))
+        .env("XDG_CONFIG_HOME", tmp_dir.path())
+        .arg("--quiet")
+        .arg("--config")
+        .arg(&config_path)
+        .args(["--profile", "test"])
+        .status()
+        .expect("Failed to start rusync");
+
+    assert!(status.success());
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    Ok(())
+}
+
+#[test]
+fn turn_off_configured_flag() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let config_path = tmp_dir.path().join("config.toml");
+    fs::write(&config_path, "delete = true\n")?;
+    let extra_path = dest_path.join("extra.txt");
+    fs::create_dir_all(&dest_path)?;
+    fs::write(&extra_path, "")?;
+    let rusync = |option: &str| {
+        Command::new(env!("CARGO_BIN_EXE_rusync"))
+            .env("XDG_CONFIG_HOME", tmp_dir.path())
+            .arg("--quiet")
+            .arg("--config")
+            .arg(&config_path)
+            .arg(option)
+            .arg(&src_path)
+            .arg(&dest_path)
+            .status()
+            .expect("Failed to start rusync")
+    };
+
+    assert!(rusync("--no-delete").success());
+    assert!(extra_path.exists());
+    assert!(rusync("--no-perms").success());
+    assert!(!extra_path.exists());
+    Ok(())
+}
+
+#[test]
+fn write_stats_json() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let stats_path = tmp_dir.path().join("stats.json");
+    let status = Command::new(env!("CARGO_BIN_EXE_rusync"))
+        .arg("--quiet")
+        .arg("--stats-json")
+        .arg(&stats_path)
+        .arg(&src_path)
+        .arg(&dest_path)
+        .status()
+        .expect("Failed to start rusync");
+    assert!(status.success());
+
+    let json = fs::read_to_string(&stats_path)?;
+    assert!(json.starts_with("{\"num_files\":5,"), "{}", json);
+    assert!(json.contains("\"copied\":5,"), "{}", json);
+    assert!(json.ends_with("}\n"));
+    Ok(())
+}
+
+#[test]
+fn syncer_builder() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+
+    let built = rusync::Syncer::builder().destination(&dest_path).build();
+    assert!(matches!(built, Err(rusync::SyncError::NoSource)));
+    let built = rusync::Syncer::builder()
+        .source(tmp_dir.path().join("missing"))
+        .destination(&dest_path)
+        .build();
+    assert!(matches!(built, Err(rusync::SyncError::InvalidSource(_))));
+    let built = rusync::Syncer::builder()
+        .source(&src_path)
+        .destination(&dest_path)
+        .filters(vec![rusync::Filter::ExcludeRegex("(".to_string())])
+        .build();
+    assert!(matches!(built, Err(rusync::SyncError::InvalidPattern(..))));
+
+    let syncer = rusync::Syncer::builder()
+        .source(&src_path)
+        .destination(&dest_path)
+        .workers(2)
+        .filters(vec![rusync::Filter::Exclude("a_dir/".to_string())])
+        .build()
+        .unwrap();
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.copied, 2);
+    assert!(!dest_path.join("a_dir").exists());
+    Ok(())
+}
+
+#[test]
+fn modify_window() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    new_test_syncer(&src_path, &dest_path).sync().unwrap();
+
+    // Less than a second newer
+    make_recent(&src_path.join("top.txt"))?;
+    let src_one = src_path.join("a_dir/one.txt");
+    let mtime = FileTime::from_last_modification_time(&fs::metadata(&src_one)?);
+    let mtime = FileTime::from_seconds_since_1970(mtime.seconds_relative_to_1970() + 5, 0);
+    filetime::set_file_times(&src_one, mtime, mtime)?;
+    let options = rusync::SyncOptions {
+        modify_window: std::time::Duration::from_secs(2),
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 1);
+    assert_eq!(stats.up_to_date, 4);
+    Ok(())
+}
+
+#[test]
+fn only_update_existing_files() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(&dest_path)?;
+    fs::write(dest_path.join("top.txt"), "old top")?;
+
+    let options = rusync::SyncOptions {
+        existing: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 1);
+    assert_eq!(stats.missing_skipped, 4);
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    assert!(!dest_path.join("a_dir").exists());
+    Ok(())
+}
+
+#[test]
+fn preserve_modification_times() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let src_top = src_path.join("top.txt");
+    let old_mtime = FileTime::from_seconds_since_1970(1_000_000_000, 0);
+    filetime::set_file_times(&src_top, old_mtime, old_mtime)?;
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    let dest_meta = fs::metadata(dest_path.join("top.txt"))?;
+    let dest_mtime = FileTime::from_last_modification_time(&dest_meta);
+    assert_eq!(dest_mtime.seconds_relative_to_1970(), 1_000_000_000);
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.copied, 0);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn preserve_ownership() -> Result<(), std::io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let src_top = src_path.join("top.txt");
+    if unix::fs::chown(&src_top, Some(1234), Some(5678)).is_err() {
+        // Not running as root, nothing to test
+        return Ok(());
+    }
+
+    let options = rusync::SyncOptions {
+        preserve_owner: true,
+        preserve_group: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    let dest_meta = fs::metadata(dest_path.join("top.txt"))?;
+    assert_eq!(dest_meta.uid(), 1234);
+    assert_eq!(dest_meta.gid(), 5678);
+    assert_eq!(stats.warnings, 0);
+    Ok(())
+}
+
+#[test]
+fn prune_empty_dirs() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(src_path.join("empty_dir"))?;
+    fs::create_dir_all(dest_path.join("empty_dir"))?;
+    fs::write(dest_path.join("empty_dir/stale.txt"), "stale")?;
+    fs::create_dir_all(dest_path.join("old/nested"))?;
+    fs::create_dir_all(dest_path.join("kept"))?;
+
+    let options = rusync::SyncOptions {
+        delete_extraneous: true,
+        prune_empty_dirs: true,
+        ..Default::default()
+    };
+    let mut syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.exclude("kept");
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert!(!dest_path.join("empty_dir").exists());
+    assert!(!dest_path.join("old").exists());
+    assert!(dest_path.join("kept").is_dir());
+    assert!(dest_path.join("b_dir/c_dir/three.txt").exists());
+    // old, old/nested and empty_dir/stale.txt are extraneous, and then
+    // empty_dir is pruned
+    assert_eq!(stats.deleted, 4);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn sync_directory_attributes() -> Result<(), std::io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let c_dir = src_path.join("b_dir/c_dir");
+    fs::set_permissions(&c_dir, fs::Permissions::from_mode(0o750))?;
+    let mtime = FileTime::from_seconds_since_1970(1_000_000_000, 0);
+    filetime::set_file_times(&c_dir, mtime, mtime)?;
+    filetime::set_file_times(src_path.join("b_dir"), mtime, mtime)?;
+    fs::create_dir_all(dest_path.join("b_dir"))?;
+    fs::write(dest_path.join("b_dir/extra.txt"), "extra")?;
+
+    let options = rusync::SyncOptions {
+        delete_extraneous: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    let dest_c_dir = fs::metadata(dest_path.join("b_dir/c_dir"))?;
+    assert_eq!(dest_c_dir.permissions().mode() & 0o7777, 0o750);
+    assert_eq!(FileTime::from_last_modification_time(&dest_c_dir), mtime);
+    // Even though an extraneous file was deleted from it
+    let dest_b_dir = fs::metadata(dest_path.join("b_dir"))?;
+    assert_eq!(FileTime::from_last_modification_time(&dest_b_dir), mtime);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn chmod_destination() -> Result<(), std::io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let src_top = src_path.join("top.txt");
+    fs::set_permissions(&src_top, fs::Permissions::from_mode(0o600))?;
+    fs::set_permissions(src_path.join("a_dir"), fs::Permissions::from_mode(0o700))?;
+
+    let mut syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        rusync::SyncOptions::default(),
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.chmod("D755,F644").unwrap();
+    syncer.chmod("Fu+x").unwrap();
+    assert!(syncer.chmod("F64x").is_err());
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+    assert_eq!(mode(&dest_path.join("top.txt")), 0o744);
+    assert_eq!(mode(&dest_path.join("a_dir/one.txt")), 0o744);
+    assert_eq!(mode(&dest_path.join("a_dir")), 0o755);
+    assert_eq!(mode(&dest_path.join("b_dir/c_dir")), 0o755);
+    assert_eq!(mode(&src_top), 0o600);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn map_ownership() -> Result<(), std::io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let src_top = src_path.join("top.txt");
+    if unix::fs::chown(&src_top, Some(1234), Some(5678)).is_err() {
+        // Not running as root, nothing to test
+        return Ok(());
+    }
+    unix::fs::chown(src_path.join("a_dir/one.txt"), Some(2000), Some(2000))?;
+
+    let options = rusync::SyncOptions {
+        preserve_owner: true,
+        preserve_group: true,
+        numeric_ids: true,
+        ..Default::default()
+    };
+    let mut syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.map_users("1234:4321,2000-2999:3000").unwrap();
+    syncer.map_groups("*:8765").unwrap();
+    assert!(syncer.map_users("root:0").is_err());
+    let stats = syncer.sync().unwrap();
+
+    let dest_meta = fs::metadata(dest_path.join("top.txt"))?;
+    assert_eq!(dest_meta.uid(), 4321);
+    assert_eq!(dest_meta.gid(), 8765);
+    let dest_meta = fs::metadata(dest_path.join("a_dir/one.txt"))?;
+    assert_eq!(dest_meta.uid(), 3000);
+    assert_eq!(dest_meta.gid(), 8765);
+    assert_eq!(stats.warnings, 0);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn preserve_hard_links() -> Result<(), std::io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::hard_link(src_path.join("top.txt"), src_path.join("a_dir/link.txt"))?;
+
+    let new_syncer = || {
+        let options = rusync::SyncOptions {
+            preserve_hard_links: true,
+            ..Default::default()
+        };
+        rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options,
+            Box::new(DummyProgressInfo {}),
+        )
+    };
+    let stats = new_syncer().sync().unwrap();
+    assert_eq!(stats.hardlinks_created, 1);
+
+    let dest_top = fs::metadata(dest_path.join("top.txt"))?;
+    let dest_link = fs::metadata(dest_path.join("a_dir/link.txt"))?;
+    assert_eq!(dest_top.ino(), dest_link.ino());
+
+    let stats = new_syncer().sync().unwrap();
+    assert_eq!(stats.hardlinks_created, 0);
+    assert_eq!(stats.copied, 0);
+    Ok(())
+}
+
+#[test]
+fn sparse_copy() -> Result<(), std::io::Error> {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let src_sparse = src_path.join("sparse.img");
+    let mut file = fs::File::create(&src_sparse)?;
+    file.write_all(b"start")?;
+    file.seek(SeekFrom::Start(4 * 1024 * 1024))?;
+    file.write_all(b"end")?;
+    file.set_len(8 * 1024 * 1024)?;
+    drop(file);
+
+    let options = rusync::SyncOptions {
+        sparse: true,
+        reflink: rusync::ReflinkMode::Never,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.sparse_copied, 1);
+    let dest_sparse = dest_path.join("sparse.img");
+    assert_eq!(fs::read(&src_sparse)?, fs::read(&dest_sparse)?);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn make_fifo(path: &Path) {
+    let status = Command::new("mkfifo")
+        .arg(path)
+        .status()
+        .expect("Failed to start mkfifo process");
+    assert!(status.success(), "could not create fifo");
+}
+
+#[test]
+#[cfg(unix)]
+fn skip_special_files_by_default() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    make_fifo(&src_path.join("fifo"));
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.specials_skipped, 1);
+    assert_eq!(stats.warnings, 1);
+    assert_eq!(stats.errors, 0);
+    assert!(fs::symlink_metadata(dest_path.join("fifo")).is_err());
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn copy_special_files() -> Result<(), std::io::Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    make_fifo(&src_path.join("fifo"));
+
+    let new_syncer = || {
+        let options = rusync::SyncOptions {
+            copy_specials: true,
+            ..Default::default()
+        };
+        rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options,
+            Box::new(DummyProgressInfo {}),
+        )
+    };
+    let stats = new_syncer().sync().unwrap();
+    assert_eq!(stats.specials_created, 1);
+    let dest_meta = fs::symlink_metadata(dest_path.join("fifo"))?;
+    assert!(dest_meta.file_type().is_fifo());
+
+    let stats = new_syncer().sync().unwrap();
+    assert_eq!(stats.specials_created, 0);
+    assert_eq!(stats.errors, 0);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn setup_symlinks_test(tmp_path: &Path) -> std::io::Result<(PathBuf, PathBuf)> {
+    let (src_path, dest_path) = setup_test(tmp_path);
+    unix::fs::symlink("top.txt", src_path.join("link_to_top"))?;
+    unix::fs::symlink("a_dir", src_path.join("link_to_dir"))?;
+    Ok((src_path, dest_path))
+}
+
+#[cfg(unix)]
+fn new_symlinks_syncer(
+    src_path: &Path,
+    dest_path: &Path,
+    symlinks: rusync::SymlinkPolicy,
+) -> rusync::Syncer {
+    let options = rusync::SyncOptions {
+        symlinks,
+        ..Default::default()
+    };
+    rusync::Syncer::new(src_path, dest_path, options, Box::new(DummyProgressInfo {}))
+}
+
+#[test]
+#[cfg(unix)]
+fn preserve_symlinks() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_symlinks_test(tmp_dir.path())?;
+    let syncer = new_symlinks_syncer(&src_path, &dest_path, rusync::SymlinkPolicy::Preserve);
+    let stats = syncer.sync().unwrap();
+
+    // Symlinks to directories are walked through, like before SymlinkPolicy
+    assert_eq!(stats.symlink_created, 1);
+    let dest_link_to_top = dest_path.join("link_to_top");
+    assert_eq!(dest_link_to_top.read_link()?.to_string_lossy(), "top.txt");
+    let dest_link_to_dir = dest_path.join("link_to_dir");
+    assert!(fs::symlink_metadata(&dest_link_to_dir)?.is_dir());
+    assert_same_contents(
+        &src_path.join("a_dir/one.txt"),
+        &dest_link_to_dir.join("one.txt"),
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn preserve_all_symlinks() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_symlinks_test(tmp_dir.path())?;
+    let syncer = new_symlinks_syncer(&src_path, &dest_path, rusync::SymlinkPolicy::PreserveAll);
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.symlink_created, 2);
+    let dest_link = dest_path.join("link_to_dir");
+    assert_eq!(dest_link.read_link()?.to_string_lossy(), "a_dir");
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn dereference_symlinks() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_symlinks_test(tmp_dir.path())?;
+    let syncer = new_symlinks_syncer(&src_path, &dest_path, rusync::SymlinkPolicy::Dereference);
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.symlink_created, 0);
+    let dest_link_to_top = dest_path.join("link_to_top");
+    assert!(!fs::symlink_metadata(&dest_link_to_top)?
+        .file_type()
+        .is_symlink());
+    assert_same_contents(&src_path.join("top.txt"), &dest_link_to_top);
+    let dest_link_to_dir = dest_path.join("link_to_dir");
+    assert!(fs::symlink_metadata(&dest_link_to_dir)?.is_dir());
+    assert_same_contents(
+        &src_path.join("a_dir/one.txt"),
+        &dest_link_to_dir.join("one.txt"),
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn skip_symlinks() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_symlinks_test(tmp_dir.path())?;
+    let syncer = new_symlinks_syncer(&src_path, &dest_path, rusync::SymlinkPolicy::Skip);
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.symlinks_skipped, 2);
+    assert!(fs::symlink_metadata(dest_path.join("link_to_top")).is_err());
+    assert!(fs::symlink_metadata(dest_path.join("link_to_dir")).is_err());
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn follow_dir_symlinks() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_symlinks_test(tmp_dir.path())?;
+    let options = rusync::SyncOptions {
+        symlinks: rusync::SymlinkPolicy::PreserveAll,
+        follow_dir_symlinks: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.symlink_created, 1);
+    assert!(fs::symlink_metadata(dest_path.join("link_to_top"))?
+        .file_type()
+        .is_symlink());
+    let dest_link_to_dir = dest_path.join("link_to_dir");
+    assert!(fs::symlink_metadata(&dest_link_to_dir)?.is_dir());
+    assert_same_contents(
+        &src_path.join("a_dir/one.txt"),
+        &dest_link_to_dir.join("one.txt"),
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn symlink_loop() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    std::os::unix::fs::symlink("..", src_path.join("b_dir/loop"))?;
+    let options = rusync::SyncOptions {
+        follow_dir_symlinks: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 1);
+    assert_eq!(stats.copied, 5);
+    assert!(!dest_path.join("b_dir/loop").exists());
+    Ok(())
+}
+
+#[test]
+fn sync_with_several_workers() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let many_path = src_path.join("many");
+    fs::create_dir(&many_path)?;
+    for i in 0..50 {
+        fs::write(
+            many_path.join(format!("{}.txt", i)),
+            format!("file {}\n", i),
+        )?;
+    }
+
+    let mut syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.num_workers(4);
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 55);
+    assert_eq!(stats.errors, 0);
+    for i in 0..50 {
+        let name = format!("many/{}.txt", i);
+        assert_same_contents(&src_path.join(&name), &dest_path.join(&name));
+    }
+    Ok(())
+}
+
+#[test]
+fn limit_bandwidth() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::write(src_path.join("big.bin"), vec![1u8; 512 * 1024])?;
+
+    let options = rusync::SyncOptions {
+        bwlimit: Some(2 * 1024 * 1024),
+        // Cloning files transfers no bytes at all
+        reflink: rusync::ReflinkMode::Never,
+        ..Default::default()
+    };
+    let mut syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    // The limit applies to all the workers put together
+    syncer.num_workers(4);
+    let start = std::time::Instant::now();
+    syncer.sync().unwrap();
+
+    // 512 KiB at 2 MiB/s
+    assert!(start.elapsed() >= std::time::Duration::from_millis(240));
+    assert_same_contents(&src_path.join("big.bin"), &dest_path.join("big.bin"));
+    Ok(())
+}
+
+fn new_reflink_syncer(src: &Path, dest: &Path, reflink: rusync::ReflinkMode) -> rusync::Syncer {
+    let options = rusync::SyncOptions {
+        reflink,
+        ..Default::default()
+    };
+    rusync::Syncer::new(src, dest, options, Box::new(DummyProgressInfo {}))
+}
+
+#[test]
+fn reflink_falls_back_to_copy() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_reflink_syncer(&src_path, &dest_path, rusync::ReflinkMode::Auto);
+    let stats = syncer.sync().unwrap();
+
+    // Whether files get cloned depends on the file system running the tests
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.copied, 5);
+    assert!(stats.cloned <= stats.copied);
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    Ok(())
+}
+
+#[test]
+fn reflink_never() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_reflink_syncer(&src_path, &dest_path, rusync::ReflinkMode::Never);
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 5);
+    assert_eq!(stats.cloned, 0);
+    Ok(())
+}
+
+#[test]
+fn count_copied_and_skipped_bytes() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let src_path = tmp_dir.path().join("src");
+    let dest_path = tmp_dir.path().join("dest");
+    fs::create_dir_all(src_path.join("sub"))?;
+    fs::write(src_path.join("a.txt"), vec![b'a'; 10_000])?;
+    fs::write(src_path.join("sub/b.txt"), vec![b'b'; 500])?;
+
+    let syncer = new_reflink_syncer(&src_path, &dest_path, rusync::ReflinkMode::Never);
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.bytes_total, 10_500);
+    assert_eq!(stats.bytes_copied, 10_500);
+    assert_eq!(stats.bytes_skipped, 0);
+    assert!(stats.avg_throughput() > 0.0);
+    assert_eq!(stats.speedup(), Some(1.0));
+
+    fs::write(src_path.join("sub/b.txt"), vec![b'c'; 600])?;
+    let syncer = new_reflink_syncer(&src_path, &dest_path, rusync::ReflinkMode::Never);
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.bytes_total, 10_600);
+    assert_eq!(stats.bytes_copied, 600);
+    assert_eq!(stats.bytes_skipped, 10_000);
+    assert!(stats.speedup().unwrap() > 17.0);
+    Ok(())
+}
+
+#[test]
+fn update_in_place() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let src_image = src_path.join("disk.img");
+    let mut contents: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    fs::write(&src_image, &contents)?;
+    let options = rusync::SyncOptions {
+        in_place: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+
+    contents[100_000..100_010].copy_from_slice(b"0123456789");
+    fs::write(&src_image, &contents)?;
+    make_recent(&src_image)?;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 1);
+    assert!(stats.bytes_saved > 190_000);
+    assert_same_contents(&src_image, &dest_path.join("disk.img"));
+    Ok(())
+}
+
+fn new_partial_syncer(src: &Path, dest: &Path) -> rusync::Syncer {
+    let options = rusync::SyncOptions {
+        partial: true,
+        ..Default::default()
+    };
+    rusync::Syncer::new(src, dest, options, Box::new(DummyProgressInfo {}))
+}
+
+#[test]
+fn resume_from_part_file() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    // Only the resumed file, so that all the bytes are accounted for
+    let src_path = tmp_dir.path().join("src");
+    let dest_path = tmp_dir.path().join("dest");
+    fs::create_dir_all(&src_path)?;
+    let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+    fs::write(src_path.join("big.bin"), &contents)?;
+    fs::create_dir_all(&dest_path)?;
+    // As if the previous sync was interrupted
+    let part_path = dest_path.join("big.bin.rusync-part");
+    fs::write(&part_path, &contents[0..120_000])?;
+
+    let stats = new_partial_syncer(&src_path, &dest_path).sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.bytes_skipped, 120_000);
+    assert_eq!(stats.bytes_copied, 180_000);
+    assert_same_contents(&src_path.join("big.bin"), &dest_path.join("big.bin"));
+    assert!(!part_path.exists());
+    Ok(())
+}
+
+#[test]
+fn resume_from_partial_dir() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let src_path = tmp_dir.path().join("src");
+    let dest_path = tmp_dir.path().join("dest");
+    fs::create_dir_all(src_path.join("a_dir"))?;
+    let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+    fs::write(src_path.join("a_dir/big.bin"), &contents)?;
+    // As if the previous sync was interrupted
+    let partial_dir = dest_path.join(".rusync-partial");
+    let part_path = partial_dir.join("a_dir/big.bin");
+    fs::create_dir_all(part_path.parent().unwrap())?;
+    fs::write(&part_path, &contents[0..120_000])?;
+
+    let options = rusync::SyncOptions {
+        partial: true,
+        partial_dir: true,
+        delete_extraneous: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.deleted, 0);
+    assert_eq!(stats.bytes_skipped, 120_000);
+    assert_eq!(stats.bytes_copied, 180_000);
+    assert_same_contents(
+        &src_path.join("a_dir/big.bin"),
+        &dest_path.join("a_dir/big.bin"),
+    );
+    assert!(!dest_path.join("a_dir/big.bin.rusync-part").exists());
+    assert!(!part_path.exists());
+    assert!(!partial_dir.join("a_dir").exists());
+    assert!(partial_dir.exists());
+    Ok(())
+}
+
+#[test]
+fn copy_through_mmap() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(src_path.join("big.bin"), &contents)?;
+    fs::create_dir_all(&dest_path)?;
+    let part_path = dest_path.join("big.bin.rusync-part");
+    fs::write(&part_path, &contents[0..150_000])?;
+
+    let options = rusync::SyncOptions {
+        mmap_threshold: Some(1000),
+        partial: true,
+        reflink: rusync::ReflinkMode::Never,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.copied, 6);
+    assert_same_contents(&src_path.join("big.bin"), &dest_path.join("big.bin"));
+    assert_same_contents(
+        &src_path.join("a_dir/foo.exe"),
+        &dest_path.join("a_dir/foo.exe"),
+    );
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    Ok(())
+}
+
+#[test]
+fn copy_with_direct_io() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    // Not a whole number of blocks
+    let contents: Vec<u8> = (0..300_001u32).map(|i| (i % 239) as u8).collect();
+    fs::write(src_path.join("big.bin"), &contents)?;
+
+    let options = rusync::SyncOptions {
+        direct_io: true,
+        reflink: rusync::ReflinkMode::Never,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert_same_contents(&src_path.join("big.bin"), &dest_path.join("big.bin"));
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    Ok(())
+}
+
+#[test]
+fn preallocate_destination() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 233) as u8).collect();
+    fs::write(src_path.join("big.bin"), &contents)?;
+    fs::create_dir_all(&dest_path)?;
+    // Bigger than the source: preallocating must not keep its size
+    fs::write(dest_path.join("top.txt"), vec![b'x'; 100_000])?;
+
+    let options = rusync::SyncOptions {
+        preallocate: true,
+        reflink: rusync::ReflinkMode::Never,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert_same_contents(&src_path.join("big.bin"), &dest_path.join("big.bin"));
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    Ok(())
+}
+
+#[test]
+#[cfg(not(windows))]
+fn vss_requires_windows() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+
+    let options = rusync::SyncOptions {
+        vss: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let result = syncer.sync();
+
+    assert!(matches!(result, Err(rusync::SyncError::Snapshot(_))));
+    assert!(!dest_path.join("top.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn discard_mismatching_part_file() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(&dest_path)?;
+    let part_path = dest_path.join("top.txt.rusync-part");
+    fs::write(&part_path, "not the start of top.txt")?;
+
+    new_partial_syncer(&src_path, &dest_path).sync().unwrap();
+
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    assert!(!part_path.exists());
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn atomic_writes() -> Result<(), std::io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    new_test_syncer(&src_path, &dest_path).sync().unwrap();
+    let dest_top = dest_path.join("top.txt");
+    let old_inode = fs::metadata(&dest_top)?.ino();
+    fs::write(src_path.join("top.txt"), "new contents")?;
+    make_recent(&src_path.join("top.txt"))?;
+
+    let options = rusync::SyncOptions {
+        atomic: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 1);
+    assert_eq!(fs::read_to_string(&dest_top)?, "new contents");
+    assert_ne!(fs::metadata(&dest_top)?.ino(), old_inode);
+    assert!(!dest_path.join(".top.txt.rusync-tmp").exists());
+    Ok(())
+}
+
+#[test]
+fn fsync_written_files() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let options = rusync::SyncOptions {
+        fsync: true,
+        atomic: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.copied, 5);
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn keep_syncing_after_errors() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    unix::fs::symlink("no-such", src_path.join("broken"))?;
+
+    let syncer = new_symlinks_syncer(&src_path, &dest_path, rusync::SymlinkPolicy::Dereference);
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 5);
+    assert_eq!(stats.errors, 1);
+    let (path, error) = &stats.error_list[0];
+    assert_eq!(path, Path::new("broken"));
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    Ok(())
+}
+
+#[test]
+fn keep_walking_after_read_errors() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(dest_path.join("b_dir"))?;
+    fs::write(dest_path.join("b_dir/extraneous.txt"), "old")?;
+    // Not valid UTF-8: b_dir cannot be walked
+    fs::write(src_path.join("b_dir/.gitignore"), [0xff, 0xfe])?;
+
+    let options = rusync::SyncOptions {
+        respect_gitignore: true,
+        delete_extraneous: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 4);
+    assert_eq!(stats.errors, 1);
+    let (path, error) = &stats.error_list[0];
+    assert_eq!(path, Path::new("b_dir"));
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    // Nothing is deleted after an incomplete walk
+    assert_eq!(stats.deleted, 0);
+    assert!(dest_path.join("b_dir/extraneous.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn invalid_source() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let src_path = tmp_dir.path().join("no-such");
+    let dest_path = tmp_dir.path().join("dest");
+    let syncer = new_test_syncer(&src_path, &dest_path);
+
+    let err = syncer.sync().unwrap_err();
+
+    match err {
+        rusync::SyncError::InvalidSource(path) => assert_eq!(path, src_path),
+        _ => panic!("unexpected error: {}", err),
+    }
+    assert!(!dest_path.exists());
+    Ok(())
+}
+
+#[test]
+fn remote_destination_is_rejected() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, _) = setup_test(tmp_dir.path());
+    let dest_path = Path::new("user@example.com:backups");
+    let syncer = new_test_syncer(&src_path, dest_path);
+
+    let err = syncer.sync().unwrap_err();
+
+    match err {
+        rusync::SyncError::RemoteDestination(path) => assert_eq!(path, dest_path),
+        _ => panic!("unexpected error: {}", err),
+    }
+    assert!(!dest_path.exists());
+    Ok(())
+}
+
+// Records every callback, so that tests can check how ProgressInfo is used
+struct RecordingProgressInfo {
+    events: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+impl RecordingProgressInfo {
+    fn record(&self, event: String) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+impl ProgressInfo for RecordingProgressInfo {
+    fn start(&mut self, _source: &str, _destination: &str) {
+        self.record("start".to_string());
+    }
+
+    fn new_file(&mut self, name: &str) {
+        self.record(format!("new_file {}", name));
+    }
+
+    fn done_syncing(&mut self, name: &str) {
+        self.record(format!("done_syncing {}", name));
+    }
+
+    fn end(&mut self, stats: &rusync::Stats) {
+        self.record(format!("end {}", stats.copied));
+    }
+}
+
+#[test]
+fn progress_info_callbacks() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let events = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let progress_info = RecordingProgressInfo {
+        events: events.clone(),
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        rusync::SyncOptions::default(),
+        Box::new(progress_info),
+    );
+    syncer.sync().unwrap();
+
+    let events = events.lock().unwrap();
+    assert_eq!(events.first().unwrap(), "start");
+    assert_eq!(events.last().unwrap(), "end 5");
+    assert!(events.contains(&"new_file top.txt".to_string()));
+    assert!(events.contains(&"done_syncing top.txt".to_string()));
+    Ok(())
+}
+
+#[test]
+fn quiet_sync() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let events = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let progress_info = RecordingProgressInfo {
+        events: events.clone(),
+    };
+    let options = rusync::SyncOptions {
+        quiet: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(&src_path, &dest_path, options, Box::new(progress_info));
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 5);
+    assert!(events.lock().unwrap().is_empty());
+    Ok(())
+}
+
+struct ItemizingProgressInfo {
+    changes: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+impl ProgressInfo for ItemizingProgressInfo {
+    fn itemized(&mut self, name: &str, changes: &str) {
+        let mut all_changes = self.changes.lock().unwrap();
+        all_changes.push(format!("{} {}", changes, name));
+    }
+}
+
+struct TotalsProgressInfo {
+    totals: std::sync::Arc<std::sync::Mutex<Vec<(usize, usize, bool)>>>,
+    scanned: std::sync::Arc<std::sync::Mutex<Vec<(u64, u64)>>>,
+}
+
+impl ProgressInfo for TotalsProgressInfo {
+    fn scanning(&mut self, entries_found: u64, bytes_found: u64) {
+        let mut scanned = self.scanned.lock().unwrap();
+        scanned.push((entries_found, bytes_found));
+    }
+
+    fn progress(&mut self, progress: &rusync::progress::Progress) {
+        let mut totals = self.totals.lock().unwrap();
+        totals.push((
+            progress.num_files,
+            progress.total_size,
+            progress.totals_known,
+        ));
+    }
+}
+
+struct EntriesProgressInfo {
+    sizes: std::sync::Arc<std::sync::Mutex<Vec<(String, u64)>>>,
+}
+
+impl ProgressInfo for EntriesProgressInfo {
+    fn new_entry(&mut self, entry: &rusync::Entry) {
+        let mut sizes = self.sizes.lock().unwrap();
+        sizes.push((entry.description().to_string(), entry.size()));
+    }
+}
+
+#[test]
+fn filter_entries_on_metadata() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let sizes = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let progress_info = EntriesProgressInfo {
+        sizes: sizes.clone(),
+    };
+    let mut syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        rusync::SyncOptions::default(),
+        Box::new(progress_info),
+    );
+    syncer.filter_entries(|entry| entry.size() < 1000);
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 4);
+    assert!(!dest_path.join("a_dir/foo.exe").exists());
+    let mut sizes = sizes.lock().unwrap().clone();
+    sizes.sort();
+    assert_eq!(sizes[0], ("a_dir/one.txt".to_string(), 4));
+    assert_eq!(sizes[3], ("top.txt".to_string(), 16));
+    Ok(())
+}
+
+#[test]
+fn prescan_totals() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let totals = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let scanned = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let progress_info = TotalsProgressInfo {
+        totals: totals.clone(),
+        scanned: scanned.clone(),
+    };
+    let options = rusync::SyncOptions {
+        prescan: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(&src_path, &dest_path, options, Box::new(progress_info));
+    let stats = syncer.sync().unwrap();
+
+    let totals = totals.lock().unwrap();
+    assert!(!totals.is_empty());
+    // Known from the first file on
+    let expected = (5, stats.total_size, true);
+    assert!(totals.iter().all(|t| *t == expected), "{:?}", totals);
+    // Once the walk is done
+    let scanned = scanned.lock().unwrap();
+    assert_eq!(*scanned, [(5, stats.total_size as u64)]);
+    Ok(())
+}
+
+#[test]
+fn itemize_changes() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let changes = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let new_syncer = || {
+        let progress_info = ItemizingProgressInfo {
+            changes: changes.clone(),
+        };
+        rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            rusync::SyncOptions::default(),
+            Box::new(progress_info),
+        )
+    };
+    new_syncer().sync().unwrap();
+    assert!(changes
+        .lock()
+        .unwrap()
+        .contains(&">f+++++++++ top.txt".to_string()));
+
+    changes.lock().unwrap().clear();
+    fs::write(src_path.join("top.txt"), "new contents")?;
+    make_recent(&src_path.join("top.txt"))?;
+    new_syncer().sync().unwrap();
+    let changes = changes.lock().unwrap();
+    assert!(changes.contains(&">f.st...... top.txt".to_string()));
+    assert!(changes.iter().any(|c| c.starts_with(".f......... ")));
+    Ok(())
+}
+
+#[test]
+fn write_log_file() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let log_path = tmp_dir.path().join("rusync.log");
+    let options = rusync::SyncOptions {
+        delete_extraneous: true,
+        quiet: true,
+        ..Default::default()
+    };
+    let mut syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.log_file(&log_path)?;
+    syncer.sync().unwrap();
+
+    fs::write(dest_path.join("extraneous.txt"), "")?;
+    let mut syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.log_file(&log_path)?;
+    syncer.sync().unwrap();
+
+    // Each sync appends to the log
+    let log = fs::read_to_string(&log_path)?;
+    let lines: Vec<&str> = log.lines().collect();
+    assert!(lines.iter().any(|l| l.ends_with(" copied: top.txt")));
+    assert!(lines.iter().any(|l| l.ends_with(" up to date: top.txt")));
+    assert!(lines
+        .iter()
+        .any(|l| l.ends_with(" deleted: extraneous.txt")));
+    assert_eq!(
+        lines.iter().filter(|l| l.contains("sync started")).count(),
+        2
+    );
+    Ok(())
+}
+
+#[test]
+fn backup_overwritten_and_deleted_files() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let options = rusync::SyncOptions {
+        delete_extraneous: true,
+        ..Default::default()
+    };
+    let new_syncer = || {
+        let mut syncer = rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options,
+            Box::new(DummyProgressInfo {}),
+        );
+        syncer.backup_dir(Path::new("backup"));
+        syncer
+    };
+    new_syncer().sync().unwrap();
+    let old_contents = fs::read_to_string(dest_path.join("top.txt"))?;
+
+    fs::write(src_path.join("top.txt"), "new contents")?;
+    make_recent(&src_path.join("top.txt"))?;
+    fs::remove_dir_all(src_path.join("a_dir"))?;
+    let stats = new_syncer().sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert_eq!(
+        fs::read_to_string(dest_path.join("top.txt"))?,
+        "new contents"
+    );
+    let backup_path = dest_path.join("backup");
+    assert_eq!(
+        fs::read_to_string(backup_path.join("top.txt"))?,
+        old_contents
+    );
+    assert!(!dest_path.join("a_dir").exists());
+    assert!(backup_path.join("a_dir").is_dir());
+    // The backup dir is not deleted even though it is not in the source
+    new_syncer().sync().unwrap();
+    assert!(backup_path.join("top.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn backup_with_suffix() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let options = rusync::SyncOptions {
+        delete_extraneous: true,
+        ..Default::default()
+    };
+    let new_syncer = || {
+        let mut syncer = rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options,
+            Box::new(DummyProgressInfo {}),
+        );
+        syncer.backup_suffix(".bak");
+        syncer
+    };
+    new_syncer().sync().unwrap();
+    let old_contents = fs::read_to_string(dest_path.join("top.txt"))?;
+
+    fs::write(src_path.join("top.txt"), "new contents")?;
+    make_recent(&src_path.join("top.txt"))?;
+    fs::remove_file(src_path.join("a_dir/one.txt"))?;
+    let stats = new_syncer().sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert_eq!(
+        fs::read_to_string(dest_path.join("top.txt"))?,
+        "new contents"
+    );
+    assert_eq!(
+        fs::read_to_string(dest_path.join("top.txt.bak"))?,
+        old_contents
+    );
+    assert!(!dest_path.join("a_dir/one.txt").exists());
+    assert!(dest_path.join("a_dir/one.txt.bak").exists());
+    // Backups are not deleted even though they are not in the source
+    new_syncer().sync().unwrap();
+    assert!(dest_path.join("top.txt.bak").exists());
+    Ok(())
+}
+
+#[test]
+fn detect_conflicts() -> Result<(), std::io::Error> {
+    let policies = [
+        (rusync::ConflictPolicy::SourceWins, "from source"),
+        (rusync::ConflictPolicy::NewerWins, "from destination"),
+        (rusync::ConflictPolicy::KeepBoth, "from source"),
+        (rusync::ConflictPolicy::ReportOnly, "from destination"),
+    ];
+    for (policy, expected) in policies {
+        let tmp_dir = TempDir::new("test-rusync")?;
+        let (src_path, dest_path) = setup_test(tmp_dir.path());
+        let options = rusync::SyncOptions {
+            conflicts: Some(policy),
+            ..Default::default()
+        };
+        let sync = || {
+            let syncer = rusync::Syncer::new(
+                &src_path,
+                &dest_path,
+                options,
+                Box::new(DummyProgressInfo {}),
+            );
+            syncer.sync().unwrap()
+        };
+        sync();
+        assert!(dest_path.join(".rusync-state").exists());
+
+        // Only changed in the source: not a conflict
+        fs::write(src_path.join("a_dir/one.txt"), "new one")?;
+        make_recent(&src_path.join("a_dir/one.txt"))?;
+        let top_src = src_path.join("top.txt");
+        let top_dest = dest_path.join("top.txt");
+        fs::write(&top_src, "from source")?;
+        fs::write(&top_dest, "from destination")?;
+        let atime = FileTime::from_seconds_since_1970(1_500_000_000, 0);
+        filetime::set_file_times(
+            &top_src,
+            atime,
+            FileTime::from_seconds_since_1970(1_600_000_000, 0),
+        )?;
+        filetime::set_file_times(
+            &top_dest,
+            atime,
+            FileTime::from_seconds_since_1970(1_700_000_000, 0),
+        )?;
+        let stats = sync();
+
+        assert_eq!(stats.errors, 0, "{:?}", policy);
+        assert_eq!(stats.warnings, 1, "{:?}", policy);
+        assert_eq!(
+            fs::read_to_string(dest_path.join("a_dir/one.txt"))?,
+            "new one"
+        );
+        assert_eq!(fs::read_to_string(&top_dest)?, expected, "{:?}", policy);
+        let kept = expected == "from destination";
+        assert_eq!(stats.conflicts_skipped, kept as u64, "{:?}", policy);
+        if policy == rusync::ConflictPolicy::KeepBoth {
+            assert_eq!(
+                fs::read_to_string(dest_path.join("top.txt.conflict"))?,
+                "from destination"
+            );
+        }
+        // Kept files are still conflicts, the other ones were resolved
+        let stats = sync();
+        assert_eq!(stats.conflicts_skipped, kept as u64, "{:?}", policy);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn link_unchanged_files_from_previous_snapshot() -> Result<(), std::io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, _) = setup_test(tmp_dir.path());
+    let first_path = tmp_dir.path().join("backups/first");
+    let second_path = tmp_dir.path().join("backups/second");
+    new_test_syncer(&src_path, &first_path).sync().unwrap();
+
+    fs::write(src_path.join("top.txt"), "new contents")?;
+    make_recent(&src_path.join("top.txt"))?;
+    let mut syncer = new_test_syncer(&src_path, &second_path);
+    syncer.link_dest(Path::new("../first"));
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.copied, 1);
+    assert_eq!(stats.files_linked, 4);
+    let inode = |path: &Path| fs::metadata(path).unwrap().ino();
+    let unchanged = "a_dir/one.txt";
+    assert_eq!(
+        inode(&first_path.join(unchanged)),
+        inode(&second_path.join(unchanged))
+    );
+    assert_ne!(
+        inode(&first_path.join("top.txt")),
+        inode(&second_path.join("top.txt"))
+    );
+    assert_eq!(
+        fs::read_to_string(second_path.join("top.txt"))?,
+        "new contents"
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn resync_does_not_change_linked_snapshot() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, _) = setup_test(tmp_dir.path());
+    let first_path = tmp_dir.path().join("backups/first");
+    let second_path = tmp_dir.path().join("backups/second");
+    new_test_syncer(&src_path, &first_path).sync().unwrap();
+    let mut syncer = new_test_syncer(&src_path, &second_path);
+    syncer.link_dest(Path::new("../first"));
+    syncer.sync().unwrap();
+    let expected = fs::read_to_string(first_path.join("top.txt"))?;
+
+    let src_top = src_path.join("top.txt");
+    fs::write(&src_top, "new contents")?;
+    make_recent(&src_top)?;
+    let stats = new_test_syncer(&src_path, &second_path).sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.copied, 1);
+    assert_eq!(fs::read_to_string(first_path.join("top.txt"))?, expected);
+    assert_eq!(
+        fs::read_to_string(second_path.join("top.txt"))?,
+        "new contents"
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn in_place_does_not_change_linked_snapshot() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, _) = setup_test(tmp_dir.path());
+    let contents: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    let src_big = src_path.join("big.bin");
+    fs::write(&src_big, &contents)?;
+    let first_path = tmp_dir.path().join("backups/first");
+    let second_path = tmp_dir.path().join("backups/second");
+    new_test_syncer(&src_path, &first_path).sync().unwrap();
+    let mut syncer = new_test_syncer(&src_path, &second_path);
+    syncer.link_dest(Path::new("../first"));
+    syncer.sync().unwrap();
+
+    let mut changed = contents.clone();
+    changed[50_000] = !changed[50_000];
+    fs::write(&src_big, &changed)?;
+    make_recent(&src_big)?;
+    let options = rusync::SyncOptions {
+        in_place: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &second_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert_eq!(fs::read(first_path.join("big.bin"))?, contents);
+    assert_eq!(fs::read(second_path.join("big.bin"))?, changed);
+    Ok(())
+}
+
+#[test]
+fn write_manifest_in_destination() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let manifest_path = dest_path.join("MANIFEST.csv");
+    let options = rusync::SyncOptions {
+        delete_extraneous: true,
+        ..Default::default()
+    };
+    // Sync twice, to check that the manifest does not list itself and
+    // does not prevent anything from being deleted
+    for _ in 0..2 {
+        let mut syncer = rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options,
+            Box::new(DummyProgressInfo {}),
+        );
+        syncer.manifest(&manifest_path, rusync::ManifestFormat::Csv);
+        syncer.sync().unwrap();
+    }
+
+    let manifest = fs::read_to_string(&manifest_path)?;
+    let lines: Vec<&str> = manifest.lines().collect();
+    assert_eq!(lines[0], "path,size,modified,sha256");
+    let paths: Vec<&str> = lines[1..]
+        .iter()
+        .map(|l| l.split(',').next().unwrap())
+        .collect();
+    assert_eq!(
+        paths,
+        vec![
+            "a_dir/foo.exe",
+            "a_dir/one.txt",
+            "a_dir/two.txt",
+            "b_dir/c_dir/three.txt",
+            "top.txt"
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn checksums_with_other_hash() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let manifest_path = tmp_dir.path().join("MANIFEST.csv");
+    for hash in [rusync::HashAlgorithm::Blake3, rusync::HashAlgorithm::Xxh3] {
+        let options = rusync::SyncOptions {
+            checksum: true,
+            checksum_cache: true,
+            hash,
+            ..Default::default()
+        };
+        let mut syncer = rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options,
+            Box::new(DummyProgressInfo {}),
+        );
+        syncer.manifest(&manifest_path, rusync::ManifestFormat::Csv);
+        let stats = syncer.sync().unwrap();
+        assert_eq!(stats.errors, 0);
+
+        let manifest = fs::read_to_string(&manifest_path)?;
+        let header = manifest.lines().next().unwrap();
+        assert_eq!(header, format!("path,size,modified,{}", hash));
+    }
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    let cache = fs::read_to_string(dest_path.join(".rusync-checksums"))?;
+    assert!(cache.starts_with("algorithm xxh3\n"));
+    Ok(())
+}
+
+#[test]
+fn bisync_both_ways() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(&dest_path)?;
+    let bisync = || {
+        rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            rusync::SyncOptions::default(),
+            Box::new(DummyProgressInfo {}),
+        )
+        .bisync()
+        .unwrap()
+    };
+    let report = bisync();
+    assert_eq!(report.to_destination.len(), 5);
+    assert!(report.errors.is_empty());
+    assert_same_contents(
+        &src_path.join("a_dir/one.txt"),
+        &dest_path.join("a_dir/one.txt"),
+    );
+
+    fs::write(dest_path.join("top.txt"), "changed in dest")?;
+    make_recent(&dest_path.join("top.txt"))?;
+    fs::write(dest_path.join("new.txt"), "new")?;
+    fs::remove_dir_all(src_path.join("b_dir"))?;
+    let report = bisync();
+    assert_eq!(
+        report.to_source,
+        vec![PathBuf::from("new.txt"), PathBuf::from("top.txt")]
+    );
+    assert_eq!(
+        report.deleted_in_destination,
+        vec![
+            PathBuf::from("b_dir"),
+            PathBuf::from("b_dir/c_dir"),
+            PathBuf::from("b_dir/c_dir/three.txt")
+        ]
+    );
+    assert!(report.to_destination.is_empty());
+    assert!(report.conflicts.is_empty());
+    assert_eq!(
+        fs::read_to_string(src_path.join("top.txt"))?,
+        "changed in dest"
+    );
+    assert!(!dest_path.join("b_dir").exists());
+
+    // Modified on both sides: both versions are kept
+    fs::write(src_path.join("top.txt"), "changed in source")?;
+    fs::write(dest_path.join("top.txt"), "changed in dest again")?;
+    make_recent(&dest_path.join("top.txt"))?;
+    let report = bisync();
+    assert_eq!(report.conflicts.len(), 1);
+    for dir in [&src_path, &dest_path] {
+        assert_eq!(
+            fs::read_to_string(dir.join("top.txt"))?,
+            "changed in source"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("top.txt.conflict"))?,
+            "changed in dest again"
+        );
+    }
+    let report = bisync();
+    assert!(report.to_destination.is_empty() && report.to_source.is_empty());
+    assert!(report.conflicts.is_empty());
+    Ok(())
+}
+
+#[test]
+fn bisync_first_run_with_same_files() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(&dest_path)?;
+    fs::copy(src_path.join("top.txt"), dest_path.join("top.txt"))?;
+    make_recent(&dest_path.join("top.txt"))?;
+    fs::create_dir(dest_path.join("a_dir"))?;
+    fs::write(dest_path.join("a_dir/one.txt"), "not the same")?;
+    let report = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        rusync::SyncOptions::default(),
+        Box::new(DummyProgressInfo {}),
+    )
+    .bisync()
+    .unwrap();
+    // Only the files that differ conflict
+    let conflicting: Vec<_> = report.conflicts.iter().map(|(path, _)| path).collect();
+    assert_eq!(conflicting, vec![&PathBuf::from("a_dir/one.txt")]);
+    assert!(!dest_path.join("top.txt.conflict").exists());
+    assert!(dest_path.join("a_dir/one.txt.conflict").exists());
+    assert!(!report.to_destination.contains(&PathBuf::from("top.txt")));
+    Ok(())
+}
+
+#[test]
+fn state_db_skips_unchanged_files() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let db_path = tmp_dir.path().join("state.db");
+    let sync = || {
+        let options = rusync::SyncOptions {
+            delete_extraneous: true,
+            ..Default::default()
+        };
+        let mut syncer = rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options,
+            Box::new(DummyProgressInfo {}),
+        );
+        syncer.state_db(&db_path);
+        syncer.sync().unwrap()
+    };
+    let stats = sync();
+    assert!(stats.copied > 0);
+    assert!(db_path.exists());
+
+    // Only the recorded entries are checked for deletion
+    fs::write(dest_path.join("unrecorded.txt"), "")?;
+    fs::remove_file(src_path.join("a_dir/one.txt"))?;
+    let stats = sync();
+    assert_eq!(stats.copied, 0);
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.deleted, 1);
+    assert!(!dest_path.join("a_dir/one.txt").exists());
+    assert!(dest_path.join("unrecorded.txt").exists());
+
+    // Destination files changed or removed by other programs are noticed
+    fs::write(dest_path.join("top.txt"), "changed in dest")?;
+    fs::remove_file(dest_path.join("a_dir/two.txt"))?;
+    let stats = sync();
+    assert_eq!(stats.copied, 2);
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    assert_same_contents(
+        &src_path.join("a_dir/two.txt"),
+        &dest_path.join("a_dir/two.txt"),
+    );
+    let stats = sync();
+    assert_eq!(stats.copied, 0);
+
+    fs::write(src_path.join("top.txt"), "changed in source")?;
+    make_recent(&src_path.join("top.txt"))?;
+    let stats = sync();
+    assert_eq!(stats.copied, 1);
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    Ok(())
+}
+
+#[test]
+fn state_db_detects_rewritten_files() -> Result<(), std::io::Error> {
+    // Digests are recorded when comparing the files, or when checking the
+    // copies
+    let with_checksum = rusync::SyncOptions {
+        checksum: true,
+        ..Default::default()
+    };
+    let with_verify = rusync::SyncOptions {
+        verify_copies: true,
+        ..Default::default()
+    };
+    for options in [with_checksum, with_verify].iter() {
+        let tmp_dir = TempDir::new("test-rusync")?;
+        let (src_path, dest_path) = setup_test(tmp_dir.path());
+        let db_path = tmp_dir.path().join("state.db");
+        let sync = || {
+            let mut syncer = rusync::Syncer::new(
+                &src_path,
+                &dest_path,
+                *options,
+                Box::new(DummyProgressInfo {}),
+            );
+            syncer.state_db(&db_path);
+            syncer.sync().unwrap()
+        };
+        sync();
+
+        // Rewritten with the same contents
+        let src_top = src_path.join("top.txt");
+        let contents = fs::read(&src_top)?;
+        fs::write(&src_top, &contents)?;
+        make_recent(&src_top)?;
+        let stats = sync();
+        assert_eq!(stats.copied, 0);
+        let mtime =
+            |path: &Path| FileTime::from_last_modification_time(&fs::metadata(path).unwrap());
+        assert_eq!(mtime(&src_top), mtime(&dest_path.join("top.txt")));
+
+        // Same size and modification time, but different contents
+        if options.checksum {
+            let src_one = src_path.join("a_dir/one.txt");
+            let one_mtime = mtime(&src_one);
+            let contents = fs::read(&src_one)?;
+            fs::write(&src_one, vec![b'x'; contents.len()])?;
+            filetime::set_file_times(&src_one, one_mtime, one_mtime)?;
+            let stats = sync();
+            assert_eq!(stats.copied, 1);
+            assert_same_contents(&src_one, &dest_path.join("a_dir/one.txt"));
+        }
+
+        // The recorded digest says nothing about a destination file
+        // changed since then
+        let dest_two = dest_path.join("a_dir/two.txt");
+        let contents = fs::read(&dest_two)?;
+        fs::write(&dest_two, vec![b'x'; contents.len()])?;
+        let src_two = src_path.join("a_dir/two.txt");
+        fs::write(&src_two, fs::read(&src_two)?)?;
+        make_recent(&src_two)?;
+        let stats = sync();
+        assert_eq!(stats.copied, 1);
+        assert_same_contents(&src_two, &dest_two);
+    }
+    Ok(())
+}
+
+#[test]
+fn verify_reports_differences() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = || {
+        rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            rusync::SyncOptions::default(),
+            Box::new(DummyProgressInfo {}),
+        )
+    };
+    let check = |src_path: &Path| {
+        Command::new(env!("CARGO_BIN_EXE_rusync"))
+            .arg("--check")
+            .arg(src_path)
+            .arg(&dest_path)
+            .output()
+            .expect("Failed to start rusync")
+            .status
+    };
+    syncer().sync().unwrap();
+    let verification = syncer().verify(rusync::Comparison::Checksum).unwrap();
+    assert!(verification.is_identical());
+    assert_eq!(check(&src_path).code(), Some(0));
+
+    fs::remove_file(dest_path.join("top.txt"))?;
+    fs::write(dest_path.join("extra.txt"), "")?;
+    // Same size and modification time, but different contents
+    let dest_two = dest_path.join("a_dir/two.txt");
+    let contents = fs::read(&dest_two)?;
+    fs::write(&dest_two, vec![b'x'; contents.len()])?;
+    let src_mtime =
+        FileTime::from_last_modification_time(&fs::metadata(src_path.join("a_dir/two.txt"))?);
+    filetime::set_file_times(&dest_two, src_mtime, src_mtime)?;
+
+    let verification = syncer()
+        .verify(rusync::Comparison::ModificationTime)
+        .unwrap();
+    assert_eq!(verification.missing, vec![PathBuf::from("top.txt")]);
+    assert_eq!(verification.extra, vec![PathBuf::from("extra.txt")]);
+    assert!(verification.differing.is_empty());
+
+    let verification = syncer().verify(rusync::Comparison::Checksum).unwrap();
+    assert_eq!(
+        verification.differing,
+        vec![(
+            PathBuf::from("a_dir/two.txt"),
+            "contents differ".to_string()
+        )]
+    );
+    assert!(!verification.is_identical());
+    assert_eq!(check(&src_path).code(), Some(4));
+    assert_eq!(check(&tmp_dir.path().join("missing")).code(), Some(1));
+    Ok(())
+}
+
+#[test]
+fn remove_source_files() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let options = rusync::SyncOptions {
+        remove_source_files: true,
+        ..Default::default()
+    };
+    let mut syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.exclude("a_dir/one.txt");
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.errors, 0);
+
+    assert!(dest_path.join("top.txt").exists());
+    assert!(dest_path.join("b_dir/c_dir/three.txt").exists());
+    assert!(!src_path.join("top.txt").exists());
+    // Excluded files stay, and so do the directories containing them
+    assert!(src_path.join("a_dir/one.txt").exists());
+    assert!(!src_path.join("a_dir/two.txt").exists());
+    assert!(!src_path.join("b_dir").exists());
+    assert!(src_path.exists());
+    Ok(())
+}
+
+#[test]
+fn skip_files_bigger_than_max_size() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::write(src_path.join("big.bin"), vec![0_u8; 100_000])?;
+    let options = rusync::SyncOptions {
+        max_size: Some(10_000),
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.size_skipped, 1);
+    assert_eq!(stats.errors, 0);
+    assert!(!dest_path.join("big.bin").exists());
+    assert!(dest_path.join("top.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn ignore_files_smaller_than_min_size() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let options = rusync::SyncOptions {
+        min_size: Some(1000),
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    // Only a_dir/foo.exe is big enough
+    assert_eq!(stats.num_files, 1);
+    assert_eq!(stats.copied, 1);
+    assert!(dest_path.join("a_dir/foo.exe").exists());
+    assert!(!dest_path.join("top.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn ignore_files_by_modification_time() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let old_mtime = FileTime::from_seconds_since_1970(1_600_000_000, 0);
+    filetime::set_file_times(src_path.join("top.txt"), old_mtime, old_mtime)?;
+    let one_day_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(24 * 3600);
+    let options = rusync::SyncOptions {
+        newer_than: Some(one_day_ago),
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+    assert!(dest_path.join("a_dir/one.txt").exists());
+    assert!(!dest_path.join("top.txt").exists());
+
+    let options = rusync::SyncOptions {
+        older_than: Some(one_day_ago),
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.num_files, 1);
+    assert!(dest_path.join("top.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn skip_hidden_entries() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(src_path.join(".git/objects"))?;
+    fs::write(src_path.join(".git/objects/abc"), "")?;
+    fs::write(src_path.join("a_dir/.DS_Store"), "")?;
+    fs::create_dir_all(&dest_path)?;
+    fs::write(dest_path.join(".local"), "")?;
+    let options = rusync::SyncOptions {
+        skip_hidden: true,
+        delete_extraneous: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+    assert!(!dest_path.join(".git").exists());
+    assert!(!dest_path.join("a_dir/.DS_Store").exists());
+    assert!(dest_path.join("a_dir/one.txt").exists());
+    assert!(dest_path.join(".local").exists());
+    Ok(())
+}
+
+#[test]
+fn read_filters_from_file() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let rules_path = tmp_dir.path().join("rules.txt");
+    fs::write(&rules_path, "# skip executables\n*.exe\n\nb_dir/\n")?;
+    let mut syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.exclude_from(&rules_path)?;
+    syncer.sync().unwrap();
+    assert!(dest_path.join("a_dir/one.txt").exists());
+    assert!(!dest_path.join("a_dir/foo.exe").exists());
+    assert!(!dest_path.join("b_dir").exists());
+    Ok(())
+}
+
+#[test]
+fn exclude_with_regex() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let mut syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.exclude_regex(r"^a_dir/(one|two)\.txt$").unwrap();
+    assert!(syncer.exclude_regex("(unclosed").is_err());
+    syncer.sync().unwrap();
+    assert!(!dest_path.join("a_dir/one.txt").exists());
+    assert!(!dest_path.join("a_dir/two.txt").exists());
+    assert!(dest_path.join("a_dir/foo.exe").exists());
+    assert!(dest_path.join("top.txt").exists());
+    Ok(())
+}
+
+struct PlanningProgressInfo {
+    planned: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+impl ProgressInfo for PlanningProgressInfo {
+    fn planned(&mut self, name: &str, change: rusync::progress::Change, size: u64) {
+        let mut planned = self.planned.lock().unwrap();
+        planned.push(format!("{:?} {} {}", change, name, size));
+    }
+}
+
+#[test]
+fn dry_run() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    new_test_syncer(&src_path, &dest_path).sync().unwrap();
+    fs::write(src_path.join("new.txt"), "new")?;
+    fs::write(src_path.join("top.txt"), "changed")?;
+    fs::write(dest_path.join("extra.txt"), "extra")?;
+
+    let planned = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let progress_info = PlanningProgressInfo {
+        planned: planned.clone(),
+    };
+    let options = rusync::SyncOptions {
+        dry_run: true,
+        delete_extraneous: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(&src_path, &dest_path, options, Box::new(progress_info));
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.errors, 0);
+
+    // Nothing changed
+    assert!(!dest_path.join("new.txt").exists());
+    assert!(dest_path.join("extra.txt").exists());
+    assert_eq!(
+        fs::read_to_string(dest_path.join("top.txt"))?,
+        "this is the top\n"
+    );
+
+    let planned = planned.lock().unwrap();
+    assert!(planned.contains(&"New new.txt 3".to_string()));
+    assert!(planned.contains(&"Changed top.txt 7".to_string()));
+    assert!(planned.contains(&"Deleted extra.txt 5".to_string()));
+    assert!(planned.contains(&"Unchanged a_dir/one.txt 4".to_string()));
+    Ok(())
+}
+
+#[test]
+fn cancel_sync() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(&dest_path)?;
+    fs::write(dest_path.join("extra.txt"), "extra")?;
+    let options = rusync::SyncOptions {
+        delete_extraneous: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let token = syncer.cancellation_token();
+    std::thread::spawn(move || token.cancel()).join().unwrap();
+    let stats = syncer.sync().unwrap();
+
+    assert!(stats.cancelled);
+    assert_eq!(stats.copied, 0);
+    assert!(!dest_path.join("top.txt").exists());
+    // Nothing is deleted after a cancellation
+    assert!(dest_path.join("extra.txt").exists());
+    Ok(())
+}
+
+// Minimal executor, to avoid depending on an async runtime
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    struct ThreadWaker(std::thread::Thread);
+    impl std::task::Wake for ThreadWaker {
+        fn wake(self: std::sync::Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+    let waker = std::sync::Arc::new(ThreadWaker(std::thread::current())).into();
+    let mut context = std::task::Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            std::task::Poll::Ready(output) => return output,
+            std::task::Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+#[test]
+fn async_sync() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let mut syncer =
+        rusync::r#async::Syncer::new(&src_path, &dest_path, rusync::SyncOptions::default());
+    syncer.exclude("*.exe");
+    let mut progress = syncer.progress();
+    let stats = block_on(syncer.sync()).unwrap();
+    assert_eq!(stats.copied, 4);
+    assert!(dest_path.join("top.txt").exists());
+    assert!(!dest_path.join("a_dir/foo.exe").exists());
+
+    // The stream ends once the sync is done, and no update is lost
+    let mut updates = vec![];
+    while let Some(update) = block_on(progress.next()) {
+        updates.push(update);
+    }
+    assert!(updates.len() >= stats.copied as usize);
+    let last = updates.last().unwrap();
+    assert_eq!(last.total_done, last.total_size);
+
+    // Usable like any stream
+    let mut progress = std::pin::pin!(progress);
+    let end = block_on(std::future::poll_fn(|cx| {
+        rusync::r#async::Stream::poll_next(progress.as_mut(), cx)
+    }));
+    assert!(end.is_none());
+    Ok(())
+}
+
+#[test]
+fn merge_several_sources() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let other_src = tmp_dir.path().join("other");
+    fs::create_dir_all(other_src.join("a_dir"))?;
+    fs::write(other_src.join("a_dir/other.txt"), "other")?;
+    fs::write(other_src.join("top.txt"), "from other")?;
+    let sources = [src_path.clone(), other_src.clone()];
+
+    let syncer = rusync::Syncer::new_multi(
+        &sources,
+        &dest_path,
+        rusync::SyncOptions::default(),
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.errors, 1);
+    assert_eq!(stats.error_list[0].0, PathBuf::from("top.txt"));
+    assert!(dest_path.join("a_dir/one.txt").exists());
+    assert!(dest_path.join("a_dir/other.txt").exists());
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+
+    let options = rusync::SyncOptions {
+        collisions: rusync::CollisionPolicy::LastWins,
+        delete_extraneous: true,
+        ..Default::default()
+    };
+    fs::write(dest_path.join("extra.txt"), "extra")?;
+    let syncer = rusync::Syncer::new_multi(
+        &sources,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.errors, 0);
+    assert_eq!(fs::read_to_string(dest_path.join("top.txt"))?, "from other");
+    // Entries of the first source are not extraneous
+    assert!(dest_path.join("a_dir/one.txt").exists());
+    assert!(!dest_path.join("extra.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn nested_sources() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let sources = [src_path.clone(), src_path.join("a_dir")];
+    let syncer = rusync::Syncer::new_multi(
+        &sources,
+        &dest_path,
+        rusync::SyncOptions::default(),
+        Box::new(DummyProgressInfo {}),
+    );
+    let err = syncer.sync().unwrap_err();
+    assert!(matches!(err, rusync::SyncError::NestedSources(_, _)));
+    assert!(!dest_path.exists());
+    Ok(())
+}
+
+#[test]
+fn normalize_names() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    // Decomposed, as written on macOS
+    let src_dir = src_path.join("Re\u{301}sume\u{301}");
+    fs::create_dir(&src_dir)?;
+    fs::write(src_dir.join("cafe\u{301}.txt"), "coffee")?;
+
+    let options = rusync::SyncOptions {
+        normalization: rusync::Normalization::Nfc,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+    let dest_file = dest_path.join("R\u{e9}sum\u{e9}/caf\u{e9}.txt");
+    assert_same_contents(&src_dir.join("cafe\u{301}.txt"), &dest_file);
+
+    fs::write(src_dir.join("cafe\u{301}.txt"), "more coffee")?;
+    let options = rusync::SyncOptions {
+        normalization: rusync::Normalization::Compare,
+        delete_extraneous: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.deleted, 0);
+    assert_eq!(stats.errors, 0);
+    assert_same_contents(&src_dir.join("cafe\u{301}.txt"), &dest_file);
+    assert!(!dest_path.join("Re\u{301}sume\u{301}").exists());
+    Ok(())
+}
+
+#[test]
+fn case_insensitive_destination() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::write(src_path.join("Top.txt"), "not the top")?;
+    // As if the destination had been written by an older sync
+    fs::create_dir_all(dest_path.join("B_DIR/c_dir"))?;
+    fs::write(dest_path.join("B_DIR/c_dir/THREE.txt"), "three")?;
+
+    let options = rusync::SyncOptions {
+        case_insensitive: true,
+        delete_extraneous: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 1);
+    assert_eq!(stats.copied, 5);
+    assert_eq!(stats.deleted, 0);
+    assert!(dest_path.join("B_DIR/c_dir/THREE.txt").exists());
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn run_hooks() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let hook_output = tmp_dir.path().join("hook.txt");
+    let mut syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.pre_hook(&format!(
+        "test ! -e \"$RUSYNC_DESTINATION\" && echo pre > {:?}",
+        hook_output
+    ));
+    syncer.post_hook(&format!(
+        "echo $RUSYNC_STATUS $RUSYNC_FILES_COPIED >> {:?}",
+        hook_output
+    ));
+    syncer.sync().unwrap();
+    assert_eq!(fs::read_to_string(&hook_output)?, "pre\nsuccess 5\n");
+
+    // The sync does not start when the pre-sync hook fails
+    let other_dest = tmp_dir.path().join("other");
+    let mut syncer = new_test_syncer(&src_path, &other_dest);
+    syncer.pre_hook("exit 3");
+    let err = syncer.sync().unwrap_err();
+    assert!(matches!(err, rusync::SyncError::Hook(_)));
+    assert!(!other_dest.exists());
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn stay_on_one_file_system() -> Result<(), std::io::Error> {
+    use std::os::unix::fs::MetadataExt;
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    // Mounting needs root, but when dereferencing symlinks, a link to a
+    // directory of another file system is just as good
+    let other_fs = match TempDir::new_in("/dev/shm", "test-rusync") {
+        Ok(other_fs) => other_fs,
+        Err(_) => return Ok(()),
+    };
+    if fs::metadata(other_fs.path())?.dev() == fs::metadata(&src_path)?.dev() {
+        return Ok(());
+    }
+    fs::write(other_fs.path().join("other.txt"), "other")?;
+    unix::fs::symlink(other_fs.path(), src_path.join("mounted"))?;
+    let options = rusync::SyncOptions {
+        one_file_system: true,
+        symlinks: rusync::SymlinkPolicy::Dereference,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.errors, 0);
+    assert!(dest_path.join("top.txt").exists());
+    assert!(!dest_path.join("mounted").exists());
+    Ok(())
+}
+
+fn walked_entries(order: rusync::WalkOrder) -> Result<Vec<String>, std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let sizes = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let progress_info = EntriesProgressInfo {
+        sizes: sizes.clone(),
+    };
+    let options = rusync::SyncOptions {
+        walk_order: order,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(&src_path, &dest_path, options, Box::new(progress_info));
+    syncer.sync().unwrap();
+    let sizes = sizes.lock().unwrap();
+    Ok(sizes.iter().map(|(name, _)| name.clone()).collect())
+}
+
+#[test]
+fn walk_order() -> Result<(), std::io::Error> {
+    let sorted = walked_entries(rusync::WalkOrder::Sorted)?;
+    assert_eq!(
+        sorted,
+        [
+            "top.txt",
+            "a_dir/foo.exe",
+            "a_dir/one.txt",
+            "a_dir/two.txt",
+            "b_dir/c_dir/three.txt"
+        ]
+    );
+
+    let breadth_first = walked_entries(rusync::WalkOrder::BreadthFirst)?;
+    assert_eq!(breadth_first.len(), 5);
+    assert_eq!(breadth_first[0], "top.txt");
+    assert_eq!(breadth_first[4], "b_dir/c_dir/three.txt");
+    Ok(())
+}
+
+#[test]
+fn deterministic_log_file() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let options = rusync::SyncOptions {
+        deterministic: true,
+        delete_extraneous: true,
+        ..Default::default()
+    };
+    let sync_with_log = |log_path: &Path| -> Result<String, std::io::Error> {
+        fs::create_dir_all(dest_path.join("old/dir"))?;
+        fs::write(dest_path.join("old/dir/extraneous.txt"), "")?;
+        fs::write(dest_path.join("extraneous.txt"), "")?;
+        let mut syncer = rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options,
+            Box::new(DummyProgressInfo {}),
+        );
+        syncer.num_workers(4);
+        syncer.log_file(log_path)?;
+        syncer.sync().unwrap();
+        fs::read_to_string(log_path)
+    };
+
+    let first = sync_with_log(&tmp_dir.path().join("first.log"))?;
+    fs::remove_dir_all(&dest_path)?;
+    let second = sync_with_log(&tmp_dir.path().join("second.log"))?;
+
+    assert_eq!(first, second);
+    let lines: Vec<&str> = first.lines().collect();
+    assert!(lines[0].starts_with("sync started from "));
+    assert_eq!(lines[1], "copied: top.txt");
+    assert_eq!(lines[2], "copied: a_dir/foo.exe");
+    Ok(())
+}
+
+struct ChunksProgressInfo {
+    done: std::sync::Arc<std::sync::Mutex<Vec<usize>>>,
+}
+
+impl ProgressInfo for ChunksProgressInfo {
+    fn progress(&mut self, progress: &rusync::progress::Progress) {
+        if progress.current_file == "big.bin" {
+            self.done.lock().unwrap().push(progress.file_done);
+        }
+    }
+}
+
+#[test]
+fn copy_with_buffer_size() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 241) as u8).collect();
+    fs::write(src_path.join("big.bin"), &contents)?;
+    let done = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let progress_info = ChunksProgressInfo { done: done.clone() };
+    let options = rusync::SyncOptions {
+        buffer_size: 10_000,
+        reflink: rusync::ReflinkMode::Never,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(&src_path, &dest_path, options, Box::new(progress_info));
+    syncer.sync().unwrap();
+
+    assert_same_contents(&src_path.join("big.bin"), &dest_path.join("big.bin"));
+    // One progress event per chunk
+    let done = done.lock().unwrap();
+    assert_eq!(done.len(), 30);
+    assert_eq!(done.last(), Some(&300_000));
+    Ok(())
+}
@@ -0,0 +1,92 @@
+"""Generate src/unicode_tables.rs from the Unicode database shipped with Python
+
+Usage: python3 tools/gen-unicode-tables.py > src/unicode_tables.rs
+"""
+import sys
+import unicodedata
+
+PER_LINE = 4
+
+
+def rust_char(code):
+    return "'\\u{%X}'" % code
+
+
+def decompositions():
+    res = []
+    for code in range(sys.maxunicode + 1):
+        decomposition = unicodedata.decomposition(chr(code))
+        # Compatibility decompositions start with a <tag>
+        if not decomposition or decomposition.startswith("<"):
+            continue
+        parts = [int(x, 16) for x in decomposition.split()]
+        second = parts[1] if len(parts) > 1 else 0
+        res.append((code, parts[0], second))
+    return res
+
+
+def compositions(decomposed):
+    res = []
+    for code, first, second in decomposed:
+        if not second:
+            continue
+        # Skip composition exclusions: they never appear in NFC
+        if unicodedata.normalize("NFC", chr(code)) != chr(code):
+            continue
+        res.append((first, second, code))
+    return sorted(res)
+
+
+def combining_classes():
+    res = []
+    for code in range(sys.maxunicode + 1):
+        klass = unicodedata.combining(chr(code))
+        if not klass:
+            continue
+        if res and res[-1][1] == code - 1 and res[-1][2] == klass:
+            res[-1][1] = code
+        else:
+            res.append([code, code, klass])
+    return res
+
+
+def print_table(name, type_, rows, format_row):
+    print("#[rustfmt::skip]")
+    print("pub static %s: &[%s] = &[" % (name, type_))
+    for i in range(0, len(rows), PER_LINE):
+        print("    " + " ".join(format_row(row) for row in rows[i : i + PER_LINE]))
+    print("];")
+
+
+def main():
+    decomposed = decompositions()
+    print("// Generated by tools/gen-unicode-tables.py, do not edit")
+    print("// Unicode version: %s" % unicodedata.unidata_version)
+    print()
+    print("// Canonical decompositions: (composed, first, second or '\\0')")
+    print_table(
+        "DECOMPOSITIONS",
+        "(char, char, char)",
+        decomposed,
+        lambda r: "(%s, %s, %s)," % tuple(rust_char(x) for x in r),
+    )
+    print()
+    print("// Primary composites, sorted by (first, second)")
+    print_table(
+        "COMPOSITIONS",
+        "(char, char, char)",
+        compositions(decomposed),
+        lambda r: "(%s, %s, %s)," % tuple(rust_char(x) for x in r),
+    )
+    print()
+    print("// Ranges of characters with a non-zero canonical combining class")
+    print_table(
+        "COMBINING_CLASSES",
+        "(char, char, u8)",
+        combining_classes(),
+        lambda r: "(%s, %s, %d)," % (rust_char(r[0]), rust_char(r[1]), r[2]),
+    )
+
+
+if __name__ == "__main__":
+    main()